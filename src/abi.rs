@@ -0,0 +1,80 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Contract ABI **************************************************************
+//
+// Every function of a loaded ABI defines a constant named after it holding
+// the function id of internal calls. Code dictionaries with 32-bit keys built
+// during the compilation are checked against the functions afterwards.
+
+use ever_block::Result;
+
+use crate::{Engine, Position, Severity, disasm::selectors::abi_functions};
+
+/// Function of the loaded ABI
+pub(crate) struct AbiFunction {
+    id: u32,
+    signature: String,
+}
+
+/// Width of function ids in the dictionary of public functions
+pub(crate) const FUNCTION_ID_BITS: usize = 32;
+
+impl Engine {
+    /// Reads the ABI JSON defining a constant with the id of every function
+    #[cfg(feature = "fs")]
+    pub fn load_abi(&mut self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ever_block::error!("failed to read ABI {}: {}", path.display(), e))?;
+        self.load_abi_json(&text, &path.to_string_lossy())
+    }
+
+    /// Takes the ABI JSON named as given in diagnostics, see `load_abi`
+    pub fn load_abi_json(&mut self, text: &str, name: &str) -> Result<()> {
+        for (id, name, signature) in abi_functions(text)? {
+            let id = id & 0x7FFF_FFFF;
+            self.constants.entry(name).or_insert_with(|| id.to_string());
+            self.abi_functions.push(AbiFunction { id, signature });
+        }
+        self.abi_filename = name.to_string();
+        Ok(())
+    }
+
+    /// Warns about functions of the ABI missing in the code dictionaries
+    /// and about dictionary keys which are not functions of the ABI
+    pub fn check_abi(&mut self) {
+        if self.abi_functions.is_empty() {
+            return
+        }
+        let position = Position::new(self.abi_filename.clone(), 0, 0);
+        let mut missing = Vec::new();
+        for function in &self.abi_functions {
+            if !self.code_dict_keys.contains(&function.id) {
+                missing.push(format!("function {} (id 0x{:08x}) is not in the code dictionary", function.signature, function.id));
+            }
+        }
+        let mut extra = Vec::new();
+        for key in &self.code_dict_keys {
+            if !self.abi_functions.iter().any(|function| function.id == *key) {
+                extra.push(format!("code dictionary key 0x{:08x} is not a function of the ABI", key));
+            }
+        }
+        for message in missing {
+            self.diagnose(Severity::Warning, "abi-missing", position.clone(), message);
+        }
+        for message in extra {
+            self.diagnose(Severity::Warning, "abi-extra", position.clone(), message);
+        }
+    }
+}
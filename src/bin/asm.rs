@@ -11,71 +11,955 @@
  * limitations under the License.
  */
 
-use std::{error::Error, io::Write, process::ExitCode};
+use std::{
+    collections::BTreeMap, error::Error, io::{IsTerminal, Read, Write}, path::{Path, PathBuf}, process::ExitCode,
+    time::{Duration, SystemTime},
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
-use ever_assembler::{DbgInfo, Engine, Units};
-use ever_block::Cell;
+use ever_assembler::{
+    CompileError, DbgInfo, Engine, Opts, PackingPolicy, Severity, TvmVersion, Units,
+    disasm::{disasm_ex, disasm_with_dbg, disasm_with_positions, source::FileSources},
+    format::format_verified,
+    gas::split_hints, libraries::build_library_dict, lint::lint, lsp::publish_diagnostics, manifest::BuildManifest,
+    project::{DEFAULT_PROFILE, DEFAULT_PROJECT_FILE, Project},
+    roundtrip::{check_corpus, check_file},
+    stateinit::{build_stateinit, stateinit_address},
+    stats::{code_stats, opcode_histogram, CodeLimits, LARGEST_CELLS},
+};
+use ever_block::{Cell, Serializable, SliceData, base64_decode, base64_encode, read_boc};
+use serde_json::{json, Value};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(after_help = "Exit codes: 0 success, 1 other failures, 2 invalid arguments, \
+    3 compilation errors, 4 code limits exceeded")]
 struct Args {
-    /// Input assembly sources
+    #[command(subcommand)]
+    command: Option<Commands>,
+    /// Input assembly sources, "-" for stdin
     #[arg(required = true)]
     inputs: Vec<String>,
-    /// Output boc filename ("output.boc" by default)
-    #[arg(short, long)]
+    /// Output boc filename ("output.boc" by default), "-" for stdout
+    #[arg(short, long, visible_alias = "output")]
     boc: Option<String>,
     /// Output debug map filename ("output.debug.json" by default)
     #[arg(short, long)]
     dbg: Option<String>,
+    /// Output StateInit boc filename, the contract address is printed (not written by default)
+    #[arg(long)]
+    stateinit: Option<String>,
+    /// Data cell boc of the StateInit (the one defined by .data by default)
+    #[arg(long, requires = "stateinit")]
+    data: Option<String>,
+    /// Library dictionary boc of the StateInit (the one of --library cells by default)
+    #[arg(long, requires = "stateinit")]
+    libraries: Option<String>,
+    /// Boc with the code of a public library referred to by the code
+    #[arg(long = "library")]
+    library_cells: Vec<String>,
+    /// Output filename of the dictionary of referred libraries found among --library cells
+    #[arg(long)]
+    lib_dict: Option<String>,
+    /// Output filename of the JSON list of referred libraries
+    #[arg(long)]
+    lib_manifest: Option<String>,
+    /// Output filename of the JSON map of exception names to exit codes (not written by default)
+    #[arg(long)]
+    exceptions: Option<String>,
+    /// Workchain of the contract address
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    workchain: i8,
+    /// Encoding of the output bocs
+    #[arg(long, value_enum, default_value_t = BocFormat::Bin)]
+    format: BocFormat,
+    /// Format of the debug map
+    #[arg(long, value_enum, default_value_t = DbgFormat::Json)]
+    dbg_format: DbgFormat,
+    /// Output source map filename (not written by default)
+    #[arg(long)]
+    sourcemap: Option<String>,
+    /// Output directory of the DWARF sections of the debug map (not written by default)
+    #[cfg(feature = "dwarf")]
+    #[arg(long)]
+    dwarf: Option<PathBuf>,
+    /// Warn about straight-line code exceeding this static gas estimate
+    #[arg(long)]
+    gas_threshold: Option<u64>,
+    /// Print cell statistics and the largest cells of the code
+    #[arg(long)]
+    stats: bool,
+    /// Output build manifest filename (not written by default)
+    #[arg(short, long)]
+    manifest: Option<String>,
+    /// Place fragments larger than this number of bits behind CALLREF instead of inlining
+    #[arg(long)]
+    inline_max_bits: Option<usize>,
+    /// Placement of fragments not fitting into the current cell (greedy, balanced, minimize-depth)
+    #[arg(long)]
+    packing: Option<PackingPolicy>,
+    /// Skip failing fragments and write artifacts of the compiled ones to this file on failure
+    #[arg(long)]
+    partial: Option<String>,
+    /// Define a constant (NAME=VALUE)
+    #[arg(short = 'D', long = "define", value_parser = parse_define)]
+    defines: Vec<(String, String)>,
+    /// Directory to look up included files in
+    #[arg(short = 'I', long = "include-path")]
+    include_paths: Vec<PathBuf>,
+    /// Contract ABI defining function id constants and checked against the code dictionary
+    #[arg(long)]
+    abi: Option<String>,
+    /// Directory to keep compiled fragments in for the next builds
+    #[arg(long)]
+    cache: Option<PathBuf>,
+    /// Run the peephole optimizer
+    #[arg(short = 'O', long, conflicts_with = "partial")]
+    optimize: bool,
+    /// Remove code following unconditional returns, jumps and throws
+    #[arg(long, conflicts_with = "partial")]
+    strip_dead_code: bool,
+    /// Go on compiling after errors and report all of them
+    #[arg(long, conflicts_with_all = ["partial", "optimize", "strip_dead_code"])]
+    all_errors: bool,
+    /// Format of errors and warnings, color on a terminal and text otherwise by default
+    #[arg(long, value_enum)]
+    diagnostics: Option<DiagnosticsFormat>,
+    /// Reject instructions newer than this TVM version (base, copyleft, v19, v20)
+    #[arg(long)]
+    target: Option<TvmVersion>,
+    /// Fail if the tree of code cells is deeper than this
+    #[arg(long)]
+    max_depth: Option<u16>,
+    /// Fail if the code takes more cells than this
+    #[arg(long)]
+    max_cells: Option<usize>,
+    /// Fail if the code takes more bits than this
+    #[arg(long)]
+    max_bits: Option<usize>,
+    /// Rebuild on changes of the inputs and of the files in the directory if given
+    #[arg(long, value_name = "DIR", num_args = 0..=1)]
+    watch: Option<Option<PathBuf>>,
+    /// Output filename of the JSON report of the build with its result, code hash, stats and diagnostics
+    #[arg(long)]
+    report: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BocFormat {
+    /// binary boc
+    Bin,
+    /// boc encoded in base64
+    Base64,
+    /// boc encoded in hex
+    Hex,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DbgFormat {
+    /// map of cell hashes to positions
+    Json,
+    /// plain map of cell hashes to positions without the version, as written before version 2
+    JsonV1,
+    /// compact binary form with a table of filenames
+    Binary,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DiagnosticsFormat {
+    /// human-readable lines on stderr
+    Text,
+    /// LSP publishDiagnostics parameters grouped by file on stdout
+    Json,
+    /// text with the source lines of errors, the spans underlined in color
+    Color,
+}
+
+/// Directory of regression samples for the roundtrip check
+const DEFAULT_CORPUS: &str = "corpus/roundtrip";
+
+fn parse_define(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got {}", s))
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Disassemble a code boc back to assembly text
+    Disasm {
+        /// input boc
+        boc: String,
+        /// debug map to comment instructions with source positions
+        #[arg(short, long)]
+        dbg: Option<String>,
+        /// output assembly filename (stdout by default)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// interpret the boc as StateInit and take the code cell
+        #[arg(short, long)]
+        stateinit: bool,
+        /// print full listing w/o collapsing of identical cells
+        #[arg(short, long)]
+        full: bool,
+        /// print source lines found by the debug map above the instructions
+        #[arg(short = 'S', long, requires = "dbg")]
+        source: bool,
+    },
+    /// Format assembly sources checking that the code stays the same
+    Fmt {
+        /// sources to format
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        /// rewrite the files instead of printing them
+        #[arg(short, long)]
+        write: bool,
+        /// only check that the files are formatted
+        #[arg(long, conflicts_with = "write")]
+        check: bool,
+    },
+    /// Check assembly sources for common mistakes
+    Lint {
+        /// sources to check
+        #[arg(required = true)]
+        inputs: Vec<String>,
+    },
+    /// Check that the code boc is the one a build manifest was made for
+    Verify {
+        /// build manifest
+        manifest: String,
+        /// code boc, e.g. taken from the deployed contract
+        boc: String,
+        /// interpret the boc as StateInit and take the code cell
+        #[arg(short, long)]
+        stateinit: bool,
+    },
+    /// Print cell statistics of a code boc
+    Stats {
+        /// input boc
+        boc: String,
+        /// interpret the boc as StateInit and take the code cell
+        #[arg(short, long)]
+        stateinit: bool,
+        /// print the number of instructions and bits by mnemonic
+        #[arg(long)]
+        opcodes: bool,
+    },
+    /// Check that code compiles back to the same cells after disassembling
+    Roundtrip {
+        /// `.code` and `.boc` samples or directories of them ("corpus/roundtrip" by default)
+        paths: Vec<PathBuf>,
+    },
+    /// Build targets of a project file
+    Build {
+        /// targets to build (all by default)
+        targets: Vec<String>,
+        /// project file ("asm.toml" by default)
+        #[arg(short, long)]
+        project: Option<String>,
+        /// build profile ("debug" by default)
+        #[arg(long, conflicts_with = "release")]
+        profile: Option<String>,
+        /// use the release profile
+        #[arg(short, long)]
+        release: bool,
+    },
+}
+
+/// Options of a single assembling run
+struct BuildOptions {
+    inputs: Vec<String>,
+    boc: String,
+    dbg: String,
+    format: BocFormat,
+    dbg_format: DbgFormat,
+    sourcemap: Option<String>,
+    #[cfg(feature = "dwarf")]
+    dwarf: Option<PathBuf>,
+    stateinit: Option<String>,
+    data: Option<String>,
+    libraries: Option<String>,
+    library_cells: Vec<String>,
+    lib_dict: Option<String>,
+    lib_manifest: Option<String>,
+    exceptions: Option<String>,
+    workchain: i8,
+    manifest: Option<String>,
+    stats: bool,
+    gas_threshold: Option<u64>,
+    inline_max_bits: Option<usize>,
+    packing: Option<PackingPolicy>,
+    partial: Option<String>,
+    defines: BTreeMap<String, String>,
+    include_paths: Vec<PathBuf>,
+    abi: Option<String>,
+    cache: Option<PathBuf>,
+    optimize: bool,
+    strip_dead_code: bool,
+    all_errors: bool,
+    diagnostics: DiagnosticsFormat,
+    target: Option<TvmVersion>,
+    limits: CodeLimits,
+    /// keep the boc if it has the same code, the other outputs are written anyway
+    keep_unchanged: bool,
+    report: Option<String>,
+}
+
+/// Exit code of failures other than the ones below
+const EXIT_FAILURE: u8 = 1;
+
+/// Category of a failed build, exiting with its own code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FailureKind {
+    Compile,
+    Limits,
+}
+
+impl FailureKind {
+    fn exit_code(self) -> u8 {
+        match self {
+            FailureKind::Compile => 3,
+            FailureKind::Limits => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BuildFailure {
+    kind: FailureKind,
+    message: String,
+}
+
+impl std::fmt::Display for BuildFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for BuildFailure {}
+
+fn exit_code(error: &(dyn Error + 'static)) -> u8 {
+    error.downcast_ref::<BuildFailure>().map_or(EXIT_FAILURE, |failure| failure.kind.exit_code())
+}
+
+/// Prefixes the message of the error keeping its category
+fn with_context(context: &str, error: Box<dyn Error>) -> Box<dyn Error> {
+    match error.downcast::<BuildFailure>() {
+        Ok(failure) => Box::new(BuildFailure { kind: failure.kind, message: format!("{}: {}", context, failure.message) }),
+        Err(error) => format!("{}: {}", context, error).into(),
+    }
 }
 
 fn main() -> ExitCode {
-    if let Err(e) = main_impl() {
-        eprintln!("{}", e);
-        ExitCode::from(1)
-    } else {
-        ExitCode::from(0)
+    match main_impl() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(exit_code(&*e))
+        }
     }
 }
 
 fn main_impl() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
-    let output = args.boc.unwrap_or("output.boc".to_string());
-    let dbgmap = args.dbg.unwrap_or("output.debug.json".to_string());
+    match args.command {
+        Some(Commands::Disasm { boc, dbg, output, stateinit, full, source }) =>
+            subcommand_disasm(boc, dbg, output, stateinit, full, source),
+        Some(Commands::Fmt { inputs, write, check }) => subcommand_fmt(&inputs, write, check),
+        Some(Commands::Lint { inputs }) => subcommand_lint(&inputs),
+        Some(Commands::Roundtrip { paths }) => subcommand_roundtrip(&paths),
+        Some(Commands::Stats { boc, stateinit, opcodes }) => {
+            let root = read_cell(&boc)?;
+            let code = if stateinit { root.reference(0)? } else { root };
+            print!("{}", code_stats(&code, None, LARGEST_CELLS));
+            if opcodes {
+                print!("{}", opcode_histogram(&code)?);
+            }
+            Ok(())
+        }
+        Some(Commands::Verify { manifest, boc, stateinit }) => {
+            let manifest: BuildManifest = serde_json::from_slice(&std::fs::read(manifest)?)?;
+            let root = read_cell(&boc)?;
+            manifest.verify(&if stateinit { root.reference(0)? } else { root })?;
+            eprintln!("code hash {} matches", manifest.code_hash);
+            Ok(())
+        }
+        Some(Commands::Build { targets, project, profile, release }) => {
+            let profile = match profile {
+                Some(profile) => profile,
+                None if release => "release".to_string(),
+                None => DEFAULT_PROFILE.to_string(),
+            };
+            subcommand_build(project.unwrap_or(DEFAULT_PROJECT_FILE.to_string()), &profile, &targets)
+        }
+        None => {
+            let diagnostics = match args.diagnostics {
+                Some(format) => format,
+                None if std::io::stderr().is_terminal() => DiagnosticsFormat::Color,
+                None => DiagnosticsFormat::Text,
+            };
+            let opts = BuildOptions {
+                inputs: args.inputs,
+                boc: args.boc.unwrap_or("output.boc".to_string()),
+                dbg: args.dbg.unwrap_or("output.debug.json".to_string()),
+                format: args.format,
+                dbg_format: args.dbg_format,
+                sourcemap: args.sourcemap,
+                #[cfg(feature = "dwarf")]
+                dwarf: args.dwarf,
+                stateinit: args.stateinit,
+                data: args.data,
+                libraries: args.libraries,
+                library_cells: args.library_cells,
+                lib_dict: args.lib_dict,
+                lib_manifest: args.lib_manifest,
+                exceptions: args.exceptions,
+                workchain: args.workchain,
+                manifest: args.manifest,
+                stats: args.stats,
+                gas_threshold: args.gas_threshold,
+                inline_max_bits: args.inline_max_bits,
+                packing: args.packing,
+                partial: args.partial,
+                defines: args.defines.into_iter().collect(),
+                include_paths: args.include_paths,
+                abi: args.abi,
+                cache: args.cache,
+                optimize: args.optimize,
+                strip_dead_code: args.strip_dead_code,
+                all_errors: args.all_errors,
+                diagnostics,
+                target: args.target,
+                limits: CodeLimits { max_depth: args.max_depth, max_cells: args.max_cells, max_bits: args.max_bits },
+                keep_unchanged: args.watch.is_some(),
+                report: args.report,
+            };
+            match args.watch {
+                Some(dir) => watch(&opts, dir.as_deref()),
+                None => build(&opts),
+            }
+        }
+    }
+}
+
+/// Machine-readable result of a build
+#[derive(Default)]
+struct BuildReport {
+    code_hash: Option<String>,
+    stats: Option<Value>,
+    diagnostics: Vec<Value>,
+}
+
+impl BuildReport {
+    /// Records the errors and diagnostics flattened from the ones grouped by file
+    fn add_diagnostics(&mut self, errors: &[CompileError], engine: &Engine) {
+        self.diagnostics.clear();
+        for file in publish_diagnostics(errors, engine.diagnostics()).as_array().into_iter().flatten() {
+            for diagnostic in file["diagnostics"].as_array().into_iter().flatten() {
+                let mut diagnostic = diagnostic.clone();
+                diagnostic["file"] = file["uri"].clone();
+                self.diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    fn to_json(&self, result: &Result<(), Box<dyn Error>>) -> Value {
+        json!({
+            "success": result.is_ok(),
+            "exit_code": result.as_ref().map_or_else(|e| exit_code(&**e), |_| 0),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+            "code_hash": self.code_hash,
+            "stats": self.stats,
+            "diagnostics": self.diagnostics,
+        })
+    }
+}
+
+fn build(opts: &BuildOptions) -> Result<(), Box<dyn Error>> {
+    let mut build_report = BuildReport::default();
+    let result = build_reporting(opts, &mut build_report);
+    if let Some(filename) = &opts.report {
+        std::fs::write(filename, serde_json::to_string_pretty(&build_report.to_json(&result))?)?;
+    }
+    result
+}
 
+/// Fails the build with the errors reported in the chosen format
+fn fail(
+    opts: &BuildOptions, kind: FailureKind, errors: &[CompileError], engine: &Engine, build_report: &mut BuildReport
+) -> Box<dyn Error> {
+    build_report.add_diagnostics(errors, engine);
+    let message = report(opts.diagnostics, opts.boc == STDIO, errors, engine).to_string();
+    Box::new(BuildFailure { kind, message })
+}
+
+fn build_reporting(opts: &BuildOptions, build_report: &mut BuildReport) -> Result<(), Box<dyn Error>> {
     let mut engine = Engine::new("");
+    engine.set_inline_max_bits(opts.inline_max_bits);
+    if let Some(policy) = opts.packing {
+        engine.set_packing_policy(policy);
+    }
+    if let Some(version) = opts.target {
+        engine.set_target_version(version);
+    }
+    engine.set_limits(opts.limits);
+    for (name, value) in &opts.defines {
+        engine.define_const(name, value);
+    }
+    for path in &opts.include_paths {
+        engine.add_include_path(path);
+    }
+    if let Some(path) = &opts.abi {
+        engine.load_abi(path)?;
+    }
+    if let Some(dir) = &opts.cache {
+        engine.enable_cache(Some(dir.clone()))?;
+    }
 
+    let compile_opts = Opts { optimize: opts.optimize, strip_dead_code: opts.strip_dead_code };
     let mut units = Units::new();
-    for input in args.inputs {
-        let code = std::fs::read_to_string(input.clone())?;
+    let mut sources = Vec::new();
+    for input in opts.inputs.iter().cloned() {
+        let (input, code) = read_source(&input)?;
+        sources.push((input.clone(), code.clone()));
         engine.reset(input);
-        units = engine.compile_toplevel(&code)
-            .map_err(|e| e.to_string())?;
+        let result = match &opts.partial {
+            None if opts.all_errors => engine.compile_recovering(&code),
+            None => engine.compile_with_opts(&code, compile_opts).map_err(|e| vec!(e)),
+            Some(filename) => match engine.compile_partial(&code) {
+                Ok(units) => Ok(units),
+                Err(partial) => {
+                    std::fs::write(filename, serde_json::to_string_pretty(&partial.to_json()?)?)?;
+                    Err(partial.errors)
+                }
+            }
+        };
+        units = match result {
+            Ok(units) => units,
+            Err(errors) => return Err(fail(opts, FailureKind::Compile, &errors, &engine, build_report)),
+        };
     }
-    let (b, d) = units.finalize();
+    engine.check_abi();
+    report(opts.diagnostics, opts.boc == STDIO, &[], &engine);
+    build_report.add_diagnostics(&[], &engine);
+    if let Some(stats) = engine.cache_stats() {
+        eprintln!("cache: {}", stats);
+    }
+    let (b, d) = engine.finalize(units);
 
     let c = b.into_cell()?;
-    write_boc(&c, &output)?;
+    let mut dbg = DbgInfo::from(c.clone(), d);
+    engine.annotate_dbg(&mut dbg);
+    let stats = code_stats(&c, Some(&dbg), LARGEST_CELLS);
+    build_report.code_hash = Some(c.repr_hash().to_hex_string());
+    build_report.stats = Some(json!({
+        "cells": stats.cells,
+        "bits": stats.bits,
+        "depth": stats.depth,
+        "merged_cells": engine.dedup_stats().merged,
+    }));
+    if let Err(e) = engine.check_limits(&c, &dbg) {
+        return Err(fail(opts, FailureKind::Limits, &[e], &engine, build_report))
+    }
+    // the boc is kept for the watchers of its file, the other outputs may still change
+    if opts.keep_unchanged && read_cell(&opts.boc).map_or(false, |old| old.repr_hash() == c.repr_hash()) {
+        eprintln!("code hash {} is unchanged", c.repr_hash().to_hex_string());
+    } else {
+        write_boc(&c, &opts.boc, opts.format)?;
+        let hash = format!("code hash {}", c.repr_hash().to_hex_string());
+        match opts.boc == STDIO {
+            true => eprintln!("{}", hash),
+            false => println!("{}", hash),
+        }
+    }
+    let library_cells = opts.library_cells.iter().map(|f| read_cell(f)).collect::<Result<Vec<_>, _>>()?;
+    let (lib_dict, lib_manifest) = build_library_dict(&c, &library_cells)?;
+    for (hash, resolved) in &lib_manifest.libraries {
+        if !resolved && (opts.lib_dict.is_some() || !opts.library_cells.is_empty()) {
+            eprintln!("warning: library {} is not found among --library cells", hash);
+        }
+    }
+    if let Some(filename) = &opts.lib_dict {
+        match &lib_dict {
+            Some(dict) => write_boc(dict, filename, opts.format)?,
+            None => eprintln!("warning: no libraries to write to {}", filename),
+        }
+    }
+    if let Some(filename) = &opts.lib_manifest {
+        std::fs::write(filename, serde_json::to_string_pretty(&lib_manifest)?)?;
+    }
+    if let Some(filename) = &opts.exceptions {
+        std::fs::write(filename, serde_json::to_string_pretty(engine.exceptions())?)?;
+    }
+    if let Some(filename) = &opts.stateinit {
+        let data = match opts.data.as_deref() {
+            Some(filename) => Some(read_cell(filename)?),
+            // the data defined by the source
+            None => engine.data().map(|data| data.clone().into_cell()).transpose()?,
+        };
+        let libraries = match &opts.libraries {
+            Some(filename) => Some(read_cell(filename)?),
+            None => lib_dict,
+        };
+        let state_init = build_stateinit(c.clone(), data, libraries);
+        write_boc(&state_init.serialize()?, filename, opts.format)?;
+        let address = stateinit_address(&state_init, opts.workchain)?;
+        match opts.boc == STDIO {
+            true => eprintln!("{}", address),
+            false => println!("{}", address),
+        }
+    }
+
+    if let Some(threshold) = opts.gas_threshold {
+        for hint in split_hints(c.clone(), Some(&dbg), threshold)? {
+            eprintln!("warning: {}", hint);
+        }
+    }
+    if opts.stats {
+        match opts.boc == STDIO {
+            true => eprintln!("{}{}", stats, engine.dedup_stats()),
+            false => println!("{}{}", stats, engine.dedup_stats()),
+        }
+    }
+    if let Some(filename) = &opts.manifest {
+        let mut manifest = BuildManifest::new(&c, &dbg)?;
+        for (name, code) in &sources {
+            manifest.add_source(name, code.as_bytes());
+        }
+        manifest.set_option("inputs", opts.inputs.join(" "));
+        if let Some(threshold) = opts.gas_threshold {
+            manifest.set_option("gas-threshold", threshold);
+        }
+        if let Some(bits) = opts.inline_max_bits {
+            manifest.set_option("inline-max-bits", bits);
+        }
+        if let Some(policy) = opts.packing {
+            manifest.set_option("packing", policy);
+        }
+        if opts.optimize {
+            manifest.set_option("optimize", true);
+        }
+        if opts.strip_dead_code {
+            manifest.set_option("strip-dead-code", true);
+        }
+        if let Some(version) = opts.target {
+            manifest.set_option("target", version);
+        }
+        for (name, value) in &opts.defines {
+            manifest.set_option(&format!("define.{}", name), value);
+        }
+        if let Some(path) = &opts.abi {
+            manifest.set_option("abi", path);
+        }
+        std::fs::write(filename, manifest.to_canonical_json()?)?;
+    }
+    if let Some(filename) = &opts.sourcemap {
+        std::fs::write(filename, serde_json::to_string(&dbg.to_sourcemap())?)?;
+    }
+    #[cfg(feature = "dwarf")]
+    if let Some(dir) = &opts.dwarf {
+        std::fs::create_dir_all(dir)?;
+        let name = opts.inputs.first().map(String::as_str).unwrap_or("stdin");
+        for (section, bytes) in dbg.to_dwarf(name)? {
+            std::fs::write(dir.join(section.trim_start_matches('.')), bytes)?;
+        }
+    }
+    write_dbg(dbg, &opts.dbg, opts.dbg_format)?;
 
-    let dbg = DbgInfo::from(c, d);
-    write_dbg(dbg, &dbgmap)?;
+    Ok(())
+}
+
+fn subcommand_build(filename: String, profile: &str, targets: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(&filename);
+    let project = Project::load(path)?;
+    let root = path.parent().unwrap_or(Path::new(""));
+    for plan in project.plans(root, profile, targets)? {
+        for output in [Some(&plan.boc), Some(&plan.dbg), plan.stateinit.as_ref(), plan.manifest.as_ref()].into_iter().flatten() {
+            if let Some(dir) = output.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        eprintln!("building {} ({})", plan.name, profile);
+        let to_string = |p: &PathBuf| p.to_string_lossy().to_string();
+        build(&BuildOptions {
+            inputs: plan.sources.iter().map(to_string).collect(),
+            boc: to_string(&plan.boc),
+            dbg: to_string(&plan.dbg),
+            format: BocFormat::Bin,
+            dbg_format: DbgFormat::Json,
+            sourcemap: None,
+            #[cfg(feature = "dwarf")]
+            dwarf: None,
+            stateinit: plan.stateinit.as_ref().map(to_string),
+            data: plan.data.as_ref().map(to_string),
+            libraries: None,
+            library_cells: Vec::new(),
+            lib_dict: None,
+            lib_manifest: None,
+            exceptions: None,
+            workchain: plan.workchain,
+            manifest: plan.manifest.as_ref().map(to_string),
+            stats: false,
+            gas_threshold: plan.profile.gas_threshold,
+            inline_max_bits: plan.profile.inline_max_bits,
+            packing: None,
+            partial: None,
+            defines: plan.defines.clone(),
+            include_paths: plan.include_paths.clone(),
+            abi: None,
+            cache: None,
+            optimize: plan.profile.optimize(),
+            strip_dead_code: plan.profile.strip_dead_code(),
+            all_errors: false,
+            diagnostics: DiagnosticsFormat::Text,
+            target: plan.tvm_version,
+            limits: CodeLimits::default(),
+            keep_unchanged: false,
+            report: None,
+        }).map_err(|e| with_context(&format!("target {}", plan.name), e))?;
+    }
+    Ok(())
+}
 
+fn subcommand_fmt(inputs: &[String], write: bool, check: bool) -> Result<(), Box<dyn Error>> {
+    let mut unformatted = Vec::new();
+    for input in inputs {
+        let source = std::fs::read_to_string(input)?;
+        let formatted = format_verified(&source).map_err(|e| format!("{}: {}", input, e))?;
+        if check {
+            if formatted != source {
+                unformatted.push(input.as_str());
+            }
+        } else if write {
+            if formatted != source {
+                std::fs::write(input, formatted)?;
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+    if !unformatted.is_empty() {
+        return Err(format!("not formatted: {}", unformatted.join(", ")).into())
+    }
     Ok(())
 }
 
-fn write_boc(cell: &Cell, output: &str) -> Result<(), Box<dyn Error>> {
+fn subcommand_lint(inputs: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut warnings = 0;
+    for input in inputs {
+        let source = std::fs::read_to_string(input)?;
+        for diagnostic in lint(input, &source).map_err(|e| e.to_string())? {
+            warnings += (diagnostic.severity >= Severity::Warning) as usize;
+            println!("{}", diagnostic);
+        }
+    }
+    if warnings > 0 {
+        return Err(format!("{} warnings", warnings).into())
+    }
+    Ok(())
+}
+
+fn subcommand_roundtrip(paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let default = [PathBuf::from(DEFAULT_CORPUS)];
+    let paths = if paths.is_empty() { &default[..] } else { paths };
+    let mut failures = Vec::new();
+    for path in paths {
+        match path.is_dir() {
+            true => failures.extend(check_corpus(path)?),
+            false => if let Err(e) = check_file(path) {
+                failures.push((path.clone(), e.to_string()));
+            }
+        }
+    }
+    for (path, error) in &failures {
+        eprintln!("{}: {}", path.display(), error);
+    }
+    if !failures.is_empty() {
+        return Err(format!("{} samples failed", failures.len()).into())
+    }
+    Ok(())
+}
+
+fn subcommand_disasm(
+    filename: String,
+    dbg: Option<String>,
+    output: Option<String>,
+    stateinit: bool,
+    full: bool,
+    source: bool,
+) -> Result<(), Box<dyn Error>> {
+    let boc = std::fs::read(filename)?;
+    let roots = read_boc(boc)?.roots;
+    let root = roots.get(0).ok_or("boc is empty")?;
+    let cell = if stateinit {
+        root.reference(0)?
+    } else {
+        root.clone()
+    };
+    let mut slice = SliceData::load_cell(cell)?;
+    let text = match dbg {
+        Some(dbg) => {
+            let dbg = read_dbg(&dbg)?;
+            match source {
+                true => disasm_with_dbg(&mut slice, &dbg, &FileSources::new("."))?,
+                false => disasm_with_positions(&mut slice, !full, &dbg)?,
+            }
+        }
+        None => disasm_ex(&mut slice, !full)?,
+    };
+    match output {
+        Some(output) => std::fs::write(output, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+/// Prints warnings and errors returning the summary of the errors,
+/// JSON goes to stderr if stdout is taken by the boc
+fn report(format: DiagnosticsFormat, to_stderr: bool, errors: &[CompileError], engine: &Engine) -> Box<dyn Error> {
+    match format {
+        DiagnosticsFormat::Text => {
+            for diagnostic in engine.diagnostics() {
+                eprintln!("{}", diagnostic);
+            }
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n").into()
+        }
+        DiagnosticsFormat::Json => {
+            let json = publish_diagnostics(errors, engine.diagnostics());
+            match to_stderr {
+                true => eprintln!("{}", json),
+                false => println!("{}", json),
+            }
+            format!("{} errors", errors.len()).into()
+        }
+        DiagnosticsFormat::Color => {
+            for diagnostic in engine.diagnostics() {
+                eprintln!("{}", diagnostic);
+            }
+            errors.iter().map(render_colored).collect::<String>().trim_end().to_string().into()
+        }
+    }
+}
+
+/// Error rendered with its source line read from the file it refers to
+fn render_colored(error: &CompileError) -> String {
+    let filename = [error.innermost(), error].iter()
+        .map(|e| e.position().filename.clone())
+        .find(|filename| !filename.is_empty());
+    let source = filename.and_then(|filename| std::fs::read_to_string(filename).ok());
+    error.render(source.as_deref(), true)
+}
+
+/// Period of checking the watched files for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.is_dir() {
+            true => collect_files(&path, files)?,
+            false => files.push(path),
+        }
+    }
+    Ok(())
+}
+
+/// Modification times of the watched files
+fn watched_files(opts: &BuildOptions, dir: Option<&Path>) -> Result<Vec<(PathBuf, Option<SystemTime>)>, Box<dyn Error>> {
+    let mut files = opts.inputs.iter().map(PathBuf::from).collect::<Vec<_>>();
+    if let Some(dir) = dir {
+        collect_files(dir, &mut files)?;
+    }
+    files.sort();
+    files.dedup();
+    Ok(files.into_iter().map(|path| {
+        let modified = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        (path, modified)
+    }).collect())
+}
+
+/// Rebuilds whenever the inputs or the files of the directory change, until interrupted
+fn watch(opts: &BuildOptions, dir: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    if opts.inputs.iter().any(|input| input == STDIO) {
+        return Err("stdin can't be watched".into())
+    }
+    let mut last = None;
+    loop {
+        let files = watched_files(opts, dir)?;
+        if last.as_ref() != Some(&files) {
+            match build(opts) {
+                Ok(()) => eprintln!("built {}", opts.boc),
+                Err(e) => eprintln!("{}", e),
+            }
+            eprintln!("watching {} files for changes", files.len());
+            last = Some(files);
+        }
+        std::thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Name of stdin as an input and of stdout as an output
+const STDIO: &str = "-";
+
+/// Reads the source returning it along with the name to use in positions
+fn read_source(input: &str) -> Result<(String, String), Box<dyn Error>> {
+    if input == STDIO {
+        let mut code = String::new();
+        std::io::stdin().read_to_string(&mut code)?;
+        return Ok(("<stdin>".to_string(), code))
+    }
+    Ok((input.to_string(), std::fs::read_to_string(input)?))
+}
+
+fn write_boc(cell: &Cell, output: &str, format: BocFormat) -> Result<(), Box<dyn Error>> {
     let bytes = ever_block::write_boc(cell)?;
+    let bytes = match format {
+        BocFormat::Bin => bytes,
+        BocFormat::Base64 => base64_encode(bytes).into_bytes(),
+        BocFormat::Hex => hex::encode(bytes).into_bytes(),
+    };
+    if output == STDIO {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&bytes)?;
+        stdout.flush()?;
+        return Ok(())
+    }
     let mut file = std::fs::File::create(output)?;
     file.write_all(&bytes)?;
     Ok(())
 }
 
-fn write_dbg(dbg: DbgInfo, output: &str) -> Result<(), Box<dyn Error>> {
-    let json = serde_json::to_string_pretty(&dbg)?;
+fn write_dbg(dbg: DbgInfo, output: &str, format: DbgFormat) -> Result<(), Box<dyn Error>> {
     let mut file = std::fs::File::create(output)?;
-    file.write_all(json.as_bytes())?;
+    match format {
+        DbgFormat::Json => file.write_all(serde_json::to_string_pretty(&dbg)?.as_bytes())?,
+        DbgFormat::JsonV1 => file.write_all(serde_json::to_string_pretty(&dbg.flat())?.as_bytes())?,
+        DbgFormat::Binary => dbg.write_binary(&mut file)?,
+    }
     Ok(())
 }
+
+/// Reads the root cell of the boc, binary or encoded in base64 or hex
+fn read_cell(filename: &str) -> Result<Cell, Box<dyn Error>> {
+    let bytes = std::fs::read(filename)?;
+    let bytes = match std::str::from_utf8(&bytes).map(str::trim) {
+        Ok(text) if !text.is_empty() => match hex::decode(text) {
+            Ok(bytes) => bytes,
+            Err(_) => base64_decode(text)?,
+        }
+        _ => bytes,
+    };
+    let roots = read_boc(bytes)?.roots;
+    Ok(roots.get(0).ok_or_else(|| format!("{} is empty", filename))?.clone())
+}
+
+/// Reads the debug map in either format
+fn read_dbg(filename: &str) -> Result<DbgInfo, Box<dyn Error>> {
+    let bytes = std::fs::read(filename)?;
+    if DbgInfo::is_binary(&bytes) {
+        return Ok(DbgInfo::read_binary(&mut bytes.as_slice())?)
+    }
+    Ok(serde_json::from_slice(&bytes)?)
+}
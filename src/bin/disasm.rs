@@ -11,12 +11,15 @@
  * limitations under the License.
  */
 
-use std::{process::ExitCode, collections::HashSet, io::Write};
+use std::{process::ExitCode, collections::HashSet, io::Write, str::FromStr};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
-use ever_assembler::disasm::{fmt::print_tree_of_cells, loader::Loader, disasm_ex};
-use ever_block::{error, Cell, Status, read_boc, SliceData, write_boc};
+use ever_assembler::disasm::{
+    diff, extract_constants, find, find_cell, fmt::print_tree_of_cells, idioms::IdiomMode, loader::Loader, selectors::SelectorRegistry, symbols::SymbolMap, DisasmOptions,
+};
+use ever_assembler::DbgInfo;
+use ever_block::{error, Cell, Status, read_boc, SliceData, UInt256, write_boc};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -59,7 +62,112 @@ enum Commands {
         /// print full assembler listing w/o collapsing of identical cells
         #[arg(short, long)]
         full: bool,
+        #[command(flatten)]
+        selection: Selection,
+        #[command(flatten)]
+        listing: Listing,
+        #[command(flatten)]
+        annotations: Annotations,
     },
+    /// Print instruction level differences of two code bocs as a unified diff
+    Diff {
+        /// old code boc
+        old: String,
+        /// new code boc
+        new: String,
+        /// interpret the bocs as StateInit and take the code cells
+        #[arg(short, long)]
+        stateinit: bool,
+        /// number of unchanged instructions shown around the changes
+        #[arg(short = 'U', long, default_value_t = 3)]
+        context: usize,
+    },
+    /// Find sequences of instructions in a code boc, e.g. "PUSHINT * ; SENDRAWMSG"
+    Find {
+        /// input boc
+        boc: String,
+        /// instructions separated by ';', '*' matches any operands or instruction
+        pattern: String,
+        /// interpret the boc as StateInit and take the code cell
+        #[arg(short, long)]
+        stateinit: bool,
+    },
+    /// List constants pushed by the code of a boc with their locations
+    Constants {
+        /// input boc
+        boc: String,
+        /// interpret the boc as StateInit and take the code cell
+        #[arg(short, long)]
+        stateinit: bool,
+    },
+    /// Print the control-flow graph of a code boc in Graphviz DOT format
+    Cfg {
+        /// input boc
+        boc: String,
+        /// interpret the boc as StateInit and take the code cell
+        #[arg(short, long)]
+        stateinit: bool,
+    },
+}
+
+/// Part of the code to list, the whole code by default
+#[derive(Args)]
+struct Selection {
+    /// list only the method of the dispatcher with this id
+    #[arg(long, allow_hyphen_values = true, conflicts_with = "cell")]
+    method: Option<i64>,
+    /// list only the code starting at the cell with this hash
+    #[arg(long)]
+    cell: Option<String>,
+}
+
+/// Layout of the listing
+#[derive(Args)]
+struct Listing {
+    /// number of nested cells to expand, deeper ones are collapsed
+    #[arg(long)]
+    max_depth: Option<usize>,
+    /// list referenced cells in separate sections instead of inline
+    #[arg(long)]
+    sections: bool,
+    /// prefix instructions with their cell hashes and bit offsets, along with raw bytes
+    #[arg(long)]
+    offsets: bool,
+    /// show raw bytes of instructions
+    #[arg(long)]
+    bytes: bool,
+    /// omit hashes of cells
+    #[arg(long)]
+    no_hashes: bool,
+    /// list methods of dictionary dispatchers by their ids with the calls between them
+    #[arg(long)]
+    methods: bool,
+    /// listing of known compiler idioms (off, annotate, fold)
+    #[arg(long, default_value = "fold")]
+    idioms: IdiomMode,
+}
+
+/// Width of the raw bytes column
+const BYTECODE_WIDTH: usize = 12;
+
+/// Sources of comments and names added to the listing
+#[derive(Args)]
+struct Annotations {
+    /// registry of known selectors (JSON object mapping names to ids)
+    #[arg(long)]
+    selectors: Vec<String>,
+    /// contract ABI to take function ids and names from
+    #[arg(long)]
+    abi: Vec<String>,
+    /// symbol file naming methods and cells
+    #[arg(long)]
+    symbols: Vec<String>,
+    /// debug map to title cells with source positions
+    #[arg(long)]
+    dbg: Option<String>,
+    /// comment instructions with their static gas
+    #[arg(long)]
+    gas: bool,
 }
 
 fn main() -> ExitCode {
@@ -78,7 +186,12 @@ fn main_impl() -> Status {
         Commands::Extract { boc, output_boc, index, root } =>
             subcommand_extract(boc, output_boc, index, root),
         Commands::Fragment { bitstring } => subcommand_fragment(bitstring),
-        Commands::Text { boc, stateinit, full } => subcommand_text(boc, stateinit, full),
+        Commands::Text { boc, stateinit, full, selection, listing, annotations } =>
+            subcommand_text(boc, stateinit, full, selection, listing, annotations),
+        Commands::Diff { old, new, stateinit, context } => subcommand_diff(old, new, stateinit, context),
+        Commands::Find { boc, pattern, stateinit } => subcommand_find(boc, pattern, stateinit),
+        Commands::Constants { boc, stateinit } => subcommand_constants(boc, stateinit),
+        Commands::Cfg { boc, stateinit } => subcommand_cfg(boc, stateinit),
     }
 }
 
@@ -141,13 +254,49 @@ fn subcommand_fragment(fragment: String) -> Status {
 
     let mut loader = Loader::new(false);
     let code = loader.load(&mut slice, false)?;
-    let text = code.print("", true, 12);
+    let text = code.print("", true, BYTECODE_WIDTH);
 
     print!("{}", text);
     Ok(())
 }
 
-fn subcommand_text(filename: String, stateinit: bool, full: bool) -> Status {
+fn subcommand_text(
+    filename: String,
+    stateinit: bool,
+    full: bool,
+    selection: Selection,
+    listing: Listing,
+    annotations: Annotations,
+) -> Status {
+    let Annotations { selectors, abi, symbols, dbg, gas } = annotations;
+    let mut registry = SelectorRegistry::new();
+    let mut symbol_map = SymbolMap::new();
+    for filename in selectors {
+        let text = std::fs::read_to_string(&filename)
+            .map_err(|e| error!("failed to read selectors file: {}", e))?;
+        registry.add_json(&text).map_err(|e| error!("{}: {}", filename, e))?;
+    }
+    for filename in abi {
+        let text = std::fs::read_to_string(&filename)
+            .map_err(|e| error!("failed to read abi file: {}", e))?;
+        registry.add_abi(&text).map_err(|e| error!("{}: {}", filename, e))?;
+        symbol_map.add_abi(&text).map_err(|e| error!("{}: {}", filename, e))?;
+    }
+    for filename in symbols {
+        let text = std::fs::read_to_string(&filename)
+            .map_err(|e| error!("failed to read symbols file: {}", e))?;
+        symbol_map.add_json(&text).map_err(|e| error!("{}: {}", filename, e))?;
+    }
+    if let Some(filename) = dbg {
+        let bytes = std::fs::read(&filename)
+            .map_err(|e| error!("failed to read debug map: {}", e))?;
+        let dbg = match DbgInfo::is_binary(&bytes) {
+            true => DbgInfo::read_binary(&mut bytes.as_slice()).map_err(|e| error!("{}: {}", filename, e))?,
+            false => serde_json::from_slice::<DbgInfo>(&bytes).map_err(|e| error!("{}: {}", filename, e))?,
+        };
+        symbol_map.add_dbg(&dbg);
+    }
+
     let boc = std::fs::read(filename)
         .map_err(|e| error!("failed to read input file: {}", e))?;
     let roots = read_boc(boc).map_err(|e| error!("{}", e))?.roots;
@@ -168,6 +317,95 @@ fn subcommand_text(filename: String, stateinit: bool, full: bool) -> Status {
         root0.clone()
     };
 
-    print!("{}", disasm_ex(&mut SliceData::load_cell(cell)?, !full)?);
+    let opts = DisasmOptions {
+        collapse: !full,
+        max_depth: listing.max_depth,
+        sections: listing.sections,
+        offsets: listing.offsets,
+        bytecode_width: if listing.bytes || listing.offsets { BYTECODE_WIDTH } else { 0 },
+        hashes: !listing.no_hashes,
+        methods: listing.methods,
+        idioms: listing.idioms,
+    };
+    let cell = match selection.cell {
+        Some(hash) => {
+            let hash = UInt256::from_str(&hash).ok().ok_or_else(|| error!("invalid hash {}", hash))?;
+            find_cell(&cell, &hash).ok_or_else(|| error!("cell {} is not found", hash.to_hex_string()))?
+        }
+        None => cell,
+    };
+    let mut code = Loader::with_options(&opts).load(&mut SliceData::load_cell(cell)?, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    if let Some(id) = selection.method {
+        code = code.method(id).ok_or_else(|| error!("method {} is not found", id))?.code;
+    }
+    code.annotate_selectors(&registry);
+    code.annotate_symbols(&symbol_map);
+    if gas {
+        code.annotate_gas();
+    }
+    code.recognize_idioms(opts.idioms);
+    print!("{}", code.print_with(&opts));
+    Ok(())
+}
+
+fn read_code(filename: &str, stateinit: bool) -> ever_block::Result<Cell> {
+    let boc = std::fs::read(filename)
+        .map_err(|e| error!("failed to read {}: {}", filename, e))?;
+    let roots = read_boc(boc).map_err(|e| error!("{}", e))?.roots;
+    let root0 = roots.get(0)
+        .ok_or_else(|| error!("boc {} is empty", filename))?;
+    if stateinit {
+        root0.reference(0)
+    } else {
+        Ok(root0.clone())
+    }
+}
+
+fn subcommand_diff(old: String, new: String, stateinit: bool, context: usize) -> Status {
+    let diff = diff(&read_code(&old, stateinit)?, &read_code(&new, stateinit)?);
+    if diff.is_empty() {
+        println!("code is the same");
+        return Ok(())
+    }
+    print!("{}", diff.unified(context));
+    let (added, removed, changed) = diff.summary();
+    println!("{} added, {} removed, {} changed instructions", added, removed, changed);
+    Ok(())
+}
+
+fn subcommand_find(filename: String, pattern: String, stateinit: bool) -> Status {
+    let cell = read_code(&filename, stateinit)?;
+    let found = find(&mut SliceData::load_cell(cell)?, &pattern)?;
+    for m in &found {
+        println!("{}:{}  {}", m.cell.to_hex_string(), m.offset, m.insns.join("; "));
+    }
+    eprintln!("{} matches", found.len());
+    Ok(())
+}
+
+fn subcommand_constants(filename: String, stateinit: bool) -> Status {
+    let cell = read_code(&filename, stateinit)?;
+    for constant in extract_constants(&mut SliceData::load_cell(cell)?)? {
+        println!("{}:{}  {:<12} {}", constant.cell.to_hex_string(), constant.offset, constant.insn, constant.value);
+    }
+    Ok(())
+}
+
+fn subcommand_cfg(filename: String, stateinit: bool) -> Status {
+    let boc = std::fs::read(filename)
+        .map_err(|e| error!("failed to read input file: {}", e))?;
+    let roots = read_boc(boc).map_err(|e| error!("{}", e))?.roots;
+    let root0 = roots.get(0)
+        .ok_or_else(|| error!("boc is empty"))?;
+    let cell = if stateinit {
+        root0.reference(0)?
+    } else {
+        root0.clone()
+    };
+
+    let mut code = Loader::new(false).load(&mut SliceData::load_cell(cell)?, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    print!("{}", code.to_cfg().to_dot());
     Ok(())
 }
@@ -0,0 +1,236 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Programmatic construction of TVM code
+//!
+//! [`Asm`] writes instructions through the same handlers and cell writer as the
+//! text assembler, so the result is identical to compiling the equivalent source,
+//! while nested continuations are built by closures instead of text blocks:
+//!
+//! ```ignore
+//! let (code, dbg) = Asm::new().pushint(5).pushcont(|b| b.nop()).finalize()?;
+//! ```
+//!
+//! The first failing instruction is remembered and reported by [`Asm::finalize`];
+//! instructions written after it are ignored. Debug positions refer to the
+//! sequential number of the instruction unless set with [`Asm::loc`].
+
+use ever_block::{BuilderData, SliceData};
+
+use crate::{
+    CompileError, CompileResult, DbgInfo, DbgNode, DbgPos, Engine, OperationError, Position,
+    TvmVersion, Unit, Units, Writer,
+    complex::{ref_command, write_cont, write_ref},
+};
+
+pub struct Asm {
+    engine: Engine,
+    units: Units,
+    /// number of instructions written so far
+    count: usize,
+    error: Option<CompileError>,
+}
+
+impl Default for Asm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Asm {
+    pub fn new() -> Self {
+        Self::with_source_name("")
+    }
+
+    /// Creates the builder naming the source in debug positions and errors
+    pub fn with_source_name(name: &str) -> Self {
+        Self { engine: Engine::new(name), units: Units::new(), count: 0, error: None }
+    }
+
+    /// Sets the source position of the following instructions, as `.loc` does;
+    /// line 0 resets it to the instruction numbers
+    pub fn loc(mut self, filename: &str, line: usize) -> Self {
+        self.engine.dbgpos = match line {
+            0 => None,
+            line => Some(DbgPos { filename: self.engine.files.intern(filename), line, ..Default::default() }),
+        };
+        self
+    }
+
+    /// Rejects instructions introduced by TVM versions later than the given one
+    pub fn target(mut self, version: TvmVersion) -> Self {
+        self.engine.set_target_version(version);
+        self
+    }
+
+    /// Writes an instruction with the operands given as in the source,
+    /// e.g. `insn("PUSH", &["s2"])`
+    pub fn insn(self, name: &str, operands: &[&str]) -> Self {
+        let name = name.to_ascii_uppercase();
+        if let Some(version) = self.engine.unsupported(&name) {
+            let position = Position::new(self.engine.source_name.to_string(), self.count + 1, 1);
+            return self.reject(CompileError::UnsupportedForTarget(position, name, version))
+        }
+        self.write(&name, |engine, units, pos| {
+            let handler = *engine.handlers.get(name.as_str())
+                .ok_or_else(|| OperationError::Internal(format!("unknown instruction {}", name)))?;
+            // handlers tell the instruction form without an argument by the zero position
+            let (line, column) = match operands.is_empty() {
+                true => (0, 0),
+                false => (pos.line, 1),
+            };
+            let saved = engine.set_pos(line, column);
+            let result = handler(engine, operands, units, pos);
+            engine.set_pos(saved.0, saved.1);
+            result
+        })
+    }
+
+    /// Writes the code built separately, e.g. a fragment shared by several places
+    pub fn inline(self, unit: Unit) -> Self {
+        self.write(".INLINE", |_, units, _| units.write_unit(unit))
+    }
+
+    pub fn nop(self) -> Self { self.insn("NOP", &[]) }
+    pub fn ret(self) -> Self { self.insn("RET", &[]) }
+    pub fn swap(self) -> Self { self.insn("SWAP", &[]) }
+    pub fn dup(self) -> Self { self.insn("DUP", &[]) }
+    pub fn drop(self) -> Self { self.insn("DROP", &[]) }
+    pub fn execute(self) -> Self { self.insn("EXECUTE", &[]) }
+    pub fn ifelse(self) -> Self { self.insn("IFELSE", &[]) }
+
+    pub fn pushint(self, value: i64) -> Self {
+        self.insn("PUSHINT", &[&value.to_string()])
+    }
+    pub fn push(self, index: u8) -> Self {
+        self.insn("PUSH", &[&format!("s{}", index)])
+    }
+    pub fn pop(self, index: u8) -> Self {
+        self.insn("POP", &[&format!("s{}", index)])
+    }
+    pub fn throw(self, code: u16) -> Self {
+        self.insn("THROW", &[&code.to_string()])
+    }
+    pub fn throwif(self, code: u16) -> Self {
+        self.insn("THROWIF", &[&code.to_string()])
+    }
+    pub fn throwifnot(self, code: u16) -> Self {
+        self.insn("THROWIFNOT", &[&code.to_string()])
+    }
+
+    /// Pushes the continuation built by the closure
+    pub fn pushcont(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.block("PUSHCONT", body, write_cont)
+    }
+    pub fn callref(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.with_ref("CALLREF", body)
+    }
+    pub fn jmpref(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.with_ref("JMPREF", body)
+    }
+    pub fn ifref(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.with_ref("IFREF", body)
+    }
+    pub fn ifnotref(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.with_ref("IFNOTREF", body)
+    }
+    pub fn ifjmpref(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.with_ref("IFJMPREF", body)
+    }
+    pub fn ifnotjmpref(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.with_ref("IFNOTJMPREF", body)
+    }
+    pub fn pushrefcont(self, body: impl FnOnce(Asm) -> Asm) -> Self {
+        self.with_ref("PUSHREFCONT", body)
+    }
+
+    /// Writes an instruction taking the continuation built by the closure by reference
+    pub fn with_ref(self, name: &str, body: impl FnOnce(Asm) -> Asm) -> Self {
+        let name = name.to_ascii_uppercase();
+        let (command, role) = match ref_command(&name) {
+            Some(command) => command,
+            None => return self.fail(&name, OperationError::Internal(format!("{} does not take a reference", name)))
+        };
+        self.block(&name, body, |cont, dbg, units, pos| write_ref(command, role, cont, dbg, units, pos))
+    }
+
+    /// Puts the code into a cell tree
+    pub fn build(self) -> Result<Unit, CompileError> {
+        if let Some(error) = self.error {
+            return Err(error)
+        }
+        let mut engine = self.engine;
+        let (builder, dbg) = engine.finalize(self.units);
+        Ok(Unit::new(builder, dbg).with_diagnostics(engine.take_diagnostics()))
+    }
+
+    pub fn finalize(self) -> Result<(SliceData, DbgInfo), CompileError> {
+        Ok(self.build()?.finalize())
+    }
+
+    fn fail(self, name: &str, error: OperationError) -> Self {
+        let error = CompileError::operation(self.count, 1, name, error)
+            .with_filename(self.engine.source_name.to_string());
+        self.reject(error)
+    }
+
+    fn reject(mut self, error: CompileError) -> Self {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+        self
+    }
+
+    fn next_pos(&mut self) -> DbgPos {
+        self.count += 1;
+        self.engine.dbgpos.clone()
+            .unwrap_or_else(|| DbgPos { filename: self.engine.source_name.clone(), line: self.count, ..Default::default() })
+    }
+
+    fn write(mut self, name: &str, f: impl FnOnce(&mut Engine, &mut dyn Writer, DbgPos) -> CompileResult) -> Self {
+        if self.error.is_some() {
+            return self
+        }
+        let pos = self.next_pos();
+        match f(&mut self.engine, &mut self.units, pos) {
+            Ok(()) => self,
+            Err(e) => self.fail(name, e),
+        }
+    }
+
+    /// Builds the nested code with the same engine and writes it by the given function
+    fn block(
+        mut self,
+        name: &str,
+        body: impl FnOnce(Asm) -> Asm,
+        write: impl FnOnce(BuilderData, DbgNode, &mut dyn Writer, DbgPos) -> CompileResult,
+    ) -> Self {
+        if self.error.is_some() {
+            return self
+        }
+        let pos = self.next_pos();
+        let Asm { engine, units, count, .. } = self;
+        let inner = body(Asm { engine, units: Units::new(), count, error: None });
+        let Asm { engine, units: nested, count, error } = inner;
+        let outer = Asm { engine, units, count, error: None };
+        if let Some(error) = error {
+            return outer.fail(name, OperationError::Nested(Box::new(error)))
+        }
+        let (cont, dbg) = nested.finalize();
+        let mut outer = outer;
+        match write(cont, dbg, &mut outer.units, pos) {
+            Ok(()) => outer,
+            Err(e) => outer.fail(name, e),
+        }
+    }
+}
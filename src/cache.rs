@@ -0,0 +1,298 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Cache of compiled fragments
+//!
+//! A fragment is looked up by the sha256 of its body along with the options
+//! the code depends on: target version, codepage, inline limit, packing policy
+//! and the constants defined, as well as the version of the assembler and of
+//! the cache format, so entries written by another encoder are not served.
+//! Bodies referring to other fragments, macros or
+//! files are compiled every time, and so are the ones producing diagnostics.
+//! Debug positions are kept relative to the start of the body, so a fragment
+//! moved around the source is still found. Entries are kept in memory and,
+//! if a directory is given and the `fs` feature is on, in its files named by the key.
+
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf, sync::Arc};
+use serde::{Deserialize, Serialize};
+use ever_block::{error, read_boc, sha256_digest, write_boc, BuilderData, Result};
+
+use crate::{DbgNode, Engine, lexer::mentions_any};
+
+/// Directives and instructions making the code of a body depend on more than its text
+pub(crate) const DEPENDENT: [&str; 14] = [
+    ".inline", ".inline-computed", ".inline-computed-cell", ".expand", ".include", ".code-dict-cell",
+    ".code-dict-slice", ".method", ".method-id", ".dispatch", ".fragment", ".fragref", ".loc", "callproc",
+];
+
+/// Version of the layout of cache entries, to be bumped when it changes
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Counters of cache lookups
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// fragments added to the cache
+    pub stored: usize,
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} hits, {} misses, {} stored", self.hits, self.misses, self.stored)
+    }
+}
+
+/// Compiled fragment with positions relative to the start of its body
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// boc of the code in hex
+    code: String,
+    dbg: DbgNode,
+}
+
+#[derive(Default)]
+pub(crate) struct CompileCache {
+    entries: HashMap<String, CacheEntry>,
+    dir: Option<PathBuf>,
+    stats: CacheStats,
+}
+
+/// Whether the code of the body depends on its text and the options only
+pub(crate) fn is_cacheable(body: &str) -> bool {
+    !mentions_any(body, &DEPENDENT)
+}
+
+/// Moves positions so that the ones at the start `from` end up at `to`
+fn rebase(dbg: &mut DbgNode, filename: &Arc<str>, from: (usize, usize), to: (usize, usize)) {
+    for (_, pos) in dbg.offsets.iter_mut() {
+        if pos.line == from.0 && pos.column != 0 {
+            pos.column = pos.column + to.1 - from.1;
+        }
+        pos.line = pos.line + to.0 - from.0;
+        pos.filename = filename.clone();
+    }
+    for child in dbg.children.iter_mut() {
+        rebase(child, filename, from, to);
+    }
+}
+
+impl CompileCache {
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{}.json", key)))
+    }
+
+    #[cfg(feature = "fs")]
+    fn load(&self, key: &str) -> Option<CacheEntry> {
+        let text = std::fs::read_to_string(self.path(key)?).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    #[cfg(not(feature = "fs"))]
+    fn load(&self, _key: &str) -> Option<CacheEntry> {
+        None
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key) || self.path(key).map_or(false, |path| path.exists())
+    }
+
+    fn get(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = match self.entries.get(key) {
+            Some(entry) => Some(entry.clone()),
+            None => self.load(key),
+        };
+        match entry {
+            Some(entry) => {
+                self.stats.hits += 1;
+                self.entries.insert(key.to_string(), entry.clone());
+                Some(entry)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: CacheEntry) {
+        #[cfg(feature = "fs")]
+        if let Some(path) = self.path(&key) {
+            let written = serde_json::to_string(&entry).map_err(|e| error!("{}", e))
+                .and_then(|text| std::fs::write(&path, text).map_err(|e| error!("{}", e)));
+            if let Err(e) = written {
+                log::warn!(target: "tvm", "cannot write {}: {}", path.display(), e);
+            }
+        }
+        self.entries.insert(key, entry);
+        self.stats.stored += 1;
+    }
+}
+
+impl Engine {
+    /// Caches compiled fragments in memory and, if the directory is given, on disk
+    pub fn enable_cache(&mut self, dir: Option<PathBuf>) -> Result<()> {
+        if let Some(dir) = &dir {
+            #[cfg(feature = "fs")]
+            std::fs::create_dir_all(dir)?;
+            #[cfg(not(feature = "fs"))]
+            ever_block::fail!("cache directory {} needs the fs feature", dir.display());
+        }
+        self.cache = Some(CompileCache { dir, ..Default::default() });
+        Ok(())
+    }
+
+    /// Lookups of the cache so far, None if it is not enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats)
+    }
+
+    /// Key of the body if the engine is in a state to cache its code
+    pub(crate) fn cache_key(&self, body: &str) -> Option<String> {
+        if self.cache.is_none() || self.ir.is_some() || self.dbgpos.is_some() || !is_cacheable(body) {
+            return None
+        }
+        let constants = self.constants.iter().collect::<BTreeMap<_, _>>();
+        let options = format!("{} {} {:?} {} {:?} {:?} {:?}",
+            env!("CARGO_PKG_VERSION"), CACHE_FORMAT_VERSION,
+            self.target_version, self.code_page, self.inline_max_bits, self.packing_policy, constants);
+        let mut data = body.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(options.as_bytes());
+        Some(hex::encode(sha256_digest(&data)))
+    }
+
+    /// Whether the code of the body is in the cache, without counting a lookup
+    pub(crate) fn is_cached(&self, body: &str) -> bool {
+        match (self.cache.as_ref(), self.cache_key(body)) {
+            (Some(cache), Some(key)) => cache.contains(&key),
+            _ => false
+        }
+    }
+
+    /// Cached code of the body starting at the current position
+    pub(crate) fn cached_fragment(&mut self, key: &str) -> Option<(BuilderData, DbgNode)> {
+        let entry = self.cache.as_mut()?.get(key)?;
+        let cell = read_boc(hex::decode(&entry.code).ok()?).ok()?.withdraw_single_root().ok()?;
+        let builder = BuilderData::from_cell(&cell).ok()?;
+        let mut dbg = entry.dbg;
+        rebase(&mut dbg, &self.source_name, (1, 1), (self.line_no, self.char_no));
+        Some((builder, dbg))
+    }
+
+    /// Stores the code of the body started at the given line and column
+    pub(crate) fn cache_fragment(&mut self, key: String, start: (usize, usize), builder: &BuilderData, dbg: &DbgNode) {
+        let code = match builder.clone().into_cell().and_then(|cell| write_boc(&cell)) {
+            Ok(boc) => hex::encode(boc),
+            Err(_) => return
+        };
+        let mut dbg = dbg.clone();
+        rebase(&mut dbg, &Arc::from(""), start, (1, 1));
+        if let Some(cache) = self.cache.as_mut() {
+            cache.insert(key, CacheEntry { code, dbg });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbgPos, PackingPolicy, test_helpers::compile};
+
+    /// Lines, columns and files of the positions of the node and its children
+    fn positions(dbg: &DbgNode) -> Vec<(usize, usize, String)> {
+        let mut result = dbg.offsets.iter()
+            .map(|(_, pos)| (pos.line, pos.column, pos.filename.to_string()))
+            .collect::<Vec<_>>();
+        for child in &dbg.children {
+            result.extend(positions(child));
+        }
+        result
+    }
+
+    fn node(positions: &[(usize, usize)]) -> DbgNode {
+        let filename = Arc::from("a.code");
+        let mut dbg = DbgNode::default();
+        for (offset, (line, column)) in positions.iter().enumerate() {
+            dbg.offsets.push((offset * 8, DbgPos { filename: Arc::clone(&filename), line: *line, column: *column, ..Default::default() }));
+        }
+        dbg
+    }
+
+    #[test]
+    fn rebase_positions() {
+        // the body starts at line 5 column 8, the columns of the lines after it are kept
+        let mut dbg = node(&[(5, 10), (5, 14), (6, 3), (7, 0)]);
+        dbg.children.push(node(&[(5, 8), (8, 1)]));
+        let original = positions(&dbg);
+        rebase(&mut dbg, &Arc::from(""), (5, 8), (1, 1));
+        assert_eq!(
+            positions(&dbg).into_iter().map(|(line, column, _)| (line, column)).collect::<Vec<_>>(),
+            vec!((1, 3), (1, 7), (2, 3), (3, 0), (1, 1), (4, 1))
+        );
+        rebase(&mut dbg, &Arc::from("a.code"), (1, 1), (5, 8));
+        assert_eq!(positions(&dbg), original);
+    }
+
+    #[test]
+    fn cache_hits() {
+        // the same body at another place of the source
+        let source = ".fragment f, { INC\n  DEC\n}\n  .fragment g, { INC\n  DEC\n}\n.fragment h, { NOP\n}";
+        let mut plain = Engine::new("a.code");
+        compile(&mut plain, source).unwrap();
+
+        let mut engine = Engine::new("a.code");
+        engine.enable_cache(None).unwrap();
+        compile(&mut engine, source).unwrap();
+        assert_eq!(engine.cache_stats(), Some(CacheStats { hits: 1, misses: 2, stored: 2 }));
+        for name in ["f", "g", "h"] {
+            let hash = |engine: &Engine| engine.named_units[name].builder().clone().into_cell().unwrap().repr_hash();
+            assert_eq!(hash(&engine), hash(&plain));
+            assert_eq!(positions(engine.named_units[name].dbg()), positions(plain.named_units[name].dbg()));
+        }
+        assert!(engine.is_cached(" NOP\n"));
+        assert_eq!(engine.cache_stats().unwrap().hits, 1);
+    }
+
+    #[test]
+    fn cache_key() {
+        let mut engine = Engine::new("");
+        assert_eq!(engine.cache_key("INC"), None);
+        engine.enable_cache(None).unwrap();
+        let key = engine.cache_key("INC").unwrap();
+        assert_ne!(engine.cache_key("DEC").unwrap(), key);
+
+        engine.define_const("N", "1");
+        let with_constant = engine.cache_key("INC").unwrap();
+        assert_ne!(with_constant, key);
+        engine.set_inline_max_bits(Some(100));
+        let with_limit = engine.cache_key("INC").unwrap();
+        assert_ne!(with_limit, with_constant);
+        engine.set_packing_policy(PackingPolicy::Balanced);
+        assert_ne!(engine.cache_key("INC").unwrap(), with_limit);
+    }
+
+    #[test]
+    fn cache_dependent() {
+        let mut engine = Engine::new("");
+        engine.enable_cache(None).unwrap();
+        for body in [".inline f", "PUSHCONT {\n  CALLPROC f\n}", "CALLREF .fragref f", ".include \"lib.code\"", ".loc a.code, 1"] {
+            assert_eq!(engine.cache_key(body), None, "{}", body);
+        }
+        // only the commands are looked at, not the comments and strings
+        assert!(is_cacheable("INC ; .inline f\nPUSHSLICE \".inline\""));
+
+        compile(&mut engine, ".fragment g, { INC\n}\n.fragment f, { .inline g\n}\n.fragment h, { .inline g\n}").unwrap();
+        assert_eq!(engine.cache_stats(), Some(CacheStats { hits: 0, misses: 1, stored: 1 }));
+    }
+}
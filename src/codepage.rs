@@ -0,0 +1,136 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Instruction sets selected by SETCP
+//!
+//! The assembler and the disassembler keep a table of instructions per
+//! codepage. `SETCP n` switches to the table of codepage `n` for the rest of
+//! the continuation, the enclosing code goes on with its own codepage.
+//! Directives are available in every codepage. A codepage unknown to the
+//! engine leaves the current table in place.
+
+use std::collections::HashMap;
+
+use crate::{
+    CompileHandler, Engine, Position, Severity,
+    disasm::{handlers::Handlers, loader::Loader},
+};
+
+/// Codepage of TVM instructions
+pub trait CodePage {
+    /// number selecting the codepage by SETCP
+    fn number(&self) -> i16;
+    /// assembler handlers by mnemonic
+    fn compile_handlers(&self) -> HashMap<&'static str, CompileHandler>;
+    /// disassembler table of opcodes
+    fn load_handlers(&self) -> Handlers;
+}
+
+/// The standard codepage
+pub struct CodePage0;
+
+impl CodePage for CodePage0 {
+    fn number(&self) -> i16 {
+        0
+    }
+    fn compile_handlers(&self) -> HashMap<&'static str, CompileHandler> {
+        let simple = Engine::simple_command_tables().into_iter().flatten()
+            .map(|command| (command.name, command.handler));
+        Engine::complex_command_table().iter().copied()
+            .chain(simple)
+            .filter(|(name, _)| !is_directive(name))
+            .collect()
+    }
+    fn load_handlers(&self) -> Handlers {
+        Handlers::new_code_page_0()
+    }
+}
+
+fn is_directive(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+impl Engine {
+    /// Makes the instructions of the codepage available after `SETCP n`
+    pub fn register_code_page(&mut self, page: &dyn CodePage) {
+        self.code_pages.insert(page.number(), page.compile_handlers());
+    }
+
+    /// Number of the codepage the code is being assembled in
+    pub fn code_page(&self) -> i16 {
+        self.code_page
+    }
+
+    /// Takes the instructions of the current codepage as the one built in
+    pub(crate) fn init_code_pages(&mut self) {
+        let instructions = self.handlers.iter()
+            .filter(|(name, _)| !is_directive(name))
+            .map(|(name, handler)| (*name, *handler))
+            .collect();
+        self.code_pages.insert(self.code_page, instructions);
+    }
+
+    /// Switches the instruction table to the codepage if it is known
+    pub(crate) fn select_code_page(&mut self, number: i16) -> bool {
+        if number == self.code_page {
+            return true
+        }
+        let instructions = match self.code_pages.get(&number) {
+            Some(instructions) => instructions.clone(),
+            None => return false
+        };
+        self.handlers.retain(|name, _| is_directive(name));
+        self.handlers.extend(instructions);
+        self.code_page = number;
+        true
+    }
+
+    /// Follows SETCP compiled successfully
+    pub(crate) fn check_code_page(&mut self, operation: &str, par: &[&str], position: Position) {
+        let number = match (operation, par) {
+            ("SETCP0", []) => Some(0),
+            ("SETCP", [number]) => number.parse::<i16>().ok(),
+            _ => None
+        };
+        if let Some(number) = number {
+            if !self.select_code_page(number) {
+                self.diagnose(Severity::Warning, "unknown-codepage", position, format!(
+                    "codepage {} is not known, instructions are assembled as of codepage {}", number, self.code_page
+                ));
+            }
+        }
+    }
+}
+
+impl Loader {
+    /// Makes the instructions of the codepage decoded after `SETCP n`
+    pub fn register_code_page(&mut self, page: &dyn CodePage) {
+        self.code_pages.insert(page.number(), page.load_handlers());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_page_0_tables() {
+        let mut engine = Engine::new("");
+        let built_in = engine.code_pages.remove(&0).unwrap();
+        let mut names = CodePage0.compile_handlers().into_keys().collect::<Vec<_>>();
+        let mut expected = built_in.into_keys().collect::<Vec<_>>();
+        names.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(names, expected);
+    }
+}
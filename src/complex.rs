@@ -12,23 +12,24 @@
 */
 
 use std::collections::{BTreeMap, HashMap};
-use std::{marker::PhantomData, ops::Range};
-use ever_block::{error, BuilderData, Cell, HashmapE, HashmapType, SliceData, Status};
+use std::{marker::PhantomData, ops::Range, sync::Arc};
+use ever_block::{error, BuilderData, Cell, CellType, HashmapE, HashmapType, LevelMask, SliceData, Status};
 
 use super::errors::{
-    OperationError, ParameterError,
+    OperationError, ParameterError, Position, Severity,
 };
 
 use super::{
-    Unit, Units, CompileResult, Engine, EnsureParametersCountInRange,
+    Unit, Writer, CompileHandler, CompileResult, Engine, EnsureParametersCountInRange,
     convert::to_big_endian_octet_string,
     errors::ToOperationParameterError,
     parse::*,
 };
-use num::{BigInt, Num, Integer};
+use num::{bigint::Sign, BigInt, Integer, ToPrimitive};
 use crate::{
-    DbgInfo,
-    debug::{DbgPos, DbgNode}
+    DbgInfo, abi::FUNCTION_ID_BITS,
+    debug::{CellRole, DbgPos, DbgNode},
+    writer::callable,
 };
 
 trait CommandBehaviourModifier {
@@ -55,7 +56,7 @@ fn compile_with_register(
     symbol: char,
     range: Range<isize>,
     code: &[u8],
-    destination: &mut Units,
+    destination: &mut dyn Writer,
     pos: DbgPos,
 ) -> CompileResult {
     let reg = parse_register(register, symbol, range).parameter("arg 0")? as u8;
@@ -69,7 +70,7 @@ fn compile_with_any_register(
     code_stack_short: &[u8],
     code_stack_long: &[u8],
     code_ctrls: &[u8],
-    destination: &mut Units,
+    destination: &mut dyn Writer,
     pos: DbgPos,
 ) -> CompileResult {
     compile_with_register(register, 'S', 0..16, code_stack_short, destination, pos.clone()).or_else(
@@ -83,7 +84,7 @@ fn compile_with_any_register(
     )
 }
 
-fn compile_call(_engine: &mut Engine,  par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+pub(crate) fn compile_call(_engine: &mut Engine,  par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     par.assert_len(1)?;
     let number = parse_const_u14(par[0]).parameter("Number")?;
     if number < 256 {
@@ -97,7 +98,52 @@ fn compile_call(_engine: &mut Engine,  par: &[&str], destination: &mut Units, po
     }
 }
 
-fn compile_ref(engine: &mut Engine, par: &[&str], destination: &mut Units, command: &[u8], pos: DbgPos) -> CompileResult {
+/// Opcodes of instructions taking a continuation by reference along with the role of the cell
+pub(crate) fn ref_command(name: &str) -> Option<(&'static [u8], CellRole)> {
+    let command: &'static [u8] = match name {
+        "CALLREF"      => &[0xDB, 0x3C],
+        "JMPREF"       => &[0xDB, 0x3D],
+        "IFREF"        => &[0xE3, 0x00],
+        "IFNOTREF"     => &[0xE3, 0x01],
+        "IFJMPREF"     => &[0xE3, 0x02],
+        "IFNOTJMPREF"  => &[0xE3, 0x03],
+        "IFREFELSE"    => &[0xE3, 0x0D],
+        "IFELSEREF"    => &[0xE3, 0x0E],
+        "PUSHREFCONT"  => &[0x8A],
+        "PUSHREF"      => return Some((&[0x88], CellRole::Data)),
+        "PUSHREFSLICE" => return Some((&[0x89], CellRole::Data)),
+        "STREFCONST"   => return Some((&[0xCF, 0x20], CellRole::Data)),
+        _ => return None
+    };
+    Some((command, CellRole::Code))
+}
+
+/// Writes the instruction with the compiled cell attached as a reference
+pub(crate) fn write_ref(
+    command: &[u8],
+    role: CellRole,
+    cont: BuilderData,
+    dbg: DbgNode,
+    destination: &mut dyn Writer,
+    pos: DbgPos,
+) -> CompileResult {
+    let dbg2 = DbgNode::from_ext(pos, vec!(dbg.with_role(role)));
+    destination.write_composite_command(command, vec!(cont), dbg2)
+}
+
+fn compile_ref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, name: &str, pos: DbgPos) -> CompileResult {
+    let (command, role) = ref_command(name)
+        .ok_or_else(|| OperationError::Internal(format!("{} does not take a reference", name)))?;
+    if let Some(name) = par.first().filter(|name| engine.is_label_reference(name)) {
+        par.assert_len(1)?;
+        let (cont, dbg) = engine.resolve_label(name)?.into_parts();
+        return write_ref(command, role, cont, dbg, destination, pos)
+    }
+    if par.first().map_or(false, |p| p.eq_ignore_ascii_case(".fragref")) {
+        par.assert_len(2)?;
+        let cont = engine.fragref_placeholder(par[1])?;
+        return write_ref(command, role, cont, DbgNode::from(pos.clone()), destination, pos)
+    }
     if engine.line_no == 0 && engine.char_no == 0 {
         // the case of instruction form without an argument
         return destination.write_command(command, DbgNode::from(pos));
@@ -107,83 +153,103 @@ fn compile_ref(engine: &mut Engine, par: &[&str], destination: &mut Units, comma
         .compile(par[0])
         .map_err(|e| OperationError::Nested(Box::new(e)))?
         .finalize();
-    let dbg2 = DbgNode::from_ext(pos, vec!(dbg));
-    destination.write_composite_command(command, vec!(cont), dbg2)
+    write_ref(command, role, cont, dbg, destination, pos)
+}
+
+fn compile_block_or_label(engine: &mut Engine, par: &str) -> Result<(BuilderData, DbgNode), OperationError> {
+    if engine.is_label_reference(par) {
+        Ok(engine.resolve_label(par)?.into_parts())
+    } else {
+        Ok(engine
+            .compile(par)
+            .map_err(|e| OperationError::Nested(Box::new(e)))?
+            .finalize())
+    }
 }
 
-fn compile_callref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xDB, 0x3C], pos)
+fn compile_callref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "CALLREF", pos)
 }
 
-fn compile_jmpref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xDB, 0x3D], pos)
+fn compile_jmpref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "JMPREF", pos)
 }
 
-fn compile_ifref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xE3, 0x00], pos)
+fn compile_ifref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "IFREF", pos)
 }
 
-fn compile_ifnotref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xE3, 0x01], pos)
+fn compile_ifnotref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "IFNOTREF", pos)
 }
 
-fn compile_ifjmpref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xE3, 0x02], pos)
+fn compile_ifjmpref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "IFJMPREF", pos)
 }
 
-fn compile_ifnotjmpref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xE3, 0x03], pos)
+fn compile_ifnotjmpref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "IFNOTJMPREF", pos)
 }
 
-fn compile_ifrefelse(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xE3, 0x0D], pos)
+fn compile_ifrefelse(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "IFREFELSE", pos)
 }
 
-fn compile_ifelseref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0xE3, 0x0E], pos)
+fn compile_ifelseref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "IFELSEREF", pos)
 }
 
-fn compile_ifrefelseref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_ifrefelseref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     if engine.line_no == 0 && engine.char_no == 0 {
         // the case of instruction form without an argument
         return destination.write_command(&[0xE3, 0x0F], DbgNode::from(pos));
     }
     par.assert_len(2)?;
-    let (cont1, dbg1) = engine
-        .compile(par[0])
-        .map_err(|e| OperationError::Nested(Box::new(e)))?
-        .finalize();
-    let (cont2, dbg2) = engine
-        .compile(par[1])
-        .map_err(|e| OperationError::Nested(Box::new(e)))?
-        .finalize();
+    let (cont1, dbg1) = compile_block_or_label(engine, par[0])?;
+    let (cont2, dbg2) = compile_block_or_label(engine, par[1])?;
     let dbg = DbgNode::from_ext(pos, vec!(dbg1, dbg2));
     destination.write_composite_command(&[0xE3, 0x0F], vec!(cont1, cont2), dbg)
 }
 
-fn compile_pushref(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0x88], pos)
+fn compile_strefconst(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "STREFCONST", pos)
+}
+
+fn compile_stref2const(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    if engine.line_no == 0 && engine.char_no == 0 {
+        // the case of instruction form without an argument
+        return destination.write_command(&[0xCF, 0x21], DbgNode::from(pos));
+    }
+    par.assert_len(2)?;
+    let (cell1, dbg1) = compile_block_or_label(engine, par[0])?;
+    let (cell2, dbg2) = compile_block_or_label(engine, par[1])?;
+    let dbg = DbgNode::from_ext(pos, vec!(dbg1.with_role(CellRole::Data), dbg2.with_role(CellRole::Data)));
+    destination.write_composite_command(&[0xCF, 0x21], vec!(cell1, cell2), dbg)
+}
+
+fn compile_pushref(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "PUSHREF", pos)
 }
 
-fn compile_pushrefslice(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0x89], pos)
+fn compile_pushrefslice(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "PUSHREFSLICE", pos)
 }
 
-fn compile_pushrefcont(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
-    compile_ref(engine, par, destination, &[0x8A], pos)
+fn compile_pushrefcont(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_ref(engine, par, destination, "PUSHREFCONT", pos)
 }
 
-fn compile_pop(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_pop(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     par.assert_len(1)?;
     compile_with_any_register(par[0], &[0x30], &[0x57, 0x00], &[0xED, 0x50], destination, pos)
 }
 
-fn compile_push(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+pub(crate) fn compile_push(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     par.assert_len(1)?;
     compile_with_any_register(par[0],  &[0x20], &[0x56, 0x00], &[0xED, 0x40], destination, pos)
 }
 
-fn write_pushcont(cont: BuilderData, dbg: DbgNode, destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn write_pushcont(cont: BuilderData, dbg: DbgNode, destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     let r = cont.references_used() as u8;
     if r > 3 {
         return Err(OperationError::NotFitInSlice)
@@ -205,7 +271,7 @@ fn write_pushcont(cont: BuilderData, dbg: DbgNode, destination: &mut Units, pos:
     destination.write_composite_command(&code, refs, dbg2)
 }
 
-fn compile_pushcont(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_pushcont(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     if engine.line_no == 0 && engine.char_no == 0 {
         return Err(OperationError::MissingBlock)
     }
@@ -214,39 +280,45 @@ fn compile_pushcont(engine: &mut Engine, par: &[&str], destination: &mut Units,
         .compile(par[0])
         .map_err(|e| OperationError::Nested(Box::new(e)))?
         .finalize();
-    if cont.references_used() > 0 {
-        write_pushcont(cont.clone(), dbg.clone(), destination, pos.clone()).or_else(|_| {
-            let dbg2 = DbgNode::from_ext(pos, vec!(dbg));
-            destination.write_composite_command(&[0x8E, 0x80], vec!(cont), dbg2)
-        })
+    if !fits_pushcont(&cont) {
+        engine.diagnose(Severity::Warning, "oversized-continuation", Position::from_dbgpos(&pos), format!(
+            "continuation of {} bits and {} refs does not fit into PUSHCONT and takes a separate cell",
+            cont.bits_used(), cont.references_used()));
+    }
+    write_cont(cont, dbg, destination, pos)
+}
+
+/// Whether the continuation can be written inside PUSHCONT rather than by a reference
+fn fits_pushcont(cont: &BuilderData) -> bool {
+    cont.references_used() <= 3 && cont.data().len() <= 125
+}
+
+/// Writes PUSHCONT with the compiled continuation choosing the shortest form;
+/// the continuation not fitting into the instruction goes to a separate cell
+pub(crate) fn write_cont(cont: BuilderData, dbg: DbgNode, destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    let n = cont.data().len();
+    if cont.references_used() == 0 && n <= 15 {
+        let mut command = vec![0x90 | n as u8];
+        let mut dbg2 = DbgNode::from(pos);
+        dbg2.inline_node(command.len() * 8, dbg);
+        command.extend_from_slice(cont.data());
+        destination.write_command(command.as_slice(), dbg2)
+    } else if fits_pushcont(&cont) {
+        write_pushcont(cont.clone(), dbg.clone(), destination, pos.clone())
+            .or_else(|_| write_cont_by_ref(cont, dbg, destination, pos))
     } else {
-        let n = cont.data().len();
-        if n <= 15 {
-            let mut command = vec![0x90 | n as u8];
-            let mut dbg2 = DbgNode::from(pos);
-            dbg2.inline_node(command.len() * 8, dbg);
-            command.extend_from_slice(cont.data());
-            destination.write_command(command.as_slice(), dbg2)
-        } else if n <= 125 {
-            let mut command = vec![0x8E, n as u8];
-            let mut dbg2 = DbgNode::from(pos);
-            dbg2.inline_node(command.len() * 8, dbg);
-            command.extend_from_slice(cont.data());
-            destination.write_command(command.as_slice(), dbg2)
-        } else if n <= 127 {
-            //We cannot put command and code in one cell, because it will
-            //be more than 1023 bits: 127 bytes (pushcont data) + 2 bytes(opcode).
-            //Write as r = 1 and xx = 0x00.
-            let dbg2 = DbgNode::from_ext(pos, vec!(dbg));
-            destination.write_composite_command(&[0x8E, 0x80], vec!(cont), dbg2)
-        } else {
-            log::error!(target: "compile", "Maybe cell longer than 1024 bit?");
-            Err(OperationError::NotFitInSlice)
-        }
+        write_cont_by_ref(cont, dbg, destination, pos)
     }
 }
 
-fn compile_callxargs(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+/// Writes PUSHCONT of the empty code referring to the continuation (r = 1, xx = 0),
+/// as the instruction and the code together exceed a cell
+fn write_cont_by_ref(cont: BuilderData, dbg: DbgNode, destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    let dbg2 = DbgNode::from_ext(pos, vec!(dbg));
+    destination.write_composite_command(&[0x8E, 0x80], vec!(cont), dbg2)
+}
+
+fn compile_callxargs(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     par.assert_len(2)?;
     let pargs = parse_const_u4(par[0]).parameter("pargs")?;
     if par[1] == "-1" {
@@ -265,7 +337,7 @@ macro_rules! div_variant {
             pub fn $command(
                 _engine: &mut Engine,
                 par: &[&str],
-                destination: &mut Units,
+                destination: &mut dyn Writer,
                 pos: DbgPos,
             ) -> CompileResult {
                 par.assert_len_in(0..=1)?;
@@ -322,7 +394,7 @@ div_variant!(
 );
 
 impl<M: CommandBehaviourModifier> Div<M> {
-    pub fn lshift(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+    pub fn lshift(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
         par.assert_len_in(0..=1)?;
         destination.write_command(
             &M::modify({
@@ -336,7 +408,7 @@ impl<M: CommandBehaviourModifier> Div<M> {
         )
     }
 
-    fn rshift(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+    fn rshift(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
         par.assert_len_in(0..=1)?;
         let command = if par.len() == 1 {
             vec![0xAB, parse_const_u8_plus_one(par[0]).parameter("value")?]
@@ -348,7 +420,7 @@ impl<M: CommandBehaviourModifier> Div<M> {
 
 }
 
-fn compile_setcontargs(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_setcontargs(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     par.assert_len_in(1..=2)?;
     let rargs = parse_const_u4(par[0]).parameter("register")?;
     let nargs = if par.len() == 2 {
@@ -360,39 +432,29 @@ fn compile_setcontargs(_engine: &mut Engine, par: &[&str], destination: &mut Uni
 }
 
 #[rustfmt::skip]
-fn compile_pushint(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+pub(crate) fn compile_pushint(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     par.assert_len(1)?;
-    let (sub_str, radix) = if par[0].len() > 2 && (par[0][0..2].eq("0x") || par[0][0..2].eq("0X")) {
-        (par[0][2..].to_string(), 16)
-    } else if par[0].len() > 3 && (par[0][0..3].eq("-0x") || par[0][0..3].eq("-0X")) {
-        let mut sub_str = par[0].to_string();
-        sub_str.replace_range(1..3, "");
-        (sub_str, 16)
-    } else {
-        (par[0].to_string(), 10)
-    };
-    destination.write_command(match i32::from_str_radix(sub_str.as_str(), radix) {
-        Ok(number @ -5..=10) =>
-            Ok(vec![0x70 | ((number & 0x0F) as u8)]),
-        Ok(number @ -128..=127) =>
-            Ok(vec![0x80, (number & 0xFF) as u8]),
-        Ok(number @ -32768..=32767) =>
-            Ok(vec![0x81, ((number >> 8) & 0xFF) as u8, (number & 0xFF) as u8]),
-        _ => if let Ok(int) = BigInt::from_str_radix(sub_str.as_str(), radix) {
-            if let Some(mut int_bytes) = to_big_endian_octet_string(&int) {
+    let int = parse_int_literal(par[0]).parameter("arg 0")?;
+    let bytecode = match int.to_i32() {
+        Some(number @ -5..=10) =>
+            vec![0x70 | ((number & 0x0F) as u8)],
+        Some(number @ -128..=127) =>
+            vec![0x80, (number & 0xFF) as u8],
+        Some(number @ -32768..=32767) =>
+            vec![0x81, ((number >> 8) & 0xFF) as u8, (number & 0xFF) as u8],
+        _ => match to_big_endian_octet_string(&int) {
+            Some(mut int_bytes) => {
                 let mut bytecode = vec![0x82];
                 bytecode.append(&mut int_bytes);
-                Ok(bytecode)
-            } else {
-                Err(ParameterError::OutOfRange.parameter("arg 0"))
+                bytecode
             }
-        } else {
-            Err(ParameterError::OutOfRange.parameter("arg 0"))
+            None => return Err(ParameterError::OutOfRange.parameter("arg 0"))
         }
-    }?.as_slice(), DbgNode::from(pos))
+    };
+    destination.write_command(bytecode.as_slice(), DbgNode::from(pos))
 }
 
-fn compile_bchkbits(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_bchkbits(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     destination.write_command({
         if par.len() == 1 {
             Ok(vec![0xCF, 0x38, parse_const_u8_plus_one(par[0]).parameter("value")?])
@@ -402,7 +464,7 @@ fn compile_bchkbits(_engine: &mut Engine, par: &[&str], destination: &mut Units,
     }?.as_slice(), DbgNode::from(pos))
 }
 
-fn compile_bchkbitsq(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_bchkbitsq(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     if par.len() == 1 {
         destination.write_command(
             vec![0xCF, 0x3C, parse_const_u8_plus_one(par[0]).parameter("value")?].as_slice(),
@@ -416,7 +478,7 @@ fn compile_bchkbitsq(_engine: &mut Engine, par: &[&str], destination: &mut Units
 fn compile_dumpstr(
     _engine: &mut Engine,
     par: &[&str],
-    destination: &mut Units,
+    destination: &mut dyn Writer,
     mut buffer: Vec<u8>,
     max_len: usize,
     pos: DbgPos,
@@ -425,7 +487,7 @@ fn compile_dumpstr(
     let string = parse_string(par[0]);
     let string = string.as_slice();
     let len = string.len();
-    if len > max_len {
+    if len == 0 || len > max_len {
         return Err(ParameterError::OutOfRange.parameter(par[0]))
     }
     buffer[1] |= (len - 1 + 16 - max_len) as u8;
@@ -433,19 +495,19 @@ fn compile_dumpstr(
     destination.write_command(buffer.as_slice(), DbgNode::from(pos))
 }
 
-fn compile_dumptosfmt(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_dumptosfmt(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     compile_dumpstr(engine, par, destination, vec![0xFE, 0xF0], 16, pos)
 }
 
-fn compile_logstr(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_logstr(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     compile_dumpstr(engine, par, destination, vec![0xFE, 0xF0, 0x00], 15, pos)
 }
 
-fn compile_printstr(engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_printstr(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     compile_dumpstr(engine, par, destination, vec![0xFE, 0xF0, 0x01], 15, pos)
 }
 
-fn compile_stsliceconst(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult {
+fn compile_stsliceconst(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
     par.assert_len(1)?;
     if par[0] == "0" {
         destination.write_command(&[0xCF, 0x81], DbgNode::from(pos))
@@ -457,17 +519,56 @@ fn compile_stsliceconst(_engine: &mut Engine, par: &[&str], destination: &mut Un
     }
 }
 
-fn compile_pushslice(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+/// Bytes of a string literal stored in a cell of a chain
+const STRING_CELL_BYTES: usize = 127;
+
+/// Puts the bytes into a chain of cells each referring to the next one
+fn string_chain(bytes: &[u8]) -> Result<BuilderData, OperationError> {
+    let mut next: Option<BuilderData> = None;
+    for chunk in bytes.chunks(STRING_CELL_BYTES).rev() {
+        let mut cell = BuilderData::with_raw(chunk.to_vec(), chunk.len() * 8)
+            .map_err(|_| OperationError::NotFitInSlice)?;
+        if let Some(next) = next.take() {
+            let next = next.into_cell().map_err(|_| OperationError::NotFitInSlice)?;
+            cell.checked_append_reference(next).map_err(|_| OperationError::NotFitInSlice)?;
+        }
+        next = Some(cell);
+    }
+    Ok(next.unwrap_or_default())
+}
+
+pub(crate) fn compile_pushslice(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     par.assert_len(1)?;
-    let buffer = match compile_slice(par[0], vec![0x8B, 0], 8, 0, 4) {
+    if let Some(string) = parse_quoted_string(par[0]) {
+        let hex = format!("x{}", hex::encode(string));
+        let buffer = compile_slice(&hex, vec![0x8B, 0], 8, 0, 4)
+            .or_else(|_| compile_slice(&hex, vec![0x8D, 0], 8, 3, 7));
+        return match buffer {
+            Ok(buffer) => destination.write_command(buffer.as_slice(), DbgNode::from(pos)),
+            // too long to be pushed inline, PUSHREFSLICE of a chain of cells
+            Err(_) => {
+                let chain = string_chain(string.as_bytes())?;
+                write_ref(&[0x89], CellRole::Data, chain, DbgNode::default(), destination, pos)
+            }
+        }
+    }
+    let address;
+    let literal = match par[0].get(0..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("addr:") => {
+            address = parse_address(par[0]).parameter("arg 0")?;
+            address.as_str()
+        }
+        _ => par[0]
+    };
+    let buffer = match compile_slice(literal, vec![0x8B, 0], 8, 0, 4) {
         Ok(buffer) => buffer,
-        Err(_) => compile_slice(par[0], vec![0x8D, 0], 8, 3, 7).parameter("arg 0")?
+        Err(_) => compile_slice(literal, vec![0x8D, 0], 8, 3, 7).parameter("arg 0")?
     };
     destination.write_command(buffer.as_slice(), DbgNode::from(pos))
 }
 
-fn compile_xchg(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+fn compile_xchg(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     par.assert_len_in(0..=2)?;
     if par.is_empty() {
@@ -503,7 +604,7 @@ fn compile_xchg(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos
     }
 }
 
-fn compile_throw_helper(par: &[&str], short_opcode: u8, long_opcode: u8, destination: &mut Units, pos: DbgPos)
+fn compile_throw_helper(par: &[&str], short_opcode: u8, long_opcode: u8, destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     par.assert_len(1)?;
     let number = parse_const_u11(par[0]).parameter("Number")?;
@@ -545,7 +646,7 @@ pub(super) fn compile_slice(par: &str, mut prefix: Vec<u8>, offset: usize, r: us
     Ok(prefix)
 }
 
-fn compile_sdbegins(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+fn compile_sdbegins(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     par.assert_len(1)?;
     // Regular version have special two aliaces: SDBEGINS '0', SDBEGINS '1'
@@ -559,32 +660,42 @@ fn compile_sdbegins(_engine: &mut Engine, par: &[&str], destination: &mut Units,
     }
 }
 
-fn compile_sdbeginsq(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+fn compile_sdbeginsq(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     par.assert_len(1)?;
     let buffer = compile_slice(par[0], vec![0xD7, 0x2C], 14, 0, 7).parameter("arg 0")?;
     destination.write_command(buffer.as_slice(), DbgNode::from(pos))
 }
 
-fn compile_throw(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+fn compile_throw(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     compile_throw_helper(par, 0x00, 0xC0, destination, pos)
 }
 
-fn compile_throwif(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+fn compile_throwif(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     compile_throw_helper(par, 0x40, 0xD0, destination, pos)
 }
 
-fn compile_throwifnot(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+fn compile_throwifnot(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     compile_throw_helper(par, 0x80, 0xE0, destination, pos)
 }
 
-fn compile_blob(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos: DbgPos)
+fn compile_blob(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
     par.assert_len(1)?;
     let data = par[0];
+    if let Some(string) = parse_quoted_string(data) {
+        // the tail not fitting into a cell goes to a chain of cells referred to
+        let bytes = string.as_bytes();
+        if bytes.len() <= STRING_CELL_BYTES {
+            return destination.write_command(bytes, DbgNode::from(pos))
+        }
+        let (head, tail) = bytes.split_at(STRING_CELL_BYTES);
+        let dbg = DbgNode::from_ext(pos, vec!(DbgNode::default().with_role(CellRole::Data)));
+        return destination.write_composite_command(head, vec!(string_chain(tail)?), dbg)
+    }
     if !data.to_ascii_lowercase().starts_with('x') {
         return Err(ParameterError::UnexpectedType.parameter("parameter"))
     }
@@ -593,7 +704,41 @@ fn compile_blob(_engine: &mut Engine, par: &[&str], destination: &mut Units, pos
     destination.write_command_bitstring(slice.storage(), slice.remaining_bits(), DbgNode::from(pos))
 }
 
-fn compile_cell(engine: &mut Engine, par: &[&str], destination: &mut Units, _pos: DbgPos)
+/// Writes an integer of the given bit length into the cell, e.g. `.int 64, -42`
+fn compile_int_data(par: &[&str], signed: bool, destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+    let bits = par[0].parse::<usize>().ok()
+        .filter(|bits| (1..=1023).contains(bits))
+        .ok_or_else(|| ParameterError::OutOfRange.parameter("bits"))?;
+    let value = parse_int_literal(par[1]).parameter("value")?;
+    let (min, max) = match signed {
+        true => (-(BigInt::from(1) << (bits - 1)), BigInt::from(1) << (bits - 1)),
+        false => (BigInt::from(0), BigInt::from(1) << bits),
+    };
+    if value < min || value >= max {
+        return Err(ParameterError::OutOfRange.parameter("value"))
+    }
+    // two's complement aligned to the most significant bit
+    let value = match value.sign() {
+        Sign::Minus => value + (BigInt::from(1) << bits),
+        _ => value,
+    };
+    let len = bits.div_ceil(8);
+    let (_, digits) = (value << (len * 8 - bits)).to_bytes_be();
+    let mut data = vec![0; len.saturating_sub(digits.len())];
+    data.extend_from_slice(&digits);
+    destination.write_command_bitstring(&data[data.len() - len..], bits, DbgNode::from(pos))
+}
+
+fn compile_int(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_int_data(par, true, destination, pos)
+}
+
+fn compile_uint(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    compile_int_data(par, false, destination, pos)
+}
+
+fn compile_cell(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos)
 -> CompileResult {
     if engine.line_no == 0 && engine.char_no == 0 {
         return Err(OperationError::MissingBlock)
@@ -604,22 +749,43 @@ fn compile_cell(engine: &mut Engine, par: &[&str], destination: &mut Units, _pos
         .map_err(|e| OperationError::Nested(Box::new(e)))?
         .finalize();
     let mut dbg2 = DbgNode::default();
-    dbg2.append_node(dbg);
+    dbg2.append_node(dbg.with_role(CellRole::Data));
     destination.write_composite_command(&[], vec!(cont), dbg2)
 }
 
-fn compile_inline(engine: &mut Engine, par: &[&str], destination: &mut Units, _pos: DbgPos)
+fn compile_inline(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos)
 -> CompileResult {
-    par.assert_len(1)?;
+    par.assert_len_in(1..=2)?;
     let name = par[0];
-    if let Some(unit) = engine.named_units.get(name) {
-        destination.write_unit(unit.clone())
+    let max_bits = match par.get(1) {
+        Some(bits) => Some(bits.parse::<usize>()
+            .map_err(|_| ParameterError::UnexpectedType.parameter("max bits"))?),
+        None => engine.inline_max_bits,
+    };
+    let unit = engine.named_units.get(name)
+        .ok_or_else(|| OperationError::FragmentIsNotDefined(name.to_string()))?
+        .clone()
+        .with_fragment(name);
+    let bits = unit.tree_bits();
+    let mut call = max_bits.map_or(false, |max_bits| bits > max_bits)
+        || engine.packing_policy.prefers_call(destination, &unit);
+    if call && !callable(&unit) {
+        engine.diagnose(Severity::Note, "inline-kept", Position::from_dbgpos(&pos),
+            format!("fragment {} of {} bits is inlined since it depends on the continuation it runs in", name, bits));
+        call = false;
+    }
+    if call {
+        engine.diagnose(Severity::Note, "inline-by-callref", Position::from_dbgpos(&pos),
+            format!("fragment {} of {} bits is called by CALLREF instead of inlining", name, bits));
+        let (cont, dbg) = unit.into_parts();
+        let dbg = DbgNode::from_ext(pos, vec!(dbg));
+        destination.write_composite_command(&[0xDB, 0x3C], vec!(cont), dbg)
     } else {
-        Err(OperationError::FragmentIsNotDefined(name.to_string()))
+        destination.write_unit(unit.inlined_at(name, &pos))
     }
 }
 
-fn compile_code_dict_cell(engine: &mut Engine, par: &[&str], destination: &mut Units, _pos: DbgPos) -> CompileResult {
+fn build_code_dict(engine: &mut Engine, par: &[&str]) -> Result<(Option<Cell>, DbgInfo), OperationError> {
     par.assert_len(2)?;
     let dict_key_bitlen = par[0].parse::<usize>()
         .map_err(|e| OperationError::CodeDictConstruction(e.to_string()))?;
@@ -631,9 +797,7 @@ fn compile_code_dict_cell(engine: &mut Engine, par: &[&str], destination: &mut U
         return Err(OperationError::CodeDictConstruction("Odd number of tokens".to_string()))
     }
 
-    let mut map = HashMap::new();
-    let mut dict = HashmapE::with_bit_len(dict_key_bitlen);
-    let mut info = DbgInfo::default();
+    let mut entries = Vec::new();
     for pair in tokens.chunks(2) {
         // parse the key
         let key = pair[0];
@@ -645,9 +809,26 @@ fn compile_code_dict_cell(engine: &mut Engine, par: &[&str], destination: &mut U
         if key_slice.remaining_bits() != dict_key_bitlen {
             return Err(OperationError::CodeDictConstruction(format!("key {} should have {} bits", key, dict_key_bitlen)))
         }
+        entries.push((key_slice, pair[1].to_string()));
+    }
+    build_dict_of_fragments(engine, dict_key_bitlen, entries)
+}
 
+/// Builds a dictionary of the named fragments by their keys
+fn build_dict_of_fragments(
+    engine: &mut Engine,
+    dict_key_bitlen: usize,
+    entries: Vec<(SliceData, String)>,
+) -> Result<(Option<Cell>, DbgInfo), OperationError> {
+    let mut map = HashMap::new();
+    let mut dict = HashmapE::with_bit_len(dict_key_bitlen);
+    let mut info = DbgInfo::default();
+    for (key_slice, name) in entries {
+        if dict_key_bitlen == FUNCTION_ID_BITS {
+            engine.code_dict_keys.insert(key_slice.clone().get_next_u32()?);
+        }
         // get an assembled fragment by the name
-        let name = pair[1];
+        let name = name.as_str();
         let (value_slice, mut value_dbg) = engine.named_units.get(name)
             .ok_or(OperationError::CodeDictConstruction(format!("Fragment {} is not defined", name)))?
             .clone()
@@ -674,16 +855,38 @@ fn compile_code_dict_cell(engine: &mut Engine, par: &[&str], destination: &mut U
         info.append(&mut value_dbg);
     }
 
-    let dict_cell = dict.data().cloned().unwrap_or_default();
+    Ok((dict.data().cloned(), info))
+}
+
+fn compile_code_dict_cell(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    let (dict_cell, info) = build_code_dict(engine, par)?;
+    let dict_cell = dict_cell.unwrap_or_default();
     let b = BuilderData::from_cell(&dict_cell)
         .map_err(|_| ParameterError::UnexpectedType.parameter("parameter"))?;
 
     let mut dbg = DbgNode::default();
-    dbg.append_node(make_dbgnode(dict_cell, info));
+    dbg.append_node(make_dbgnode(dict_cell, info, CellRole::Dict));
 
     destination.write_composite_command(&[], vec!(b), dbg)
 }
 
+fn compile_code_dict_slice(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    let (dict_cell, info) = build_code_dict(engine, par)?;
+    let dict_cell = dict_cell
+        .ok_or_else(|| OperationError::CodeDictConstruction("Dictionary is empty".to_string()))?;
+
+    // write the root label with its refs as a whole at the current position
+    let mut refs = Vec::new();
+    for r in 0..dict_cell.references_count() {
+        let c = dict_cell.reference(r)?;
+        refs.push(BuilderData::from_cell(&c)?);
+    }
+    let slice = SliceData::load_cell_ref(&dict_cell)?;
+    let dbg = make_dbgnode(dict_cell, info, CellRole::Dict);
+
+    destination.write_composite_command_bitstring(slice.storage(), slice.remaining_bits(), refs, dbg)
+}
+
 fn adjust_debug_map(map: &mut DbgInfo, before: SliceData, after: SliceData) -> Status {
     let hash_before = before.cell().repr_hash();
     let hash_after = after.cell().repr_hash();
@@ -702,15 +905,20 @@ fn adjust_debug_map(map: &mut DbgInfo, before: SliceData, after: SliceData) -> S
 
 struct DbgNodeMaker {
     info: DbgInfo,
+    role: CellRole,
 }
 
 impl DbgNodeMaker {
-    fn new(info: DbgInfo) -> Self {
-        Self { info }
+    fn new(info: DbgInfo, role: CellRole) -> Self {
+        Self { info, role }
     }
     fn make(&self, cell: Cell) -> DbgNode {
-        let mut node = DbgNode::default();
+        let mut node = DbgNode::default().with_role(self.role);
         if let Some(map) = self.info.get(&cell.repr_hash()) {
+            // a cell starting with an instruction is a code cell
+            if map.contains_key(&0) {
+                node.role = CellRole::Code;
+            }
             for (offset, pos) in map {
                 node.offsets.push((*offset, pos.clone()))
             }
@@ -724,93 +932,231 @@ impl DbgNodeMaker {
     }
 }
 
-fn make_dbgnode(cell: Cell, dbginfo: DbgInfo) -> DbgNode {
-    DbgNodeMaker::new(dbginfo).make(cell)
+pub(crate) fn make_dbgnode(cell: Cell, dbginfo: DbgInfo, role: CellRole) -> DbgNode {
+    DbgNodeMaker::new(dbginfo, role).make(cell)
 }
 
-fn compile_inline_computed_cell(engine: &mut Engine, par: &[&str], destination: &mut Units, _pos: DbgPos) -> CompileResult {
+pub(crate) fn compile_fragment(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
     par.assert_len(2)?;
-
     let name = par[0];
-    let (code, mut _value_dbg) = engine.named_units.get(name)
-        .ok_or(OperationError::FragmentIsNotDefined(name.to_string()))?
-        .clone()
-        .finalize();
+    let key = engine.cache_key(par[1]);
+    let (builder, dbg) = match key.as_deref().and_then(|key| engine.cached_fragment(key)) {
+        Some(code) => code,
+        None => {
+            let start = (engine.line_no, engine.char_no);
+            let diagnostics = engine.diagnostics.len();
+            let (builder, dbg) = match engine.take_precompiled(name, par[1]) {
+                Some(code) => code,
+                None => engine
+                    .compile(par[1])
+                    .map_err(|e| OperationError::Nested(Box::new(e)))?
+                    .finalize(),
+            };
+            if let Some(key) = key.filter(|_| engine.diagnostics.len() == diagnostics) {
+                engine.cache_fragment(key, start, &builder, &dbg);
+            }
+            (builder, dbg)
+        }
+    };
+    engine.emit(|| crate::events::CompileEvent::FragmentCompiled {
+        name: name.to_string(),
+        bits: builder.bits_used(),
+        refs: builder.references_used(),
+    });
+    let unit = Unit::new(builder, dbg);
+    if engine.named_units.insert(name.to_string(), unit).is_some() {
+        return Err(OperationError::FragmentIsAlreadyDefined(name.to_string()))
+    }
+    engine.dbgpos = None;
+    Ok(())
+}
 
-    let param2 = par[1];
-    let capabilities = if param2.to_ascii_lowercase().starts_with("0x") {
-        u64::from_str_radix(&param2[2..], 16)
-            .map_err(|_| ParameterError::NotSupported.parameter("capabilities"))?
-    } else {
-        par[1].parse::<u64>()
-            .map_err(|_| ParameterError::NotSupported.parameter("capabilities"))?
+/// Width of method ids in the dictionary of the dispatcher
+const METHOD_ID_BITS: usize = 19;
+
+/// Parses the method id given as a number or as a quoted signature to compute it from
+pub(crate) fn parse_method_id(text: &str) -> Result<u32, ParameterError> {
+    if let Some(signature) = text.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        return Ok(crate::constants::method_id(signature))
+    }
+    let id = match text.to_ascii_lowercase().strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
     };
+    match id {
+        Ok(id) if id < 1 << METHOD_ID_BITS => Ok(id),
+        Ok(_) => Err(ParameterError::OutOfRange),
+        Err(_) => Err(ParameterError::UnexpectedType),
+    }
+}
 
-    // initialize and run vm
-    let mut vm = ever_vm::executor::Engine::with_capabilities(capabilities).setup_with_libraries(
-        code, None, None, None, vec![]);
-    match vm.execute() {
-        Err(_e) => {
-            return Err(OperationError::CellComputeError)
-        }
-        Ok(code) => {
-            if code != 0 {
-                return Err(OperationError::CellComputeError)
-            }
+pub(crate) fn compile_method(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    par.assert_len(3)?;
+    let id = parse_method_id(par[0]).map_err(|e| e.parameter("method id"))?;
+    if let Some((name, _)) = engine.methods.get(&id) {
+        return Err(OperationError::CodeDictConstruction(format!("method id {} is already taken by {}", id, name)))
+    }
+    compile_fragment(engine, &par[1..], destination, pos.clone())?;
+    engine.methods.insert(id, (par[1].to_string(), pos));
+    Ok(())
+}
+
+/// Writes `DICTPUSHCONST 19` with the dictionary of the methods defined so far followed by `DICTUGETJMPZ`
+pub(crate) fn write_dispatcher(engine: &mut Engine, destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    let methods = std::mem::take(&mut engine.methods);
+    let mut entries = Vec::new();
+    for (id, (name, _)) in methods {
+        let mut key = BuilderData::new();
+        key.append_bits(id as usize, METHOD_ID_BITS)?;
+        entries.push((SliceData::load_builder(key)?, name));
+    }
+    let (dict_cell, info) = build_dict_of_fragments(engine, METHOD_ID_BITS, entries)?;
+    let dict_cell = dict_cell
+        .ok_or_else(|| OperationError::CodeDictConstruction("No methods are defined".to_string()))?;
+    let dict = BuilderData::from_cell(&dict_cell)?;
+    let dbg = make_dbgnode(dict_cell, info, CellRole::Dict);
+    let command = [0xF4, 0xA4 | (METHOD_ID_BITS >> 8) as u8, METHOD_ID_BITS as u8];
+    write_ref(&command, CellRole::Dict, dict, dbg, destination, pos.clone())?;
+    destination.write_command(&[0xF4, 0xBD], DbgNode::from(pos))
+}
+
+fn compile_dispatch(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    par.assert_empty()?;
+    write_dispatcher(engine, destination, pos)
+}
+
+/// Maximum nesting of macro expansions
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+#[derive(Clone)]
+pub(crate) struct Macro {
+    params: Vec<String>,
+    body: String,
+    filename: Arc<str>,
+    line: usize,
+    column: usize,
+}
+
+/// Parses `name(arg1, arg2)` split by the tokenizer at commas
+fn parse_macro_call(par: &[&str]) -> Result<(String, Vec<String>), OperationError> {
+    let call = par.join(",");
+    let (name, args) = match call.split_once('(') {
+        Some((name, args)) => {
+            let args = args.strip_suffix(')')
+                .ok_or_else(|| ParameterError::UnexpectedType.parameter("macro arguments"))?;
+            let args = args.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect();
+            (name.to_string(), args)
         }
+        None => (call, Vec::new())
     };
+    if name.is_empty() || name.contains(')') {
+        return Err(ParameterError::UnexpectedType.parameter("macro name"))
+    }
+    Ok((name, args))
+}
 
-    // get a cell from the top of the stack
-    let cell = vm.stack().get(0).as_cell()
-        .map_err(|_| OperationError::CellComputeNotACell)?;
-
-    // pull refs and data from the cell separately
-    let mut refs = Vec::new();
-    for r in 0..cell.references_count() {
-        let c = cell.reference(r)
-            .map_err(|_| OperationError::CellComputeInternal)?;
-        let b = BuilderData::from_cell(&c)
-            .map_err(|_| OperationError::CellComputeInternal)?;
-        refs.push(b);
+/// Replaces tokens of the body equal to parameter names with the arguments
+fn substitute_macro_params(body: &str, params: &[String], args: &[String]) -> String {
+    let is_token_char = |ch: char| ch.is_ascii_alphanumeric() || "-_./\\$@".contains(ch);
+    let mut text = String::new();
+    let mut token = String::new();
+    let mut in_comment = false;
+    let flush = |token: &mut String, text: &mut String| {
+        match params.iter().position(|p| p == token) {
+            Some(i) => text.push_str(&args[i]),
+            None => text.push_str(token),
+        }
+        token.clear();
+    };
+    for ch in body.chars() {
+        if !in_comment && is_token_char(ch) {
+            token.push(ch);
+            continue
+        }
+        flush(&mut token, &mut text);
+        if ch == ';' {
+            in_comment = true
+        } else if ch == '\n' || ch == '\r' {
+            in_comment = false
+        }
+        text.push(ch);
     }
-    let slice = SliceData::load_cell_ref(cell)
-        .map_err(|_| OperationError::CellComputeInternal)?;
-    let dbg_node = make_dbgnode(cell.clone(), DbgInfo::default());
+    flush(&mut token, &mut text);
+    text
+}
 
-    // write the cell's data and refs
-    destination.write_command_bitstring(slice.storage(), slice.remaining_bits(), DbgNode::default())?;
-    destination.write_composite_command(&[], refs, dbg_node)
+fn compile_macro(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    if par.len() < 2 {
+        return Err(OperationError::MissingRequiredParameters)
+    }
+    let (name, params) = parse_macro_call(&par[..par.len() - 1])?;
+    let body = par[par.len() - 1].to_string();
+    let mac = Macro {
+        params,
+        body,
+        filename: engine.source_name.clone(),
+        line: engine.line_no,
+        column: engine.char_no,
+    };
+    if engine.macros.insert(name.clone(), mac).is_some() {
+        return Err(OperationError::MacroIsAlreadyDefined(name))
+    }
+    Ok(())
 }
 
-fn compile_fragment(engine: &mut Engine, par: &[&str], _destination: &mut Units, _pos: DbgPos) -> CompileResult {
-    par.assert_len(2)?;
-    let name = par[0];
-    let (builder, dbg) = engine
-        .compile(par[1])
+fn compile_expand(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    if par.is_empty() {
+        return Err(OperationError::MissingRequiredParameters)
+    }
+    let (name, args) = parse_macro_call(par)?;
+    let mac = engine.macros.get(&name)
+        .ok_or_else(|| OperationError::MacroIsNotDefined(name.clone()))?;
+    if mac.params.len() != args.len() {
+        return Err(ParameterError::OutOfRange.parameter("number of macro arguments"))
+    }
+    if engine.expansion_depth >= MAX_EXPANSION_DEPTH {
+        return Err(OperationError::Internal(format!("macro {} is expanded too deeply", name)))
+    }
+    let body = substitute_macro_params(&mac.body, &mac.params, &args);
+    let (filename, line, column) = (mac.filename.clone(), mac.line, mac.column);
+
+    // the expanded code gets positions of the macro body
+    let source_name = std::mem::replace(&mut engine.source_name, filename);
+    let (line_no, char_no) = engine.set_pos(line, column);
+    let dbgpos = engine.dbgpos.take();
+    engine.expansion_depth += 1;
+    let result = engine.compile(&body);
+    engine.expansion_depth -= 1;
+    engine.dbgpos = dbgpos;
+    engine.set_pos(line_no, char_no);
+    engine.source_name = source_name;
+
+    let (builder, dbg) = result
         .map_err(|e| OperationError::Nested(Box::new(e)))?
         .finalize();
-    let unit = Unit::new(builder, dbg);
-    if engine.named_units.insert(name.to_string(), unit).is_some() {
-        return Err(OperationError::FragmentIsAlreadyDefined(name.to_string()))
-    }
-    engine.dbgpos = None;
-    Ok(())
+    destination.write_unit(Unit::new(builder, dbg).with_fragment(&name).inlined_at(&name, &pos))
 }
 
-fn compile_loc(engine: &mut Engine, par: &[&str], _destination: &mut Units, _pos: DbgPos) -> CompileResult {
-    par.assert_len(2)?;
-    let filename = par[0];
+fn compile_loc(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len_in(2..=3)?;
+    let filename = par[0].strip_prefix('"').and_then(|name| name.strip_suffix('"')).unwrap_or(par[0]);
     let line = par[1].parse::<usize>()
         .map_err(|_| ParameterError::NotSupported.parameter("line number"))?;
+    let column = match par.get(2) {
+        Some(column) => column.parse::<usize>()
+            .map_err(|_| ParameterError::NotSupported.parameter("column number"))?,
+        None => 0
+    };
     if line == 0 {
         engine.dbgpos = None;
     } else {
-        engine.dbgpos = Some(DbgPos { filename: filename.to_string(), line });
+        let filename = engine.files.intern(filename);
+        engine.dbgpos = Some(DbgPos { filename, line, column, ..Default::default() });
     }
     Ok(())
 }
 
-fn compile_library_cell(_engine: &mut Engine, par: &[&str], destination: &mut Units, _pos: DbgPos) -> CompileResult {
+fn compile_library_cell(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
     par.assert_len(1)?;
 
     let hash = hex::decode(par[0])
@@ -818,130 +1164,376 @@ fn compile_library_cell(_engine: &mut Engine, par: &[&str], destination: &mut Un
 
     let mut b = BuilderData::with_raw(vec!(0x02), 8)?;
     b.append_raw(hash.as_slice(), 256)?;
-    b.set_type(ever_block::CellType::LibraryReference);
+    b.set_type(CellType::LibraryReference);
+
+    let mut dbg = DbgNode::default();
+    dbg.append_node(DbgNode::default().with_role(CellRole::Library));
+    destination.write_composite_command(&[], vec!(b), dbg)
+}
+
+fn compile_pruned_branch(_engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+
+    let hash = hex::decode(par[0])
+        .map_err(|_| ParameterError::UnexpectedType.parameter("hash"))?;
+    if hash.len() != 32 {
+        return Err(ParameterError::OutOfRange.parameter("hash"))
+    }
+    let depth = par[1].parse::<u16>()
+        .map_err(|_| ParameterError::UnexpectedType.parameter("depth"))?;
+
+    // the branch pruned at level 1
+    let mut b = BuilderData::with_raw(vec!(0x01, 0x01), 16)?;
+    b.append_raw(hash.as_slice(), 256)?;
+    b.append_raw(&depth.to_be_bytes(), 16)?;
+    b.set_type(CellType::PrunedBranch);
+    b.set_level_mask(LevelMask::with_mask(1));
+
+    let mut dbg = DbgNode::default();
+    dbg.append_node(DbgNode::default().with_role(CellRole::Data));
+    destination.write_composite_command(&[], vec!(b), dbg)
+}
+
+/// Compiles the block into a cell to be referred to by a merkle cell
+fn compile_merkle_child(engine: &mut Engine, source: &str) -> Result<(Cell, DbgNode), OperationError> {
+    let (builder, dbg) = engine
+        .compile(source)
+        .map_err(|e| OperationError::Nested(Box::new(e)))?
+        .finalize();
+    Ok((builder.into_cell()?, dbg.with_role(CellRole::Data)))
+}
 
+/// Writes the merkle cell referring to the children as a reference of the current cell
+fn write_merkle_cell(mut b: BuilderData, children: Vec<(Cell, DbgNode)>, destination: &mut dyn Writer) -> CompileResult {
+    let mut level_mask = 0;
+    let mut node = DbgNode::default();
+    for (child, dbg) in children {
+        level_mask |= child.level_mask().mask();
+        b.checked_append_reference(child)?;
+        node.append_node(dbg);
+    }
+    // merkle cells hide one level of their children
+    b.set_level_mask(LevelMask::with_mask(level_mask >> 1));
     let mut dbg = DbgNode::default();
-    dbg.append_node(DbgNode::default());
+    dbg.append_node(node.with_role(CellRole::Data));
     destination.write_composite_command(&[], vec!(b), dbg)
 }
 
+fn compile_merkle_proof(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(1)?;
+    let (child, dbg) = compile_merkle_child(engine, par[0])?;
+
+    let mut b = BuilderData::with_raw(vec!(0x03), 8)?;
+    b.append_raw(child.hash(0).as_slice(), 256)?;
+    b.append_raw(&child.depth(0).to_be_bytes(), 16)?;
+    b.set_type(CellType::MerkleProof);
+    write_merkle_cell(b, vec!((child, dbg)), destination)
+}
+
+fn compile_merkle_update(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+    let (old, old_dbg) = compile_merkle_child(engine, par[0])?;
+    let (new, new_dbg) = compile_merkle_child(engine, par[1])?;
+
+    let mut b = BuilderData::with_raw(vec!(0x04), 8)?;
+    b.append_raw(old.hash(0).as_slice(), 256)?;
+    b.append_raw(new.hash(0).as_slice(), 256)?;
+    b.append_raw(&old.depth(0).to_be_bytes(), 16)?;
+    b.append_raw(&new.depth(0).to_be_bytes(), 16)?;
+    b.set_type(CellType::MerkleUpdate);
+    write_merkle_cell(b, vec!((old, old_dbg), (new, new_dbg)), destination)
+}
+
 // Compilation engine *********************************************************
 
+/// Instructions and directives with handlers written by hand
+#[rustfmt::skip]
+const COMPLEX_COMMANDS: &[(&str, CompileHandler)] = &[
+    // Alphabetically sorted
+    ("-ROLL",          Engine::ROLLREV),
+    ("-ROLLX",         Engine::ROLLREVX),
+    ("-ROT",           Engine::ROTREV),
+    ("2DROP",          Engine::DROP2),
+    ("2DUP",           Engine::DUP2),
+    ("2OVER",          Engine::OVER2),
+    ("2ROT",           Engine::ROT2),
+    ("2SWAP",          Engine::SWAP2),
+    ("CALL",           compile_call),
+    ("CALLDICT",       compile_call),
+    ("CALLREF",        compile_callref),
+    ("CALLXARGS",      compile_callxargs),
+    ("BCHKBITS",       compile_bchkbits),
+    ("BCHKBITSQ",      compile_bchkbitsq),
+    ("DEBUGSTR",       compile_dumptosfmt),
+    ("DUMPTOSFMT",     compile_dumptosfmt),
+    ("IFREF",          compile_ifref),
+    ("IFNOTREF",       compile_ifnotref),
+    ("IFJMPREF",       compile_ifjmpref),
+    ("IFNOTJMPREF",    compile_ifnotjmpref),
+    ("IFREFELSE",      compile_ifrefelse),
+    ("IFELSEREF",      compile_ifelseref),
+    ("IFREFELSEREF",   compile_ifrefelseref),
+    ("JMPDICT",        Engine::JMP),
+    ("JMPREF",         compile_jmpref),
+    ("LOGSTR",         compile_logstr),
+    ("LSHIFT",         Div::<Signaling>::lshift),
+    ("LSHIFTDIV",      Div::<Signaling>::lshiftdiv),
+    ("LSHIFTDIVC",     Div::<Signaling>::lshiftdivc),
+    ("LSHIFTDIVMOD",   Div::<Signaling>::lshiftdivmod),
+    ("LSHIFTDIVMODC",  Div::<Signaling>::lshiftdivmodc),
+    ("LSHIFTDIVMODR",  Div::<Signaling>::lshiftdivmodr),
+    ("LSHIFTDIVR",     Div::<Signaling>::lshiftdivr),
+    ("LSHIFTMOD",      Div::<Signaling>::lshiftmod),
+    ("LSHIFTMODC",     Div::<Signaling>::lshiftmodc),
+    ("LSHIFTMODR",     Div::<Signaling>::lshiftmodr),
+    ("MODPOW2",        Div::<Signaling>::modpow2),
+    ("MODPOW2C",       Div::<Signaling>::modpow2c),
+    ("MODPOW2R",       Div::<Signaling>::modpow2r),
+    ("MULMODPOW2",     Div::<Signaling>::mulmodpow2),
+    ("MULMODPOW2C",    Div::<Signaling>::mulmodpow2c),
+    ("MULMODPOW2R",    Div::<Signaling>::mulmodpow2r),
+    ("MULRSHIFT",      Div::<Signaling>::mulrshift),
+    ("MULRSHIFTC",     Div::<Signaling>::mulrshiftc),
+    ("MULRSHIFTMOD",   Div::<Signaling>::mulrshiftmod),
+    ("MULRSHIFTMODC",  Div::<Signaling>::mulrshiftmodc),
+    ("MULRSHIFTMODR",  Div::<Signaling>::mulrshiftmodr),
+    ("MULRSHIFTR",     Div::<Signaling>::mulrshiftr),
+    ("POP",            compile_pop),
+    ("PRINTSTR",       compile_printstr),
+    ("PUSH",           compile_push),
+    ("PUSHCONT",       compile_pushcont),
+    ("PUSHINT",        compile_pushint),
+    ("PUSHREF",        compile_pushref),
+    ("PUSHREFCONT",    compile_pushrefcont),
+    ("PUSHSLICE",      compile_pushslice),
+    ("PUSHREFSLICE",   compile_pushrefslice),
+    ("SETCONTARGS",    compile_setcontargs),
+    ("SWAP",           compile_xchg),
+    ("QLSHIFT",        Div::<Quiet>::lshift),
+    ("QLSHIFTDIV",     Div::<Quiet>::lshiftdiv),
+    ("QLSHIFTDIVC",    Div::<Quiet>::lshiftdivc),
+    ("QLSHIFTDIVMOD",  Div::<Quiet>::lshiftdivmod),
+    ("QLSHIFTDIVMODC", Div::<Quiet>::lshiftdivmodc),
+    ("QLSHIFTDIVMODR", Div::<Quiet>::lshiftdivmodr),
+    ("QLSHIFTDIVR",    Div::<Quiet>::lshiftdivr),
+    ("QLSHIFTMOD",     Div::<Quiet>::lshiftmod),
+    ("QLSHIFTMODC",    Div::<Quiet>::lshiftmodc),
+    ("QLSHIFTMODR",    Div::<Quiet>::lshiftmodr),
+    ("QMODPOW2",       Div::<Quiet>::modpow2),
+    ("QMODPOW2C",      Div::<Quiet>::modpow2c),
+    ("QMODPOW2R",      Div::<Quiet>::modpow2r),
+    ("QMULMODPOW2",    Div::<Quiet>::mulmodpow2),
+    ("QMULMODPOW2C",   Div::<Quiet>::mulmodpow2c),
+    ("QMULMODPOW2R",   Div::<Quiet>::mulmodpow2r),
+    ("QMULRSHIFT",     Div::<Quiet>::mulrshift),
+    ("QMULRSHIFTC",    Div::<Quiet>::mulrshiftc),
+    ("QMULRSHIFTMOD",  Div::<Quiet>::mulrshiftmod),
+    ("QMULRSHIFTMODC", Div::<Quiet>::mulrshiftmodc),
+    ("QMULRSHIFTMODR", Div::<Quiet>::mulrshiftmodr),
+    ("QMULRSHIFTR",    Div::<Quiet>::mulrshiftr),
+    ("QRSHIFT",        Div::<Quiet>::rshift),
+    ("QRSHIFTC",       Div::<Quiet>::rshiftc),
+    ("QRSHIFTMOD",     Div::<Quiet>::rshiftmod),
+    ("QRSHIFTMODC",    Div::<Quiet>::rshiftmodc),
+    ("QRSHIFTMODR",    Div::<Quiet>::rshiftmodr),
+    ("QRSHIFTR",       Div::<Quiet>::rshiftr),
+    ("RSHIFT",         Div::<Signaling>::rshift),
+    ("RSHIFTMOD",      Div::<Signaling>::rshiftmod),
+    ("RSHIFTMODC",     Div::<Signaling>::rshiftmodc),
+    ("RSHIFTMODR",     Div::<Signaling>::rshiftmodr),
+    ("RSHIFTR",        Div::<Signaling>::rshiftr),
+    ("RSHIFTC",        Div::<Signaling>::rshiftc),
+    ("SDBEGINS",       compile_sdbegins),
+    ("SDBEGINSQ",      compile_sdbeginsq),
+    ("STREFCONST",     compile_strefconst),
+    ("STREF2CONST",    compile_stref2const),
+    ("STSLICECONST",   compile_stsliceconst),
+    ("THROW",          compile_throw),
+    ("THROWIF",        compile_throwif),
+    ("THROWIFNOT",     compile_throwifnot),
+    ("XCHG",           compile_xchg),
+    // Pseudo instructions
+    (".BITS",          compile_blob),
+    (".BLOB",          compile_blob),
+    (".SLICE",         compile_blob),
+    (".CELL",          compile_cell),
+    (".INLINE",        compile_inline),
+    (".INT",           compile_int),
+    (".REF",           compile_cell),
+    (".UINT",          compile_uint),
+    (".LIBRARY-CELL",  compile_library_cell),
+    (".MERKLE-PROOF",  compile_merkle_proof),
+    (".MERKLE-UPDATE", compile_merkle_update),
+    (".PRUNED-BRANCH", compile_pruned_branch),
+
+    (".CODE-DICT-CELL",       compile_code_dict_cell),
+    (".CODE-DICT-SLICE",      compile_code_dict_slice),
+    (".FRAGMENT",             compile_fragment),
+    (".METHOD",               compile_method),
+    (".DISPATCH",             compile_dispatch),
+    (".LOC",                  compile_loc),
+    (".MACRO",                compile_macro),
+    (".INCLUDE",           crate::include::compile_include),
+    (".DEFINE",               crate::constants::compile_define),
+    (".METHOD-ID",            crate::constants::compile_method_id),
+    (".EXCEPTION",            crate::constants::compile_exception),
+    (".GLOBAL",               crate::constants::compile_global),
+    (".VARS",                 crate::vars::compile_vars),
+    (".PROC",                 crate::procs::compile_proc),
+    (".DATA",                 crate::data::compile_data),
+    ("CALLPROC",              crate::procs::compile_callproc),
+    (".EXPAND",               compile_expand),
+];
+
 impl Engine {
+    /// Table of the instructions and directives compiled by hand-written handlers
+    pub(crate) fn complex_command_table() -> &'static [(&'static str, CompileHandler)] {
+        COMPLEX_COMMANDS
+    }
 
     #[rustfmt::skip]
     pub fn add_complex_commands(&mut self) {
-        // Alphabetically sorted
-        self.handlers.insert("-ROLL",          Engine::ROLLREV);
-        self.handlers.insert("-ROLLX",         Engine::ROLLREVX);
-        self.handlers.insert("-ROT",           Engine::ROTREV);
-        self.handlers.insert("2DROP",          Engine::DROP2);
-        self.handlers.insert("2DUP",           Engine::DUP2);
-        self.handlers.insert("2OVER",          Engine::OVER2);
-        self.handlers.insert("2ROT",           Engine::ROT2);
-        self.handlers.insert("2SWAP",          Engine::SWAP2);
-        self.handlers.insert("CALL",           compile_call);
-        self.handlers.insert("CALLDICT",       compile_call);
-        self.handlers.insert("CALLREF",        compile_callref);
-        self.handlers.insert("CALLXARGS",      compile_callxargs);
-        self.handlers.insert("BCHKBITS",       compile_bchkbits);
-        self.handlers.insert("BCHKBITSQ",      compile_bchkbitsq);
-        self.handlers.insert("DEBUGSTR",       compile_dumptosfmt);
-        self.handlers.insert("DUMPTOSFMT",     compile_dumptosfmt);
-        self.handlers.insert("IFREF",          compile_ifref);
-        self.handlers.insert("IFNOTREF",       compile_ifnotref);
-        self.handlers.insert("IFJMPREF",       compile_ifjmpref);
-        self.handlers.insert("IFNOTJMPREF",    compile_ifnotjmpref);
-        self.handlers.insert("IFREFELSE",      compile_ifrefelse);
-        self.handlers.insert("IFELSEREF",      compile_ifelseref);
-        self.handlers.insert("IFREFELSEREF",   compile_ifrefelseref);
-        self.handlers.insert("JMPDICT",        Engine::JMP);
-        self.handlers.insert("JMPREF",         compile_jmpref);
-        self.handlers.insert("LOGSTR",         compile_logstr);
-        self.handlers.insert("LSHIFT",         Div::<Signaling>::lshift);
-        self.handlers.insert("LSHIFTDIV",      Div::<Signaling>::lshiftdiv);
-        self.handlers.insert("LSHIFTDIVC",     Div::<Signaling>::lshiftdivc);
-        self.handlers.insert("LSHIFTDIVMOD",   Div::<Signaling>::lshiftdivmod);
-        self.handlers.insert("LSHIFTDIVMODC",  Div::<Signaling>::lshiftdivmodc);
-        self.handlers.insert("LSHIFTDIVMODR",  Div::<Signaling>::lshiftdivmodr);
-        self.handlers.insert("LSHIFTDIVR",     Div::<Signaling>::lshiftdivr);
-        self.handlers.insert("LSHIFTMOD",      Div::<Signaling>::lshiftmod);
-        self.handlers.insert("LSHIFTMODC",     Div::<Signaling>::lshiftmodc);
-        self.handlers.insert("LSHIFTMODR",     Div::<Signaling>::lshiftmodr);
-        self.handlers.insert("MODPOW2",        Div::<Signaling>::modpow2);
-        self.handlers.insert("MODPOW2C",       Div::<Signaling>::modpow2c);
-        self.handlers.insert("MODPOW2R",       Div::<Signaling>::modpow2r);
-        self.handlers.insert("MULMODPOW2",     Div::<Signaling>::mulmodpow2);
-        self.handlers.insert("MULMODPOW2C",    Div::<Signaling>::mulmodpow2c);
-        self.handlers.insert("MULMODPOW2R",    Div::<Signaling>::mulmodpow2r);
-        self.handlers.insert("MULRSHIFT",      Div::<Signaling>::mulrshift);
-        self.handlers.insert("MULRSHIFTC",     Div::<Signaling>::mulrshiftc);
-        self.handlers.insert("MULRSHIFTMOD",   Div::<Signaling>::mulrshiftmod);
-        self.handlers.insert("MULRSHIFTMODC",  Div::<Signaling>::mulrshiftmodc);
-        self.handlers.insert("MULRSHIFTMODR",  Div::<Signaling>::mulrshiftmodr);
-        self.handlers.insert("MULRSHIFTR",     Div::<Signaling>::mulrshiftr);
-        self.handlers.insert("POP",            compile_pop);
-        self.handlers.insert("PRINTSTR",       compile_printstr);
-        self.handlers.insert("PUSH",           compile_push);
-        self.handlers.insert("PUSHCONT",       compile_pushcont);
-        self.handlers.insert("PUSHINT",        compile_pushint);
-        self.handlers.insert("PUSHREF",        compile_pushref);
-        self.handlers.insert("PUSHREFCONT",    compile_pushrefcont);
-        self.handlers.insert("PUSHSLICE",      compile_pushslice);
-        self.handlers.insert("PUSHREFSLICE",   compile_pushrefslice);
-        self.handlers.insert("SETCONTARGS",    compile_setcontargs);
-        self.handlers.insert("SWAP",           compile_xchg);
-        self.handlers.insert("QLSHIFT",        Div::<Quiet>::lshift);
-        self.handlers.insert("QLSHIFTDIV",     Div::<Quiet>::lshiftdiv);
-        self.handlers.insert("QLSHIFTDIVC",    Div::<Quiet>::lshiftdivc);
-        self.handlers.insert("QLSHIFTDIVMOD",  Div::<Quiet>::lshiftdivmod);
-        self.handlers.insert("QLSHIFTDIVMODC", Div::<Quiet>::lshiftdivmodc);
-        self.handlers.insert("QLSHIFTDIVMODR", Div::<Quiet>::lshiftdivmodr);
-        self.handlers.insert("QLSHIFTDIVR",    Div::<Quiet>::lshiftdivr);
-        self.handlers.insert("QLSHIFTMOD",     Div::<Quiet>::lshiftmod);
-        self.handlers.insert("QLSHIFTMODC",    Div::<Quiet>::lshiftmodc);
-        self.handlers.insert("QLSHIFTMODR",    Div::<Quiet>::lshiftmodr);
-        self.handlers.insert("QMODPOW2",       Div::<Quiet>::modpow2);
-        self.handlers.insert("QMODPOW2C",      Div::<Quiet>::modpow2c);
-        self.handlers.insert("QMODPOW2R",      Div::<Quiet>::modpow2r);
-        self.handlers.insert("QMULMODPOW2",    Div::<Quiet>::mulmodpow2);
-        self.handlers.insert("QMULMODPOW2C",   Div::<Quiet>::mulmodpow2c);
-        self.handlers.insert("QMULMODPOW2R",   Div::<Quiet>::mulmodpow2r);
-        self.handlers.insert("QMULRSHIFT",     Div::<Quiet>::mulrshift);
-        self.handlers.insert("QMULRSHIFTC",    Div::<Quiet>::mulrshiftc);
-        self.handlers.insert("QMULRSHIFTMOD",  Div::<Quiet>::mulrshiftmod);
-        self.handlers.insert("QMULRSHIFTMODC", Div::<Quiet>::mulrshiftmodc);
-        self.handlers.insert("QMULRSHIFTMODR", Div::<Quiet>::mulrshiftmodr);
-        self.handlers.insert("QMULRSHIFTR",    Div::<Quiet>::mulrshiftr);
-        self.handlers.insert("QRSHIFT",        Div::<Quiet>::rshift);
-        self.handlers.insert("QRSHIFTC",       Div::<Quiet>::rshiftc);
-        self.handlers.insert("QRSHIFTMOD",     Div::<Quiet>::rshiftmod);
-        self.handlers.insert("QRSHIFTMODC",    Div::<Quiet>::rshiftmodc);
-        self.handlers.insert("QRSHIFTMODR",    Div::<Quiet>::rshiftmodr);
-        self.handlers.insert("QRSHIFTR",       Div::<Quiet>::rshiftr);
-        self.handlers.insert("RSHIFT",         Div::<Signaling>::rshift);
-        self.handlers.insert("RSHIFTMOD",      Div::<Signaling>::rshiftmod);
-        self.handlers.insert("RSHIFTMODC",     Div::<Signaling>::rshiftmodc);
-        self.handlers.insert("RSHIFTMODR",     Div::<Signaling>::rshiftmodr);
-        self.handlers.insert("RSHIFTR",        Div::<Signaling>::rshiftr);
-        self.handlers.insert("RSHIFTC",        Div::<Signaling>::rshiftc);
-        self.handlers.insert("SDBEGINS",       compile_sdbegins);
-        self.handlers.insert("SDBEGINSQ",      compile_sdbeginsq);
-        self.handlers.insert("SETCONTARGS",    compile_setcontargs);
-        self.handlers.insert("STSLICECONST",   compile_stsliceconst);
-        self.handlers.insert("THROW",          compile_throw);
-        self.handlers.insert("THROWIF",        compile_throwif);
-        self.handlers.insert("THROWIFNOT",     compile_throwifnot);
-        self.handlers.insert("XCHG",           compile_xchg);
-        // Pseudo instructions
-        self.handlers.insert(".BLOB",          compile_blob);
-        self.handlers.insert(".CELL",          compile_cell);
-        self.handlers.insert(".INLINE",        compile_inline);
-        self.handlers.insert(".LIBRARY-CELL",  compile_library_cell);
-
-        self.handlers.insert(".CODE-DICT-CELL",       compile_code_dict_cell);
-        self.handlers.insert(".INLINE-COMPUTED-CELL", compile_inline_computed_cell);
-        self.handlers.insert(".FRAGMENT",             compile_fragment);
-        self.handlers.insert(".LOC",                  compile_loc);
+        self.handlers.extend(Self::complex_command_table().iter().copied());
+        #[cfg(feature = "eval")]
+        self.handlers.insert(".INLINE-COMPUTED-CELL", crate::eval::compile_inline_computed_cell);
+        #[cfg(feature = "eval")]
+        self.handlers.insert(".INLINE-COMPUTED",      crate::eval::compile_inline_computed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_code_to_cell, PackingPolicy};
+    use crate::test_helpers::operation_error;
+
+    #[test]
+    fn macro_call_parsing() {
+        assert_eq!(parse_macro_call(&["pair(a", "b)"]).unwrap(), ("pair".to_string(), vec!("a".to_string(), "b".to_string())));
+        assert_eq!(parse_macro_call(&["plain"]).unwrap(), ("plain".to_string(), vec!()));
+        assert_eq!(parse_macro_call(&["empty()"]).unwrap(), ("empty".to_string(), vec!()));
+        assert!(parse_macro_call(&["open(a"]).is_err());
+        assert!(parse_macro_call(&["(a)"]).is_err());
+    }
+
+    #[test]
+    fn macro_params_substitution() {
+        let params = ["a".to_string()];
+        let args = ["1".to_string()];
+        assert_eq!(
+            substitute_macro_params("PUSHINT a\nPUSHINT ab ; a\nPUSHINT a", &params, &args),
+            "PUSHINT 1\nPUSHINT ab ; a\nPUSHINT 1"
+        );
+    }
+
+    #[test]
+    fn macro_expansion() {
+        let expanded = compile_code_to_cell("
+            .macro add_n(x) {
+                PUSHINT x
+                ADD
+            }
+            .macro pair(a, b) {
+                PUSHINT a
+                PUSHINT b
+            }
+            .expand add_n(5)
+            .expand pair(1, 2)
+            .expand add_n(7)
+        ").unwrap();
+        let plain = compile_code_to_cell("PUSHINT 5\nADD\nPUSHINT 1\nPUSHINT 2\nPUSHINT 7\nADD").unwrap();
+        assert_eq!(expanded.repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn macro_call_site() {
+        let (_, dbg) = Engine::new("main.code")
+            .build(None, ".macro m {\n  INC\n}\nNOP\n.expand m")
+            .unwrap()
+            .finalize();
+        let (_, positions) = dbg.iter().next().unwrap();
+        let pos = &positions[&8];
+        assert_eq!(&*pos.filename, "main.code");
+        assert_eq!(pos.inlined_at.len(), 1);
+        let site = &pos.inlined_at[0];
+        assert_eq!((site.name.as_str(), &*site.filename, site.line), ("m", "main.code", 5));
+    }
+
+    #[test]
+    fn macro_errors() {
+        assert_eq!(operation_error(&mut Engine::new(""), ".expand nothing"), OperationError::MacroIsNotDefined("nothing".to_string()));
+        assert_eq!(
+            operation_error(&mut Engine::new(""), ".macro m { NOP }\n.macro m { INC }"),
+            OperationError::MacroIsAlreadyDefined("m".to_string())
+        );
+        assert!(matches!(
+            operation_error(&mut Engine::new(""), ".macro m(x) { PUSHINT x }\n.expand m"),
+            OperationError::Parameter(_, ParameterError::OutOfRange)
+        ));
+        assert!(matches!(operation_error(&mut Engine::new(""), ".macro r { .expand r }\n.expand r"), OperationError::Internal(_)));
+    }
+
+    fn packed(policy: PackingPolicy, fragment: &str) -> Cell {
+        let source = format!(".fragment f, {{\n{}\n}}\n{}.inline f", fragment, "NOP\n".repeat(120));
+        let mut engine = Engine::new("");
+        engine.set_packing_policy(policy);
+        engine.build(None, &source).unwrap().finalize().0.cell().clone()
+    }
+
+    #[test]
+    fn packing_keeps_semantics() {
+        let plain = "INC\n".repeat(10);
+        let greedy = packed(PackingPolicy::Greedy, &plain);
+        assert_ne!(packed(PackingPolicy::MinimizeDepth, &plain).repr_hash(), greedy.repr_hash());
+        for fragment in ["IFRET", "RETALT", "PUSHCTR c0", "POPCTR c1", "PUSHCONT {\n  RET\n}\nIF"] {
+            let fragment = format!("{}{}", plain, fragment);
+            let greedy = packed(PackingPolicy::Greedy, &fragment);
+            assert_eq!(packed(PackingPolicy::MinimizeDepth, &fragment).repr_hash(), greedy.repr_hash(), "{}", fragment);
+        }
+    }
+
+    #[test]
+    fn inline_over_limit() {
+        let call = |fragment: &str| {
+            let mut engine = Engine::new("");
+            let source = format!(".fragment f, {{\n{}\n}}\n.inline f, 8", fragment);
+            let cell = crate::test_helpers::compile(&mut engine, &source).unwrap();
+            let kept = engine.diagnostics().iter().any(|d| d.code == "inline-kept");
+            (cell.references_count() == 1, kept)
+        };
+        let plain = "INC\n".repeat(4);
+        assert_eq!(call(&plain), (true, false));
+        // called by CALLREF the fragment would return from the call only
+        assert_eq!(call(&format!("{}RET", plain)), (false, true));
+        assert_eq!(call(&format!("{}PUSHCTR c0", plain)), (false, true));
+    }
+
+    #[test]
+    fn int_data() {
+        for (source, bits, value) in [
+            (".int 8, -1", 8, 0xFF),
+            (".int 8, 127", 8, 0x7F),
+            (".int 12, -2", 12, 0xFFE),
+            (".int 64, -42", 64, -42i64 as u64),
+            (".uint 1, 1", 1, 1),
+            (".uint 16, 0xABCD", 16, 0xABCD),
+        ] {
+            let mut slice = SliceData::load_cell(compile_code_to_cell(source).unwrap()).unwrap();
+            assert_eq!(slice.remaining_bits(), bits, "{}", source);
+            assert_eq!(slice.get_next_int(bits).unwrap(), value, "{}", source);
+        }
+        let mut slice = SliceData::load_cell(compile_code_to_cell(".int 300, -1").unwrap()).unwrap();
+        assert_eq!(slice.remaining_bits(), 300);
+        assert_eq!(slice.get_next_int(64).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn int_data_errors() {
+        for source in [".int 8, 128", ".int 8, -129", ".uint 8, 256", ".uint 8, -1", ".int 0, 0", ".uint 1024, 0"] {
+            assert!(matches!(
+                operation_error(&mut Engine::new(""), source),
+                OperationError::Parameter(_, ParameterError::OutOfRange)
+            ), "{}", source);
+        }
     }
 }
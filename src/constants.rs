@@ -0,0 +1,257 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Compile-time constants ****************************************************
+//
+// Constants are substituted into instruction operands before encoding;
+// directives and blocks are left intact, except for the value of `.define`
+// and the id of `.method`, and so are the operands naming labels, fragments
+// and procedures.
+
+use std::{borrow::Cow, collections::BTreeMap};
+
+use crate::{
+    CompileError, CompileResult, DbgPos, Engine, EnsureParametersCountInRange,
+    OperationError, ParameterError, Position, ToOperationParameterError, Token, Writer,
+    complex::ref_command,
+};
+
+pub(crate) fn is_identifier(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Whether the operands of the instruction name code rather than give integers or slices
+fn takes_names(operation: &str) -> bool {
+    ref_command(operation).is_some() || matches!(operation, "IFREFELSEREF" | "STREF2CONST" | "CALLPROC")
+}
+
+/// Whether the identifier-like token is in fact a register or a bitstring literal
+fn is_register_or_literal(token: &str) -> bool {
+    let mut chars = token.chars();
+    match chars.next().map(|c| c.to_ascii_lowercase()) {
+        Some('s') | Some('c') => chars.all(|c| c.is_ascii_digit()),
+        Some('x') => chars.all(|c| c.is_ascii_hexdigit() || c == '_'),
+        Some('b') => chars.all(|c| c == '0' || c == '1'),
+        _ => false
+    }
+}
+
+impl Engine {
+    /// Defines a constant which can be used in instruction operands in place of its value
+    pub fn define_const(&mut self, name: &str, value: &str) {
+        self.constants.insert(name.to_string(), value.to_string());
+    }
+
+    /// Returns the operand with the constant substituted if the token names one
+    pub(crate) fn substitute_const<'a>(&self, operation: &str, index: usize, token: &Token<'a>) -> Cow<'a, str> {
+        let substitute = !token.block && match operation {
+            ".DEFINE" => index == 1,
+            ".METHOD" => index == 0,
+            _ => !operation.starts_with('.') && !takes_names(operation),
+        };
+        match self.constants.get(token.token) {
+            Some(value) if substitute => Cow::Owned(value.clone()),
+            // stack variables are resolved on the parsed program, any register does to parse it
+            None if substitute && self.ir.is_some() && token.token.starts_with('$') =>
+                Cow::Owned(format!("s{}", index + 1)),
+            _ => Cow::Borrowed(token.token)
+        }
+    }
+
+    /// Finds an operand which is probably a reference to an undefined constant
+    /// to report it instead of a plain parameter error
+    pub(crate) fn undefined_const(&self, operation: &str, par: &[Token]) -> Option<CompileError> {
+        if operation.starts_with('.') || takes_names(operation) {
+            return None
+        }
+        par.iter()
+            .filter(|t| !t.block && is_identifier(t.token) && !is_register_or_literal(t.token))
+            .find(|t| !self.constants.contains_key(t.token))
+            .map(|t| CompileError::UndefinedConstant(
+                Position::new(self.source_name.to_string(), t.line, t.column).with_token(t.token),
+                t.token.to_string(),
+            ))
+    }
+}
+
+pub(crate) fn compile_define(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+    let name = par[0];
+    if !is_identifier(name) || is_register_or_literal(name) {
+        return Err(ParameterError::UnexpectedType.parameter("name"))
+    }
+    if engine.constants.contains_key(name) {
+        return Err(OperationError::ConstantIsAlreadyDefined(name.to_string()))
+    }
+    engine.define_const(name, par[1]);
+    Ok(())
+}
+
+/// CRC16/XMODEM of the bytes
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+/// Method id of the function computed from its name or signature
+/// the way get-methods are numbered: `crc16(signature) | 0x10000`
+pub fn method_id(signature: &str) -> u32 {
+    crc16(signature.as_bytes()) as u32 | 0x10000
+}
+
+/// `.method-id NAME, "transfer(uint128,address)"` defines a constant holding the method id
+pub(crate) fn compile_method_id(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+    let name = par[0];
+    if !is_identifier(name) || is_register_or_literal(name) {
+        return Err(ParameterError::UnexpectedType.parameter("name"))
+    }
+    let signature = par[1].strip_prefix('"').and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| ParameterError::UnexpectedType.parameter("signature"))?;
+    if engine.constants.contains_key(name) {
+        return Err(OperationError::ConstantIsAlreadyDefined(name.to_string()))
+    }
+    engine.define_const(name, &method_id(signature).to_string());
+    Ok(())
+}
+
+/// Range of exit codes an exception can be given: 0 and 1 mean success,
+/// THROW takes codes below 2048
+const EXCEPTION_CODES: std::ops::Range<u16> = 2..2048;
+
+/// `.exception NAME, CODE` defines a constant holding the exit code
+/// and registers the name of the exception
+pub(crate) fn compile_exception(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+    let name = par[0];
+    if !is_identifier(name) || is_register_or_literal(name) {
+        return Err(ParameterError::UnexpectedType.parameter("name"))
+    }
+    let code = par[1].parse::<u16>()
+        .map_err(|_| ParameterError::UnexpectedType.parameter("code"))?;
+    if !EXCEPTION_CODES.contains(&code) {
+        return Err(ParameterError::OutOfRange.parameter("code"))
+    }
+    if engine.constants.contains_key(name) {
+        return Err(OperationError::ConstantIsAlreadyDefined(name.to_string()))
+    }
+    if let Some((other, _)) = engine.exceptions.iter().find(|(_, c)| **c == code) {
+        return Err(OperationError::ExceptionCodeIsAlreadyUsed(code, other.clone()))
+    }
+    engine.define_const(name, &code.to_string());
+    engine.exceptions.insert(name.to_string(), code);
+    Ok(())
+}
+
+/// Indexes of global variables in c7, the 0th one holds the parameters of the smart contract
+const GLOBAL_INDEXES: std::ops::RangeInclusive<u8> = 1..=254;
+
+/// `.global NAME, INDEX` defines a constant holding the index of the global
+/// variable for GETGLOB and SETGLOB, and names the variable in the debug map
+pub(crate) fn compile_global(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+    let name = par[0];
+    if !is_identifier(name) || is_register_or_literal(name) {
+        return Err(ParameterError::UnexpectedType.parameter("name"))
+    }
+    let index = par[1].parse::<u8>()
+        .map_err(|_| ParameterError::UnexpectedType.parameter("index"))?;
+    if !GLOBAL_INDEXES.contains(&index) {
+        return Err(ParameterError::OutOfRange.parameter("index"))
+    }
+    if engine.constants.contains_key(name) {
+        return Err(OperationError::ConstantIsAlreadyDefined(name.to_string()))
+    }
+    if let Some((other, _)) = engine.globals.iter().find(|(_, i)| **i == index) {
+        return Err(OperationError::GlobalIndexIsAlreadyUsed(index, other.clone()))
+    }
+    engine.define_const(name, &index.to_string());
+    engine.globals.insert(name.to_string(), index);
+    Ok(())
+}
+
+impl Engine {
+    /// Exceptions declared by `.exception`, names to exit codes
+    pub fn exceptions(&self) -> &BTreeMap<String, u16> {
+        &self.exceptions
+    }
+
+    /// Global variables named by `.global`, names to indexes
+    pub fn globals(&self) -> &BTreeMap<String, u8> {
+        &self.globals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_code_to_cell;
+    use crate::test_helpers::compile;
+
+    #[test]
+    fn registers_and_literals() {
+        for token in ["s0", "S15", "c7", "x1F_", "xa", "b101"] {
+            assert!(is_register_or_literal(token), "{}", token);
+        }
+        for token in ["size", "count", "xyz", "b2", "LIMIT"] {
+            assert!(!is_register_or_literal(token), "{}", token);
+        }
+    }
+
+    #[test]
+    fn define() {
+        let defined = compile_code_to_cell(".define N 5\nPUSHINT N\nPUSHCONT {\n  PUSHINT N\n}").unwrap();
+        let plain = compile_code_to_cell("PUSHINT 5\nPUSHCONT {\n  PUSHINT 5\n}").unwrap();
+        assert_eq!(defined.repr_hash(), plain.repr_hash());
+
+        let mut engine = Engine::new("");
+        engine.define_const("LIMIT", "10");
+        let defined = compile(&mut engine, "PUSHINT LIMIT").unwrap();
+        assert_eq!(defined.repr_hash(), compile_code_to_cell("PUSHINT 10").unwrap().repr_hash());
+    }
+
+    #[test]
+    fn define_keeps_names() {
+        let defined = compile_code_to_cell(".define done 5\nIFJMPREF done\nNOP\n.label done:\nINC").unwrap();
+        let plain = compile_code_to_cell("IFJMPREF done\nNOP\n.label done:\nINC").unwrap();
+        assert_eq!(defined.repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn define_errors() {
+        assert!(matches!(
+            compile_code_to_cell("PUSHINT LIMIT"),
+            Err(CompileError::UndefinedConstant(_, name)) if name == "LIMIT"
+        ));
+        assert!(matches!(
+            compile_code_to_cell(".define N 1\n.define N 2"),
+            Err(CompileError::Operation(_, _, OperationError::ConstantIsAlreadyDefined(name))) if name == "N"
+        ));
+        for source in [".define s1 5", ".define 1x 5", ".define x12 5"] {
+            assert!(matches!(
+                compile_code_to_cell(source),
+                Err(CompileError::Operation(_, _, OperationError::Parameter(_, ParameterError::UnexpectedType)))
+            ), "{}", source);
+        }
+    }
+}
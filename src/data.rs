@@ -0,0 +1,97 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+// Data segment **************************************************************
+//
+// `.data { .int 257, -1  .slice x1234_  .ref { ... } }` at the toplevel
+// defines the initial data of the contract: the body is compiled into a cell
+// of its own, kept apart from the code and returned by
+// `Engine::build_with_data`, or put into the StateInit by `asm --stateinit`.
+
+use ever_block::{BuilderData, Cell};
+
+use crate::{CompileError, CompileResult, DbgPos, Engine, EnsureParametersCountInRange, OperationError, Unit, Writer};
+
+pub(crate) fn compile_data(engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    if engine.line_no == 0 && engine.char_no == 0 {
+        return Err(OperationError::MissingBlock)
+    }
+    par.assert_len(1)?;
+    if engine.data.is_some() {
+        return Err(OperationError::DataIsAlreadyDefined)
+    }
+    let (data, _) = engine
+        .compile(par[0])
+        .map_err(|e| OperationError::Nested(Box::new(e)))?
+        .finalize();
+    engine.data = Some(data);
+    Ok(())
+}
+
+impl Engine {
+    /// Initial data defined by `.data` if any
+    pub fn data(&self) -> Option<&BuilderData> {
+        self.data.as_ref()
+    }
+
+    /// Builds the unit along with the data cell defined by `.data` in the source,
+    /// the data is still available by [`Engine::data`] afterwards
+    pub fn build_with_data(&mut self, name: Option<String>, source: &str) -> Result<(Unit, Option<Cell>), CompileError> {
+        self.data = None;
+        let unit = self.build(name, source)?;
+        let data = match self.data.clone() {
+            Some(data) => Some(data.into_cell()
+                .map_err(|_| CompileError::unknown(0, 0, "failure while convert BuilderData to cell"))?),
+            None => None
+        };
+        Ok((unit, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ever_block::SliceData;
+    use super::*;
+    use crate::compile_code_to_cell;
+    use crate::test_helpers::operation_error;
+
+    #[test]
+    fn data_apart_from_code() {
+        let mut engine = Engine::new("");
+        let source = ".data {\n  .int 8, 5\n  .ref {\n    .uint 16, 7\n  }\n}\nINC";
+        let (unit, data) = engine.build_with_data(None, source).unwrap();
+        let code = unit.finalize().0.into_cell().unwrap();
+        assert_eq!(code.repr_hash(), compile_code_to_cell("INC").unwrap().repr_hash());
+
+        let data = data.unwrap();
+        let mut slice = SliceData::load_cell(data.clone()).unwrap();
+        assert_eq!(slice.remaining_bits(), 8);
+        assert_eq!(slice.get_next_int(8).unwrap(), 5);
+        assert_eq!(data.references_count(), 1);
+        let mut nested = SliceData::load_cell(data.reference(0).unwrap()).unwrap();
+        assert_eq!(nested.get_next_int(16).unwrap(), 7);
+        assert_eq!(engine.data().map(|data| data.references_used()), Some(1));
+
+        let (_, data) = engine.build_with_data(None, "INC").unwrap();
+        assert!(data.is_none());
+    }
+
+    #[test]
+    fn data_defined_once() {
+        assert_eq!(
+            operation_error(&mut Engine::new(""), ".data {\n  .int 8, 1\n}\n.data {\n  .int 8, 2\n}"),
+            OperationError::DataIsAlreadyDefined
+        );
+    }
+}
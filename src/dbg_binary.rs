@@ -0,0 +1,306 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Binary debug map **********************************************************
+//
+// The JSON debug map repeats the filename in every position and gets huge for
+// big contracts. The binary form keeps filenames and names of inlined code in
+// a string table referred to by index, and all the numbers as LEB128 varints:
+//
+//   magic "TDBG", format version byte
+//   string table: count, then length and UTF-8 bytes of each string
+//   cells: count, then for each one its 32-byte hash and count of positions,
+//   each position being offset, filename, line, column and the inline sites,
+//   each site being name, filename, line and column
+//   procedures: count, then for each one its name, number of arguments,
+//   number of results plus one, method id plus one or 0 and position
+//   global variables: count, then for each one its name and index
+//
+// Version 1 ends after the cells and is still read.
+
+use std::{collections::{BTreeMap, HashMap}, io::{Error, ErrorKind, Read, Result, Write}, sync::Arc};
+use ever_block::UInt256;
+
+use crate::{DbgInfo, DbgPos, InlineSite, ProcInfo};
+
+const MAGIC: &[u8; 4] = b"TDBG";
+const FORMAT_VERSION: u8 = 2;
+
+fn invalid<S: ToString>(message: S) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_varint(out: &mut impl Write, mut value: usize) -> Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return out.write_all(&[byte])
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint(input: &mut impl Read) -> Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let mut byte = [0u8];
+        input.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+/// Reads the bytes of the given length failing if the input is shorter,
+/// without trusting the length for the allocation
+fn read_bytes(input: &mut impl Read, len: usize) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "binary debug map is truncated"))
+    }
+    Ok(bytes)
+}
+
+/// Strings of the map in the order of their first appearance
+#[derive(Default)]
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    indices: HashMap<&'a str, usize>,
+}
+
+impl<'a> StringTable<'a> {
+    fn add(&mut self, string: &'a str) {
+        if !self.indices.contains_key(string) {
+            self.indices.insert(string, self.strings.len());
+            self.strings.push(string);
+        }
+    }
+    fn index(&self, string: &str) -> usize {
+        self.indices[string]
+    }
+    fn add_pos(&mut self, pos: &'a DbgPos) {
+        self.add(&pos.filename);
+        for site in &pos.inlined_at {
+            self.add(&site.name);
+            self.add(&site.filename);
+        }
+    }
+}
+
+fn write_pos(out: &mut impl Write, table: &StringTable, pos: &DbgPos) -> Result<()> {
+    for value in [table.index(&pos.filename), pos.line, pos.column, pos.inlined_at.len()] {
+        write_varint(out, value)?;
+    }
+    for site in &pos.inlined_at {
+        for value in [table.index(&site.name), table.index(&site.filename), site.line, site.column] {
+            write_varint(out, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_pos(table: &[Arc<str>], input: &mut impl Read) -> Result<DbgPos> {
+    let mut pos = DbgPos {
+        filename: read_string(table, input)?,
+        line: read_varint(input)?,
+        column: read_varint(input)?,
+        inlined_at: Vec::new(),
+    };
+    for _ in 0..read_varint(input)? {
+        pos.inlined_at.push(InlineSite {
+            name: read_string(table, input)?.to_string(),
+            filename: read_string(table, input)?,
+            line: read_varint(input)?,
+            column: read_varint(input)?,
+        });
+    }
+    Ok(pos)
+}
+
+fn read_number<T: TryFrom<usize>>(input: &mut impl Read, what: &str) -> Result<T> {
+    let value = read_varint(input)?;
+    T::try_from(value).map_err(|_| invalid(format!("{} {} is out of range", what, value)))
+}
+
+fn read_string(table: &[Arc<str>], input: &mut impl Read) -> Result<Arc<str>> {
+    let index = read_varint(input)?;
+    table.get(index).cloned().ok_or_else(|| invalid(format!("string index {} is out of the table", index)))
+}
+
+impl DbgInfo {
+    /// Writes the map in the compact binary form
+    pub fn write_binary(&self, out: &mut impl Write) -> Result<()> {
+        let mut table = StringTable::default();
+        for (_, positions) in self.iter() {
+            for pos in positions.values() {
+                table.add_pos(pos);
+            }
+        }
+        for (name, proc) in self.procs() {
+            table.add(name);
+            table.add_pos(&proc.pos);
+        }
+        for name in self.globals().keys() {
+            table.add(name);
+        }
+        out.write_all(MAGIC)?;
+        out.write_all(&[FORMAT_VERSION])?;
+        write_varint(out, table.strings.len())?;
+        for string in &table.strings {
+            write_varint(out, string.len())?;
+            out.write_all(string.as_bytes())?;
+        }
+        write_varint(out, self.len())?;
+        for (hash, positions) in self.iter() {
+            out.write_all(hash.as_slice())?;
+            write_varint(out, positions.len())?;
+            for (offset, pos) in positions {
+                write_varint(out, *offset)?;
+                write_pos(out, &table, pos)?;
+            }
+        }
+        write_varint(out, self.procs().len())?;
+        for (name, proc) in self.procs() {
+            let nrets = (proc.nrets as isize + 1) as usize;
+            let id = proc.id.map_or(0, |id| id as usize + 1);
+            for value in [table.index(name), proc.nargs as usize, nrets, id] {
+                write_varint(out, value)?;
+            }
+            write_pos(out, &table, &proc.pos)?;
+        }
+        write_varint(out, self.globals().len())?;
+        for (name, index) in self.globals() {
+            write_varint(out, table.index(name))?;
+            write_varint(out, *index as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the map written by [`DbgInfo::write_binary`]
+    pub fn read_binary(input: &mut impl Read) -> Result<Self> {
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("not a binary debug map"))
+        }
+        let version = header[4];
+        if !(1..=FORMAT_VERSION).contains(&version) {
+            return Err(invalid(format!("unsupported binary debug map version {}", version)))
+        }
+        let mut table = Vec::new();
+        for _ in 0..read_varint(input)? {
+            let len = read_varint(input)?;
+            let bytes = read_bytes(input, len)?;
+            table.push(Arc::from(String::from_utf8(bytes).map_err(invalid)?));
+        }
+        let mut info = DbgInfo::default();
+        for _ in 0..read_varint(input)? {
+            let mut hash = [0u8; 32];
+            input.read_exact(&mut hash)?;
+            let mut positions = BTreeMap::new();
+            for _ in 0..read_varint(input)? {
+                let offset = read_varint(input)?;
+                positions.insert(offset, read_pos(&table, input)?);
+            }
+            info.insert(UInt256::from(hash), positions);
+        }
+        if version == 1 {
+            return Ok(info)
+        }
+        let mut procs = BTreeMap::new();
+        for _ in 0..read_varint(input)? {
+            let name = read_string(&table, input)?.to_string();
+            let nargs = read_number(input, "number of arguments")?;
+            let nrets = read_number::<u8>(input, "number of results")?;
+            let id = match read_varint(input)? {
+                0 => None,
+                id => Some(u32::try_from(id - 1).map_err(|_| invalid(format!("method id {} is out of range", id - 1)))?),
+            };
+            let nrets = i8::try_from(nrets as i16 - 1).map_err(|_| invalid("number of results is out of range"))?;
+            procs.insert(name, ProcInfo { nargs, nrets, id, pos: read_pos(&table, input)? });
+        }
+        info.set_procs(procs);
+        let mut globals = BTreeMap::new();
+        for _ in 0..read_varint(input)? {
+            let name = read_string(&table, input)?.to_string();
+            globals.insert(name, read_number(input, "global index")?);
+        }
+        info.set_globals(globals);
+        Ok(info)
+    }
+
+    /// Whether the bytes start a binary debug map rather than a JSON one
+    pub fn is_binary(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(filename: &str, line: usize, column: usize) -> DbgPos {
+        DbgPos { filename: Arc::from(filename), line, column, inlined_at: Vec::new() }
+    }
+
+    fn sample() -> DbgInfo {
+        let mut info = DbgInfo::default();
+        let mut inlined = pos("lib.code", 7, 3);
+        inlined.inlined_at.push(InlineSite { name: "f".to_string(), filename: Arc::from("main.code"), line: 2, column: 1 });
+        info.insert(UInt256::from([1; 32]), BTreeMap::from([(0, pos("main.code", 1, 1)), (16, inlined)]));
+        info.insert(UInt256::from([2; 32]), BTreeMap::from([(0, pos("main.code", 5, 0)), (8, pos("lib.code", 9, 12))]));
+        info.insert(UInt256::from([3; 32]), BTreeMap::new());
+        info.set_procs(BTreeMap::from([
+            ("add".to_string(), ProcInfo { nargs: 2, nrets: 1, id: None, pos: pos("main.code", 10, 1) }),
+            ("any".to_string(), ProcInfo { nargs: 0, nrets: -1, id: Some(0x1_0000), pos: pos("lib.code", 1, 1) }),
+        ]));
+        info.set_globals(BTreeMap::from([("counter".to_string(), 10)]));
+        info
+    }
+
+    #[test]
+    fn round_trip() {
+        let info = sample();
+        let mut bytes = Vec::new();
+        info.write_binary(&mut bytes).unwrap();
+        assert!(DbgInfo::is_binary(&bytes));
+        let read = DbgInfo::read_binary(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, info);
+        // filenames are written once
+        assert_eq!(bytes.windows(9).filter(|w| **w == *b"main.code").count(), 1);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut bytes = Vec::new();
+        sample().write_binary(&mut bytes).unwrap();
+        for len in [0, 3, 5, 12, bytes.len() / 2, bytes.len() - 1] {
+            assert!(DbgInfo::read_binary(&mut &bytes[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn huge_string_length() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        write_varint(&mut bytes, 1).unwrap();
+        write_varint(&mut bytes, usize::MAX >> 1).unwrap();
+        bytes.extend_from_slice(b"short");
+        let error = DbgInfo::read_binary(&mut bytes.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
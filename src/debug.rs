@@ -12,13 +12,69 @@
 */
 
 use serde::{Serialize, ser::SerializeMap, Deserialize, de::{Error, MapAccess, Visitor}};
-use std::collections::BTreeMap;
-use ever_block::{Cell, UInt256};
+use std::{collections::{BTreeMap, HashSet, btree_map::Entry}, sync::Arc};
+use ever_block::{error, Cell, SliceData, UInt256};
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct DbgPos {
-    pub filename: String,
+    /// shared by the positions of the same file
+    #[serde(with = "interned")]
+    pub filename: Arc<str>,
     pub line: usize,
+    /// column of the instruction, 0 if unknown
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub column: usize,
+    /// places the code was inlined at, the innermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inlined_at: Vec<InlineSite>,
+}
+
+/// Call site of inlined code, like `.inline` of a fragment or `.expand` of a macro
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InlineSite {
+    /// name of the fragment or macro
+    pub name: String,
+    #[serde(with = "interned")]
+    pub filename: Arc<str>,
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub column: usize,
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+/// Filenames written as plain strings
+mod interned {
+    use std::sync::Arc;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(filename: &Arc<str>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(filename)
+    }
+
+    pub fn deserialize<'a, D: Deserializer<'a>>(deserializer: D) -> Result<Arc<str>, D::Error> {
+        String::deserialize(deserializer).map(Arc::from)
+    }
+}
+
+/// Table of filenames sharing a single allocation per file
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FileTable {
+    files: HashSet<Arc<str>>,
+}
+
+impl FileTable {
+    /// Shared name of the file, added to the table if not there yet
+    pub fn intern(&mut self, filename: &str) -> Arc<str> {
+        if let Some(name) = self.files.get(filename) {
+            return name.clone()
+        }
+        let name = Arc::<str>::from(filename);
+        self.files.insert(name.clone());
+        name
+    }
 }
 
 impl std::fmt::Display for DbgPos {
@@ -26,29 +82,60 @@ impl std::fmt::Display for DbgPos {
         let filename = if self.filename.is_empty() {
             "<none>"
         } else {
-            self.filename.as_str()
+            &*self.filename
         };
-        write!(f, "{}:{}", filename, self.line)
+        write!(f, "{}:{}", filename, self.line)?;
+        if self.column != 0 {
+            write!(f, ":{}", self.column)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// What a cell of the compiled tree is used for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CellRole {
+    #[default]
+    Code,
+    Data,
+    Dict,
+    Library,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct DbgNode {
     pub offsets: Vec<(usize, DbgPos)>,
     pub children: Vec<DbgNode>,
+    pub role: CellRole,
+    /// name of the fragment the cell was produced from
+    pub fragment: Option<String>,
 }
 
 impl DbgNode {
     pub fn from_ext(pos: DbgPos, dbgs: Vec<DbgNode>) -> Self {
         Self {
             offsets: vec!((0, pos)),
-            children: dbgs
+            children: dbgs,
+            ..Default::default()
         }
     }
+    pub fn with_role(self, role: CellRole) -> Self {
+        let mut clone = self;
+        clone.role = role;
+        clone
+    }
     pub fn from(pos: DbgPos) -> Self {
         Self::from_ext(pos, vec!())
     }
+    /// Merges the node of code written into the same cell at the offset,
+    /// the cell keeps its own role and fragment unless it has none
     pub fn inline_node(&mut self, offset: usize, dbg: DbgNode) {
+        if self.role == CellRole::default() {
+            self.role = dbg.role;
+        }
+        if self.fragment.is_none() {
+            self.fragment = dbg.fragment;
+        }
         for (o, p) in dbg.offsets {
             self.offsets.push((o + offset, p));
         }
@@ -60,6 +147,24 @@ impl DbgNode {
         assert!(self.children.len() < 4);
         self.children.push(dbg)
     }
+    /// Records that the code was inlined under the name at the given position
+    pub fn inlined_at(self, name: &str, site: &DbgPos) -> Self {
+        let mut clone = self;
+        let site = InlineSite {
+            name: name.to_string(),
+            filename: site.filename.clone(),
+            line: site.line,
+            column: site.column,
+        };
+        let mut stack = vec!(&mut clone);
+        while let Some(node) = stack.pop() {
+            for (_, pos) in node.offsets.iter_mut() {
+                pos.inlined_at.push(site.clone());
+            }
+            stack.extend(node.children.iter_mut());
+        }
+        clone
+    }
 }
 
 impl std::fmt::Display for DbgNode {
@@ -71,24 +176,100 @@ impl std::fmt::Display for DbgNode {
     }
 }
 
+/// Procedure defined by `.proc` along with its calling convention
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcInfo {
+    /// number of values taken from the stack
+    pub nargs: u8,
+    /// number of values returned, -1 for any
+    pub nrets: i8,
+    /// id in the dictionary of methods if the procedure is called by CALLDICT
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    pub pos: DbgPos,
+}
+
 #[derive(Default, PartialEq, Eq)]
 pub struct DbgInfo {
-    map: BTreeMap<[u8; 32], BTreeMap<usize, DbgPos>>
+    map: BTreeMap<[u8; 32], BTreeMap<usize, DbgPos>>,
+    /// procedures by name, not written to the plain map of version 1
+    procs: BTreeMap<String, ProcInfo>,
+    /// indexes of global variables by name, not written to the plain map of version 1
+    globals: BTreeMap<String, u8>,
 }
 
-impl Serialize for DbgInfo {
+/// Version of the debug map schema; older assemblers wrote version 1 as a plain
+/// map of cells without the version, which is still read. Version 3 extends
+/// version 2 with the procedures and the global variables, a map without them
+/// is written as version 2
+pub const DBG_INFO_VERSION: u32 = 3;
+
+/// Version of the schema of a map of cells only
+const DBG_INFO_CELLS_VERSION: u32 = 2;
+
+struct Cells<'a>(&'a BTreeMap<[u8; 32], BTreeMap<usize, DbgPos>>);
+
+impl Serialize for Cells<'_> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut map = serializer.serialize_map(Some(self.map.len()))?;
-        for (k, v) in &self.map {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (k, v) in self.0 {
             map.serialize_entry(&hex::encode(k), v)?
         }
         map.end()
     }
 }
 
+impl Serialize for DbgInfo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // cells and offsets are kept sorted, so the output is canonical
+        let extended = !self.procs.is_empty() || !self.globals.is_empty();
+        let mut map = serializer.serialize_map(Some(if extended { 4 } else { 2 }))?;
+        match extended {
+            true => map.serialize_entry("version", &DBG_INFO_VERSION)?,
+            false => map.serialize_entry("version", &DBG_INFO_CELLS_VERSION)?,
+        }
+        map.serialize_entry("cells", &Cells(&self.map))?;
+        if extended {
+            map.serialize_entry("procs", &self.procs)?;
+            map.serialize_entry("globals", &self.globals)?;
+        }
+        map.end()
+    }
+}
+
+/// Debug map written in the plain form of version 1, a map of cell hashes to
+/// positions without the version, as read by tools predating version 2;
+/// procedures and global variables are not written
+pub struct FlatDbgInfo<'a>(&'a DbgInfo);
+
+impl Serialize for FlatDbgInfo<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        Cells(&self.0.map).serialize(serializer)
+    }
+}
+
+/// Cells of a versioned debug map
+struct VersionedCells(BTreeMap<[u8; 32], BTreeMap<usize, DbgPos>>);
+
+impl<'a> Deserialize<'a> for VersionedCells {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'a>
+    {
+        let info = deserializer.deserialize_map(DbgInfoVisitor::new())?;
+        Ok(VersionedCells(info.map))
+    }
+}
+
 struct DbgInfoVisitor {
     marker: std::marker::PhantomData<fn() -> DbgInfo>
 }
@@ -113,13 +294,40 @@ impl<'a> Visitor<'a> for DbgInfoVisitor {
         M: MapAccess<'a>,
     {
         let mut map = BTreeMap::<[u8; 32], BTreeMap<usize, DbgPos>>::new();
-        while let Some((key, value)) = access.next_entry()? {
+        let mut procs = BTreeMap::new();
+        let mut globals = BTreeMap::new();
+        while let Some(key) = access.next_key::<String>()? {
+            match key.as_str() {
+                "version" => {
+                    let version = access.next_value::<u32>()?;
+                    if version > DBG_INFO_VERSION {
+                        return Err(M::Error::custom(format!("unsupported debug map version {}", version)))
+                    }
+                    continue
+                }
+                "cells" => {
+                    map.append(&mut access.next_value::<VersionedCells>()?.0);
+                    continue
+                }
+                "procs" => {
+                    procs = access.next_value()?;
+                    continue
+                }
+                "globals" => {
+                    globals = access.next_value()?;
+                    continue
+                }
+                _ => ()
+            }
+            let value = access.next_value()?;
             let v = hex::decode::<String>(key).map_err(M::Error::custom)?;
             let arr: [u8; 32] = v.try_into()
                 .map_err(|ev: Vec<u8>| M::Error::custom(format!("bytestring size must be 32 not {}", ev.len())))?;
             map.insert(arr, value);
         }
-        Ok(DbgInfo { map })
+        let mut info = DbgInfo { map, procs, globals };
+        info.intern_files();
+        Ok(info)
     }
 }
 
@@ -140,18 +348,145 @@ impl std::fmt::Debug for DbgInfo {
 
 impl DbgInfo {
     pub fn from(cell: Cell, node: DbgNode) -> Self {
-        let mut info = DbgInfo { map: BTreeMap::new() };
+        let mut info = DbgInfo { map: BTreeMap::new(), procs: BTreeMap::new(), globals: BTreeMap::new() };
         info.collect(cell, node);
         info
     }
     pub fn len(&self) -> usize {
         self.map.len()
     }
+    /// View of the map serialized in the plain form of version 1
+    pub fn flat(&self) -> FlatDbgInfo {
+        FlatDbgInfo(self)
+    }
+    /// Names of the files referred to by positions, in the order of first appearance
+    pub fn files(&self) -> Vec<Arc<str>> {
+        let mut seen = HashSet::new();
+        let mut files = Vec::new();
+        for positions in self.map.values() {
+            for pos in positions.values() {
+                let sites = pos.inlined_at.iter().map(|site| &site.filename);
+                for filename in std::iter::once(&pos.filename).chain(sites) {
+                    if seen.insert(filename.clone()) {
+                        files.push(filename.clone());
+                    }
+                }
+            }
+        }
+        files
+    }
+    /// Makes the positions of the same file share its name
+    fn intern_files(&mut self) {
+        let mut table = FileTable::default();
+        for positions in self.map.values_mut() {
+            for pos in positions.values_mut() {
+                pos.filename = table.intern(&pos.filename);
+                for site in pos.inlined_at.iter_mut() {
+                    site.filename = table.intern(&site.filename);
+                }
+            }
+        }
+    }
     pub fn is_empty(&self) -> bool {
         self.map.is_empty()
     }
     pub fn append(&mut self, other: &mut Self) {
         self.map.append(&mut other.map);
+        self.procs.append(&mut other.procs);
+        self.globals.append(&mut other.globals);
+    }
+    /// Procedures defined by `.proc` by name
+    pub fn procs(&self) -> &BTreeMap<String, ProcInfo> {
+        &self.procs
+    }
+    pub fn set_procs(&mut self, procs: BTreeMap<String, ProcInfo>) {
+        self.procs = procs;
+    }
+    /// Indexes of global variables named by `.global`
+    pub fn globals(&self) -> &BTreeMap<String, u8> {
+        &self.globals
+    }
+    pub fn set_globals(&mut self, globals: BTreeMap<String, u8>) {
+        self.globals = globals;
+    }
+    /// Adds positions of the other map; for cells known to both, positions of
+    /// this map win and the hashes of the cells whose positions differ are returned
+    pub fn merge(&mut self, other: DbgInfo) -> Vec<UInt256> {
+        let mut conflicts = Vec::new();
+        for (name, proc) in other.procs {
+            self.procs.entry(name).or_insert(proc);
+        }
+        for (name, index) in other.globals {
+            self.globals.entry(name).or_insert(index);
+        }
+        for (hash, positions) in other.map {
+            match self.map.entry(hash) {
+                Entry::Vacant(entry) => {
+                    entry.insert(positions);
+                }
+                Entry::Occupied(mut entry) => {
+                    if *entry.get() != positions {
+                        conflicts.push(UInt256::from(hash));
+                    }
+                    for (offset, pos) in positions {
+                        entry.get_mut().entry(offset).or_insert(pos);
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+    /// Checks that every cell of the map is in the tree of the code;
+    /// returns hashes of the cells missing
+    pub fn validate(&self, root: &Cell) -> Result<(), Vec<UInt256>> {
+        let mut hashes = HashSet::new();
+        let mut stack = vec!(root.clone());
+        while let Some(cell) = stack.pop() {
+            if hashes.insert(cell.repr_hash().inner()) {
+                stack.extend((0..cell.references_count()).filter_map(|i| cell.reference(i).ok()));
+            }
+        }
+        let missing = self.map.keys()
+            .filter(|hash| !hashes.contains(*hash))
+            .map(|hash| UInt256::from(*hash))
+            .collect::<Vec<_>>();
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(missing),
+        }
+    }
+    /// Moves positions to the new hashes of the cells changed on the way from
+    /// the old root to the new one, e.g. by prefixing the code with SETCP.
+    /// A changed cell must end with the data of the old one, its positions are
+    /// shifted by the bits prepended; a cell referring to the old one wraps it
+    /// and needs nothing.
+    pub fn rebase(&mut self, old_root: &Cell, new_root: &Cell) -> ever_block::Result<()> {
+        let mut stack = vec!((old_root.clone(), new_root.clone()));
+        while let Some((old, new)) = stack.pop() {
+            let old_hash = old.repr_hash();
+            if new.repr_hash() == old_hash {
+                continue
+            }
+            let wrapped = (0..new.references_count())
+                .any(|i| new.reference(i).map_or(false, |child| child.repr_hash() == old_hash));
+            if wrapped {
+                continue
+            }
+            let shift = prepended_bits(&old, &new).ok_or_else(|| error!(
+                "cell {} does not end with the data of cell {}",
+                new.repr_hash().to_hex_string(), old_hash.to_hex_string()
+            ))?;
+            if let Some(positions) = self.map.remove(old_hash.as_slice()) {
+                let positions = positions.into_iter().map(|(offset, pos)| (offset + shift, pos)).collect();
+                self.map.insert(new.repr_hash().inner(), positions);
+            }
+            if old.references_count() == new.references_count() {
+                for i in 0..old.references_count() {
+                    stack.push((old.reference(i)?, new.reference(i)?));
+                }
+            }
+        }
+        Ok(())
     }
     pub fn insert(&mut self, key: UInt256, tree: BTreeMap<usize, DbgPos>) {
         self.map.entry(key.inner()).or_insert(tree);
@@ -165,6 +500,53 @@ impl DbgInfo {
     pub fn first_entry(&self) -> Option<&BTreeMap<usize, DbgPos>> {
         self.map.iter().next().map(|k_v| k_v.1)
     }
+    /// Cell hashes with positions of their instructions
+    pub fn iter(&self) -> impl Iterator<Item = (UInt256, &BTreeMap<usize, DbgPos>)> {
+        self.map.iter().map(|(k, v)| (UInt256::from(*k), v))
+    }
+    /// Source map in the format of version 3 where lines of the generated code
+    /// are cells listed in `x_cells` by their hashes and columns are bit offsets
+    /// of instructions
+    pub fn to_sourcemap(&self) -> serde_json::Value {
+        let mut sources = Vec::<&str>::new();
+        let mut mappings = String::new();
+        // every field but the generated column is relative to the previous segment overall
+        let (mut prev_source, mut prev_line, mut prev_column) = (0i64, 0i64, 0i64);
+        for (i, positions) in self.map.values().enumerate() {
+            if i > 0 {
+                mappings.push(';');
+            }
+            let mut prev_offset = 0i64;
+            for (j, (offset, pos)) in positions.iter().enumerate() {
+                let source = match sources.iter().position(|s| **s == *pos.filename) {
+                    Some(index) => index,
+                    None => {
+                        sources.push(&pos.filename);
+                        sources.len() - 1
+                    }
+                } as i64;
+                let line = pos.line.saturating_sub(1) as i64;
+                let column = pos.column.saturating_sub(1) as i64;
+                if j > 0 {
+                    mappings.push(',');
+                }
+                for value in [*offset as i64 - prev_offset, source - prev_source, line - prev_line, column - prev_column] {
+                    encode_vlq(&mut mappings, value);
+                }
+                prev_offset = *offset as i64;
+                prev_source = source;
+                prev_line = line;
+                prev_column = column;
+            }
+        }
+        serde_json::json!({
+            "version": 3,
+            "sources": sources,
+            "names": [],
+            "mappings": mappings,
+            "x_cells": self.map.keys().map(hex::encode).collect::<Vec<_>>(),
+        })
+    }
     fn collect(&mut self, cell: Cell, dbg: DbgNode) {
         let mut stack = vec!((cell.clone(), dbg));
         while let Some((cell, mut dbg)) = stack.pop() {
@@ -186,3 +568,63 @@ impl DbgInfo {
         }
     }
 }
+
+/// Number of bits prepended to the data of the old cell to get the new one
+fn prepended_bits(old: &Cell, new: &Cell) -> Option<usize> {
+    let shift = new.bit_length().checked_sub(old.bit_length())?;
+    let mut suffix = SliceData::load_cell_ref(new).ok()?;
+    suffix.shrink_data(shift..);
+    let old = SliceData::load_cell_ref(old).ok()?;
+    (suffix.get_bytestring(0) == old.get_bytestring(0)).then_some(shift)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Appends the value in base64 VLQ, sign in the lowest bit, 5 bits per digit
+fn encode_vlq(out: &mut String, value: i64) {
+    let mut vlq = match value < 0 {
+        true => ((-value as u64) << 1) | 1,
+        false => (value as u64) << 1,
+    };
+    loop {
+        let mut digit = (vlq & 0x1f) as usize;
+        vlq >>= 5;
+        if vlq != 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit] as char);
+        if vlq == 0 {
+            break
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> DbgInfo {
+        let mut info = DbgInfo::default();
+        let pos = DbgPos { filename: Arc::from("a.code"), line: 3, ..Default::default() };
+        info.insert(UInt256::from([1; 32]), BTreeMap::from([(0, pos.clone()), (8, DbgPos { line: 4, ..pos })]));
+        info
+    }
+
+    #[test]
+    fn versioned_json() {
+        let info = sample();
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["version"], DBG_INFO_CELLS_VERSION);
+        assert!(json["cells"].is_object());
+        assert_eq!(serde_json::from_value::<DbgInfo>(json).unwrap(), info);
+    }
+
+    #[test]
+    fn flat_json() {
+        let info = sample();
+        let json = serde_json::to_value(info.flat()).unwrap();
+        assert_eq!(json[hex::encode([1; 32])]["8"]["line"], 4);
+        assert!(json.get("version").is_none());
+        assert_eq!(serde_json::from_value::<DbgInfo>(json).unwrap(), info);
+    }
+}
@@ -0,0 +1,128 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Diagnostics ***************************************************************
+//
+// Warnings and notes do not stop the compilation; they are collected by the
+// engine, logged with the `compile` target and handed over to the unit built.
+
+use crate::{DbgPos, Diagnostic, Engine, Position, Severity};
+
+/// Mnemonics kept for compatibility with Fift along with the preferred ones
+const DEPRECATED: [(&str, &str); 8] = [
+    ("-ROLL", "ROLLREV"),
+    ("-ROLLX", "ROLLREVX"),
+    ("-ROT", "ROTREV"),
+    ("2DROP", "DROP2"),
+    ("2DUP", "DUP2"),
+    ("2OVER", "OVER2"),
+    ("2ROT", "ROT2"),
+    ("2SWAP", "SWAP2"),
+];
+
+/// Exception codes thrown by TVM itself, from stack underflow to capability error;
+/// other codes below 64 are only conventionally left to TVM
+const RESERVED_EXCEPTIONS: std::ops::RangeInclusive<u32> = 2..=16;
+
+/// Maximum number of mnemonics suggested for an unknown one
+const MAX_SUGGESTIONS: usize = 3;
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Preferred mnemonic for the one kept for compatibility with Fift
+pub(crate) fn preferred_mnemonic(name: &str) -> Option<&'static str> {
+    DEPRECATED.iter().find(|(deprecated, _)| *deprecated == name).map(|(_, preferred)| *preferred)
+}
+
+impl Position {
+    pub(crate) fn from_dbgpos(pos: &DbgPos) -> Self {
+        Position::new(pos.filename.to_string(), pos.line, pos.column)
+    }
+}
+
+impl Engine {
+    pub(crate) fn diagnose<S: ToString>(&mut self, severity: Severity, code: &'static str, position: Position, message: S) {
+        let diagnostic = Diagnostic::new(severity, code, position, message);
+        log::warn!(target: "compile", "{}", diagnostic);
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Diagnostics collected since the engine was created or the last build
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Mnemonics and directives known to the engine
+    pub fn mnemonics(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.handlers.keys().copied()
+    }
+
+    /// Known mnemonics nearest to the unknown one, uppercased
+    pub(crate) fn suggest(&self, name: &str) -> Vec<String> {
+        let threshold = (name.chars().count() / 3).max(1);
+        let mut candidates = self.mnemonics()
+            .map(|known| (levenshtein(name, known), known))
+            .filter(|(distance, _)| *distance <= threshold)
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.into_iter().take(MAX_SUGGESTIONS).map(|(_, known)| known.to_string()).collect()
+    }
+
+    /// Looks for suspicious usage of an instruction compiled successfully
+    pub(crate) fn check_command(&mut self, operation: &str, par: &[&str], position: Position) {
+        if let Some(preferred) = preferred_mnemonic(operation) {
+            self.diagnose(Severity::Warning, "deprecated-mnemonic", position.clone(),
+                format!("{} is deprecated, use {} instead", operation, preferred));
+        }
+        let exception = match (operation, par) {
+            ("THROW" | "THROWIF" | "THROWIFNOT", [code]) => code.parse::<u32>().ok(),
+            _ => None
+        };
+        if let Some(code) = exception.filter(|code| RESERVED_EXCEPTIONS.contains(code)) {
+            self.diagnose(Severity::Warning, "reserved-exception", position,
+                format!("exception code {} is thrown by TVM itself", code));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_exceptions() {
+        for (source, reserved) in [("THROW 5", true), ("THROWIF 16", true), ("THROW 40", false), ("THROW 100", false)] {
+            let mut engine = Engine::new("");
+            engine.compile_toplevel(source).unwrap();
+            let warned = engine.diagnostics().iter().any(|d| d.code == "reserved-exception");
+            assert_eq!(warned, reserved, "{}", source);
+        }
+    }
+}
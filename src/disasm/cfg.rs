@@ -0,0 +1,211 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use ever_block::UInt256;
+
+use super::types::{Code, Instruction, InstructionParameter};
+
+/// Basic block is identified by the cell and the bit offset of its first instruction
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId {
+    pub cell: UInt256,
+    pub offset: usize,
+}
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.cell.to_hex_string(), self.offset)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    pub id: NodeId,
+    /// printed instructions of the block
+    pub instructions: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// execution goes on with the next block
+    Fallthrough,
+    /// unconditional transfer: JMPREF or an implicit jump to the next cell
+    Jump,
+    /// conditional transfer without return: IFJMP and alike
+    ConditionalJump,
+    /// continuation is executed and returns: CALLREF, IF and alike
+    Call,
+    /// continuation is pushed on the stack to be executed by another instruction
+    Continuation,
+}
+
+#[derive(Clone, Debug)]
+pub struct Edge {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub kind: EdgeKind,
+    /// instruction making the transfer
+    pub label: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+}
+
+fn edge_kind(name: &str) -> EdgeKind {
+    match name {
+        "IMPLICIT-JMP" | "JMPREF" | "JMPREFDATA" => EdgeKind::Jump,
+        "IFJMPREF" | "IFNOTJMPREF" => EdgeKind::ConditionalJump,
+        "PUSHCONT" | "PUSHREFCONT" => EdgeKind::Continuation,
+        _ => EdgeKind::Call,
+    }
+}
+
+/// Whether the instruction ends a basic block
+fn ends_block(insn: &Instruction) -> bool {
+    let name = insn.name();
+    name.starts_with("IF") || name.starts_with("JMP") || name.starts_with("CALL") || name.starts_with("RET")
+        || name.starts_with("THROW") || name.starts_with("REPEAT") || name.starts_with("UNTIL")
+        || name.starts_with("WHILE") || name.starts_with("AGAIN")
+        || matches!(name, "EXECUTE" | "IMPLICIT-JMP" | "DICTIGETJMP" | "DICTUGETJMP" | "DICTIGETJMPZ" | "DICTUGETJMPZ")
+}
+
+/// Whether the instruction never passes control to the next one
+fn terminates(insn: &Instruction) -> bool {
+    matches!(insn.name(), "RET" | "RETALT" | "RETARGS" | "RETDATA" | "JMPX" | "JMPXDATA" | "JMPREF"
+        | "JMPREFDATA" | "JMPDICT" | "THROW" | "THROWANY" | "AGAIN" | "AGAINEND" | "IMPLICIT-JMP")
+}
+
+fn print_insn(insn: &Instruction) -> String {
+    Code::single(insn.clone()).print("", false, 0).trim_end().to_string()
+}
+
+impl Code {
+    /// Splits the code into basic blocks connected by control transfers
+    pub fn to_cfg(&self) -> Cfg {
+        let mut cfg = Cfg::default();
+        let mut seen = HashSet::new();
+        add_code(self, None, &mut cfg, &mut seen);
+        cfg
+    }
+}
+
+fn node_id(insn: &Instruction, cell: Option<&UInt256>) -> NodeId {
+    match insn.bytecode() {
+        Some(bytecode) => NodeId { cell: bytecode.cell().repr_hash(), offset: bytecode.pos() },
+        None => NodeId { cell: cell.cloned().unwrap_or_default(), offset: 0 },
+    }
+}
+
+fn add_nested(insn: &Instruction, from: Option<&NodeId>, kind: EdgeKind, cfg: &mut Cfg, seen: &mut HashSet<NodeId>) -> Option<NodeId> {
+    let mut entry = None;
+    for param in insn.params() {
+        if let InstructionParameter::Code { code, cell } = param {
+            let hash = cell.as_ref().map(|c| c.repr_hash());
+            if let Some(to) = add_code(code, hash.as_ref(), cfg, seen) {
+                if let Some(from) = from {
+                    cfg.edges.push(Edge { from: from.clone(), to: to.clone(), kind, label: insn.name().to_string() });
+                }
+                entry.get_or_insert(to);
+            }
+        }
+    }
+    entry
+}
+
+/// Adds blocks of the code returning the id of its entry
+fn add_code(code: &Code, cell: Option<&UInt256>, cfg: &mut Cfg, seen: &mut HashSet<NodeId>) -> Option<NodeId> {
+    let first = code.iter().next()?;
+    if first.name() != "IMPLICIT-JMP" && seen.contains(&node_id(first, cell)) {
+        // the cell is referenced again
+        return Some(node_id(first, cell))
+    }
+    let mut entry = None;
+    let mut current: Option<BasicBlock> = None;
+    let mut previous: Option<(NodeId, bool)> = None;
+    for insn in code.iter() {
+        if insn.name() == "IMPLICIT-JMP" && current.is_none() {
+            // the jump to the next cell follows the end of a block
+            let from = match previous.take() {
+                Some((from, true)) => Some(from),
+                _ => None
+            };
+            let to = add_nested(insn, from.as_ref(), EdgeKind::Jump, cfg, seen);
+            if entry.is_none() {
+                entry = to;
+            }
+            continue
+        }
+        let block = current.get_or_insert_with(|| BasicBlock { id: node_id(insn, cell), instructions: Vec::new() });
+        if entry.is_none() {
+            entry = Some(block.id.clone());
+        }
+        if let Some((from, true)) = previous.take() {
+            cfg.edges.push(Edge { from, to: block.id.clone(), kind: EdgeKind::Fallthrough, label: String::new() });
+        }
+        let from = block.id.clone();
+        if insn.name() != "IMPLICIT-JMP" {
+            block.instructions.push(print_insn(insn));
+        }
+        add_nested(insn, Some(&from), edge_kind(insn.name()), cfg, seen);
+        if ends_block(insn) {
+            if let Some(block) = current.take() {
+                previous = Some((block.id.clone(), !terminates(insn)));
+                if seen.insert(block.id.clone()) {
+                    cfg.blocks.push(block);
+                }
+            }
+        }
+    }
+    if let Some(block) = current.take() {
+        if seen.insert(block.id.clone()) {
+            cfg.blocks.push(block);
+        }
+    }
+    entry
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Cfg {
+    /// Serializes the graph in Graphviz DOT format
+    pub fn to_dot(&self) -> String {
+        let mut text = String::from("digraph cfg {\n  node [shape=box, fontname=\"monospace\"];\n");
+        for block in &self.blocks {
+            let mut label = format!("#{}\\l", escape(&block.id.to_string()));
+            for insn in &block.instructions {
+                label += &escape(insn);
+                label += "\\l";
+            }
+            text += &format!("  \"{}\" [label=\"{}\"];\n", block.id, label);
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Fallthrough => "solid",
+                EdgeKind::Jump => "bold",
+                EdgeKind::ConditionalJump => "dashed",
+                EdgeKind::Call => "solid",
+                EdgeKind::Continuation => "dotted",
+            };
+            text += &format!("  \"{}\" -> \"{}\" [label=\"{}\", style={}];\n",
+                edge.from, edge.to, escape(&edge.label), style);
+        }
+        text += "}\n";
+        text
+    }
+}
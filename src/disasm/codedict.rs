@@ -11,7 +11,7 @@
  * limitations under the License.
  */
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use ever_block::{Cell, HashmapE, HashmapType, Result, SliceData, fail};
 use super::{
     types::{Instruction, InstructionParameter, Code},
@@ -35,7 +35,7 @@ impl Code {
         for pair in code.chunks_mut(2) {
             if let Some(params) = match_dictpushconst_dictugetjmp(pair) {
                 // TODO transform cell to code right here (for nested dicts)
-                params.push(InstructionParameter::CodeDictMarker)
+                params.push(InstructionParameter::CodeDictMarker(Default::default()))
             }
         }
     }
@@ -57,6 +57,86 @@ impl Code {
     pub fn elaborate_dictpushconst_dictugetjmp(&mut self) {
         self.traverse_code_tree(Self::process_dictpushconst_dictugetjmp)
     }
+
+    /// Methods of the dispatchers elaborated in the code and its blocks
+    pub fn dict_methods(&self) -> Vec<Method> {
+        let mut methods = Vec::new();
+        for insn in self.iter() {
+            match insn.params().as_slice() {
+                [InstructionParameter::Length(key_size), InstructionParameter::Cell { cell: Some(cell), .. },
+                    InstructionParameter::CodeDictMarker(names)] => {
+                    let mut map = DelimitedHashmapE::new(cell.clone(), *key_size);
+                    if map.mark().is_ok() {
+                        methods.extend(map.methods(names));
+                    }
+                }
+                params => for param in params {
+                    if let InstructionParameter::Code { code, .. } = param {
+                        methods.extend(code.dict_methods());
+                    }
+                }
+            }
+        }
+        methods
+    }
+
+    /// Method of a dispatcher by its id, a negative one may also be given as an unsigned key
+    pub fn method(&self, id: i64) -> Option<Method> {
+        self.dict_methods().into_iter().find(|method| {
+            let key = match method.key_size {
+                0..=63 => (id as u64) & ((1u64 << method.key_size) - 1),
+                _ => id as u64,
+            };
+            method.id == id || method.id == signed_key(key, method.key_size)
+        })
+    }
+}
+
+/// Method of a code dictionary
+#[derive(Clone, Debug)]
+pub struct Method {
+    /// key of the dictionary taken as a signed method id
+    pub id: i64,
+    /// length of the dictionary keys in bits
+    pub key_size: usize,
+    pub name: Option<String>,
+    pub code: Code,
+    /// ids of the methods of the same dictionary the code calls or jumps to
+    pub calls: Vec<i64>,
+    /// ids of the methods calling or jumping to this one
+    pub called_by: Vec<i64>,
+}
+
+/// Key of the given length as a signed number
+fn signed_key(key: u64, bits: usize) -> i64 {
+    match bits {
+        0 => 0,
+        1..=63 if (key >> (bits - 1)) & 1 == 1 => key as i64 - (1i64 << bits),
+        _ => key as i64
+    }
+}
+
+/// Keys of the dictionary methods referred to by CALL, JMPDICT and PREPARE in the code
+fn collect_calls(code: &Code, ids: &HashSet<u64>, names: &BTreeMap<u64, String>, calls: &mut Vec<u64>) {
+    for insn in code.iter() {
+        let call = matches!(insn.name(), "CALL" | "JMPDICT" | "PREPARE");
+        for param in insn.params() {
+            let key = match param {
+                InstructionParameter::Nargs(id) if call && *id >= 0 => Some(*id as u64),
+                // the id replaced by the name of the method
+                InstructionParameter::Symbol(name) if call =>
+                    names.iter().find(|(_, n)| *n == name).map(|(key, _)| *key),
+                InstructionParameter::Code { code, .. } => {
+                    collect_calls(code, ids, names, calls);
+                    None
+                }
+                _ => None
+            };
+            if let Some(key) = key.filter(|key| ids.contains(key)) {
+                calls.push(key);
+            }
+        }
+    }
 }
 
 pub(super) struct DelimitedHashmapE {
@@ -137,7 +217,36 @@ impl DelimitedHashmapE {
         }
         Ok(())
     }
-    fn print_impl(&self, cell: &Cell, indent: &str, path: Vec<u8>) -> String {
+    /// Methods of the dictionary ordered by id, with the calls between them
+    pub fn methods(&self, names: &BTreeMap<u64, String>) -> Vec<Method> {
+        let key_size = self.dict.bit_len();
+        let ids = self.map.values().map(|(key, _, _)| *key).collect::<HashSet<_>>();
+        let mut methods = self.map.values().map(|(key, _, code)| {
+            let mut calls = Vec::new();
+            collect_calls(code, &ids, names, &mut calls);
+            calls.sort_unstable();
+            calls.dedup();
+            Method {
+                id: signed_key(*key, key_size),
+                key_size,
+                name: names.get(key).cloned(),
+                code: code.clone(),
+                calls: calls.into_iter().map(|key| signed_key(key, key_size)).collect(),
+                called_by: Vec::new(),
+            }
+        }).collect::<Vec<_>>();
+        methods.sort_by_key(|method| method.id);
+        let callers = methods.iter()
+            .flat_map(|method| method.calls.iter().map(move |callee| (*callee, method.id)))
+            .collect::<Vec<_>>();
+        for (callee, caller) in callers {
+            if let Some(method) = methods.iter_mut().find(|method| method.id == callee) {
+                method.called_by.push(caller);
+            }
+        }
+        methods
+    }
+    fn print_impl(&self, cell: &Cell, indent: &str, path: Vec<u8>, names: &BTreeMap<u64, String>) -> String {
         let mut text = String::new();
         text += &format!("{}.cell ", indent);
         text += &format!("{{ ;; #{}\n", cell.repr_hash().to_hex_string());
@@ -146,7 +255,10 @@ impl DelimitedHashmapE {
         if let Some((id, offset, code)) = self.map.get(&path) {
             let aux = slice.get_next_slice(*offset).unwrap();
             text += &format!("{}.blob x{}\n", inner_indent, aux.to_hex_string());
-            text += &format!("{};; method {}\n", inner_indent, id);
+            match names.get(id) {
+                Some(name) => text += &format!("{};; method {} ({})\n", inner_indent, id, name),
+                None => text += &format!("{};; method {}\n", inner_indent, id),
+            }
             text += &code.print(&inner_indent, true, 0);
         } else {
             if slice.remaining_bits() > 0 {
@@ -155,13 +267,13 @@ impl DelimitedHashmapE {
             for i in 0..cell.references_count() {
                 let mut path = path.clone();
                 path.push(i as u8);
-                text += &self.print_impl(&cell.reference(i).unwrap(), inner_indent.as_str(), path);
+                text += &self.print_impl(&cell.reference(i).unwrap(), inner_indent.as_str(), path, names);
             }
         }
         text += &format!("{}}}\n", indent);
         text
     }
-    pub fn print(&self, indent: &str) -> String {
-        self.print_impl(self.dict.data().unwrap(), indent, vec!())
+    pub fn print(&self, indent: &str, names: &BTreeMap<u64, String>) -> String {
+        self.print_impl(self.dict.data().unwrap(), indent, vec!(), names)
     }
 }
@@ -0,0 +1,116 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use num::BigInt;
+use ever_block::{Cell, Deserializable, MsgAddressInt, Result, SliceData, UInt256};
+
+use super::{loader::Loader, types::{Code, InstructionParameter}};
+
+/// Value of a constant decoded as far as it looks meaningful
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConstantValue {
+    Integer(BigInt),
+    /// internal address taking the whole slice
+    Address(String),
+    /// whole bytes of printable ASCII
+    String(String),
+    /// bits as a slice literal along with the number of references
+    Bits(String, usize),
+    /// cell not decoded, by its hash
+    Cell(UInt256),
+}
+
+/// Constant pushed by the code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Constant {
+    pub insn: &'static str,
+    pub value: ConstantValue,
+    /// cell and bit offset of the instruction
+    pub cell: UInt256,
+    pub offset: usize,
+}
+
+fn decode_slice(slice: &SliceData) -> ConstantValue {
+    let mut reader = slice.clone();
+    if let Ok(address) = MsgAddressInt::construct_from(&mut reader) {
+        if reader.remaining_bits() == 0 && slice.remaining_references() == 0 {
+            return ConstantValue::Address(address.to_string())
+        }
+    }
+    let bits = slice.remaining_bits();
+    if bits > 0 && bits % 8 == 0 && slice.remaining_references() == 0 {
+        let bytes = slice.get_bytestring(0);
+        if bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            return ConstantValue::String(String::from_utf8_lossy(&bytes).to_string())
+        }
+    }
+    ConstantValue::Bits(format!("x{}", slice.to_hex_string()), slice.remaining_references())
+}
+
+fn decode_cell(cell: &Cell) -> ConstantValue {
+    match SliceData::load_cell_ref(cell) {
+        Ok(slice) if cell.references_count() == 0 => match decode_slice(&slice) {
+            ConstantValue::Bits(..) => ConstantValue::Cell(cell.repr_hash()),
+            value => value,
+        }
+        _ => ConstantValue::Cell(cell.repr_hash()),
+    }
+}
+
+fn collect(code: &Code, constants: &mut Vec<Constant>, seen: &mut HashSet<(UInt256, usize)>) {
+    for insn in code.iter() {
+        for param in insn.params() {
+            if let InstructionParameter::Code { code, .. } = param {
+                collect(code, constants, seen);
+            }
+        }
+        let bytecode = match insn.bytecode() {
+            Some(bytecode) => bytecode,
+            None => continue
+        };
+        let value = match (insn.name(), insn.params().first()) {
+            ("PUSHINT", Some(InstructionParameter::Integer(i))) => ConstantValue::Integer(BigInt::from(*i)),
+            ("PUSHINT", Some(InstructionParameter::BigInteger(i))) => ConstantValue::Integer(i.clone()),
+            ("PUSHSLICE", Some(InstructionParameter::Slice(slice))) => decode_slice(slice),
+            ("PUSHREF" | "PUSHREFSLICE", Some(InstructionParameter::Cell { cell: Some(cell), .. })) => decode_cell(cell),
+            _ => continue
+        };
+        let (cell, offset) = (bytecode.cell().repr_hash(), bytecode.pos());
+        // code of a cell referred to several times is loaded as many times
+        if seen.insert((cell.clone(), offset)) {
+            constants.push(Constant { insn: insn.name(), value, cell, offset });
+        }
+    }
+}
+
+/// Constants pushed by PUSHINT, PUSHSLICE and PUSHREF in the code with their locations
+pub fn extract_constants(slice: &mut SliceData) -> Result<Vec<Constant>> {
+    let code = Loader::new(false).load(slice, false)?;
+    let mut constants = Vec::new();
+    collect(&code, &mut constants, &mut HashSet::new());
+    Ok(constants)
+}
+
+impl std::fmt::Display for ConstantValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConstantValue::Integer(i) => write!(f, "{}", i),
+            ConstantValue::Address(address) => write!(f, "address {}", address),
+            ConstantValue::String(s) => write!(f, "{:?}", s),
+            ConstantValue::Bits(bits, 0) => write!(f, "{}", bits),
+            ConstantValue::Bits(bits, refs) => write!(f, "{} with {} refs", bits, refs),
+            ConstantValue::Cell(hash) => write!(f, "cell #{}", hash.to_hex_string()),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+//! Instruction level diff of two code trees
+//!
+//! The trees are walked together matching cells by their place among the
+//! references; cells with the same hash are skipped along with their subtrees.
+//! Instructions of the matched cells are aligned by the longest common
+//! subsequence of their texts, a removed instruction followed by an added one
+//! makes a changed one.
+
+use ever_block::{Cell, SliceData, UInt256};
+
+use super::{loader::Loader, types::{Code, InstructionParameter}};
+
+/// Instruction at its bit offset in the cell
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffInsn {
+    pub offset: usize,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffOp {
+    Same(DiffInsn, DiffInsn),
+    Removed(DiffInsn),
+    Added(DiffInsn),
+    Changed(DiffInsn, DiffInsn),
+}
+
+/// Differences of the cells at the same place of both trees
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CellDiff {
+    /// indexes of the references leading to the cell from the root
+    pub path: Vec<usize>,
+    /// hash of the old cell, None if the cell is added
+    pub old: Option<UInt256>,
+    /// hash of the new cell, None if the cell is removed
+    pub new: Option<UInt256>,
+    pub ops: Vec<DiffOp>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CodeDiff {
+    /// cells differing in the trees, parents first
+    pub cells: Vec<CellDiff>,
+}
+
+/// Instructions decoded from the cell itself in the order of their offsets,
+/// the ones of inline blocks included
+fn cell_insns(loader: &mut Loader, cell: &Cell) -> Vec<DiffInsn> {
+    fn collect(code: &Code, hash: &UInt256, insns: &mut Vec<DiffInsn>) {
+        for insn in code.iter() {
+            if let Some(bytecode) = insn.bytecode().filter(|b| &b.cell().repr_hash() == hash) {
+                let text = Code::single(insn.clone()).print("", false, 0).trim_end().to_string();
+                insns.push(DiffInsn { offset: bytecode.pos(), text });
+            }
+            for param in insn.params() {
+                if let InstructionParameter::Code { code, .. } = param {
+                    collect(code, hash, insns);
+                }
+            }
+        }
+    }
+    let code = match SliceData::load_cell_ref(cell).and_then(|mut slice| loader.load(&mut slice, false)) {
+        Ok(code) => code,
+        Err(_) => return Vec::new()
+    };
+    let mut insns = Vec::new();
+    collect(&code, &cell.repr_hash(), &mut insns);
+    insns.sort_by_key(|insn| insn.offset);
+    insns
+}
+
+/// Aligns the instructions by the longest common subsequence of their texts
+fn align(old: Vec<DiffInsn>, new: Vec<DiffInsn>) -> Vec<DiffOp> {
+    let (n, m) = (old.len(), new.len());
+    // common[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = match old[i].text == new[j].text {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let flush = |ops: &mut Vec<DiffOp>, removed: &mut Vec<DiffInsn>, added: &mut Vec<DiffInsn>| {
+        let changed = removed.len().min(added.len());
+        let mut removed = removed.drain(..);
+        let mut added = added.drain(..);
+        for (old, new) in removed.by_ref().zip(added.by_ref()).take(changed) {
+            ops.push(DiffOp::Changed(old, new));
+        }
+        ops.extend(removed.map(DiffOp::Removed));
+        ops.extend(added.map(DiffOp::Added));
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i].text == new[j].text {
+            flush(&mut ops, &mut removed, &mut added);
+            ops.push(DiffOp::Same(old[i].clone(), new[j].clone()));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            removed.push(old[i].clone());
+            i += 1;
+        } else {
+            added.push(new[j].clone());
+            j += 1;
+        }
+    }
+    flush(&mut ops, &mut removed, &mut added);
+    ops
+}
+
+struct Walker {
+    old: Loader,
+    new: Loader,
+    diff: CodeDiff,
+}
+
+impl Walker {
+    fn walk(&mut self, old: Option<Cell>, new: Option<Cell>, path: Vec<usize>) {
+        let (old_hash, new_hash) = (old.as_ref().map(Cell::repr_hash), new.as_ref().map(Cell::repr_hash));
+        if old_hash == new_hash {
+            return
+        }
+        let old_insns = old.as_ref().map_or(Vec::new(), |cell| cell_insns(&mut self.old, cell));
+        let new_insns = new.as_ref().map_or(Vec::new(), |cell| cell_insns(&mut self.new, cell));
+        let ops = align(old_insns, new_insns);
+        self.diff.cells.push(CellDiff { path: path.clone(), old: old_hash, new: new_hash, ops });
+        let refs = |cell: &Option<Cell>| cell.as_ref().map_or(0, |cell| cell.references_count());
+        for i in 0..refs(&old).max(refs(&new)) {
+            let child = |cell: &Option<Cell>| cell.as_ref().and_then(|cell| cell.reference(i).ok());
+            let mut path = path.clone();
+            path.push(i);
+            self.walk(child(&old), child(&new), path);
+        }
+    }
+}
+
+/// Instruction level differences of two code trees
+pub fn diff(old: &Cell, new: &Cell) -> CodeDiff {
+    let mut walker = Walker { old: Loader::new(false), new: Loader::new(false), diff: CodeDiff::default() };
+    walker.walk(Some(old.clone()), Some(new.clone()), Vec::new());
+    walker.diff
+}
+
+fn short_hash(hash: &Option<UInt256>) -> String {
+    match hash {
+        Some(hash) => format!("#{}", &hash.to_hex_string()[..8]),
+        None => "none".to_string(),
+    }
+}
+
+impl CodeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    fn count(&self, f: impl Fn(&DiffOp) -> bool) -> usize {
+        self.cells.iter().flat_map(|cell| cell.ops.iter()).filter(|op| f(op)).count()
+    }
+
+    /// Numbers of added, removed and changed instructions
+    pub fn summary(&self) -> (usize, usize, usize) {
+        (
+            self.count(|op| matches!(op, DiffOp::Added(_))),
+            self.count(|op| matches!(op, DiffOp::Removed(_))),
+            self.count(|op| matches!(op, DiffOp::Changed(..))),
+        )
+    }
+
+    /// Unified diff of the listings of the differing cells keeping the given
+    /// number of unchanged instructions around the changes
+    pub fn unified(&self, context: usize) -> String {
+        let mut text = String::from("--- old\n+++ new\n");
+        for cell in &self.cells {
+            let path = cell.path.iter().map(|i| format!("/{}", i)).collect::<String>();
+            text += &format!("@@ root{} {} -> {} @@\n", path, short_hash(&cell.old), short_hash(&cell.new));
+            let changed = cell.ops.iter().enumerate()
+                .filter(|(_, op)| !matches!(op, DiffOp::Same(..)))
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            let near_change = |index: usize| changed.iter()
+                .any(|changed| index + context >= *changed && index <= changed + context);
+            let mut skipped = false;
+            for (index, op) in cell.ops.iter().enumerate() {
+                match op {
+                    DiffOp::Same(..) if !near_change(index) => {
+                        if !skipped {
+                            text += " ...\n";
+                        }
+                        skipped = true;
+                        continue
+                    }
+                    DiffOp::Same(old, _) => text += &format!(" {:>4}  {}\n", old.offset, old.text),
+                    DiffOp::Removed(old) => text += &format!("-{:>4}  {}\n", old.offset, old.text),
+                    DiffOp::Added(new) => text += &format!("+{:>4}  {}\n", new.offset, new.text),
+                    DiffOp::Changed(old, new) => {
+                        text += &format!("-{:>4}  {}\n", old.offset, old.text);
+                        text += &format!("+{:>4}  {}\n", new.offset, new.text);
+                    }
+                }
+                skipped = false;
+            }
+        }
+        text
+    }
+}
+
+impl std::fmt::Display for CodeDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.unified(3))
+    }
+}
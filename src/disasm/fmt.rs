@@ -11,10 +11,12 @@
  * limitations under the License.
  */
 
-use ever_block::{Cell, Result, SliceData};
+use std::collections::{BTreeMap, HashSet};
+use ever_block::{Cell, CellType, Result, SliceData, UInt256, fail};
 use super::{
     types::{Instruction, InstructionParameter, Code},
-    codedict::DelimitedHashmapE
+    codedict::{DelimitedHashmapE, Method},
+    DisasmOptions,
 };
 
 
@@ -49,13 +51,21 @@ pub fn print_tree_of_cells(toc: &Cell) {
     print_tree_of_cells(toc, "".to_string(), true);
 }
 
-fn print_code_dict(cell: &Cell, key_size: usize, indent: &str) -> Result<String> {
+fn print_code_dict(cell: &Cell, key_size: usize, indent: &str, names: &BTreeMap<u64, String>) -> Result<String> {
     let mut map = DelimitedHashmapE::new(cell.clone(), key_size);
     map.mark()?;
-    Ok(map.print(indent))
+    Ok(map.print(indent, names))
 }
 
-fn print_dictpushconst(insn: &Instruction, indent: &str) -> String {
+/// Comment with the hash of the cell if hashes are shown
+fn hash_comment(cell: &Cell, hashes: bool) -> String {
+    match hashes {
+        true => format!(" ;; #{}", cell.repr_hash().to_hex_string()),
+        false => String::new(),
+    }
+}
+
+fn print_dictpushconst(insn: &Instruction, indent: &str, hashes: bool) -> String {
     let key_length = if let Some(InstructionParameter::Length(l)) = insn.params().get(0) {
         *l
     } else {
@@ -68,11 +78,11 @@ fn print_dictpushconst(insn: &Instruction, indent: &str) -> String {
         unreachable!()
     };
     if let Some(cell) = cell {
-        let text = if let Some(InstructionParameter::CodeDictMarker) = insn.params().get(2) {
-            print_code_dict(cell, key_length, indent)
-                .unwrap_or_else(|_| print_cell(cell, indent, true))
+        let text = if let Some(InstructionParameter::CodeDictMarker(names)) = insn.params().get(2) {
+            print_code_dict(cell, key_length, indent, names)
+                .unwrap_or_else(|_| print_cell(cell, indent, true, hashes))
         } else {
-            print_cell(cell, indent, true)
+            print_cell(cell, indent, true, hashes)
         };
         format!("{} {}\n{}", insn.name(), key_length, text)
     } else {
@@ -80,19 +90,47 @@ fn print_dictpushconst(insn: &Instruction, indent: &str) -> String {
     }
 }
 
-fn print_cell(cell: &Cell, indent: &str, dot_cell: bool) -> String {
+fn is_exotic(cell: &Cell) -> bool {
+    cell.cell_type() != CellType::Ordinary
+}
+
+/// Directive building the exotic cell
+fn print_exotic(cell: &Cell, indent: &str, hashes: bool) -> String {
+    let data = cell.data();
+    let child = |i| cell.reference(i).map(|c| print_cell(&c, indent, false, hashes)).unwrap_or_default();
+    match (cell.cell_type(), cell.references_count()) {
+        (CellType::LibraryReference, 0) if data.len() >= 33 =>
+            format!(".library-cell {}", hex::encode(&data[1..33])),
+        // only the branches pruned at level 1 can be assembled
+        (CellType::PrunedBranch, 0) if data.len() >= 36 && data[1] == 1 =>
+            format!(".pruned-branch {}, {}", hex::encode(&data[2..34]), u16::from_be_bytes([data[34], data[35]])),
+        (CellType::MerkleProof, 1) => format!(".merkle-proof {}", child(0)),
+        (CellType::MerkleUpdate, 2) => format!(".merkle-update {}, {}", child(0), child(1)),
+        (cell_type, _) => format!(";; {:?} cell #{} x{}", cell_type, cell.repr_hash().to_hex_string(), cell.to_hex_string(true)),
+    }
+}
+
+fn print_cell(cell: &Cell, indent: &str, dot_cell: bool, hashes: bool) -> String {
     let mut text = String::new();
+    let inner_indent = String::from("  ") + indent;
+    if is_exotic(cell) {
+        if dot_cell {
+            return format!("{}{}\n", indent, print_exotic(cell, indent, hashes))
+        }
+        // a block is assembled into an ordinary cell, the exotic one becomes its reference
+        return format!("{{ ;; exotic #{}\n{}{}\n{}}}",
+            cell.repr_hash().to_hex_string(), inner_indent, print_exotic(cell, &inner_indent, hashes), indent)
+    }
     if dot_cell {
         text += &format!("{}.cell ", indent);
     }
-    text += &format!("{{ ;; #{}\n", cell.repr_hash().to_hex_string());
-    let inner_indent = String::from("  ") + indent;
+    text += &format!("{{{}\n", hash_comment(cell, hashes));
     if cell.bit_length() > 0 {
         text += &format!("{}.blob x{}\n", inner_indent, cell.to_hex_string(true));
     }
     let refs = cell.references_count();
     for i in 0..refs {
-        text += &print_cell(&cell.reference(i).unwrap(), &inner_indent, true);
+        text += &print_cell(&cell.reference(i).unwrap(), &inner_indent, true, hashes);
     }
     text += &format!("{}}}", indent);
     if dot_cell {
@@ -124,25 +162,146 @@ fn print_bytecode(slice: Option<(&SliceData, usize)>, bytecode_width: usize) ->
     text
 }
 
-impl Code {
-    pub fn print(&self, indent: &str, full: bool, bytecode_width: usize) -> String {
+/// Width of `cellhash:bitoffset` column, offsets are up to 1023
+const OFFSET_WIDTH: usize = 8 + 1 + 4;
+
+/// Location of the instruction as the debug map keys it: the first 8 hex digits
+/// of its cell hash and the bit offset in the cell
+fn print_location(bytecode: &SliceData) -> String {
+    let hash = bytecode.cell().repr_hash().to_hex_string();
+    format!("{}:{}", &hash[..8], bytecode.pos())
+}
+
+/// Listing of the code by the options, collecting the cells to be listed as sections
+struct Printer<'a> {
+    opts: &'a DisasmOptions,
+    full: bool,
+    /// cells listed after the code, in the order of the first reference
+    sections: Vec<(&'a Cell, &'a Code)>,
+    listed: HashSet<UInt256>,
+    /// methods of code dictionaries listed after the code, by key length
+    methods: Vec<(usize, Vec<Method>)>,
+}
+
+impl<'a> Printer<'a> {
+    fn new(opts: &'a DisasmOptions, full: bool) -> Self {
+        Self { opts, full, sections: Vec::new(), listed: HashSet::new(), methods: Vec::new() }
+    }
+
+    /// Columns preceding the instruction, blank for lines without one
+    fn prefix(&self, insn: Option<&Instruction>) -> String {
+        let mut text = String::new();
+        if self.opts.offsets {
+            let location = insn.and_then(|insn| insn.bytecode()).map_or(String::new(), print_location);
+            text += &format!("{:<OFFSET_WIDTH$}  ", location);
+        }
+        let bytecode = insn.and_then(|insn| insn.bytecode().map(|b| (b, insn.refs())));
+        text += &print_bytecode(bytecode, self.opts.bytecode_width);
+        text
+    }
+
+    /// Puts the code of the cell to be listed as a section, returns the reference to it
+    fn section(&mut self, cell: &'a Cell, code: &'a Code) -> String {
+        let hash = cell.repr_hash();
+        if self.listed.insert(hash.clone()) {
+            self.sections.push((cell, code));
+        }
+        format!("<#{}>", hash.to_hex_string())
+    }
+
+    fn print_sections(&mut self) -> String {
+        let mut text = String::new();
+        let mut index = 0;
+        while let Some((cell, code)) = self.sections.get(index).cloned() {
+            text += &format!("\n;; section #{}\n", cell.repr_hash().to_hex_string());
+            text += &self.print_code(code, "");
+            index += 1;
+        }
+        text
+    }
+
+    /// Puts the methods of the code dictionary to be listed after the code,
+    /// returns the instruction referring to them
+    fn list_methods(&mut self, insn: &Instruction) -> Option<String> {
+        let (key_length, cell, names) = match insn.params().as_slice() {
+            [InstructionParameter::Length(l), InstructionParameter::Cell { cell: Some(cell), .. },
+                InstructionParameter::CodeDictMarker(names)] => (*l, cell, names),
+            _ => return None
+        };
+        let mut map = DelimitedHashmapE::new(cell.clone(), key_length);
+        map.mark().ok()?;
+        let methods = map.methods(names);
+        let text = format!("{} {} ;; {} methods listed below\n", insn.name(), key_length, methods.len());
+        self.methods.push((key_length, methods));
+        Some(text)
+    }
+
+    fn print_methods(&mut self) -> String {
         let mut text = String::new();
-        for insn in self.iter() {
-            text += &print_bytecode(insn.bytecode().map(|v| (v, insn.refs())), bytecode_width);
+        for (key_length, methods) in std::mem::take(&mut self.methods) {
+            text += &format!("\n;; methods of the dictionary with {}-bit keys\n", key_length);
+            for method in methods {
+                let mut title = format!(";; method {}", method.id);
+                if let Some(name) = &method.name {
+                    title += &format!(" ({})", name);
+                }
+                let ids = |ids: &[i64]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ");
+                if !method.calls.is_empty() {
+                    title += &format!(", calls {}", ids(&method.calls));
+                }
+                if !method.called_by.is_empty() {
+                    title += &format!(", called by {}", ids(&method.called_by));
+                }
+                let mut printer = Printer::new(self.opts, true);
+                text += &title;
+                text += "\n";
+                text += &printer.print_code(&method.code, "  ");
+                text += &printer.print_sections();
+                text += &printer.print_methods();
+            }
+        }
+        text
+    }
+
+    fn print_code(&mut self, code: &'a Code, indent: &str) -> String {
+        let hashes = self.opts.hashes;
+        let mut text = String::new();
+        for insn in code.iter() {
+            if let Some(source) = insn.source() {
+                text += &format!("{}{};; {}\n", self.prefix(None), indent, source);
+            }
+            text += &self.prefix(Some(insn));
             text += indent;
-            if full {
+            if self.full {
                 match insn.name() {
+                    "DICTPUSHCONST" if self.opts.methods => {
+                        text += &self.list_methods(insn).unwrap_or_else(|| print_dictpushconst(insn, indent, hashes));
+                        continue
+                    }
                     "DICTPUSHCONST" | "PFXDICTSWITCH" => {
                         // TODO better improve assembler for these two insns
-                        text += &print_dictpushconst(insn, indent);
+                        text += &print_dictpushconst(insn, indent, hashes);
                         continue
                     }
+                    ".cell" => {
+                        if let Some(InstructionParameter::Cell { cell: Some(cell), collapsed: false }) = insn.params().get(0) {
+                            if is_exotic(cell) {
+                                text += &print_exotic(cell, indent, hashes);
+                                text += "\n";
+                                continue
+                            }
+                        }
+                    }
                     "IMPLICIT-JMP" => {
                         if let Some(InstructionParameter::Code { code, cell }) = insn.params().get(0) {
-                            let hash = cell.as_ref().unwrap().repr_hash().to_hex_string();
-                            text += &format!(".cell {{ ;; #{}\n", hash);
+                            let cell = cell.as_ref().unwrap();
+                            if self.opts.sections {
+                                text += &format!(";; continues at {}\n", self.section(cell, code));
+                                continue
+                            }
+                            text += &format!(".cell {{{}\n", hash_comment(cell, hashes));
                             let inner_indent = String::from("  ") + indent;
-                            text += &code.print(&inner_indent, full, bytecode_width);
+                            text += &self.print_code(code, &inner_indent);
                             text += indent;
                             text += "}\n";
                         } else {
@@ -157,7 +316,7 @@ impl Code {
             if insn.is_quiet() {
                 text += "Q";
             }
-            text += &print_insn_params(insn.params(), indent, full, bytecode_width);
+            text += &self.print_insn_params(insn.params(), indent);
             if let Some(comment) = insn.comment() {
                 text += &format!(" ;; {}", comment);
             }
@@ -165,98 +324,166 @@ impl Code {
         }
         text
     }
-}
 
-fn print_insn_params(params: &Vec<InstructionParameter>, indent: &str, full: bool, bytecode_width: usize) -> String {
-    use InstructionParameter::*;
+    fn print_insn_params(&mut self, params: &'a [InstructionParameter], indent: &str) -> String {
+        use InstructionParameter::*;
 
-    let mut text = String::new();
-    let len = params.len();
-    if len > 0 {
-        text += " ";
-    }
-    for (index, param) in params.iter().enumerate() {
-        let last = len == (index + 1);
-        let mut curr_is_block = false;
-        match param {
-            BigInteger(i) => {
-                text += &format!("{}", i);
-            }
-            ControlRegister(c) => {
-                text += &format!("c{}", c);
-            }
-            Integer(i) => {
-                text += &format!("{}", i);
-            }
-            Length(l) => {
-                text += &format!("{}", l);
-            }
-            LengthAndIndex(l, i) => {
-                text += &format!("{}, {}", l, i);
-            }
-            Nargs(n) => {
-                text += &format!("{}", n);
-            }
-            Pargs(p) => {
-                text += &format!("{}", p);
-            }
-            Rargs(r) => {
-                text += &format!("{}", r);
-            }
-            Slice(s) => {
-                // TODO slice may have references
-                debug_assert!(s.remaining_references() == 0);
-                text += &format!("x{}", s.to_hex_string());
-            }
-            StackRegister(r) => {
-                text += &format!("s{}", r);
-            }
-            StackRegisterPair(ra, rb) => {
-                text += &format!("s{}, s{}", ra, rb);
-            }
-            StackRegisterTriple(ra, rb, rc) => {
-                text += &format!("s{}, s{}, s{}", ra, rb, rc);
-            }
-            Code { code, cell } => {
-                if full {
-                    if let Some(cell) = cell {
-                        text += &format!("{{ ;; #{}\n", cell.repr_hash().to_hex_string());
-                    } else {
-                        text += "{\n";
-                    }
-                    let inner_indent = String::from("  ") + indent;
-                    text += &code.print(&inner_indent, full, bytecode_width);
-                    text += &print_bytecode(None, bytecode_width);
-                    text += indent;
-                    text += "}";
-                    curr_is_block = true;
+        let (full, hashes) = (self.full, self.opts.hashes);
+        let mut text = String::new();
+        let len = params.len();
+        if len > 0 {
+            text += " ";
+        }
+        for (index, param) in params.iter().enumerate() {
+            let last = len == (index + 1);
+            let mut curr_is_block = false;
+            match param {
+                BigInteger(i) => {
+                    text += &format!("{}", i);
                 }
-            }
-            Cell { cell, collapsed } => {
-                if full {
-                    if *collapsed {
-                        text += "<collapsed>";
-                    } else if let Some(cell) = cell {
-                        text += &print_cell(cell, indent, false);
-                    } else {
-                        text += "{\n";
-                        text += &print_bytecode(None, bytecode_width);
-                        text += &format!("{}  ;; missing cell\n", indent);
-                        text += &print_bytecode(None, bytecode_width);
+                ControlRegister(c) => {
+                    text += &format!("c{}", c);
+                }
+                Integer(i) => {
+                    text += &format!("{}", i);
+                }
+                Length(l) => {
+                    text += &format!("{}", l);
+                }
+                LengthAndIndex(l, i) => {
+                    text += &format!("{}, {}", l, i);
+                }
+                Nargs(n) => {
+                    text += &format!("{}", n);
+                }
+                Pargs(p) => {
+                    text += &format!("{}", p);
+                }
+                Rargs(r) => {
+                    text += &format!("{}", r);
+                }
+                Slice(s) => {
+                    // TODO slice may have references
+                    debug_assert!(s.remaining_references() == 0);
+                    text += &format!("x{}", s.to_hex_string());
+                }
+                StackRegister(r) => {
+                    text += &format!("s{}", r);
+                }
+                StackRegisterPair(ra, rb) => {
+                    text += &format!("s{}, s{}", ra, rb);
+                }
+                StackRegisterTriple(ra, rb, rc) => {
+                    text += &format!("s{}, s{}, s{}", ra, rb, rc);
+                }
+                Code { code, cell: Some(cell) } if full && self.opts.sections => {
+                    text += &self.section(cell, code);
+                }
+                Code { code, cell } => {
+                    if full {
+                        match cell {
+                            Some(cell) => text += &format!("{{{}\n", hash_comment(cell, hashes)),
+                            None => text += "{\n",
+                        }
+                        let inner_indent = String::from("  ") + indent;
+                        text += &self.print_code(code, &inner_indent);
+                        text += &self.prefix(None);
                         text += indent;
                         text += "}";
+                        curr_is_block = true;
+                    }
+                }
+                Cell { cell, collapsed } => {
+                    if full {
+                        if *collapsed {
+                            text += "<collapsed>";
+                        } else if let Some(cell) = cell {
+                            text += &print_cell(cell, indent, false, hashes);
+                        } else {
+                            text += "{\n";
+                            text += &self.prefix(None);
+                            text += &format!("{}  ;; missing cell\n", indent);
+                            text += &self.prefix(None);
+                            text += indent;
+                            text += "}";
+                        }
+                        curr_is_block = true;
                     }
-                    curr_is_block = true;
+                }
+                Symbol(name) => {
+                    text += name;
+                }
+                CodeDictMarker(_) => {
+                    // markers must have been already eliminated
+                    unreachable!()
                 }
             }
-            CodeDictMarker => {
-                // markers must have been already eliminated
-                unreachable!()
+            if !last && !curr_is_block {
+                text += ", ";
             }
         }
-        if !last && !curr_is_block {
-            text += ", ";
+        text
+    }
+}
+
+impl Code {
+    /// Rewrites the code so that its printout can be assembled back into the same cells:
+    /// slices with references are turned into raw .blob and .cell directives.
+    /// Fails if the code is incomplete, i.e. has missing cells, bits or references
+    pub fn make_reassemblable(&mut self) -> Result<()> {
+        let mut code = Code::new();
+        for mut insn in std::mem::take(self).iter().cloned() {
+            if insn.name().starts_with(";;") {
+                fail!("code has a missing or collapsed cell")
+            }
+            if let Some(comment) = insn.comment() {
+                if comment.starts_with("missing") {
+                    fail!("{} has {}", insn.name(), comment)
+                }
+            }
+            let slice_with_refs = insn.params().iter().any(|p|
+                matches!(p, InstructionParameter::Slice(s) if s.remaining_references() > 0)
+            );
+            if slice_with_refs {
+                let bytecode = insn.bytecode()
+                    .ok_or_else(|| ever_block::error!("{} has no bytecode", insn.name()))?;
+                let mut data = bytecode.clone();
+                data.shrink_references(..0);
+                code.push(Instruction::new(".blob").with_param(InstructionParameter::Slice(data)));
+                for i in 0..bytecode.remaining_references() {
+                    code.push(Instruction::new(".cell").with_param(InstructionParameter::Cell {
+                        cell: Some(bytecode.reference(i)?), collapsed: false
+                    }));
+                }
+                continue
+            }
+            let name = insn.name();
+            for param in insn.params_mut() {
+                match param {
+                    InstructionParameter::Code { code, .. } => code.make_reassemblable()?,
+                    InstructionParameter::Cell { cell: None, .. } |
+                    InstructionParameter::Cell { collapsed: true, .. } =>
+                        fail!("{} has a missing or collapsed cell", name),
+                    // only .cell writes the exotic cell itself rather than an ordinary one
+                    InstructionParameter::Cell { cell: Some(cell), .. } if name != ".cell" && is_exotic(cell) =>
+                        fail!("{} refers to an exotic cell", name),
+                    _ => ()
+                }
+            }
+            code.push(insn);
         }
+        *self = code;
+        Ok(())
+    }
+    pub fn print(&self, indent: &str, full: bool, bytecode_width: usize) -> String {
+        let opts = DisasmOptions { bytecode_width, ..DisasmOptions::default() };
+        Printer::new(&opts, full).print_code(self, indent)
+    }
+    /// Full listing of the code tuned by the options, with the sections if any following it
+    pub fn print_with(&self, opts: &DisasmOptions) -> String {
+        let mut printer = Printer::new(opts, true);
+        let text = printer.print_code(self, "");
+        let text = text + &printer.print_sections();
+        text + &printer.print_methods()
     }
-    text
 }
@@ -16,7 +16,8 @@ use ever_block::{Result, SliceData};
 use super::types::{Instruction, Signaling, Quiet};
 use super::loader::*;
 
-pub(super) type LoadHandler = fn(&mut Loader, &mut SliceData) -> Result<Instruction>;
+/// Handler decoding an instruction starting at its opcode
+pub type LoadHandler = fn(&mut Loader, &mut SliceData) -> Result<Instruction>;
 
 #[derive(Clone, Copy)]
 enum Handler {
@@ -24,6 +25,8 @@ enum Handler {
     Subset(usize),
 }
 
+/// Table of instruction decoders by the bytes of their opcodes
+#[derive(Clone)]
 pub struct Handlers {
     directs: [Handler; 256],
     subsets: Vec<Handlers>,
@@ -819,6 +822,32 @@ impl Handlers {
             .set(0x02, Loader::sha256u)
             .set(0x10, Loader::chksignu)
             .set(0x11, Loader::chksigns)
+            .add_subset(0x30, Handlers::new()
+                .set(0x00, Loader::bls_verify)
+                .set(0x01, Loader::bls_aggregate)
+                .set(0x02, Loader::bls_fastaggregateverify)
+                .set(0x03, Loader::bls_aggregateverify)
+                .set(0x10, Loader::bls_g1_add)
+                .set(0x11, Loader::bls_g1_sub)
+                .set(0x12, Loader::bls_g1_neg)
+                .set(0x13, Loader::bls_g1_mul)
+                .set(0x14, Loader::bls_g1_multiexp)
+                .set(0x15, Loader::bls_g1_zero)
+                .set(0x16, Loader::bls_map_to_g1)
+                .set(0x17, Loader::bls_g1_ingroup)
+                .set(0x18, Loader::bls_g1_iszero)
+                .set(0x20, Loader::bls_g2_add)
+                .set(0x21, Loader::bls_g2_sub)
+                .set(0x22, Loader::bls_g2_neg)
+                .set(0x23, Loader::bls_g2_mul)
+                .set(0x24, Loader::bls_g2_multiexp)
+                .set(0x25, Loader::bls_g2_zero)
+                .set(0x26, Loader::bls_map_to_g2)
+                .set(0x27, Loader::bls_g2_ingroup)
+                .set(0x28, Loader::bls_g2_iszero)
+                .set(0x30, Loader::bls_pairing)
+                .set(0x31, Loader::bls_pushr)
+            )
             .set(0x40, Loader::cdatasizeq)
             .set(0x41, Loader::cdatasize)
             .set(0x42, Loader::sdatasizeq)
@@ -830,16 +859,20 @@ impl Handlers {
         self.add_subset(0xFE, Handlers::new()
             .set(0x00, Loader::dump_stack)
             .set_range(0x01..0x0F, Loader::dump_stack_top)
+            .set(0x0F, Loader::debug)
             .set(0x10, Loader::dump_hex)
             .set(0x11, Loader::print_hex)
             .set(0x12, Loader::dump_bin)
             .set(0x13, Loader::print_bin)
             .set(0x14, Loader::dump_str)
             .set(0x15, Loader::print_str)
+            .set_range(0x16..0x1E, Loader::debug)
             .set(0x1E, Loader::debug_off)
             .set(0x1F, Loader::debug_on)
             .set_range(0x20..0x2F, Loader::dump_var)
+            .set(0x2F, Loader::debug)
             .set_range(0x30..0x3F, Loader::print_var)
+            .set_range(0x3F..0xF0, Loader::debug)
             .set_range(0xF0..0xFF, Loader::dump_string)
             .set(0xFF, Loader::dump_string)
         )
@@ -853,7 +886,8 @@ impl Handlers {
         }
     }
 
-    fn add_subset(&mut self, code: u8, subset: &mut Handlers) -> &mut Handlers {
+    /// Decodes the instructions having the byte as a prefix by the subset table
+    pub fn add_subset(&mut self, code: u8, subset: &mut Handlers) -> &mut Handlers {
         match self.directs[code as usize] {
             Handler::Direct(x) => if x as usize == Loader::unknown as usize {
                 self.directs[code as usize] = Handler::Subset(self.subsets.len());
@@ -877,12 +911,12 @@ impl Handlers {
         }
     }
 
-    fn set(&mut self, code: u8, handler: LoadHandler) -> &mut Handlers {
+    pub fn set(&mut self, code: u8, handler: LoadHandler) -> &mut Handlers {
         self.register_handler(code, handler);
         self
     }
 
-    fn set_range(&mut self, codes: std::ops::Range<u8>, handler: LoadHandler) -> &mut Handlers {
+    pub fn set_range(&mut self, codes: std::ops::Range<u8>, handler: LoadHandler) -> &mut Handlers {
         for code in codes {
             self.register_handler(code, handler);
         }
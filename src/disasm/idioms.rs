@@ -0,0 +1,180 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+use super::types::{Code, Instruction, InstructionParameter};
+
+/// How the recognized idioms show in the listing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdiomMode {
+    /// listed instruction by instruction
+    #[default]
+    Off,
+    /// the first instruction of an idiom is commented with its name
+    Annotate,
+    /// an idiom is listed as a single pseudo-op, unless it holds code to be listed
+    Fold,
+}
+
+impl std::str::FromStr for IdiomMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(IdiomMode::Off),
+            "annotate" => Ok(IdiomMode::Annotate),
+            "fold" => Ok(IdiomMode::Fold),
+            _ => Err(format!("unknown idiom mode {}, expected off, annotate or fold", s)),
+        }
+    }
+}
+
+/// Sequence of instructions emitted by compilers for a common construct.
+/// An element of the pattern is a mnemonic optionally followed by operands,
+/// `*` matches any operands; a bare mnemonic matches the instruction with any
+/// operands including blocks of code
+#[derive(Clone, Copy, Debug)]
+pub struct Idiom {
+    /// name of the pseudo-op the idiom is folded into
+    pub name: &'static str,
+    pub pattern: &'static [&'static str],
+    /// whether the instructions can be hidden, an idiom holding code
+    /// worth reading is only annotated
+    pub fold: bool,
+}
+
+/// Idioms of TON Solidity and C++ compilers
+pub const IDIOMS: &[Idiom] = &[
+    // dispatcher of functions by their ids
+    Idiom {
+        name: "SELECTOR",
+        pattern: &["SETCP 0", "DICTPUSHCONST", "DICTUGETJMPZ", "THROWARG 11"],
+        fold: false,
+    },
+    // msg.pubkey() == tvm.pubkey()
+    Idiom {
+        name: "PUBKEY-CHECK",
+        pattern: &["GETGLOB 5", "PUSH s0", "ISNULL", "PUSHCONT", "IF", "GETGLOB 2", "EQUAL", "THROWIFNOT *"],
+        fold: true,
+    },
+    // timestamp of an external message is checked against the last one
+    Idiom {
+        name: "REPLAY-PROTECTION",
+        pattern: &["GETGLOB 3", "GETGLOB 10", "PUSH s1", "LESS", "THROWIFNOT 52"],
+        fold: true,
+    },
+    Idiom {
+        name: "SIGNATURE-CHECK",
+        pattern: &["CHKSIGNU", "THROWIFNOT 40"],
+        fold: true,
+    },
+];
+
+/// Operands of the instruction as printed, None if some of them are blocks or cells
+fn operands(insn: &Instruction) -> Option<String> {
+    use InstructionParameter::*;
+    let operands = insn.params().iter().map(|param| match param {
+        BigInteger(i) => Some(i.to_string()),
+        ControlRegister(c) => Some(format!("c{}", c)),
+        Integer(i) => Some(i.to_string()),
+        Length(l) => Some(l.to_string()),
+        LengthAndIndex(l, i) => Some(format!("{}, {}", l, i)),
+        Nargs(n) => Some(n.to_string()),
+        Pargs(p) => Some(p.to_string()),
+        Rargs(r) => Some(r.to_string()),
+        Slice(s) => Some(format!("x{}", s.to_hex_string())),
+        StackRegister(r) => Some(format!("s{}", r)),
+        StackRegisterPair(ra, rb) => Some(format!("s{}, s{}", ra, rb)),
+        StackRegisterTriple(ra, rb, rc) => Some(format!("s{}, s{}, s{}", ra, rb, rc)),
+        Symbol(name) => Some(name.clone()),
+        Code { .. } | Cell { .. } | CodeDictMarker(_) => None,
+    }).collect::<Option<Vec<_>>>()?;
+    Some(operands.join(", "))
+}
+
+/// Whether the instruction matches the element of a pattern, `*` matches any instruction
+pub(super) fn matches(insn: &Instruction, element: &str) -> bool {
+    if element == "*" {
+        return true
+    }
+    let (name, expected) = match element.split_once(' ') {
+        Some((name, expected)) => (name, Some(expected)),
+        None => (element, None),
+    };
+    let name_matches = insn.name() == name && !insn.is_quiet();
+    match expected {
+        _ if !name_matches => false,
+        None | Some("*") => true,
+        Some(expected) => operands(insn).map_or(false, |operands| operands == expected),
+    }
+}
+
+/// Idiom starting the instructions if any
+fn recognize(insns: &[Instruction]) -> Option<&'static Idiom> {
+    IDIOMS.iter().find(|idiom| {
+        idiom.pattern.len() <= insns.len() &&
+            idiom.pattern.iter().zip(insns).all(|(element, insn)| matches(insn, element))
+    })
+}
+
+impl Code {
+    /// Comments or folds the instructions of known compiler idioms
+    pub fn recognize_idioms(&mut self, mode: IdiomMode) {
+        if mode == IdiomMode::Off {
+            return
+        }
+        let insns = std::mem::take(self).iter().cloned().collect::<Vec<_>>();
+        let mut index = 0;
+        while index < insns.len() {
+            let idiom = match recognize(&insns[index..]) {
+                Some(idiom) => idiom,
+                None => {
+                    let mut insn = insns[index].clone();
+                    for param in insn.params_mut() {
+                        if let InstructionParameter::Code { code, .. } = param {
+                            code.recognize_idioms(mode);
+                        }
+                    }
+                    self.push(insn);
+                    index += 1;
+                    continue
+                }
+            };
+            let count = idiom.pattern.len();
+            if mode == IdiomMode::Fold && idiom.fold {
+                let mut folded = Instruction::new(idiom.name);
+                if let Some(bytecode) = insns[index].bytecode() {
+                    folded.set_bytecode(bytecode.clone());
+                }
+                folded.set_comment(format!("{} instructions", count));
+                self.push(folded);
+            } else {
+                for (i, insn) in insns[index..index + count].iter().enumerate() {
+                    let mut insn = insn.clone();
+                    if i == 0 {
+                        let comment = match insn.comment() {
+                            Some(comment) => format!("{} ({})", comment, idiom.name),
+                            None => format!("idiom {}", idiom.name),
+                        };
+                        insn.set_comment(comment);
+                    }
+                    for param in insn.params_mut() {
+                        if let InstructionParameter::Code { code, .. } = param {
+                            code.recognize_idioms(mode);
+                        }
+                    }
+                    self.push(insn);
+                }
+            }
+            index += count;
+        }
+    }
+}
@@ -0,0 +1,104 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+use ever_block::{Cell, CellType};
+use serde_json::{json, Value};
+
+use super::types::{Code, Instruction, InstructionParameter};
+
+fn cell_to_json(cell: &Cell) -> Value {
+    let refs = (0..cell.references_count())
+        .filter_map(|i| cell.reference(i).ok())
+        .map(|child| cell_to_json(&child))
+        .collect::<Vec<_>>();
+    let mut value = json!({
+        "hash": cell.repr_hash().to_hex_string(),
+        "data": cell.to_hex_string(true),
+        "refs": refs,
+    });
+    if cell.cell_type() != CellType::Ordinary {
+        value["exotic"] = json!(format!("{:?}", cell.cell_type()));
+    }
+    value
+}
+
+fn param_to_json(param: &InstructionParameter) -> Value {
+    use InstructionParameter::*;
+    match param {
+        BigInteger(i) => json!({ "type": "integer", "value": i.to_string() }),
+        Integer(i) => json!({ "type": "integer", "value": i.to_string() }),
+        ControlRegister(c) => json!({ "type": "control-register", "value": c }),
+        Length(l) => json!({ "type": "length", "value": l }),
+        LengthAndIndex(l, i) => json!({ "type": "length-and-index", "length": l, "index": i }),
+        Nargs(n) => json!({ "type": "nargs", "value": n }),
+        Pargs(p) => json!({ "type": "pargs", "value": p }),
+        Rargs(r) => json!({ "type": "rargs", "value": r }),
+        Slice(s) => json!({
+            "type": "slice",
+            "data": s.to_hex_string(),
+            "bits": s.remaining_bits(),
+            "refs": (0..s.remaining_references())
+                .filter_map(|i| s.reference(i).ok())
+                .map(|cell| cell_to_json(&cell))
+                .collect::<Vec<_>>(),
+        }),
+        StackRegister(r) => json!({ "type": "stack-register", "value": r }),
+        StackRegisterPair(a, b) => json!({ "type": "stack-registers", "value": [a, b] }),
+        StackRegisterTriple(a, b, c) => json!({ "type": "stack-registers", "value": [a, b, c] }),
+        Code { code, cell } => json!({
+            "type": "code",
+            "cell": cell.as_ref().map(|c| c.repr_hash().to_hex_string()),
+            "instructions": code.to_json(),
+        }),
+        Cell { cell, collapsed } => json!({
+            "type": "cell",
+            "collapsed": collapsed,
+            "cell": cell.as_ref().map(cell_to_json),
+        }),
+        CodeDictMarker(names) => json!({ "type": "code-dict", "names": names }),
+        Symbol(name) => json!({ "type": "symbol", "name": name }),
+    }
+}
+
+fn insn_to_json(insn: &Instruction) -> Value {
+    let mut name = insn.name().to_string();
+    if insn.is_quiet() {
+        name += "Q";
+    }
+    let mut value = json!({
+        "name": name,
+        "operands": insn.params().iter().map(param_to_json).collect::<Vec<_>>(),
+        "refs": insn.refs(),
+    });
+    if let Some(bytecode) = insn.bytecode() {
+        value["bytecode"] = json!(bytecode.to_hex_string());
+        value["bits"] = json!(bytecode.remaining_bits());
+        value["offset"] = json!(bytecode.pos());
+        value["cell"] = json!(bytecode.cell().repr_hash().to_hex_string());
+    }
+    if let Some(comment) = insn.comment() {
+        value["comment"] = json!(comment);
+    }
+    if let Some(source) = insn.source() {
+        value["source"] = json!(source);
+    }
+    value
+}
+
+impl Code {
+    /// Array of the instructions with their bytecode, position in the cell
+    /// and operands, nested code included
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.iter().map(insn_to_json).collect())
+    }
+}
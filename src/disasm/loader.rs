@@ -11,13 +11,13 @@
  * limitations under the License.
  */
 
-use ever_block::{Result, Cell, SliceData, fail, UInt256};
+use ever_block::{Result, Cell, CellType, SliceData, error, fail, UInt256};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::ops::Not;
 use num_traits::Zero;
 
-use super::types::{Instruction, InstructionParameter, Code, OperationBehavior};
+use super::{types::{Instruction, InstructionParameter, Code, OperationBehavior}, DisasmOptions};
 use super::handlers::Handlers;
 
 macro_rules! create_handler_1 {
@@ -57,6 +57,18 @@ macro_rules! create_handler_2 {
     };
 }
 
+macro_rules! create_handler_3 {
+    ($func_name:ident, $opc:literal, $mnemonic:literal) => {
+        pub(super) fn $func_name(&mut self, slice: &mut SliceData) -> Result<Instruction> {
+            let opc = slice.get_next_int(24)?;
+            if opc != $opc {
+                fail!("invalid opcode");
+            }
+            Ok(Instruction::new($mnemonic))
+        }
+    };
+}
+
 macro_rules! create_handler_2t {
     ($func_name:ident, $opc:literal, $mnemonic:literal) => {
         pub(super) fn $func_name<T>(&mut self, slice: &mut SliceData) -> Result<Instruction>
@@ -138,20 +150,41 @@ fn comment_missing_bits_and_refs(insn: &mut Instruction, missing_bits: usize, mi
 }
 
 pub struct Loader {
-    handlers: Handlers,
+    /// instruction tables by codepage
+    pub(crate) code_pages: HashMap<i16, Handlers>,
+    code_page: i16,
     collapse: bool,
+    max_depth: Option<usize>,
+    /// number of cells the one being loaded is nested in
+    depth: usize,
     history: HashMap<UInt256, Code>,
 }
 
 impl Loader {
     pub fn new(collapse: bool) -> Self {
         Self {
-            handlers: Handlers::new_code_page_0(),
+            code_pages: HashMap::from([(0, Handlers::new_code_page_0())]),
+            code_page: 0,
             collapse,
+            max_depth: None,
+            depth: 0,
             history: HashMap::new(),
         }
     }
+    /// Loader collapsing cells as the listing options say
+    pub fn with_options(opts: &DisasmOptions) -> Self {
+        let mut loader = Self::new(opts.collapse);
+        loader.max_depth = opts.max_depth;
+        loader
+    }
+    /// Loads the code in the current codepage, SETCP in it does not affect the enclosing code
     pub fn load(&mut self, slice: &mut SliceData, inline: bool) -> Result<Code> {
+        let outer_code_page = self.code_page;
+        let result = self.load_impl(slice, inline);
+        self.code_page = outer_code_page;
+        result
+    }
+    fn load_impl(&mut self, slice: &mut SliceData, inline: bool) -> Result<Code> {
         let orig_slice = slice.clone();
         let mut code = match self.load_slice(slice) {
             Ok(code) => code,
@@ -176,7 +209,9 @@ impl Loader {
             Ordering::Equal => {
                 let next_cell = slice.checked_drain_reference().ok();
                 let mut next_code = self.load_cell(&next_cell)?;
-                if inline {
+                // an exotic cell is written by its directive as the last reference
+                let exotic = next_cell.as_ref().map_or(false, |cell| cell.cell_type() != CellType::Ordinary);
+                if inline || exotic {
                     code.append(&mut next_code)
                 } else {
                     let next = Instruction::new("IMPLICIT-JMP")
@@ -192,7 +227,9 @@ impl Loader {
         let mut code = Code::new();
         while slice.remaining_bits() > 0 {
             let mut bytecode = slice.clone();
-            let handler = self.handlers.get_handler(&mut slice.clone())?;
+            let handler = self.code_pages.get(&self.code_page)
+                .ok_or_else(|| error!("codepage {} is not known", self.code_page))?
+                .get_handler(&mut slice.clone())?;
             let mut insn = handler(self, slice)?;
 
             assert_eq!(bytecode.cell(), slice.cell());
@@ -214,14 +251,25 @@ impl Loader {
         }
     }
     fn load_cell_impl(&mut self, cell: &Cell) -> Result<Code> {
+        if cell.cell_type() != CellType::Ordinary {
+            return Ok(Code::single(Instruction::new(".cell").with_param(InstructionParameter::Cell { cell: Some(cell.clone()), collapsed: false })))
+        }
+        let collapsed = || Code::single(Instruction::new(";;").with_param(InstructionParameter::Cell { cell: Some(cell.clone()), collapsed: true }));
+        if self.max_depth.map_or(false, |max| self.depth >= max) {
+            return Ok(collapsed())
+        }
         if let Some(code) = self.history.get(&cell.repr_hash()) {
             if self.collapse {
-                Ok(Code::single(Instruction::new(";;").with_param(InstructionParameter::Cell { cell: Some(cell.clone()), collapsed: true })))
+                Ok(collapsed())
             } else {
                 Ok(code.clone())
             }
         } else {
-            let code = self.load(&mut SliceData::load_cell_ref(cell)?, false).unwrap_or_else(|_| {
+            let mut slice = SliceData::load_cell_ref(cell)?;
+            self.depth += 1;
+            let code = self.load(&mut slice, false);
+            self.depth -= 1;
+            let code = code.unwrap_or_else(|_| {
                 // failed to load the cell - emit it as-is
                 Code::single(Instruction::new(".cell").with_param(InstructionParameter::Cell { cell: Some(cell.clone()), collapsed: false }))
             });
@@ -236,6 +284,14 @@ impl Loader {
         let opc = slice.get_next_int(8)?;
         check_eq!(opc, 0xff);
         let cp = slice.get_next_byte()?;
+        // codepages -15..-1 are encoded as 0xF1..0xFF
+        let number = match cp {
+            0xF1..=0xFF => cp as i16 - 0x100,
+            _ => cp as i16,
+        };
+        if self.code_pages.contains_key(&number) {
+            self.code_page = number;
+        }
         Ok(Instruction::new("SETCP").with_param(InstructionParameter::Integer(cp as isize)))
     }
     create_handler_2!(setcpx, 0xfff0, "SETCPX");
@@ -972,8 +1028,22 @@ impl Loader {
     create_handler_2!(stbrefrq,    0xcf1d, "STBREFRQ");
     create_handler_2!(stslicerq,   0xcf1e, "STSLICERQ");
     create_handler_2!(stbrq,       0xcf1f, "STBRQ");
-    create_handler_2!(strefconst,  0xcf20, "STREFCONST");
-    create_handler_2!(stref2const, 0xcf21, "STREF2CONST");
+    pub(super) fn strefconst(&mut self, slice: &mut SliceData) -> Result<Instruction> {
+        let opc = slice.get_next_int(16)?;
+        check_eq!(opc, 0xcf20);
+        let cell = slice.checked_drain_reference().ok();
+        Ok(Instruction::new("STREFCONST").with_refs(1).with_param(InstructionParameter::Cell { cell, collapsed: false }))
+    }
+    pub(super) fn stref2const(&mut self, slice: &mut SliceData) -> Result<Instruction> {
+        let opc = slice.get_next_int(16)?;
+        check_eq!(opc, 0xcf21);
+        let cell1 = slice.checked_drain_reference().ok();
+        let cell2 = slice.checked_drain_reference().ok();
+        Ok(Instruction::new("STREF2CONST")
+            .with_refs(2)
+            .with_param(InstructionParameter::Cell { cell: cell1, collapsed: false })
+            .with_param(InstructionParameter::Cell { cell: cell2, collapsed: false }))
+    }
     create_handler_2!(endxc,       0xcf23, "ENDXC");
     create_handler_2!(stile4,      0xcf28, "STILE4");
     create_handler_2!(stule4,      0xcf29, "STULE4");
@@ -1725,6 +1795,30 @@ impl Loader {
     create_handler_2!(cdatasize,  0xf941, "CDATASIZE");
     create_handler_2!(sdatasizeq, 0xf942, "SDATASIZEQ");
     create_handler_2!(sdatasize,  0xf943, "SDATASIZE");
+    create_handler_3!(bls_verify,               0xf93000, "BLS_VERIFY");
+    create_handler_3!(bls_aggregate,            0xf93001, "BLS_AGGREGATE");
+    create_handler_3!(bls_fastaggregateverify,  0xf93002, "BLS_FASTAGGREGATEVERIFY");
+    create_handler_3!(bls_aggregateverify,      0xf93003, "BLS_AGGREGATEVERIFY");
+    create_handler_3!(bls_g1_add,               0xf93010, "BLS_G1_ADD");
+    create_handler_3!(bls_g1_sub,               0xf93011, "BLS_G1_SUB");
+    create_handler_3!(bls_g1_neg,               0xf93012, "BLS_G1_NEG");
+    create_handler_3!(bls_g1_mul,               0xf93013, "BLS_G1_MUL");
+    create_handler_3!(bls_g1_multiexp,          0xf93014, "BLS_G1_MULTIEXP");
+    create_handler_3!(bls_g1_zero,              0xf93015, "BLS_G1_ZERO");
+    create_handler_3!(bls_map_to_g1,            0xf93016, "BLS_MAP_TO_G1");
+    create_handler_3!(bls_g1_ingroup,           0xf93017, "BLS_G1_INGROUP");
+    create_handler_3!(bls_g1_iszero,            0xf93018, "BLS_G1_ISZERO");
+    create_handler_3!(bls_g2_add,               0xf93020, "BLS_G2_ADD");
+    create_handler_3!(bls_g2_sub,               0xf93021, "BLS_G2_SUB");
+    create_handler_3!(bls_g2_neg,               0xf93022, "BLS_G2_NEG");
+    create_handler_3!(bls_g2_mul,               0xf93023, "BLS_G2_MUL");
+    create_handler_3!(bls_g2_multiexp,          0xf93024, "BLS_G2_MULTIEXP");
+    create_handler_3!(bls_g2_zero,              0xf93025, "BLS_G2_ZERO");
+    create_handler_3!(bls_map_to_g2,            0xf93026, "BLS_MAP_TO_G2");
+    create_handler_3!(bls_g2_ingroup,           0xf93027, "BLS_G2_INGROUP");
+    create_handler_3!(bls_g2_iszero,            0xf93028, "BLS_G2_ISZERO");
+    create_handler_3!(bls_pairing,              0xf93030, "BLS_PAIRING");
+    create_handler_3!(bls_pushr,                0xf93031, "BLS_PUSHR");
     create_handler_2!(dump_stack, 0xfe00, "DUMPSTK");
     pub(super) fn dump_stack_top(&mut self, slice: &mut SliceData) -> Result<Instruction> {
         let opc = slice.get_next_int(12)?;
@@ -1758,24 +1852,33 @@ impl Loader {
     pub(super) fn dump_string(&mut self, slice: &mut SliceData) -> Result<Instruction> {
         let opc = slice.get_next_int(12)?;
         check_eq!(opc, 0xfef);
-        let n = slice.get_next_int(4)?;
-        let mode = slice.get_next_int(8)?;
-        match n {
-            0 => {
-                check_eq!(mode, 0x00);
-                Ok(Instruction::new("LOGFLUSH"))
+        let n = slice.get_next_int(4)? as usize;
+        let mut string = slice.get_next_slice((n + 1) * 8)?;
+        let insn = match string.get_byte(0)? {
+            0x00 if n == 0 => return Ok(Instruction::new("LOGFLUSH")),
+            0x00 => {
+                string.get_next_byte()?;
+                Instruction::new("LOGSTR")
             }
-            _ => {
-                if mode == 0x00 {
-                    let s = slice.get_next_slice(n as usize * 8)?;
-                    Ok(Instruction::new("LOGSTR").with_param(InstructionParameter::Slice(s)))
-                } else if mode == 0x01 {
-                    let s = slice.get_next_slice(n as usize * 8)?;
-                    Ok(Instruction::new("PRINTSTR").with_param(InstructionParameter::Slice(s)))
-                } else {
-                    fail!("unknown dump_string mode")
-                }
+            0x01 if n > 0 => {
+                string.get_next_byte()?;
+                Instruction::new("PRINTSTR")
+            }
+            _ => Instruction::new("DEBUGSTR")
+        };
+        let text = String::from_utf8(string.get_bytestring(0)).ok();
+        let mut insn = insn.with_param(InstructionParameter::Slice(string));
+        if let Some(text) = text {
+            if text.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+                insn.set_comment(format!("\"{}\"", text))
             }
         }
+        Ok(insn)
+    }
+    pub(super) fn debug(&mut self, slice: &mut SliceData) -> Result<Instruction> {
+        let opc = slice.get_next_int(8)?;
+        check_eq!(opc, 0xfe);
+        let z = slice.get_next_byte()?;
+        Ok(Instruction::new("DEBUG").with_param(InstructionParameter::Integer(z as isize)))
     }
 }
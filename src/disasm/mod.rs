@@ -11,22 +11,251 @@
  * limitations under the License.
  */
 
-use ever_block::{Result, SliceData};
-use self::loader::Loader;
+use std::collections::HashSet;
+use ever_block::{
+    base64_decode, error, read_boc, Cell, Deserializable, Result, SliceData, StateInit, UInt256,
+};
+use crate::{DbgInfo, DbgPos};
+use self::{
+    idioms::IdiomMode, loader::Loader, selectors::SelectorRegistry, source::SourceProvider, symbols::SymbolMap,
+    types::{Code, InstructionParameter},
+};
 
+pub mod cfg;
 pub mod codedict;
-mod handlers;
+pub mod constants;
+pub mod diff;
+pub mod handlers;
+pub mod idioms;
+mod json;
 pub mod loader;
+pub mod search;
 pub mod fmt;
+pub mod selectors;
+pub mod source;
+pub mod symbols;
 pub mod types;
+#[cfg(test)]
+mod tests;
+
+pub use self::constants::{extract_constants, Constant, ConstantValue};
+pub use self::diff::{diff, CodeDiff};
+pub use self::search::{find, Match};
+
+/// Options of the listing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DisasmOptions {
+    /// print a cell met again as collapsed instead of its code
+    pub collapse: bool,
+    /// number of nested cells to expand, deeper ones are printed as collapsed
+    pub max_depth: Option<usize>,
+    /// list the code of referenced cells in sections after the code instead of inline
+    pub sections: bool,
+    /// prefix instructions with `cellhash[:8]:bitoffset` as exceptions and the debug map locate them
+    pub offsets: bool,
+    /// width of the column with raw bytes of instructions, 0 to omit it
+    pub bytecode_width: usize,
+    /// comment blocks with the hashes of their cells
+    pub hashes: bool,
+    /// list methods of dictionary dispatchers by their ids after the code instead of dumping the dictionary cells
+    pub methods: bool,
+    /// listing of known compiler idioms
+    pub idioms: IdiomMode,
+}
+
+impl Default for DisasmOptions {
+    fn default() -> Self {
+        Self {
+            collapse: false,
+            max_depth: None,
+            sections: false,
+            offsets: false,
+            bytecode_width: 0,
+            hashes: true,
+            methods: false,
+            idioms: IdiomMode::Off,
+        }
+    }
+}
 
 pub fn disasm(slice: &mut SliceData) -> Result<String> {
     disasm_ex(slice, false)
 }
 
 pub fn disasm_ex(slice: &mut SliceData, collapsed: bool) -> Result<String> {
+    disasm_with_options(slice, &DisasmOptions { collapse: collapsed, ..DisasmOptions::default() })
+}
+
+/// Disassembles the code into the listing tuned by the options
+pub fn disasm_with_options(slice: &mut SliceData, opts: &DisasmOptions) -> Result<String> {
+    let mut loader = Loader::with_options(opts);
+    let mut code = loader.load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    code.recognize_idioms(opts.idioms);
+    Ok(code.print_with(opts))
+}
+
+/// Disassembles only the method of the dispatcher found in the code
+pub fn disasm_method(slice: &mut SliceData, method_id: i64) -> Result<String> {
+    let mut code = Loader::new(false).load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    let method = code.method(method_id)
+        .ok_or_else(|| error!("method {} is not found in the code dictionary", method_id))?;
+    Ok(method.code.print("", true, 0))
+}
+
+/// Cell of the tree with the hash
+pub fn find_cell(root: &Cell, hash: &UInt256) -> Option<Cell> {
+    let mut stack = vec!(root.clone());
+    let mut seen = HashSet::new();
+    while let Some(cell) = stack.pop() {
+        if &cell.repr_hash() == hash {
+            return Some(cell)
+        }
+        if seen.insert(cell.repr_hash()) {
+            stack.extend((0..cell.references_count()).filter_map(|i| cell.reference(i).ok()));
+        }
+    }
+    None
+}
+
+/// Disassembles only the code starting at the cell of the tree with the hash
+pub fn disasm_cell(root: &Cell, hash: &UInt256) -> Result<String> {
+    let cell = find_cell(root, hash)
+        .ok_or_else(|| error!("cell {} is not found in the tree", hash.to_hex_string()))?;
+    disasm(&mut SliceData::load_cell(cell)?)
+}
+
+/// What the root of a boc holds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BocRoot {
+    /// the code cell itself
+    #[default]
+    Code,
+    /// StateInit of a contract, the code is taken from it
+    StateInit,
+}
+
+/// Deserializes the boc and returns the code cell of its first root
+pub fn code_from_boc(bytes: &[u8], root: BocRoot) -> Result<Cell> {
+    let roots = read_boc(bytes.to_vec())?.roots;
+    let cell = roots.into_iter().next().ok_or_else(|| error!("boc is empty"))?;
+    match root {
+        BocRoot::Code => Ok(cell),
+        BocRoot::StateInit => StateInit::construct_from_cell(cell)?.code
+            .ok_or_else(|| error!("StateInit has no code")),
+    }
+}
+
+/// Disassembles the code held by the root of a serialized boc
+pub fn disasm_boc(bytes: &[u8]) -> Result<String> {
+    disasm_boc_ex(bytes, BocRoot::Code, false)
+}
+
+pub fn disasm_boc_ex(bytes: &[u8], root: BocRoot, collapsed: bool) -> Result<String> {
+    let cell = code_from_boc(bytes, root)?;
+    disasm_ex(&mut SliceData::load_cell(cell)?, collapsed)
+}
+
+/// Disassembles the code held by the root of a base64-encoded boc
+pub fn disasm_base64(s: &str) -> Result<String> {
+    disasm_base64_ex(s, BocRoot::Code, false)
+}
+
+pub fn disasm_base64_ex(s: &str, root: BocRoot, collapsed: bool) -> Result<String> {
+    disasm_boc_ex(&base64_decode(s.trim())?, root, collapsed)
+}
+
+/// Disassembles the code commenting constants which match known selectors
+pub fn disasm_with_selectors(slice: &mut SliceData, collapsed: bool, registry: &SelectorRegistry) -> Result<String> {
+    let mut loader = Loader::new(collapsed);
+    let mut code = loader.load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    code.annotate_selectors(registry);
+    Ok(code.print("", true, 0))
+}
+
+/// Disassembles the code into a JSON array of instructions, see `Code::to_json`
+pub fn disasm_json(slice: &mut SliceData) -> Result<serde_json::Value> {
+    let mut loader = Loader::new(false);
+    let mut code = loader.load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    Ok(code.to_json())
+}
+
+/// Disassembles the code commenting each instruction with its static gas
+pub fn disasm_with_gas(slice: &mut SliceData, collapsed: bool) -> Result<String> {
     let mut loader = Loader::new(collapsed);
     let mut code = loader.load(slice, false)?;
     code.elaborate_dictpushconst_dictugetjmp();
+    code.annotate_gas();
     Ok(code.print("", true, 0))
 }
+
+/// Disassembles the code showing names of the known methods and cells
+pub fn disasm_with_symbols(slice: &mut SliceData, collapsed: bool, symbols: &SymbolMap) -> Result<String> {
+    let mut loader = Loader::new(collapsed);
+    let mut code = loader.load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    code.annotate_symbols(symbols);
+    Ok(code.print("", true, 0))
+}
+
+/// Disassembles the code into text that the assembler turns back into the same cells
+pub fn disasm_reassemblable(slice: &mut SliceData) -> Result<String> {
+    let mut loader = Loader::new(false);
+    let mut code = loader.load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    code.make_reassemblable()?;
+    Ok(code.print("", true, 0))
+}
+
+/// Disassembles the code commenting each instruction with its source position
+/// if the debug info resolves it
+pub fn disasm_with_positions(slice: &mut SliceData, collapsed: bool, dbg: &DbgInfo) -> Result<String> {
+    let mut loader = Loader::new(collapsed);
+    let mut code = loader.load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    annotate_positions(&mut code, dbg);
+    Ok(code.print("", true, 0))
+}
+
+fn annotate_positions(code: &mut Code, dbg: &DbgInfo) {
+    code.annotate(&mut |insn| {
+        let bytecode = insn.bytecode()?;
+        dbg.get(&bytecode.cell().repr_hash())?.get(&bytecode.pos()).map(|pos| pos.to_string())
+    })
+}
+
+/// Disassembles the code printing the source lines above the instructions
+/// compiled from them, like `objdump -S`
+pub fn disasm_with_dbg(slice: &mut SliceData, dbg: &DbgInfo, sources: &dyn SourceProvider) -> Result<String> {
+    let mut loader = Loader::new(false);
+    let mut code = loader.load(slice, false)?;
+    code.elaborate_dictpushconst_dictugetjmp();
+    annotate_sources(&mut code, dbg, sources, &mut None);
+    Ok(code.print("", true, 0))
+}
+
+/// Sets the source line of the instructions starting a new line of the source
+fn annotate_sources(code: &mut Code, dbg: &DbgInfo, sources: &dyn SourceProvider, last: &mut Option<DbgPos>) {
+    for insn in code.iter_mut() {
+        let pos = insn.bytecode()
+            .and_then(|b| dbg.get(&b.cell().repr_hash())?.get(&b.pos()).cloned());
+        if let Some(pos) = pos {
+            let same_line = last.as_ref()
+                .map_or(false, |last| last.filename == pos.filename && last.line == pos.line);
+            if !same_line {
+                if let Some(line) = sources.line(&pos.filename, pos.line) {
+                    insn.set_source(format!("{}:{}: {}", pos.filename, pos.line, line.trim()));
+                }
+                *last = Some(pos);
+            }
+        }
+        for param in insn.params_mut() {
+            if let InstructionParameter::Code { code, .. } = param {
+                annotate_sources(code, dbg, sources, last);
+            }
+        }
+    }
+}
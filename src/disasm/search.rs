@@ -0,0 +1,101 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+//! Search of instruction sequences in code
+//!
+//! A pattern is a list of elements separated by `;`, each matching a single
+//! instruction: a mnemonic matches it with any operands, a mnemonic followed
+//! by operands as they are printed matches them exactly, `*` in place of the
+//! operands or of the whole element matches anything, e.g.
+//! `PUSHINT * ; SENDRAWMSG`. The code continued in the next cell is searched
+//! as a single sequence, so matches may cross cell boundaries; blocks of code
+//! are searched on their own.
+
+use std::collections::HashSet;
+use ever_block::{fail, Result, SliceData, UInt256};
+
+use super::{
+    idioms::matches, loader::Loader,
+    types::{Code, Instruction, InstructionParameter},
+};
+
+/// Sequence of instructions found by a pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    /// cell of the first instruction
+    pub cell: UInt256,
+    /// bit offset of the first instruction in the cell
+    pub offset: usize,
+    /// the instructions as printed
+    pub insns: Vec<String>,
+}
+
+/// Splits the pattern into elements
+fn parse_pattern(pattern: &str) -> Result<Vec<String>> {
+    let elements = pattern.split(';')
+        .map(|element| element.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>();
+    if elements.iter().any(String::is_empty) {
+        fail!("pattern {:?} has an empty element", pattern)
+    }
+    Ok(elements)
+}
+
+/// Appends the instructions of the code to the sequence following its continuation,
+/// blocks are put aside to be searched separately
+fn flatten<'a>(code: &'a Code, sequence: &mut Vec<&'a Instruction>, blocks: &mut Vec<&'a Code>) {
+    for insn in code.iter() {
+        match (insn.name(), insn.params().first()) {
+            ("IMPLICIT-JMP", Some(InstructionParameter::Code { code, .. })) => flatten(code, sequence, blocks),
+            _ => {
+                sequence.push(insn);
+                for param in insn.params() {
+                    if let InstructionParameter::Code { code, .. } = param {
+                        blocks.push(code);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Finds the sequences of instructions matching the pattern in the code
+pub fn find(slice: &mut SliceData, pattern: &str) -> Result<Vec<Match>> {
+    let elements = parse_pattern(pattern)?;
+    let code = Loader::new(false).load(slice, false)?;
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    let mut blocks = vec!(&code);
+    while let Some(block) = blocks.pop() {
+        let mut sequence = Vec::new();
+        flatten(block, &mut sequence, &mut blocks);
+        for window in sequence.windows(elements.len()) {
+            if !window.iter().zip(&elements).all(|(insn, element)| matches(insn, element)) {
+                continue
+            }
+            let bytecode = match window[0].bytecode() {
+                Some(bytecode) => bytecode,
+                None => continue
+            };
+            let (cell, offset) = (bytecode.cell().repr_hash(), bytecode.pos());
+            // code of a cell referred to several times is loaded as many times
+            if seen.insert((cell.clone(), offset)) {
+                let insns = window.iter()
+                    .map(|insn| Code::single((*insn).clone()).print("", false, 0).trim_end().to_string())
+                    .collect();
+                found.push(Match { cell, offset, insns });
+            }
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,133 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use ever_block::{error, fail, sha256_digest, Result};
+use num::{BigInt, Num};
+use serde_json::Value;
+
+use super::types::{Code, InstructionParameter};
+
+/// Known function ids and interface hashes to annotate integer constants with
+#[derive(Clone, Debug, Default)]
+pub struct SelectorRegistry {
+    names: HashMap<BigInt, String>,
+}
+
+pub(super) fn parse_id(value: &Value) -> Result<BigInt> {
+    match value {
+        Value::Number(n) => n.as_u64().map(BigInt::from)
+            .ok_or_else(|| error!("selector {} is not an unsigned integer", n)),
+        Value::String(s) => match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => BigInt::from_str_radix(hex, 16),
+            None => BigInt::from_str_radix(s, 10),
+        }.map_err(|e| error!("invalid selector {}: {}", s, e)),
+        _ => fail!("selector {} is neither a number nor a string", value)
+    }
+}
+
+/// Canonical type of an ABI parameter as used in function signatures
+fn abi_type(param: &Value) -> Result<String> {
+    let ty = param["type"].as_str().ok_or_else(|| error!("ABI parameter has no type"))?;
+    match ty.strip_prefix("tuple") {
+        Some(suffix) => {
+            let components = param["components"].as_array()
+                .ok_or_else(|| error!("ABI tuple has no components"))?
+                .iter()
+                .map(abi_type)
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!("({}){}", components.join(","), suffix))
+        }
+        None => Ok(ty.to_string())
+    }
+}
+
+fn abi_types(params: &Value) -> Result<Vec<String>> {
+    match params.as_array() {
+        Some(params) => params.iter().map(abi_type).collect(),
+        None => Ok(Vec::new())
+    }
+}
+
+/// Function ids and signatures of a contract ABI.
+/// Explicit ids are taken as is, otherwise they are computed from the signatures
+pub(crate) fn abi_functions(text: &str) -> Result<Vec<(u32, String, String)>> {
+    let abi: Value = serde_json::from_str(text).map_err(|e| error!("{}", e))?;
+    let version = abi["version"].as_str()
+        .or_else(|| abi["ABI version"].as_u64().map(|_| "1"))
+        .unwrap_or("2");
+    let major = version.split('.').next().unwrap_or("2");
+    let functions = abi["functions"].as_array()
+        .ok_or_else(|| error!("ABI has no functions"))?;
+    let mut result = Vec::new();
+    for function in functions {
+        let name = function["name"].as_str().ok_or_else(|| error!("ABI function has no name"))?;
+        let inputs = abi_types(&function["inputs"])?;
+        let outputs = abi_types(&function["outputs"])?;
+        let signature = format!("{}({})", name, inputs.join(","));
+        let id = match function.get("id") {
+            Some(id) => u32::try_from(parse_id(id)?).map_err(|e| error!("{}", e))?,
+            None => {
+                let full = format!("{}({})v{}", signature, outputs.join(","), major);
+                let hash = sha256_digest(full.as_bytes());
+                u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+            }
+        };
+        result.push((id, name.to_string(), signature));
+    }
+    Ok(result)
+}
+
+impl SelectorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn insert(&mut self, id: BigInt, name: impl Into<String>) {
+        self.names.insert(id, name.into());
+    }
+    pub fn get(&self, id: &BigInt) -> Option<&String> {
+        self.names.get(id)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+    /// Adds entries of a registry file, a JSON object mapping names to ids,
+    /// the latter being numbers or decimal or 0x-prefixed hex strings
+    pub fn add_json(&mut self, text: &str) -> Result<()> {
+        let value: Value = serde_json::from_str(text).map_err(|e| error!("{}", e))?;
+        let map = value.as_object().ok_or_else(|| error!("registry must be a JSON object"))?;
+        for (name, id) in map {
+            self.insert(parse_id(id)?, name.clone());
+        }
+        Ok(())
+    }
+    /// Adds function ids of a contract ABI, both for internal calls and answers
+    pub fn add_abi(&mut self, text: &str) -> Result<()> {
+        for (id, _, signature) in abi_functions(text)? {
+            self.insert(BigInt::from(id & 0x7FFF_FFFF), signature.clone());
+            self.insert(BigInt::from(id | 0x8000_0000), format!("{} answer", signature));
+        }
+        Ok(())
+    }
+}
+
+impl Code {
+    /// Comments PUSHINT constants which match known selectors
+    pub fn annotate_selectors(&mut self, registry: &SelectorRegistry) {
+        self.annotate(&mut |insn| match (insn.name(), insn.params().first()) {
+            ("PUSHINT", Some(InstructionParameter::Integer(i))) => registry.get(&BigInt::from(*i)).cloned(),
+            ("PUSHINT", Some(InstructionParameter::BigInteger(i))) => registry.get(i).cloned(),
+            _ => None
+        })
+    }
+}
@@ -0,0 +1,55 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::{cell::RefCell, path::PathBuf};
+
+/// Lines of the sources named in debug info
+pub trait SourceProvider {
+    /// Text of the line numbered from 1, without the line break
+    fn line(&self, filename: &str, line: usize) -> Option<String>;
+}
+
+/// Sources given as text by filename
+impl SourceProvider for HashMap<String, String> {
+    fn line(&self, filename: &str, line: usize) -> Option<String> {
+        self.get(filename)?.lines().nth(line.checked_sub(1)?).map(str::to_string)
+    }
+}
+
+/// Sources read from files relative to the root directory, each file once
+#[cfg(feature = "fs")]
+pub struct FileSources {
+    root: PathBuf,
+    files: RefCell<HashMap<String, Option<Vec<String>>>>,
+}
+
+#[cfg(feature = "fs")]
+impl FileSources {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into(), files: RefCell::new(HashMap::new()) }
+    }
+}
+
+#[cfg(feature = "fs")]
+impl SourceProvider for FileSources {
+    fn line(&self, filename: &str, line: usize) -> Option<String> {
+        let mut files = self.files.borrow_mut();
+        let lines = files.entry(filename.to_string()).or_insert_with(|| {
+            std::fs::read_to_string(self.root.join(filename)).ok()
+                .map(|text| text.lines().map(str::to_string).collect())
+        });
+        lines.as_ref()?.get(line.checked_sub(1)?).cloned()
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ * Copyright 2018-2024 EVERX DEV SOLUTIONS LTD.
+ *
+ * Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+ * this file except in compliance with the License.
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific EVERX DEV software governing permissions and
+ * limitations under the License.
+ */
+
+use std::{collections::{BTreeMap, HashMap}, str::FromStr};
+use ever_block::{error, Result, UInt256};
+use serde_json::Value;
+
+use crate::DbgInfo;
+use super::{
+    selectors::{abi_functions, parse_id},
+    types::{Code, InstructionParameter},
+};
+
+/// Names of methods and cells to show in place of raw ids and hashes
+#[derive(Clone, Debug, Default)]
+pub struct SymbolMap {
+    methods: BTreeMap<u64, String>,
+    cells: HashMap<UInt256, String>,
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_method(&mut self, id: u64, name: impl Into<String>) {
+        self.methods.insert(id, name.into());
+    }
+    pub fn add_cell(&mut self, hash: UInt256, name: impl Into<String>) {
+        self.cells.insert(hash, name.into());
+    }
+    pub fn method(&self, id: u64) -> Option<&String> {
+        self.methods.get(&id)
+    }
+    pub fn cell(&self, hash: &UInt256) -> Option<&String> {
+        self.cells.get(hash)
+    }
+    /// Adds entries of a symbol file, a JSON object like
+    /// `{"methods": {"name": id}, "cells": {"name": "hash"}}`
+    pub fn add_json(&mut self, text: &str) -> Result<()> {
+        let value: Value = serde_json::from_str(text).map_err(|e| error!("{}", e))?;
+        if let Some(methods) = value["methods"].as_object() {
+            for (name, id) in methods {
+                let id = u64::try_from(parse_id(id)?).map_err(|e| error!("{}", e))?;
+                self.add_method(id, name.clone());
+            }
+        }
+        if let Some(cells) = value["cells"].as_object() {
+            for (name, hash) in cells {
+                let hash = hash.as_str()
+                    .and_then(|h| UInt256::from_str(h).ok())
+                    .ok_or_else(|| error!("invalid hash of cell {}", name))?;
+                self.add_cell(hash, name.clone());
+            }
+        }
+        Ok(())
+    }
+    /// Names methods by the function ids of a contract ABI
+    pub fn add_abi(&mut self, text: &str) -> Result<()> {
+        for (id, name, _) in abi_functions(text)? {
+            self.add_method((id & 0x7FFF_FFFF) as u64, name);
+        }
+        Ok(())
+    }
+    /// Names cells by the source positions of their first instructions
+    pub fn add_dbg(&mut self, dbg: &DbgInfo) {
+        for (hash, positions) in dbg.iter() {
+            if let Some(pos) = positions.values().next() {
+                self.cells.entry(hash).or_insert_with(|| pos.to_string());
+            }
+        }
+    }
+}
+
+impl Code {
+    /// Replaces method ids of CALL and JMPDICT with their names, names methods
+    /// of code dictionaries and comments references to known cells
+    pub fn annotate_symbols(&mut self, symbols: &SymbolMap) {
+        for insn in self.iter_mut() {
+            let call = matches!(insn.name(), "CALL" | "JMPDICT" | "PREPARE");
+            let mut comments = Vec::new();
+            for param in insn.params_mut() {
+                match param {
+                    InstructionParameter::Nargs(id) if call && *id >= 0 => {
+                        if let Some(name) = symbols.method(*id as u64) {
+                            comments.push(format!("method {}", id));
+                            *param = InstructionParameter::Symbol(name.clone());
+                        }
+                    }
+                    InstructionParameter::CodeDictMarker(names) => {
+                        names.extend(symbols.methods.iter().map(|(id, name)| (*id, name.clone())));
+                    }
+                    InstructionParameter::Code { code, cell } => {
+                        if let Some(name) = cell.as_ref().and_then(|c| symbols.cell(&c.repr_hash())) {
+                            comments.push(name.clone());
+                        }
+                        code.annotate_symbols(symbols);
+                    }
+                    _ => ()
+                }
+            }
+            if !comments.is_empty() {
+                insn.add_comment(comments.join(", "));
+            }
+        }
+    }
+}
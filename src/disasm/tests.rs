@@ -71,6 +71,7 @@ fn round_trip_test(filename: &str, check_bin: bool) -> Status {
 }
 
 #[test]
+#[ignore = "needs the boc fixtures of src/tests/disasm/roundtrip"]
 fn round_trip() {
     let mut indices = (0..130).collect::<Vec<i32>>();
     indices.append(&mut (200..331).collect());
@@ -79,6 +80,17 @@ fn round_trip() {
     }
 }
 
+#[cfg(feature = "fs")]
+#[test]
+fn round_trip_reassemblable() -> Status {
+    let failures = crate::roundtrip::check_corpus(std::path::Path::new("corpus/roundtrip"))?;
+    for (path, error) in &failures {
+        println!("{}: {}", path.display(), error);
+    }
+    assert!(failures.is_empty(), "{} samples do not reassemble", failures.len());
+    Ok(())
+}
+
 fn check_fragment(code: &str, text: &str) -> Status {
     let builder = SliceData::from_string(code)?.as_builder();
     let mut slice = SliceData::load_builder(builder)?;
@@ -102,6 +114,10 @@ fn fragments() -> Status {
     check_fragment("e30f", "IFREFELSEREF {\n  ;; missing cell\n}{\n  ;; missing cell\n}\n")?;
     check_fragment("f4a420", "DICTPUSHCONST 32 ;; missing dict ref\n")?;
     check_fragment("ff77", "SETCP 119\n")?;
+    check_fragment("fe40", "DEBUG 64\n")?;
+    check_fragment("fef2004142", "LOGSTR x4142 ;; \"AB\"\n")?;
+    check_fragment("fef14142", "DEBUGSTR x4142 ;; \"AB\"\n")?;
+    check_fragment("fef000", "LOGFLUSH\n")?;
     Ok(())
 }
 
@@ -125,6 +141,7 @@ fn check_code(name: &str) -> Status {
 }
 
 #[test]
+#[ignore = "needs the fixtures of src/tests/disasm"]
 fn codes() -> Status {
     check_code("code-dict-1.code")?;
     Ok(())
@@ -11,9 +11,11 @@
  * limitations under the License.
  */
 
-use std::{collections::HashMap, slice::ChunksMut};
+use std::{collections::{BTreeMap, HashMap}, slice::ChunksMut};
 use ever_block::{Cell, Result, /*Bitmask,*/ SliceData, fail};
 
+use crate::instructions::{introduced_in, TvmVersion};
+
 #[derive(Debug, Default, Clone)]
 pub struct Code {
     storage: Vec<Instruction>
@@ -45,6 +47,19 @@ impl Code {
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Instruction>{
         self.storage.iter_mut()
     }
+    /// Adds the comment the function gives for an instruction to it, nested code included
+    pub fn annotate(&mut self, comment: &mut dyn FnMut(&Instruction) -> Option<String>) {
+        for insn in self.iter_mut() {
+            if let Some(text) = comment(insn) {
+                insn.add_comment(text);
+            }
+            for param in insn.params_mut() {
+                if let InstructionParameter::Code { code, .. } = param {
+                    code.annotate(comment);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -53,13 +68,15 @@ pub struct Instruction {
     params: Vec<InstructionParameter>,
     quiet: bool,
     comment: Option<String>,
+    /// source line printed above the instruction
+    source: Option<String>,
     bytecode: Option<SliceData>,
     refs: usize,
 }
 
 impl Instruction {
     pub fn new(name: &'static str) -> Self {
-        Self { name, params: vec!(), quiet: false, comment: None, bytecode: None, refs: 0 }
+        Self { name, params: vec!(), quiet: false, comment: None, source: None, bytecode: None, refs: 0 }
     }
     pub fn with_refs(self, refs: usize) -> Self {
         let mut clone = self;
@@ -94,6 +111,20 @@ impl Instruction {
     pub fn set_comment(&mut self, comment: String) {
         self.comment = Some(comment)
     }
+    /// Appends the text to the comment in parentheses, or makes it the comment if there is none
+    pub fn add_comment(&mut self, text: String) {
+        let comment = match self.comment.take() {
+            Some(comment) => format!("{} ({})", comment, text),
+            None => text,
+        };
+        self.comment = Some(comment)
+    }
+    pub fn source(&self) -> Option<&String> {
+        self.source.as_ref()
+    }
+    pub fn set_source(&mut self, source: String) {
+        self.source = Some(source)
+    }
     pub fn bytecode(&self) -> Option<&SliceData> {
         self.bytecode.as_ref()
     }
@@ -103,6 +134,10 @@ impl Instruction {
     pub fn refs(&self) -> usize {
         self.refs
     }
+    /// TVM version introducing the instruction
+    pub fn introduced(&self) -> TvmVersion {
+        introduced_in(self.name)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -122,7 +157,10 @@ pub enum InstructionParameter {
     StackRegisterTriple(isize, isize, isize),
     Code { code: Code, cell: Option<Cell> },
     Cell { cell: Option<Cell>, collapsed: bool },
-    CodeDictMarker,
+    /// marks a code dictionary, holds names of its methods if known
+    CodeDictMarker(BTreeMap<u64, String>),
+    /// name substituted for a numeric operand
+    Symbol(String),
 }
 
 // #[derive(Clone, Debug)]
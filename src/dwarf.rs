@@ -0,0 +1,101 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+// DWARF debug info **********************************************************
+//
+// The debug map is written as the DWARF line program of a single compile unit,
+// so that tools consuming DWARF can be adapted to TVM code. TVM has no linear
+// addresses, an instruction is located by its cell and bit offset, so the
+// address of a row is made of both: the first 6 bytes of the cell hash in the
+// high bits and the bit offset in the low 16 ones. Every cell is a sequence
+// of its own starting at offset 0.
+
+use std::collections::HashMap;
+use ever_block::{error, Result, UInt256};
+use gimli::{
+    Encoding, Format, LineEncoding, RunTimeEndian, SectionId,
+    write::{Address, AttributeValue, DwarfUnit, EndianVec, FileId, LineString, Sections},
+};
+
+use crate::DbgInfo;
+
+/// Bits of the address holding the bit offset in the cell
+const OFFSET_BITS: u32 = 16;
+
+/// Address standing for the bit offset in the cell
+pub fn dwarf_address(hash: &UInt256, offset: usize) -> u64 {
+    let mut prefix = [0u8; 8];
+    prefix[2..].copy_from_slice(&hash.as_slice()[..6]);
+    u64::from_be_bytes(prefix) << OFFSET_BITS | offset as u64
+}
+
+/// Prefix of the cell hash and the bit offset the address stands for
+pub fn split_dwarf_address(address: u64) -> ([u8; 6], usize) {
+    let mut prefix = [0u8; 6];
+    prefix.copy_from_slice(&(address >> OFFSET_BITS).to_be_bytes()[2..]);
+    (prefix, (address & ((1 << OFFSET_BITS) - 1)) as usize)
+}
+
+impl DbgInfo {
+    /// DWARF sections of the map by their names, e.g. `.debug_line`,
+    /// the compile unit named after the source
+    pub fn to_dwarf(&self, name: &str) -> Result<Vec<(&'static str, Vec<u8>)>> {
+        let encoding = Encoding { format: Format::Dwarf32, version: 4, address_size: 8 };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let root = dwarf.unit.root();
+        dwarf.unit.get_mut(root).set(gimli::DW_AT_name, AttributeValue::String(name.as_bytes().to_vec()));
+        dwarf.unit.get_mut(root).set(gimli::DW_AT_producer, AttributeValue::String(
+            format!("ever-assembler {}", env!("CARGO_PKG_VERSION")).into_bytes()
+        ));
+        let program = &mut dwarf.unit.line_program;
+        *program = gimli::write::LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            LineString::String(b".".to_vec()),
+            LineString::String(name.as_bytes().to_vec()),
+            None,
+        );
+        let directory = program.default_directory();
+        let mut files = HashMap::<&str, FileId>::new();
+        for (hash, positions) in self.iter() {
+            let last = match positions.keys().next_back() {
+                Some(last) => *last,
+                None => continue
+            };
+            program.begin_sequence(Some(Address::Constant(dwarf_address(&hash, 0))));
+            for (offset, pos) in positions {
+                let file = *files.entry(&*pos.filename).or_insert_with(|| {
+                    program.add_file(LineString::String(pos.filename.as_bytes().to_vec()), directory, None)
+                });
+                let row = program.row();
+                row.address_offset = *offset as u64;
+                row.file = file;
+                row.line = pos.line as u64;
+                row.column = pos.column as u64;
+                program.generate_row();
+            }
+            program.end_sequence(last as u64 + 1);
+        }
+        let mut sections = Sections::new(EndianVec::new(RunTimeEndian::Little));
+        dwarf.write(&mut sections).map_err(|e| error!("failed to write DWARF: {}", e))?;
+        let mut written = Vec::new();
+        sections.for_each(|id: SectionId, data| {
+            if !data.slice().is_empty() {
+                written.push((id.name(), data.slice().to_vec()));
+            }
+            Ok::<_, gimli::write::Error>(())
+        }).map_err(|e| error!("failed to write DWARF: {}", e))?;
+        Ok(written)
+    }
+}
@@ -14,16 +14,52 @@
 use ever_block::Error;
 use std::fmt;
 
+use crate::{DbgPos, TvmVersion};
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Position {
     pub filename: String,
     pub line: usize,
     pub column: usize,
+    /// exact text the error refers to if known
+    pub span: Option<Span>,
+}
+
+/// Range of the source text, the end is exclusive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    /// the offending token
+    pub text: String,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, text: &str) -> Self {
+        let lines = text.split('\n').collect::<Vec<_>>();
+        let last = lines.last().map_or(0, |line| line.chars().count());
+        let end_column = match lines.len() {
+            1 => column + last,
+            _ => last + 1,
+        };
+        Self { line, column, end_line: line + lines.len() - 1, end_column, text: text.to_string() }
+    }
 }
 
 impl Position {
     pub fn new(filename: String, line: usize, column: usize) -> Self {
-        Self { filename, line, column }
+        Self { filename, line, column, span: None }
+    }
+    /// Makes the position span the token starting at it
+    pub fn with_token(self, token: &str) -> Self {
+        let span = Span::new(self.line, self.column, token);
+        self.with_span(span)
+    }
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
     }
 }
 
@@ -52,7 +88,21 @@ pub enum OperationError {
     CellComputeInternal,
     FragmentIsAlreadyDefined(String),
     FragmentIsNotDefined(String),
+    MacroIsAlreadyDefined(String),
+    MacroIsNotDefined(String),
+    ProcIsNotDefined(String),
+    DataIsAlreadyDefined,
+    ConstantIsAlreadyDefined(String),
+    ExceptionCodeIsAlreadyUsed(u16, String),
+    GlobalIndexIsAlreadyUsed(u8, String),
+    UnknownStackVariable(String),
+    AmbiguousStackVariable(String),
     CodeDictConstruction(String),
+    UnresolvedLabel(String),
+    BackwardLabel(String),
+    IncludeNotFound(String),
+    IncludeCycle(Vec<String>),
+    IncludeTooDeep(String),
     Internal(String),
 }
 
@@ -62,48 +112,103 @@ impl From<Error> for OperationError {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Note,
+    Warning,
+    /// the source contradicts itself, e.g. a `; stack:` comment disagrees with the code
+    Error,
+}
+
+/// Problem found during compilation; only errors fail it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// short kebab-case kind of the problem, e.g. deprecated-mnemonic
+    pub code: &'static str,
+    pub position: Position,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<S: ToString>(severity: Severity, code: &'static str, position: Position, message: S) -> Self {
+        Self { severity, code, position, message: message.to_string() }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum CompileError {
     Syntax(Position, Explanation),
-    UnknownOperation(Position, OperationName),
+    /// unknown mnemonic along with the known ones similar to it
+    UnknownOperation(Position, OperationName, Vec<OperationName>),
     Operation(Position, OperationName, OperationError),
+    UnresolvedLabel(Position, String),
+    UndefinedConstant(Position, String),
+    UndefinedFragment(Position, String),
+    /// instruction introduced by a later TVM version than the target one
+    UnsupportedForTarget(Position, OperationName, TvmVersion),
+    /// compiled code exceeds a limit, with the positions of the cells on the path to the excess
+    LimitExceeded(Position, String, Vec<DbgPos>),
+    /// `; stack:` comment disagreeing with the code it annotates
+    StackMismatch(Position, String),
 }
 
 impl CompileError {
     pub fn syntax<S: ToString>(line: usize, column: usize, explanation: S) -> Self {
-        CompileError::Syntax(Position { filename: String::new(), line, column }, explanation.to_string())
+        CompileError::Syntax(Position::new(String::new(), line, column), explanation.to_string())
     }
     pub fn unknown<S: ToString>(line: usize, column: usize, name: S) -> Self {
-        CompileError::UnknownOperation(Position { filename: String::new(), line, column }, name.to_string())
+        CompileError::UnknownOperation(Position::new(String::new(), line, column), name.to_string(), Vec::new())
     }
     pub fn operation<S: ToString>(line: usize, column: usize, name: S, error: OperationError) -> Self {
-        CompileError::Operation(Position { filename: String::new(), line, column }, name.to_string(), error)
+        CompileError::Operation(Position::new(String::new(), line, column), name.to_string(), error)
     }
     pub fn missing_params<S: ToString>(line: usize, column: usize, name: S) -> Self {
-        CompileError::Operation(Position { filename: String::new(), line, column }, name.to_string(), OperationError::MissingRequiredParameters)
+        CompileError::Operation(Position::new(String::new(), line, column), name.to_string(), OperationError::MissingRequiredParameters)
     }
     pub fn missing_block<S: ToString>(line: usize, column: usize, name: S) -> Self {
-        CompileError::Operation(Position { filename: String::new(), line, column }, name.to_string(), OperationError::MissingBlock)
+        CompileError::Operation(Position::new(String::new(), line, column), name.to_string(), OperationError::MissingBlock)
     }
     pub fn too_many_params<S: ToString>(line: usize, column: usize, name: S) -> Self {
-        CompileError::Operation(Position { filename: String::new(), line, column }, name.to_string(), OperationError::TooManyParameters)
+        CompileError::Operation(Position::new(String::new(), line, column), name.to_string(), OperationError::TooManyParameters)
     }
     pub fn out_of_range<S1: ToString, S2: ToString>(line: usize, column: usize, name: S1, param: S2) -> Self {
         let operation = OperationError::Parameter(param.to_string(), ParameterError::OutOfRange);
-        CompileError::Operation(Position { filename: String::new(), line, column }, name.to_string(), operation)
+        CompileError::Operation(Position::new(String::new(), line, column), name.to_string(), operation)
     }
     pub fn with_filename(mut self, filename: String) -> Self {
+        self.position_mut().filename = filename;
+        self
+    }
+    /// Makes the position of the error span the offending token starting at it
+    pub fn with_token(self, token: &str) -> Self {
+        let position = self.position();
+        let span = Span::new(position.line, position.column, token);
+        self.with_span(span)
+    }
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.position_mut().span = Some(span);
+        self
+    }
+    pub fn position(&self) -> &Position {
         match self {
-            Self::Syntax(ref mut pos, _) => {
-                pos.filename = filename;
-            },
-            Self::UnknownOperation(ref mut pos, _) => {
-                pos.filename = filename;
-            },
-            Self::Operation(ref mut pos, _, _) => {
-                pos.filename = filename;
-            }
-        };
+            Self::Syntax(pos, _) | Self::UnknownOperation(pos, _, _) | Self::Operation(pos, _, _)
+            | Self::UnresolvedLabel(pos, _) | Self::UndefinedConstant(pos, _) | Self::UndefinedFragment(pos, _)
+            | Self::UnsupportedForTarget(pos, _, _) | Self::LimitExceeded(pos, _, _) | Self::StackMismatch(pos, _) => pos
+        }
+    }
+    fn position_mut(&mut self) -> &mut Position {
+        match self {
+            Self::Syntax(pos, _) | Self::UnknownOperation(pos, _, _) | Self::Operation(pos, _, _)
+            | Self::UnresolvedLabel(pos, _) | Self::UndefinedConstant(pos, _) | Self::UndefinedFragment(pos, _)
+            | Self::UnsupportedForTarget(pos, _, _) | Self::LimitExceeded(pos, _, _) | Self::StackMismatch(pos, _) => pos
+        }
+    }
+    /// Adds the known mnemonics similar to the unknown one
+    pub fn with_suggestions(mut self, candidates: Vec<String>) -> Self {
+        if let Self::UnknownOperation(_, _, ref mut suggestions) = self {
+            *suggestions = candidates;
+        }
         self
     }
     pub fn unexpected_type<S1: ToString, S2: ToString>(line: usize, column: usize, name: S1, param: S2) -> Self {
@@ -199,7 +304,21 @@ impl fmt::Display for OperationError {
             CellComputeInternal => write!(f, "Failed to compute the cell"),
             FragmentIsAlreadyDefined(name) => write!(f, "Fragment {} is already defined", name),
             FragmentIsNotDefined(name) => write!(f, "Fragment {} is not defined", name),
+            MacroIsAlreadyDefined(name) => write!(f, "Macro {} is already defined", name),
+            MacroIsNotDefined(name) => write!(f, "Macro {} is not defined", name),
+            ProcIsNotDefined(name) => write!(f, "Procedure {} is not defined", name),
+            DataIsAlreadyDefined => write!(f, "Data is already defined"),
+            ConstantIsAlreadyDefined(name) => write!(f, "Constant {} is already defined", name),
+            ExceptionCodeIsAlreadyUsed(code, name) => write!(f, "Exception code {} is already used by {}", code, name),
+            GlobalIndexIsAlreadyUsed(index, name) => write!(f, "Global index {} is already used by {}", index, name),
+            UnknownStackVariable(name) => write!(f, "Position of stack variable {} is not known here", name),
+            AmbiguousStackVariable(name) => write!(f, "Position of stack variable {} differs across branches", name),
             CodeDictConstruction(message) => write!(f, "Failed to construct code dictionary {}", message),
+            UnresolvedLabel(name) => write!(f, "Label {} is not defined", name),
+            BackwardLabel(name) => write!(f, "Label {} is referenced from its own continuation, cells can't form a loop", name),
+            IncludeNotFound(path) => write!(f, "Included file {} is not found", path),
+            IncludeCycle(chain) => write!(f, "Include cycle: {}", chain.join(" -> ")),
+            IncludeTooDeep(path) => write!(f, "Includes are nested too deeply at {}", path),
             Internal(message) => write!(f, "{}", message),
         }
     }
@@ -211,10 +330,85 @@ impl fmt::Display for CompileError {
             CompileError::Syntax(position, explanation) => {
                 write!(f, "{} Syntax error: {}", position, explanation)
             }
-            CompileError::UnknownOperation(position, name) => write!(f, "{} Unknown operation {}", position, name),
+            CompileError::UnknownOperation(position, name, suggestions) => {
+                write!(f, "{} Unknown operation {}", position, name)?;
+                if !suggestions.is_empty() {
+                    write!(f, ", did you mean {}?", suggestions.join(" or "))?;
+                }
+                Ok(())
+            }
             CompileError::Operation(position, name, error) => {
                 write!(f, "Instruction {} at {}: {}", name, position, error)
             }
+            CompileError::UnresolvedLabel(position, name) => write!(f, "{} Unresolved label {}", position, name),
+            CompileError::UndefinedConstant(position, name) => write!(f, "{} Undefined constant {}", position, name),
+            CompileError::UndefinedFragment(position, name) => write!(f, "{} Fragment {} is not defined by any unit", position, name),
+            CompileError::UnsupportedForTarget(position, name, version) =>
+                write!(f, "{} Instruction {} requires TVM {}", position, name, version),
+            CompileError::LimitExceeded(position, explanation, path) => {
+                write!(f, "{} Limit exceeded: {}", position, explanation)?;
+                for pos in path {
+                    write!(f, "\n  via {}", pos)?;
+                }
+                Ok(())
+            }
+            CompileError::StackMismatch(position, message) => write!(f, "{} Stack mismatch: {}", position, message),
         }
     }
 }
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl CompileError {
+    /// Renders the error like rustc does: the message, the position, and the
+    /// source line if given with the offending text underlined, in ANSI colors if asked
+    pub fn render(&self, source: Option<&str>, color: bool) -> String {
+        let paint = |style: &'static str| if color { style } else { "" };
+        let reset = paint(RESET);
+        let error = self.innermost();
+        let pos = match error.position().filename.is_empty() {
+            true => self.position(),
+            false => error.position(),
+        };
+        let mut text = format!("{}error[{}]{}{}: {}{}\n", paint(RED), error.code(), reset, paint(BOLD), error.message(), reset);
+        text += &format!("{}  --> {}{}\n", paint(BLUE), reset, pos);
+        let line = match source.and_then(|source| source.lines().nth(pos.line.wrapping_sub(1))) {
+            Some(line) => line,
+            None => return text
+        };
+        let (column, width) = match &pos.span {
+            Some(span) if span.line == span.end_line => (span.column, span.end_column.saturating_sub(span.column)),
+            Some(span) => (span.column, line.chars().count().saturating_sub(span.column - 1)),
+            None => (pos.column, 1),
+        };
+        // tabs are kept so the carets line up with the text above
+        let padding = line.chars().take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let number = pos.line.to_string();
+        let gutter = " ".repeat(number.len());
+        text += &format!("{} {} |{}\n", paint(BLUE), gutter, reset);
+        text += &format!("{} {} |{} {}\n", paint(BLUE), number, reset, line);
+        text += &format!("{} {} |{} {}{}{}{}\n", paint(BLUE), gutter, reset, padding, paint(RED), "^".repeat(width.max(1)), reset);
+        text
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Note => write!(f, "note"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.position, self.message)
+    }
+}
@@ -0,0 +1,244 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+// Compile-time evaluation ***************************************************
+//
+// A fragment may be executed on TVM while compiling, with the values given
+// pushed on the stack beforehand, and the value it leaves on the top of the
+// stack embedded in the code as a constant:
+//
+//     .fragment table, {
+//         ...
+//     }
+//     .inline-computed table, 0x0, 10, x{ABCD}
+//
+// An integer is pushed by PUSHINT, a slice by PUSHSLICE, or PUSHREFSLICE if
+// it has references or is too long, a cell by PUSHREF. `.inline-computed-cell`
+// instead splices the cell computed into the code. Both are available with
+// the `eval` feature, precomputed tables and hashes being their typical use.
+
+use ever_block::{BuilderData, Cell, SliceData};
+use ever_vm::stack::{integer::IntegerData, Stack, StackItem};
+use num::BigInt;
+
+use crate::{
+    CompileResult, DbgInfo, Engine, EnsureParametersCountInRange, OperationError, Writer,
+    complex::{compile_pushint, compile_pushslice, make_dbgnode, write_ref},
+    debug::{CellRole, DbgNode, DbgPos},
+    errors::{ParameterError, ToOperationParameterError},
+    parse::parse_int_literal,
+};
+
+/// Value passed to or produced by a fragment evaluated at compile time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EvalValue {
+    Int(BigInt),
+    Cell(Cell),
+    Slice(SliceData),
+}
+
+impl EvalValue {
+    fn to_stack_item(&self) -> Result<StackItem, OperationError> {
+        match self {
+            EvalValue::Int(int) => IntegerData::from_str_radix(&int.to_str_radix(16), 16)
+                .map(StackItem::integer)
+                .map_err(|_| OperationError::CellComputeInternal),
+            EvalValue::Cell(cell) => Ok(StackItem::Cell(cell.clone())),
+            EvalValue::Slice(slice) => Ok(StackItem::Slice(slice.clone())),
+        }
+    }
+
+    fn from_stack_item(item: &StackItem) -> Result<Self, OperationError> {
+        match item {
+            StackItem::Integer(int) => int.to_string().parse::<BigInt>()
+                .map(EvalValue::Int)
+                // NaN can't be embedded
+                .map_err(|_| OperationError::CellComputeError),
+            StackItem::Cell(cell) => Ok(EvalValue::Cell(cell.clone())),
+            StackItem::Slice(slice) => Ok(EvalValue::Slice(slice.clone())),
+            _ => Err(OperationError::CellComputeNotACell),
+        }
+    }
+}
+
+/// Parses capabilities given in decimal or in hex with 0x prefix
+fn parse_capabilities(par: &str) -> Result<u64, OperationError> {
+    let capabilities = match par.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("0x") => u64::from_str_radix(&par[2..], 16),
+        _ => par.parse::<u64>(),
+    };
+    capabilities.map_err(|_| ParameterError::NotSupported.parameter("capabilities"))
+}
+
+/// Parses an input of `.inline-computed`: an integer or a slice literal
+fn parse_input(par: &str) -> Result<EvalValue, OperationError> {
+    match par.get(..1) {
+        Some("x") | Some("X") => SliceData::from_string(&par[1..])
+            .map(EvalValue::Slice)
+            .map_err(|_| ParameterError::UnexpectedType.parameter("input")),
+        _ => parse_int_literal(par).map(EvalValue::Int).parameter("input"),
+    }
+}
+
+impl Engine {
+    /// Runs the fragment on the stack of the values given, the last one on the top
+    fn run_fragment(&self, name: &str, inputs: &[EvalValue], capabilities: u64) -> Result<StackItem, OperationError> {
+        let (code, _) = self.named_units.get(name)
+            .ok_or_else(|| OperationError::FragmentIsNotDefined(name.to_string()))?
+            .clone()
+            .finalize();
+        let mut stack = Stack::new();
+        for input in inputs {
+            stack.push(input.to_stack_item()?);
+        }
+        let mut vm = ever_vm::executor::Engine::with_capabilities(capabilities)
+            .setup_with_libraries(code, None, Some(stack), None, vec![]);
+        match vm.execute() {
+            Ok(0) => (),
+            _ => return Err(OperationError::CellComputeError)
+        }
+        // nothing is left to embed
+        if vm.stack().depth() == 0 {
+            return Err(OperationError::CellComputeError)
+        }
+        Ok(vm.stack().get(0).clone())
+    }
+
+    /// Executes the fragment on TVM with the inputs pushed on the stack in order
+    /// and returns the value left on the top of the stack
+    pub fn evaluate_fragment(&self, name: &str, inputs: &[EvalValue], capabilities: u64) -> Result<EvalValue, OperationError> {
+        EvalValue::from_stack_item(&self.run_fragment(name, inputs, capabilities)?)
+    }
+}
+
+pub(crate) fn compile_inline_computed_cell(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(2)?;
+    let capabilities = parse_capabilities(par[1])?;
+    let item = engine.run_fragment(par[0], &[], capabilities)?;
+    let cell = item.as_cell()
+        .map_err(|_| OperationError::CellComputeNotACell)?;
+
+    // pull refs and data from the cell separately
+    let mut refs = Vec::new();
+    for r in 0..cell.references_count() {
+        let c = cell.reference(r)
+            .map_err(|_| OperationError::CellComputeInternal)?;
+        let b = BuilderData::from_cell(&c)
+            .map_err(|_| OperationError::CellComputeInternal)?;
+        refs.push(b);
+    }
+    let slice = SliceData::load_cell_ref(cell)
+        .map_err(|_| OperationError::CellComputeInternal)?;
+    let dbg_node = make_dbgnode(cell.clone(), DbgInfo::default(), CellRole::Data);
+
+    // write the cell's data and refs
+    destination.write_command_bitstring(slice.storage(), slice.remaining_bits(), DbgNode::default())?;
+    destination.write_composite_command(&[], refs, dbg_node)
+}
+
+pub(crate) fn compile_inline_computed(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    par.assert_len_in(2..=usize::MAX)?;
+    let capabilities = parse_capabilities(par[1])?;
+    let inputs = par[2..].iter()
+        .map(|par| parse_input(par))
+        .collect::<Result<Vec<_>, _>>()?;
+    match engine.evaluate_fragment(par[0], &inputs, capabilities)? {
+        EvalValue::Int(int) => compile_pushint(engine, &[&int.to_string()], destination, pos),
+        EvalValue::Slice(slice) if slice.remaining_references() == 0 => {
+            let literal = format!("x{}", slice.to_hex_string());
+            match compile_pushslice(engine, &[&literal], destination, pos.clone()) {
+                Ok(()) => Ok(()),
+                // too long to be pushed inline
+                Err(_) => {
+                    let cell = BuilderData::from_slice(&slice);
+                    write_ref(&[0x89], CellRole::Data, cell, DbgNode::default(), destination, pos)
+                }
+            }
+        }
+        EvalValue::Slice(slice) => {
+            let builder = BuilderData::from_slice(&slice);
+            let cell = builder.clone().into_cell()
+                .map_err(|_| OperationError::CellComputeInternal)?;
+            let dbg = make_dbgnode(cell, DbgInfo::default(), CellRole::Data);
+            write_ref(&[0x89], CellRole::Data, builder, dbg, destination, pos)
+        }
+        EvalValue::Cell(cell) => {
+            let builder = BuilderData::from_cell(&cell)
+                .map_err(|_| OperationError::CellComputeInternal)?;
+            let dbg = make_dbgnode(cell, DbgInfo::default(), CellRole::Data);
+            write_ref(&[0x88], CellRole::Data, builder, dbg, destination, pos)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_code_to_cell;
+    use crate::test_helpers::compile;
+
+    fn engine_with(fragment: &str) -> Engine {
+        let mut engine = Engine::new("");
+        engine.compile_toplevel(&format!(".fragment f, {{\n{}\n}}", fragment)).unwrap();
+        engine
+    }
+
+    /// Code of `.inline-computed` of the fragment
+    fn inline_computed(fragment: &str) -> Cell {
+        compile(&mut Engine::new(""), &format!(".fragment f, {{\n{}\n}}\n.inline-computed f, 0", fragment)).unwrap()
+    }
+
+    #[test]
+    fn evaluate_int() {
+        let engine = engine_with("PUSHINT 2\nMUL");
+        let value = engine.evaluate_fragment("f", &[EvalValue::Int(BigInt::from(21))], 0).unwrap();
+        assert_eq!(value, EvalValue::Int(BigInt::from(42)));
+        let plain = compile_code_to_cell("PUSHINT 42").unwrap();
+        assert_eq!(inline_computed("PUSHINT 6\nPUSHINT 7\nMUL").repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn evaluate_slice() {
+        let engine = engine_with("PUSHSLICE xABCD");
+        assert!(matches!(engine.evaluate_fragment("f", &[], 0), Ok(EvalValue::Slice(slice)) if slice.remaining_bits() == 16));
+        let plain = compile_code_to_cell("PUSHSLICE xABCD").unwrap();
+        assert_eq!(inline_computed("PUSHSLICE xABCD").repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn evaluate_long_slice() {
+        // too long for PUSHSLICE, pushed by PUSHREFSLICE
+        let code = inline_computed(&format!("PUSHREF {{\n.blob x{}\n}}\nCTOS", "AB".repeat(120)));
+        assert_eq!(code.data()[0], 0x89);
+        assert_eq!(code.references_count(), 1);
+        assert_eq!(code.reference(0).unwrap().bit_length(), 960);
+    }
+
+    #[test]
+    fn evaluate_cell() {
+        let code = inline_computed("PUSHREF {\n.blob xABCD\n}");
+        let plain = compile_code_to_cell("PUSHREF {\n.blob xABCD\n}").unwrap();
+        assert_eq!(code.repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn evaluate_errors() {
+        let engine = engine_with("THROW 100");
+        assert_eq!(engine.evaluate_fragment("f", &[], 0), Err(OperationError::CellComputeError));
+        // nothing left on the stack
+        let engine = engine_with("");
+        assert_eq!(engine.evaluate_fragment("f", &[], 0), Err(OperationError::CellComputeError));
+        assert_eq!(engine.evaluate_fragment("g", &[], 0), Err(OperationError::FragmentIsNotDefined("g".to_string())));
+    }
+}
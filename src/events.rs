@@ -0,0 +1,98 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Structured progress events of the compilation
+//!
+//! Every event is logged with the `compile::events` target at debug level
+//! and passed to the listener installed on the engine with
+//! [`Engine::set_event_listener`](crate::Engine::set_event_listener), if any.
+//! Events are not built at all unless one of these consumers is enabled.
+
+use std::sync::Arc;
+use serde::Serialize;
+
+pub const LOG_TARGET: &str = "compile::events";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum CompileEvent {
+    /// compilation of a source started
+    UnitStarted { name: String },
+    /// `.fragment` has been compiled
+    FragmentCompiled { name: String, bits: usize, refs: usize },
+    /// code did not fit into the current cell and continues in a new one;
+    /// bits and refs are those of the filled cell
+    CellSplit { bits: usize, refs: usize },
+    /// the code cell of a source has been built
+    Finalized { name: String, bits: usize, refs: usize },
+}
+
+impl std::fmt::Display for CompileEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileEvent::UnitStarted { name } =>
+                write!(f, "unit {} started", name),
+            CompileEvent::FragmentCompiled { name, bits, refs } =>
+                write!(f, "fragment {} compiled: {} bits, {} refs", name, bits, refs),
+            CompileEvent::CellSplit { bits, refs } =>
+                write!(f, "cell split after {} bits, {} refs", bits, refs),
+            CompileEvent::Finalized { name, bits, refs } =>
+                write!(f, "unit {} finalized: {} bits, {} refs in the root cell", name, bits, refs),
+        }
+    }
+}
+
+/// Consumer of the events of an engine
+pub type Listener = Arc<dyn Fn(&CompileEvent) + Send + Sync>;
+
+/// Emits the event built by the closure if anybody listens to it
+pub(crate) fn emit(listener: Option<&Listener>, event: impl FnOnce() -> CompileEvent) {
+    let logged = log::log_enabled!(target: LOG_TARGET, log::Level::Debug);
+    if listener.is_none() && !logged {
+        return
+    }
+    let event = event();
+    if logged {
+        log::debug!(target: LOG_TARGET, "{}", event);
+    }
+    if let Some(listener) = listener {
+        listener(&event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use super::*;
+    use crate::Engine;
+
+    #[test]
+    fn listener_per_engine() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let mut engine = Engine::new("main.code");
+        engine.set_event_listener(move |event| sink.lock().unwrap().push(event.clone()));
+        let units = engine.compile_toplevel(".fragment f, {\n  INC\n}\n.inline f").unwrap();
+        engine.finalize(units);
+
+        let mut other = Engine::new("other.code");
+        let units = other.compile_toplevel("NOP").unwrap();
+        other.finalize(units);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.first(), Some(&CompileEvent::UnitStarted { name: "main.code".to_string() }));
+        assert!(events.iter().any(|event| matches!(event, CompileEvent::FragmentCompiled { name, .. } if name == "f")));
+        assert!(matches!(events.last(), Some(CompileEvent::Finalized { name, .. }) if name == "main.code"));
+        assert!(!events.iter().any(|event| event.to_string().contains("other.code")));
+    }
+}
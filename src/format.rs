@@ -0,0 +1,203 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Formatter of assembly sources
+//!
+//! Statements are kept on their lines, only the whitespace changes: lines are
+//! indented by two spaces per enclosing block, tokens are separated by single
+//! spaces with commas attached to the preceding operand, comments trailing
+//! consecutive lines are aligned to the same column and runs of blank lines
+//! are collapsed. Lines continuing a multiline string are kept as is.
+
+use ever_block::{error, Result};
+
+use crate::compile_code_to_cell;
+
+const INDENT: &str = "  ";
+
+/// Tokens of a line with its trailing comment
+#[derive(Default)]
+pub(crate) struct Line {
+    pub tokens: Vec<String>,
+    pub comment: Option<String>,
+    /// the line ends inside a string literal
+    pub in_string: bool,
+}
+
+fn flush(token: &mut String, tokens: &mut Vec<String>) {
+    if !token.is_empty() {
+        tokens.push(std::mem::take(token));
+    }
+}
+
+/// Splits the line into tokens and the comment given whether it starts inside a string
+pub(crate) fn split_line(text: &str, mut in_string: bool) -> Line {
+    let mut line = Line::default();
+    let mut token = String::new();
+    for (i, ch) in text.char_indices() {
+        if in_string {
+            token.push(ch);
+            in_string = ch != '"';
+            continue
+        }
+        match ch {
+            ';' => {
+                line.comment = Some(text[i..].trim_end().to_string());
+                break
+            }
+            ',' | '{' | '}' => {
+                flush(&mut token, &mut line.tokens);
+                line.tokens.push(ch.to_string());
+            }
+            _ if ch.is_whitespace() => flush(&mut token, &mut line.tokens),
+            _ => {
+                token.push(ch);
+                in_string = ch == '"';
+            }
+        }
+    }
+    flush(&mut token, &mut line.tokens);
+    line.in_string = in_string;
+    line
+}
+
+fn join_tokens(tokens: &[String]) -> String {
+    let mut text = String::new();
+    for token in tokens {
+        if !text.is_empty() && token != "," {
+            text.push(' ');
+        }
+        text.push_str(token);
+    }
+    text
+}
+
+/// Canonicalizes indentation, operand spacing and comment alignment of the source
+pub fn format_source(source: &str) -> String {
+    // formatted code and trailing comment of every line, None for a blank one
+    let mut lines = Vec::<Option<(String, Option<String>)>>::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    for text in source.lines() {
+        if in_string {
+            // the line continues a string literal, braces after it still count
+            let line = split_line(text, true);
+            depth = line.tokens.iter().fold(depth, |depth, token| match token.as_str() {
+                "{" => depth + 1,
+                "}" => depth.saturating_sub(1),
+                _ => depth
+            });
+            in_string = line.in_string;
+            lines.push(Some((text.to_string(), None)));
+            continue
+        }
+        let line = split_line(text, false);
+        in_string = line.in_string;
+        if line.tokens.is_empty() && line.comment.is_none() {
+            lines.push(None);
+            continue
+        }
+        let closing = line.tokens.iter().take_while(|token| *token == "}").count();
+        let indent = INDENT.repeat(depth.saturating_sub(closing));
+        for token in &line.tokens {
+            match token.as_str() {
+                "{" => depth += 1,
+                "}" => depth = depth.saturating_sub(1),
+                _ => ()
+            }
+        }
+        match line.tokens.is_empty() {
+            true => lines.push(Some((indent + line.comment.as_deref().unwrap_or_default(), None))),
+            false => lines.push(Some((indent + &join_tokens(&line.tokens), line.comment))),
+        }
+    }
+    // collapse blank lines
+    let mut kept = Vec::with_capacity(lines.len());
+    for line in lines {
+        if line.is_some() || kept.last().map_or(false, Option::is_some) {
+            kept.push(line);
+        }
+    }
+    while let Some(None) = kept.last() {
+        kept.pop();
+    }
+    let mut text = String::new();
+    let mut start = 0;
+    while start < kept.len() {
+        // consecutive lines with trailing comments are aligned together
+        let commented = |line: &Option<(String, Option<String>)>| matches!(line, Some((_, Some(_))));
+        let end = match commented(&kept[start]) {
+            true => start + kept[start..].iter().take_while(|line| commented(line)).count(),
+            false => start + 1,
+        };
+        let width = kept[start..end].iter().flatten()
+            .map(|(code, _)| code.chars().count())
+            .max()
+            .unwrap_or_default();
+        for line in &kept[start..end] {
+            match line {
+                Some((code, Some(comment))) => {
+                    text += &format!("{:<width$} {}", code, comment, width = width);
+                }
+                Some((code, None)) => text += code,
+                None => (),
+            }
+            text.push('\n');
+        }
+        start = end;
+    }
+    text
+}
+
+/// Formats the source and checks that it compiles to the same code as before
+pub fn format_verified(source: &str) -> Result<String> {
+    let formatted = format_source(source);
+    let before = compile_code_to_cell(source).map_err(|e| error!("{}", e))?;
+    let after = compile_code_to_cell(&formatted).map_err(|e| error!("formatted source: {}", e))?;
+    if before.repr_hash() != after.repr_hash() {
+        return Err(error!("formatting changes the code"))
+    }
+    Ok(formatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_layout() {
+        let source = "PUSHCONT   {\nPUSHINT 1 ; one\n      ADD   ; add it\n}\n\n\nXCHG s1 ,s2\nIF ; run\n\n";
+        let formatted = format_verified(source).unwrap();
+        assert_eq!(formatted, "PUSHCONT {\n  PUSHINT 1 ; one\n  ADD       ; add it\n}\n\nXCHG s1, s2\nIF ; run\n");
+        assert_eq!(format_source(&formatted), formatted);
+    }
+
+    #[test]
+    fn format_corpus() {
+        for entry in std::fs::read_dir("corpus/roundtrip").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "code") {
+                continue
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let formatted = format_verified(&source).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            assert_eq!(format_source(&formatted), formatted, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn format_errors() {
+        assert_eq!(format_source("; only a comment\n  ; indented\n"), "; only a comment\n; indented\n");
+        assert!(format_verified("PUSHINT 1\nUNKNOWN").is_err());
+    }
+}
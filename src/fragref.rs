@@ -0,0 +1,224 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+// Late-bound fragment references ********************************************
+//
+// `CALLREF .fragref name` and the other commands taking a reference may refer
+// to a fragment defined anywhere in the source, before or after the command.
+// A placeholder cell holding the name is put in place of the code, and once
+// the toplevel code or a unit of `build` is complete every placeholder is
+// replaced with the code of the fragment, rebuilding the cells above it. Placeholders are known by
+// the hashes of the cells the engine has made, so data looking the same is
+// left as is. Fragments may refer to each other this way as long as they
+// don't form a cycle.
+
+use std::collections::HashSet;
+use ever_block::{BuilderData, Cell, CellType, SliceData, UInt256};
+
+use crate::{CompileError, DbgNode, Engine, OperationError, Position, Units};
+
+/// Start of the data of a placeholder cell, followed by the name of the fragment
+const PLACEHOLDER_TAG: [u8; 8] = [0xF7, 0xA9, 0x4E, 0x2B, 0x6C, 0xD1, 0x38, 0x95];
+
+fn placeholder_name(cell: &Cell) -> Option<String> {
+    if cell.references_count() != 0 || cell.bit_length() % 8 != 0 || cell.bit_length() <= PLACEHOLDER_TAG.len() * 8 {
+        return None
+    }
+    let data = SliceData::load_cell_ref(cell).ok()?.get_bytestring(0);
+    let name = data.strip_prefix(&PLACEHOLDER_TAG[..])?;
+    String::from_utf8(name.to_vec()).ok()
+}
+
+fn cell_error<E>(_: E) -> CompileError {
+    CompileError::unknown(0, 0, "failure while convert BuilderData to cell")
+}
+
+impl Engine {
+    /// Cell standing for the code of the named fragment until it is resolved;
+    /// only the cells made here are resolved, not the data looking the same
+    pub(crate) fn fragref_placeholder(&mut self, name: &str) -> Result<BuilderData, OperationError> {
+        let mut data = PLACEHOLDER_TAG.to_vec();
+        data.extend_from_slice(name.as_bytes());
+        let builder = BuilderData::with_raw(&data[..], data.len() * 8).map_err(|_| OperationError::NotFitInSlice)?;
+        let cell = builder.clone().into_cell().map_err(|e| OperationError::Internal(e.to_string()))?;
+        self.fragrefs.insert(cell.repr_hash());
+        Ok(builder)
+    }
+
+    /// Name of the fragment the placeholder cell stands for
+    fn fragref_name(&self, cell: &Cell) -> Option<String> {
+        match self.fragrefs.contains(&cell.repr_hash()) {
+            true => placeholder_name(cell),
+            false => None,
+        }
+    }
+
+    /// Code of the cell with its placeholders replaced by the fragments, None if it has none
+    fn resolve_cell(&self, cell: &Cell, dbg: &DbgNode, resolving: &mut Vec<String>) -> Result<Option<(Cell, DbgNode)>, CompileError> {
+        if let Some(name) = self.fragref_name(cell) {
+            let position = match dbg.offsets.first() {
+                Some((_, pos)) => Position::from_dbgpos(pos),
+                None => Position::new(self.source_name.to_string(), 0, 0),
+            };
+            if resolving.contains(&name) {
+                let chain = resolving.iter().chain(std::iter::once(&name)).cloned().collect::<Vec<_>>();
+                let error = OperationError::Internal(format!("fragments refer to each other in a cycle: {}", chain.join(" -> ")));
+                return Err(CompileError::operation(position.line, position.column, ".FRAGREF", error)
+                    .with_filename(position.filename))
+            }
+            let (builder, code_dbg) = match self.named_units.get(&name) {
+                Some(unit) => unit.clone().with_fragment(&name).into_parts(),
+                None => return Err(CompileError::UndefinedFragment(position, name))
+            };
+            let code = builder.into_cell().map_err(cell_error)?;
+            let code_dbg = code_dbg.with_role(dbg.role);
+            resolving.push(name);
+            let resolved = self.resolve_cell(&code, &code_dbg, resolving)?;
+            resolving.pop();
+            return Ok(Some(resolved.unwrap_or((code, code_dbg))))
+        }
+        if cell.cell_type() != CellType::Ordinary || cell.references_count() == 0 {
+            return Ok(None)
+        }
+        let mut children = Vec::new();
+        let mut changed = false;
+        for i in 0..cell.references_count() {
+            let child = cell.reference(i).map_err(cell_error)?;
+            let child_dbg = dbg.children.get(i).cloned().unwrap_or_default();
+            match self.resolve_cell(&child, &child_dbg, resolving)? {
+                Some(resolved) => {
+                    changed = true;
+                    children.push(resolved);
+                }
+                None => children.push((child, child_dbg)),
+            }
+        }
+        if !changed {
+            return Ok(None)
+        }
+        let data = SliceData::load_cell_ref(cell).map_err(cell_error)?.get_bytestring(0);
+        let mut builder = BuilderData::with_raw(&data[..], cell.bit_length()).map_err(cell_error)?;
+        let mut resolved_dbg = DbgNode { children: Vec::new(), ..dbg.clone() };
+        for (child, child_dbg) in children {
+            builder.checked_append_reference(child).map_err(cell_error)?;
+            resolved_dbg.children.push(child_dbg);
+        }
+        Ok(Some((builder.into_cell().map_err(cell_error)?, resolved_dbg)))
+    }
+
+    /// Code of the builder with its placeholders replaced, None if it has none
+    fn resolve_code(&self, builder: &BuilderData, dbg: &DbgNode) -> Result<Option<(BuilderData, DbgNode)>, CompileError> {
+        let cell = builder.clone().into_cell().map_err(cell_error)?;
+        match self.resolve_cell(&cell, dbg, &mut Vec::new())? {
+            Some((cell, dbg)) => Ok(Some((BuilderData::from_cell(&cell).map_err(cell_error)?, dbg))),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the `.fragref` placeholders in the code with the fragments they refer to;
+    /// done for the toplevel code and for the units of `build` and `link`
+    pub fn resolve_fragrefs(&self, units: &mut Units) -> Result<(), CompileError> {
+        units.try_map_cells(|builder, dbg| self.resolve_code(builder, dbg))
+    }
+
+    /// Code of the unit written by `build` with its placeholders replaced
+    pub(crate) fn resolve_unit(&self, builder: BuilderData, dbg: DbgNode) -> Result<(BuilderData, DbgNode), CompileError> {
+        if self.fragrefs.is_empty() {
+            return Ok((builder, dbg))
+        }
+        Ok(self.resolve_code(&builder, &dbg)?.unwrap_or((builder, dbg)))
+    }
+
+    /// Whether a placeholder is left anywhere in the code
+    pub(crate) fn has_fragrefs(&self, builder: &BuilderData) -> bool {
+        fn find(engine: &Engine, cell: &Cell, visited: &mut HashSet<UInt256>) -> bool {
+            if !visited.insert(cell.repr_hash()) {
+                return false
+            }
+            engine.fragref_name(cell).is_some() || (0..cell.references_count())
+                .filter_map(|i| cell.reference(i).ok())
+                .any(|child| find(engine, &child, visited))
+        }
+        if self.fragrefs.is_empty() {
+            return false
+        }
+        match builder.clone().into_cell() {
+            Ok(cell) => find(self, &cell, &mut HashSet::new()),
+            Err(_) => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{compile, innermost_error};
+
+    fn hash(source: &str) -> UInt256 {
+        compile(&mut Engine::new(""), source).unwrap().repr_hash()
+    }
+
+    #[test]
+    fn fragref_forward() {
+        assert_eq!(
+            hash("CALLREF .fragref g\n.fragment g, {\n  INC\n}"),
+            hash("CALLREF {\n  INC\n}")
+        );
+        // fragments refer to the ones defined after them
+        assert_eq!(
+            hash(".fragment f, {\n  CALLREF .fragref g\n  INC\n}\n.fragment g, {\n  DEC\n}\nCALLREF .fragref f\nJMPREF .fragref g"),
+            hash("CALLREF {\n  CALLREF {\n    DEC\n  }\n  INC\n}\nJMPREF {\n  DEC\n}")
+        );
+    }
+
+    #[test]
+    fn fragref_errors() {
+        let mut engine = Engine::new("");
+        assert!(matches!(
+            compile(&mut engine, "CALLREF .fragref missing").unwrap_err(),
+            CompileError::UndefinedFragment(_, name) if name == "missing"
+        ));
+        let source = ".fragment f, {\n  CALLREF .fragref g\n}\n.fragment g, {\n  CALLREF .fragref f\n}\nCALLREF .fragref f";
+        match innermost_error(&mut Engine::new(""), source) {
+            CompileError::Operation(_, _, OperationError::Internal(message)) =>
+                assert!(message.ends_with("f -> g -> f"), "{}", message),
+            error => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn fragref_data_kept() {
+        // the data of the placeholder standing for g, which is never referred to
+        let data = format!("PUSHREF {{\n.blob x{}67\n}}", hex::encode_upper(PLACEHOLDER_TAG));
+        let mut engine = Engine::new("");
+        let code = compile(&mut engine, &format!("{}\n.fragment g, {{\n  INC\n}}", data)).unwrap();
+        assert_eq!(code.repr_hash(), hash(&data));
+        assert!(placeholder_name(&code.reference(0).unwrap()).is_some());
+    }
+
+    #[test]
+    fn fragref_build() {
+        let mut engine = Engine::new("");
+        assert!(matches!(
+            engine.build(None, "CALLREF .fragref h").unwrap_err(),
+            CompileError::UndefinedFragment(_, name) if name == "h"
+        ));
+        engine.add_unit(None, "a.code", "CALLREF .fragref h");
+        engine.add_unit(None, "b.code", ".fragment h, {\n  DEC\n}");
+        let units = engine.link().unwrap();
+        let code = units[0].clone().finalize().0.into_cell().unwrap();
+        assert!(!engine.has_fragrefs(&BuilderData::from_cell(&code).unwrap()));
+        assert_eq!(code.repr_hash(), hash("CALLREF {\n  DEC\n}"));
+    }
+}
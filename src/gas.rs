@@ -0,0 +1,204 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+use ever_block::{Cell, Result, SliceData, UInt256};
+
+use crate::{
+    DbgInfo, DbgPos,
+    disasm::{loader::Loader, types::{Code, Instruction, InstructionParameter}},
+};
+
+// TVM gas prices, see TVM Spec 1.4
+pub const GAS_BASE: u64 = 10;
+pub const GAS_PER_REF: u64 = 5;
+pub const GAS_CELL_LOAD: u64 = 100;
+pub const GAS_IMPLICIT_JMP: u64 = 10;
+
+/// Static part of the instruction price: 10 + b + 5r,
+/// where b is the bit length of the instruction and r is the number of its refs
+pub fn instruction_gas(insn: &Instruction) -> u64 {
+    let bits = insn.bytecode().map(|b| b.remaining_bits()).unwrap_or_default() as u64;
+    GAS_BASE + bits + GAS_PER_REF * insn.refs() as u64
+}
+
+/// A continuation whose straight-line static gas exceeds the threshold
+#[derive(Clone, Debug)]
+pub struct SplitHint {
+    /// hash of the first cell of the continuation
+    pub cell: UInt256,
+    /// position of the continuation's first instruction if known
+    pub position: Option<DbgPos>,
+    /// estimated static gas of the straight-line path
+    pub gas: u64,
+    /// positions of instructions where the accumulated gas crosses
+    /// the next multiple of the threshold
+    pub candidates: Vec<DbgPos>,
+}
+
+impl std::fmt::Display for SplitHint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.position {
+            Some(pos) => write!(f, "{}: ", pos)?,
+            None => write!(f, "#{}: ", self.cell.to_hex_string())?,
+        }
+        write!(f, "straight-line code costs at least {} gas", self.gas)?;
+        if !self.candidates.is_empty() {
+            let lines = self.candidates.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            write!(f, ", consider splitting at {}", lines.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn lookup(dbg: Option<&DbgInfo>, insn: &Instruction) -> Option<DbgPos> {
+    let bytecode = insn.bytecode()?;
+    dbg?.get(&bytecode.cell().repr_hash())?.get(&bytecode.pos()).cloned()
+}
+
+struct Walker<'a> {
+    dbg: Option<&'a DbgInfo>,
+    threshold: u64,
+    pending: Vec<(UInt256, &'a Code)>,
+}
+
+impl<'a> Walker<'a> {
+    /// Accumulates gas along the straight-line path starting with code,
+    /// following implicit jumps into the next cells of the chain
+    fn walk(&mut self, mut code: &'a Code, gas: &mut u64, first: &mut Option<DbgPos>, candidates: &mut Vec<DbgPos>) {
+        loop {
+            let mut next = None;
+            for insn in code.iter() {
+                if insn.name() == "IMPLICIT-JMP" {
+                    *gas += GAS_IMPLICIT_JMP + GAS_CELL_LOAD;
+                    if let Some(InstructionParameter::Code { code, .. }) = insn.params().first() {
+                        next = Some(code);
+                    }
+                    continue
+                }
+                if first.is_none() {
+                    *first = lookup(self.dbg, insn);
+                }
+                let before = *gas / self.threshold;
+                *gas += instruction_gas(insn);
+                if *gas / self.threshold > before {
+                    if let Some(pos) = lookup(self.dbg, insn) {
+                        candidates.push(pos)
+                    }
+                }
+                for param in insn.params() {
+                    if let InstructionParameter::Code { code, cell } = param {
+                        let hash = cell.as_ref().map(|c| c.repr_hash()).unwrap_or_default();
+                        self.pending.push((hash, code));
+                    }
+                }
+            }
+            match next {
+                Some(code_next) => code = code_next,
+                None => break
+            }
+        }
+    }
+}
+
+/// Estimates static gas along every straight-line continuation of the code
+/// and reports the ones exceeding the threshold
+pub fn split_hints(code: Cell, dbg: Option<&DbgInfo>, threshold: u64) -> Result<Vec<SplitHint>> {
+    let threshold = threshold.max(1);
+    let root_hash = code.repr_hash();
+    let root = Loader::new(false).load(&mut SliceData::load_cell(code)?, false)?;
+    let mut walker = Walker { dbg, threshold, pending: vec!((root_hash, &root)) };
+    let mut hints = Vec::new();
+    while let Some((cell, code)) = walker.pending.pop() {
+        let mut gas = GAS_CELL_LOAD;
+        let mut position = None;
+        let mut candidates = Vec::new();
+        walker.walk(code, &mut gas, &mut position, &mut candidates);
+        if gas > threshold {
+            hints.push(SplitHint { cell, position, gas, candidates });
+        }
+    }
+    Ok(hints)
+}
+
+/// Static gas of the instructions placed in a cell, cell loads excluded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CellGas {
+    pub cell: UInt256,
+    pub instructions: usize,
+    pub gas: u64,
+}
+
+/// Static gas of a straight-line continuation including cell loads and implicit jumps
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BranchGas {
+    /// hash of the first cell of the continuation
+    pub cell: UInt256,
+    pub gas: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GasReport {
+    /// cells in the order of their first appearance
+    pub cells: Vec<CellGas>,
+    /// the root continuation first, then nested ones
+    pub branches: Vec<BranchGas>,
+    /// static gas of all instructions of the code
+    pub total: u64,
+}
+
+fn collect_cells(code: &Code, cells: &mut Vec<CellGas>) {
+    for insn in code.iter() {
+        if let Some(bytecode) = insn.bytecode() {
+            let hash = bytecode.cell().repr_hash();
+            let gas = instruction_gas(insn);
+            match cells.iter_mut().find(|c| c.cell == hash) {
+                Some(cell) => {
+                    cell.instructions += 1;
+                    cell.gas += gas;
+                }
+                None => cells.push(CellGas { cell: hash, instructions: 1, gas }),
+            }
+        }
+        for param in insn.params() {
+            if let InstructionParameter::Code { code, .. } = param {
+                collect_cells(code, cells);
+            }
+        }
+    }
+}
+
+/// Estimates static gas of the code per cell and per straight-line continuation
+pub fn estimate_gas(slice: &SliceData) -> Result<GasReport> {
+    let root_hash = slice.cell().repr_hash();
+    let root = Loader::new(false).load(&mut slice.clone(), false)?;
+    let mut report = GasReport::default();
+    collect_cells(&root, &mut report.cells);
+    report.total = report.cells.iter().map(|c| c.gas).sum();
+
+    let mut walker = Walker { dbg: None, threshold: u64::MAX, pending: vec!((root_hash, &root)) };
+    let mut branches = Vec::new();
+    while let Some((cell, code)) = walker.pending.pop() {
+        let mut gas = GAS_CELL_LOAD;
+        walker.walk(code, &mut gas, &mut None, &mut Vec::new());
+        branches.push(BranchGas { cell, gas });
+    }
+    report.branches = branches;
+    Ok(report)
+}
+
+impl Code {
+    /// Comments every instruction with its static gas
+    pub fn annotate_gas(&mut self) {
+        self.annotate(&mut |insn| insn.bytecode().map(|_| format!("gas {}", instruction_gas(insn))))
+    }
+}
@@ -0,0 +1,154 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Includes ******************************************************************
+//
+// `.include "path"` compiles another file in place of the directive as if its
+// text was written there, so fragments, macros and constants defined in it are
+// visible to the rest of the unit. The path is looked up relative to the file
+// containing the directive, then in the include paths of the engine. Files are
+// only read with the `fs` feature, otherwise the directive fails.
+
+use std::path::PathBuf;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use crate::{
+    CompileResult, DbgPos, Engine, EnsureParametersCountInRange, OperationError, Writer,
+};
+
+/// Maximum nesting of included files
+#[cfg(feature = "fs")]
+pub(crate) const MAX_INCLUDE_DEPTH: usize = 32;
+
+impl Engine {
+    /// Adds a directory to look up included files in
+    pub fn add_include_path(&mut self, path: impl Into<PathBuf>) {
+        self.include_paths.push(path.into());
+    }
+
+    #[cfg(feature = "fs")]
+    fn find_include(&self, name: &str) -> Option<PathBuf> {
+        let current = Path::new(&*self.source_name).parent().map(Path::to_path_buf).unwrap_or_default();
+        std::iter::once(current)
+            .chain(self.include_paths.iter().cloned())
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+}
+
+#[cfg(not(feature = "fs"))]
+pub(crate) fn compile_include(_engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(1)?;
+    Err(OperationError::Internal("files can't be included without the fs feature".to_string()))
+}
+
+#[cfg(feature = "fs")]
+pub(crate) fn compile_include(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    par.assert_len(1)?;
+    let name = par[0].trim_matches('"');
+    let path = engine.find_include(name)
+        .ok_or_else(|| OperationError::IncludeNotFound(name.to_string()))?;
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+    // the outermost file is not on the stack of includes
+    let root = match engine.includes.is_empty() {
+        true => Path::new(&*engine.source_name).canonicalize().ok().map(|p| (p, engine.source_name.to_string())),
+        false => None
+    };
+    let stack = root.iter().chain(engine.includes.iter()).collect::<Vec<_>>();
+    if let Some(index) = stack.iter().position(|(p, _)| p == &canonical) {
+        let mut chain = stack[index..].iter().map(|(_, name)| name.clone()).collect::<Vec<_>>();
+        chain.push(path.display().to_string());
+        return Err(OperationError::IncludeCycle(chain))
+    }
+    if stack.len() > MAX_INCLUDE_DEPTH {
+        return Err(OperationError::IncludeTooDeep(path.display().to_string()))
+    }
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| OperationError::Internal(format!("failed to read {}: {}", path.display(), e)))?;
+
+    // the included code gets positions in its own file
+    let filename = engine.files.intern(&path.display().to_string());
+    let source_name = std::mem::replace(&mut engine.source_name, filename);
+    let (line_no, char_no) = engine.set_pos(1, 1);
+    let dbgpos = engine.dbgpos.take();
+    let depth = engine.includes.len();
+    engine.includes.extend(root);
+    engine.includes.push((canonical, engine.source_name.to_string()));
+    let result = engine.compile_impl(&text, engine.toplevel);
+    engine.includes.truncate(depth);
+    engine.dbgpos = dbgpos;
+    engine.set_pos(line_no, char_no);
+    engine.source_name = source_name;
+
+    let units = result.map_err(|e| OperationError::Nested(Box::new(e)))?;
+    let (builder, dbg) = units.finalize();
+    destination.write_unit(crate::Unit::new(builder, dbg))
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+    use crate::compile_code_to_cell;
+    use crate::test_helpers::{compile, operation_error};
+
+    /// Directory of the test with the files written there
+    fn sources(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ever-assembler-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, text) in files {
+            std::fs::write(dir.join(name), text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn include_definitions() {
+        let dir = sources("include", &[("lib.code", ".define N 5\n.macro add_n {\n  PUSHINT N\n  ADD\n}\n")]);
+        let plain = compile_code_to_cell("PUSHINT 5\nADD").unwrap();
+
+        let mut engine = Engine::new(&dir.join("main.code").display().to_string());
+        let included = compile(&mut engine, ".include \"lib.code\"\n.expand add_n").unwrap();
+        assert_eq!(included.repr_hash(), plain.repr_hash());
+
+        let mut engine = Engine::new("");
+        engine.add_include_path(&dir);
+        let included = compile(&mut engine, ".include \"lib.code\"\n.expand add_n").unwrap();
+        assert_eq!(included.repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = sources("include-cycle", &[
+            ("a.code", ".include \"b.code\"\n"),
+            ("b.code", ".include \"a.code\"\n"),
+            ("self.code", "NOP\n.include \"self.code\"\n"),
+        ]);
+        let mut engine = Engine::new(&dir.join("main.code").display().to_string());
+        assert!(matches!(
+            operation_error(&mut engine, ".include \"a.code\""),
+            OperationError::IncludeCycle(chain) if chain.len() == 3
+        ));
+        let path = dir.join("self.code");
+        let mut engine = Engine::new(&path.display().to_string());
+        assert!(matches!(
+            operation_error(&mut engine, &std::fs::read_to_string(&path).unwrap()),
+            OperationError::IncludeCycle(chain) if chain.len() == 2
+        ));
+        let mut engine = Engine::new(&dir.join("main.code").display().to_string());
+        assert_eq!(
+            operation_error(&mut engine, ".include \"missing.code\""),
+            OperationError::IncludeNotFound("missing.code".to_string())
+        );
+    }
+}
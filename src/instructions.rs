@@ -0,0 +1,244 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Metadata of the instruction set
+//!
+//! Instructions with a fixed encoding are described completely: their opcode,
+//! operands with ranges and price. Instructions compiled by hand-written
+//! handlers, like `PUSHINT` or `CALLREF`, have several encodings depending on
+//! the operands, so only their names and versions are known.
+
+use std::ops::RangeInclusive;
+
+use crate::{CompileHandler, Engine, gas::GAS_BASE};
+
+/// Row of a table generated by `simple_commands!`
+pub(crate) struct SimpleCommand {
+    pub name: &'static str,
+    pub handler: CompileHandler,
+    /// names of the operands and their parsers
+    pub operands: &'static [(&'static str, &'static str)],
+    /// byte expressions of the encoding
+    pub encoding: &'static [&'static str],
+}
+
+/// Version of TVM in terms of the global capabilities of the network
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TvmVersion {
+    /// original instruction set
+    Base,
+    /// `COPYLEFT`
+    Copyleft,
+    /// `GASREMAINING`, `MYCODE`, `INITCODEHASH`, `STORAGEFEE`
+    V19,
+    /// BLS12-381 operations
+    V20,
+}
+
+impl TvmVersion {
+    pub const LATEST: TvmVersion = TvmVersion::V20;
+}
+
+impl std::fmt::Display for TvmVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            TvmVersion::Base => "base",
+            TvmVersion::Copyleft => "copyleft",
+            TvmVersion::V19 => "v19",
+            TvmVersion::V20 => "v20",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for TvmVersion {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "base" => Ok(TvmVersion::Base),
+            "copyleft" => Ok(TvmVersion::Copyleft),
+            "v19" => Ok(TvmVersion::V19),
+            "v20" => Ok(TvmVersion::V20),
+            _ => Err(format!("unknown TVM version {}, expected base, copyleft, v19 or v20", s)),
+        }
+    }
+}
+
+/// Instructions introduced after the original instruction set,
+/// a name ending with `_` is a prefix
+const INTRODUCED: [(&str, TvmVersion); 6] = [
+    ("BLS_", TvmVersion::V20),
+    ("COPYLEFT", TvmVersion::Copyleft),
+    ("GASREMAINING", TvmVersion::V19),
+    ("INITCODEHASH", TvmVersion::V19),
+    ("MYCODE", TvmVersion::V19),
+    ("STORAGEFEE", TvmVersion::V19),
+];
+
+/// TVM version introducing the instruction
+pub fn introduced_in(name: &str) -> TvmVersion {
+    INTRODUCED.iter()
+        .find(|(prefix, _)| match prefix.ends_with('_') {
+            true => name.starts_with(*prefix),
+            false => name == *prefix,
+        })
+        .map(|(_, version)| *version)
+        .unwrap_or(TvmVersion::Base)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    /// integer constant
+    Integer,
+    /// stack register `s(i)`
+    StackRegister,
+    /// control register `c(i)`
+    ControlRegister,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Operand {
+    /// name of the operand in the encoding
+    pub name: &'static str,
+    pub kind: OperandKind,
+    /// values accepted in the source
+    pub range: RangeInclusive<i64>,
+}
+
+impl Operand {
+    /// Operand parsed by the function of the given name, None if its values are unknown
+    fn from_parser(name: &'static str, parser: &str) -> Option<Self> {
+        let (kind, range) = match parser {
+            "parse_const_u2" => (OperandKind::Integer, 0..=3),
+            "parse_const_i4" => (OperandKind::Integer, -1..=14),
+            "parse_const_u4" => (OperandKind::Integer, 0..=15),
+            "parse_const_u4_plus_one" | "parse_const_u4_nonzero" => (OperandKind::Integer, 1..=16),
+            "parse_const_u4_plus_two" => (OperandKind::Integer, 2..=17),
+            "parse_const_u4_14" => (OperandKind::Integer, 0..=14),
+            "parse_const_u4_1_14" => (OperandKind::Integer, 1..=14),
+            "parse_const_u5" => (OperandKind::Integer, 0..=31),
+            "parse_const_u10" => (OperandKind::Integer, 0..=1023),
+            "parse_const_u11" => (OperandKind::Integer, 0..=2047),
+            "parse_const_u14" => (OperandKind::Integer, 0..=16383),
+            "parse_const_u8_setcp" => (OperandKind::Integer, -15..=239),
+            "parse_const_i8" => (OperandKind::Integer, -128..=127),
+            "parse_const_u8_plus_one" => (OperandKind::Integer, 1..=256),
+            "parse_const_u8_240" => (OperandKind::Integer, 0..=239),
+            // multiples of 32 only
+            "parse_plduz_parameter" => (OperandKind::Integer, 32..=256),
+            "parse_control_register" => (OperandKind::ControlRegister, 0..=15),
+            "parse_stack_register_u4" => (OperandKind::StackRegister, 0..=15),
+            "parse_stack_register_u4_minus_one" => (OperandKind::StackRegister, -1..=14),
+            "parse_stack_register_u4_minus_two" => (OperandKind::StackRegister, -2..=13),
+            _ => return None
+        };
+        Some(Self { name, kind, range })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InstructionInfo {
+    pub name: &'static str,
+    /// leading bytes of the encoding not depending on the operands;
+    /// empty if the instruction has several encodings
+    pub opcode: Vec<u8>,
+    /// byte expressions of the encoding referring to the operands by name,
+    /// e.g. `["0xA6", "z"]` for `ADDCONST z`
+    pub encoding: Vec<&'static str>,
+    /// operands in the source order, `None` if the instruction has several forms
+    pub operands: Option<Vec<Operand>>,
+    /// basic gas price of the instruction, if its length is fixed
+    pub gas: Option<u64>,
+    pub introduced: TvmVersion,
+}
+
+fn parse_byte(expr: &str) -> Option<u8> {
+    u8::from_str_radix(expr.strip_prefix("0x")?, 16).ok()
+}
+
+impl InstructionInfo {
+    /// Metadata of the simple command, None if an operand parser is not known here
+    fn simple(name: &'static str, command: &SimpleCommand) -> Option<Self> {
+        let operands = command.operands.iter()
+            .map(|(name, parser)| Operand::from_parser(name, parser))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            name,
+            opcode: command.encoding.iter().map_while(|expr| parse_byte(expr)).collect(),
+            encoding: command.encoding.to_vec(),
+            operands: Some(operands),
+            gas: Some(GAS_BASE + 8 * command.encoding.len() as u64),
+            introduced: introduced_in(name),
+        })
+    }
+
+    fn complex(name: &'static str) -> Self {
+        Self {
+            name,
+            opcode: Vec::new(),
+            encoding: Vec::new(),
+            operands: None,
+            gas: None,
+            introduced: introduced_in(name),
+        }
+    }
+}
+
+impl Engine {
+    /// Rejects instructions introduced by TVM versions later than the given one
+    /// (the latest by default)
+    pub fn set_target_version(&mut self, version: TvmVersion) {
+        self.target_version = version;
+    }
+
+    pub fn target_version(&self) -> TvmVersion {
+        self.target_version
+    }
+
+    /// Version required by the instruction if the target does not support it
+    pub(crate) fn unsupported(&self, name: &str) -> Option<TvmVersion> {
+        Some(introduced_in(name)).filter(|version| *version > self.target_version)
+    }
+}
+
+/// Metadata of every mnemonic known to the assembler sorted by name;
+/// directives are not included
+pub fn instructions() -> impl Iterator<Item = InstructionInfo> {
+    let simple = Engine::simple_command_tables().into_iter().flatten().collect::<Vec<_>>();
+    let mut names = Engine::new("").mnemonics()
+        .filter(|name| !name.starts_with('.'))
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names.into_iter().map(move |name| {
+        // aliases kept for Fift share the encoding of the preferred mnemonic
+        let preferred = crate::diagnostics::preferred_mnemonic(name).unwrap_or(name);
+        simple.iter()
+            .find(|command| command.name == preferred)
+            .and_then(|command| InstructionInfo::simple(name, command))
+            .unwrap_or_else(|| InstructionInfo::complex(name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operand_parsers_are_known() {
+        for command in Engine::simple_command_tables().into_iter().flatten() {
+            for (name, parser) in command.operands {
+                assert!(Operand::from_parser(name, parser).is_some(), "unknown parser {} of {}", parser, command.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+use serde::{Deserialize, Serialize};
+use ever_block::Cell;
+
+use crate::{CompileError, DbgPos};
+
+pub const IR_VERSION: u32 = 1;
+
+/// Parsed program before encoding: a list of instructions and directives
+/// with their arguments, nested code blocks included
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ir {
+    pub version: u32,
+    pub instructions: Vec<IrInstruction>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IrInstruction {
+    /// mnemonic or directive in upper case, e.g. PUSHINT or .FRAGMENT
+    pub name: String,
+    pub args: Vec<IrArg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pos: Option<DbgPos>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IrArg {
+    /// plain argument as written in the source, e.g. s1 or x4_
+    Token(String),
+    /// block of code in braces
+    Block(Vec<IrInstruction>),
+    /// block in braces which is not code, e.g. the body of .code-dict-cell
+    Raw(String),
+}
+
+impl Ir {
+    pub fn new(instructions: Vec<IrInstruction>) -> Self {
+        Self { version: IR_VERSION, instructions }
+    }
+    /// Prints the program back to the assembler source
+    pub fn to_source(&self) -> String {
+        let mut text = String::new();
+        print_instructions(&self.instructions, "", false, &mut text);
+        text
+    }
+    /// Prints the program preceding instructions with `.loc` directives
+    /// so that the compiled code keeps the original source positions
+    pub fn to_source_located(&self) -> String {
+        let mut text = String::new();
+        print_instructions(&self.instructions, "", true, &mut text);
+        text
+    }
+}
+
+fn print_instructions(instructions: &[IrInstruction], indent: &str, located: bool, text: &mut String) {
+    for insn in instructions {
+        if located {
+            if insn.name == ".LOC" {
+                continue
+            }
+            if let Some(pos) = insn.pos.as_ref().filter(|pos| !pos.filename.is_empty()) {
+                // quoted, so that the name may have commas and spaces in it
+                match pos.column {
+                    0 => text.push_str(&format!("{}.loc \"{}\", {}\n", indent, pos.filename, pos.line)),
+                    column => text.push_str(&format!("{}.loc \"{}\", {}, {}\n", indent, pos.filename, pos.line, column)),
+                }
+            }
+        }
+        text.push_str(indent);
+        text.push_str(&insn.name);
+        let mut after_block = false;
+        for (i, arg) in insn.args.iter().enumerate() {
+            // a block can only be followed by another block without a comma
+            text.push_str(if i == 0 || after_block { " " } else { ", " });
+            after_block = false;
+            match arg {
+                IrArg::Token(token) => text.push_str(token),
+                IrArg::Block(code) => {
+                    text.push_str("{\n");
+                    print_instructions(code, &format!("{}  ", indent), located, text);
+                    text.push_str(indent);
+                    text.push('}');
+                    after_block = true;
+                }
+                IrArg::Raw(raw) => {
+                    text.push('{');
+                    text.push_str(raw);
+                    text.push('}');
+                    after_block = true;
+                }
+            }
+        }
+        text.push('\n');
+    }
+}
+
+/// Encodes the program into a code cell
+pub fn compile_ir(ir: &Ir) -> Result<Cell, CompileError> {
+    if ir.version != IR_VERSION {
+        return Err(CompileError::syntax(0, 0, format!("unsupported IR version {}", ir.version)))
+    }
+    crate::compile_code_to_cell(&ir.to_source())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_code_to_cell, Engine};
+
+    fn insn(name: &str, args: Vec<IrArg>) -> IrInstruction {
+        IrInstruction { name: name.to_string(), args, pos: None }
+    }
+
+    fn token(text: &str) -> IrArg {
+        IrArg::Token(text.to_string())
+    }
+
+    /// Instructions without their positions
+    fn strip_positions(instructions: &mut [IrInstruction]) {
+        for insn in instructions {
+            insn.pos = None;
+            for arg in insn.args.iter_mut() {
+                if let IrArg::Block(code) = arg {
+                    strip_positions(code);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let source = "PUSHINT 1\nXCHG s1, s2\nPUSHCONT {\n  INC\n}";
+        let mut ir = Engine::new("").parse_ir(source).unwrap();
+        assert_eq!(ir.version, IR_VERSION);
+        assert!(ir.instructions.iter().all(|insn| insn.pos.is_some()));
+        strip_positions(&mut ir.instructions);
+        assert_eq!(ir.instructions, vec!(
+            insn("PUSHINT", vec!(token("1"))),
+            insn("XCHG", vec!(token("s1"), token("s2"))),
+            insn("PUSHCONT", vec!(IrArg::Block(vec!(insn("INC", vec!()))))),
+        ));
+        let compiled = compile_ir(&ir).unwrap();
+        assert_eq!(compiled.repr_hash(), compile_code_to_cell(source).unwrap().repr_hash());
+    }
+
+    #[test]
+    fn print() {
+        let ir = Ir::new(vec!(
+            insn("XCHG", vec!(token("s1"), token("s2"))),
+            insn("PUSHCONT", vec!(IrArg::Block(vec!(insn("INC", vec!()))))),
+            insn("IFREFELSEREF", vec!(IrArg::Block(vec!(insn("NOP", vec!()))), IrArg::Block(vec!(insn("DROP", vec!()))))),
+        ));
+        assert_eq!(
+            ir.to_source(),
+            "XCHG s1, s2\nPUSHCONT {\n  INC\n}\nIFREFELSEREF {\n  NOP\n} {\n  DROP\n}\n"
+        );
+        let json = serde_json::to_string(&ir).unwrap();
+        assert_eq!(serde_json::from_str::<Ir>(&json).unwrap(), ir);
+    }
+
+    #[test]
+    fn version() {
+        let ir = Ir { version: IR_VERSION + 1, instructions: vec!(insn("NOP", vec!())) };
+        assert!(compile_ir(&ir).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Labels ********************************************************************
+//
+// `.label name:` splits a block of code into segments. The code following a label
+// up to the end of the block is its continuation: it is placed in a separate cell,
+// the preceding code falls through into it with an implicit jump, and branch
+// primitives like JMPREF or IFJMPREF may refer to it by name. Since cells can't
+// form loops, a label can only be referenced from the code preceding it.
+// Continuations are compiled on the first reference, so forward references
+// are resolved without patching the cells already built.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    CompileError, Engine, IrFrame, OperationError, Unit, Writer,
+    ir::{IrArg, IrInstruction},
+    lexer::mentions_any,
+};
+
+#[derive(Clone, Debug)]
+pub(crate) struct Segment {
+    label: Option<String>,
+    line: usize,
+    column: usize,
+    text: String,
+}
+
+/// Labels of a block being compiled
+#[derive(Default)]
+pub(crate) struct LabelScope {
+    segments: Vec<Segment>,
+    toplevel: bool,
+    resolved: HashMap<String, Unit>,
+    resolving: HashSet<String>,
+    /// recorded instructions of the segments if IR is requested
+    ir: HashMap<String, Vec<IrInstruction>>,
+}
+
+fn is_label_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+/// Splits the source into segments at `.label name:` directives of the outermost level
+fn split_labels(source: &str, mut line: usize, mut column: usize) -> Result<Vec<Segment>, CompileError> {
+    let mut segments = Vec::new();
+    let mut current = Segment { label: None, line, column, text: String::new() };
+    let mut start = 0;
+    let mut depth = 0;
+    let mut in_comment = false;
+    let mut in_string = false;
+    let mut prev = ' ';
+    let mut chars = source.char_indices().peekable();
+    while let Some((i, ch)) = chars.next() {
+        let (y, x) = (line, column);
+        let advance = |ch: char, line: &mut usize, column: &mut usize| if ch == '\n' {
+            *line += 1;
+            *column = 1
+        } else {
+            *column += 1
+        };
+        advance(ch, &mut line, &mut column);
+        let token_start = Engine::is_whitespace(prev) || prev == ',' || prev == '}';
+        prev = ch;
+        if in_comment {
+            in_comment = ch != '\n' && ch != '\r';
+            continue
+        }
+        if in_string {
+            in_string = ch != '"';
+            continue
+        }
+        match ch {
+            ';' => in_comment = true,
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '.' if depth == 0 && token_start => {
+                let rest = &source[i..];
+                let directive = rest.get(..6).map_or(false, |d| d.eq_ignore_ascii_case(".label"));
+                if !directive || !rest.get(6..).map_or(false, |r| r.starts_with(Engine::is_whitespace)) {
+                    continue
+                }
+                // skip the directive itself
+                for _ in 0..6 {
+                    let (_, ch) = chars.next().unwrap();
+                    advance(ch, &mut line, &mut column);
+                }
+                let mut name = String::new();
+                while let Some(&(_, ch)) = chars.peek() {
+                    if !name.is_empty() && !is_label_char(ch) || name.is_empty() && !Engine::is_whitespace(ch) && !is_label_char(ch) {
+                        break
+                    }
+                    if is_label_char(ch) {
+                        name.push(ch)
+                    }
+                    chars.next();
+                    advance(ch, &mut line, &mut column);
+                }
+                if name.is_empty() {
+                    return Err(CompileError::syntax(y, x, "Missing label name"))
+                }
+                match chars.next() {
+                    Some((_, ':')) => advance(':', &mut line, &mut column),
+                    _ => return Err(CompileError::syntax(line, column, format!("Missing : after label {}", name)))
+                }
+                if segments.iter().any(|s: &Segment| s.label.as_ref() == Some(&name)) {
+                    return Err(CompileError::syntax(y, x, format!("Label {} is already defined", name)))
+                }
+                current.text = source[start..i].to_string();
+                segments.push(current);
+                start = chars.peek().map_or(source.len(), |(i, _)| *i);
+                current = Segment { label: Some(name), line, column, text: String::new() };
+                prev = ':';
+            }
+            _ => ()
+        }
+    }
+    current.text = source[start..].to_string();
+    segments.push(current);
+    Ok(segments)
+}
+
+impl Engine {
+    /// Whether the token names a label declared in the enclosing blocks rather than a block of code
+    pub(crate) fn is_label_reference(&self, token: &str) -> bool {
+        self.labels.iter().any(|scope| scope.segments.iter().any(|s| s.label.as_deref() == Some(token)))
+    }
+
+    /// Compiles the block taking its labels into account
+    pub(crate) fn compile_labelled(&mut self, source: &str, toplevel: bool, destination: &mut dyn Writer) -> Result<(), CompileError> {
+        if !mentions_any(source, &[".label"]) {
+            return self.write_source(source, toplevel, destination)
+        }
+        let segments = split_labels(source, self.line_no, self.char_no)
+            .map_err(|e| e.with_filename(self.source_name.to_string()))?;
+        if segments.len() == 1 {
+            return self.write_source(source, toplevel, destination)
+        }
+        for segment in &segments[1..] {
+            let name = segment.label.clone().unwrap_or_default();
+            if self.handlers.contains_key(name.to_ascii_uppercase().as_str()) {
+                return Err(CompileError::syntax(segment.line, segment.column, format!("Label {} is a name of an instruction", name))
+                    .with_filename(self.source_name.to_string()))
+            }
+        }
+        let first_label = segments[1].label.clone().unwrap_or_default();
+        let labels = segments.iter().skip(1).filter_map(|s| s.label.clone()).collect::<Vec<_>>();
+        self.labels.push(LabelScope { segments, toplevel, ..Default::default() });
+        let result = self.compile_first_segment(source, toplevel, &first_label, destination);
+        let scope = self.labels.pop().unwrap_or_default();
+        result?;
+        if let Some(frame) = self.ir.as_mut().and_then(|frames| frames.last_mut()) {
+            let mut ir = scope.ir;
+            for name in labels {
+                frame.instructions.push(IrInstruction {
+                    name: ".LABEL".to_string(),
+                    args: vec!(IrArg::Token(format!("{}:", name))),
+                    pos: None,
+                });
+                frame.instructions.append(&mut ir.remove(&name).unwrap_or_default());
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_first_segment(&mut self, source: &str, toplevel: bool, next: &str, destination: &mut dyn Writer) -> Result<(), CompileError> {
+        let len = self.labels.last().map(|scope| scope.segments[0].text.len()).unwrap_or_default();
+        let units = self.compile_source(&source[..len], toplevel)?;
+        let (line_no, char_no) = (self.line_no, self.char_no);
+        let next = self.resolve_label(next)
+            .map_err(|e| CompileError::operation(line_no, char_no, ".LABEL", e).with_filename(self.source_name.to_string()))?;
+        let (builder, dbg) = units.finalize_with_next(next);
+        destination.write_unit(Unit::new(builder, dbg))
+            .map_err(|e| CompileError::operation(line_no, char_no, ".LABEL", e))
+    }
+
+    /// Returns the continuation of the label compiling it if needed
+    pub(crate) fn resolve_label(&mut self, name: &str) -> Result<Unit, OperationError> {
+        for depth in (0..self.labels.len()).rev() {
+            let scope = &self.labels[depth];
+            if let Some(unit) = scope.resolved.get(name) {
+                return Ok(unit.clone())
+            }
+            if let Some(index) = scope.segments.iter().position(|s| s.label.as_deref() == Some(name)) {
+                if scope.resolving.contains(name) {
+                    return Err(OperationError::BackwardLabel(name.to_string()))
+                }
+                return self.compile_label(depth, index)
+            }
+        }
+        Err(OperationError::UnresolvedLabel(name.to_string()))
+    }
+
+    fn compile_label(&mut self, depth: usize, index: usize) -> Result<Unit, OperationError> {
+        let scope = &mut self.labels[depth];
+        let segment = scope.segments[index].clone();
+        let next = scope.segments.get(index + 1).and_then(|s| s.label.clone());
+        let toplevel = scope.toplevel;
+        let name = segment.label.clone().unwrap_or_default();
+        scope.resolving.insert(name.clone());
+
+        // compile the segment in the context of its own block
+        let inner = self.labels.split_off(depth + 1);
+        let line_no = std::mem::replace(&mut self.line_no, segment.line);
+        let char_no = std::mem::replace(&mut self.char_no, segment.column);
+        let dbgpos = self.dbgpos.take();
+        let recording = self.ir.is_some();
+        if let Some(frames) = self.ir.as_mut() {
+            frames.push(IrFrame::default());
+        }
+
+        let result = self.compile_source(&segment.text, toplevel)
+            .map_err(|e| OperationError::Nested(Box::new(e)))
+            .and_then(|units| match &next {
+                Some(next) => Ok(units.finalize_with_next(self.resolve_label(next)?)),
+                None => Ok(units.finalize()),
+            });
+
+        if recording {
+            let frame = self.ir.as_mut().and_then(|frames| frames.pop()).unwrap_or_default();
+            self.labels[depth].ir.insert(name.clone(), frame.instructions);
+        }
+        self.dbgpos = dbgpos;
+        self.line_no = line_no;
+        self.char_no = char_no;
+        self.labels.extend(inner);
+        let scope = &mut self.labels[depth];
+        scope.resolving.remove(&name);
+
+        let (builder, dbg) = result?;
+        let unit = Unit::new(builder, dbg);
+        scope.resolved.insert(name, unit.clone());
+        Ok(unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ever_block::{Cell, SliceData};
+    use super::*;
+    use crate::compile_code_to_cell;
+    use crate::test_helpers::innermost_error;
+
+    fn labels(source: &str) -> Vec<(Option<String>, String)> {
+        split_labels(source, 1, 1).unwrap().into_iter().map(|s| (s.label, s.text)).collect()
+    }
+
+    fn data(cell: &Cell) -> Vec<u8> {
+        SliceData::load_cell_ref(cell).unwrap().get_bytestring(0)
+    }
+
+    #[test]
+    fn split_at_outermost_labels() {
+        assert_eq!(labels("NOP\n.label a:\nINC\n"), vec!(
+            (None, "NOP\n".to_string()),
+            (Some("a".to_string()), "\nINC\n".to_string()),
+        ));
+        assert_eq!(labels("; .label a:\nPUSHCONT {\n.label b:\n}\n").len(), 1);
+        assert!(split_labels("NOP\n.label a:\nINC\n.label a:\n", 1, 1).is_err());
+        assert!(split_labels(".label a\nNOP", 1, 1).is_err());
+        assert_eq!(labels("PUSHSLICE \"{ .label a: \"\n.LABEL b:\nNOP").len(), 2);
+    }
+
+    #[test]
+    fn forward_reference() {
+        let code = compile_code_to_cell("PUSHINT 1\nIFJMPREF done\nPUSHINT 2\n.label done:\nPUSHINT 3").unwrap();
+        let done = compile_code_to_cell("PUSHINT 3").unwrap();
+        let inline = compile_code_to_cell("PUSHINT 1\nIFJMPREF {\n  PUSHINT 3\n}\nPUSHINT 2").unwrap();
+        assert_eq!(data(&code), data(&inline));
+        // the branch and the fall through lead to the same cell
+        assert_eq!(code.references_count(), 2);
+        assert_eq!(code.reference(0).unwrap().repr_hash(), done.repr_hash());
+        assert_eq!(code.reference(1).unwrap().repr_hash(), done.repr_hash());
+    }
+
+    #[test]
+    fn backward_and_unresolved_references() {
+        assert!(matches!(
+            innermost_error(&mut Engine::new(""), "NOP\n.label again:\nJMPREF again"),
+            CompileError::Operation(_, _, OperationError::BackwardLabel(name)) if name == "again"
+        ));
+        assert!(matches!(
+            innermost_error(&mut Engine::new(""), "JMPREF b\n.label a:\nNOP\n.label b:\nJMPREF a"),
+            CompileError::Operation(_, _, OperationError::BackwardLabel(name)) if name == "b"
+        ));
+        // a name not declared as a label is compiled as code
+        assert!(matches!(
+            innermost_error(&mut Engine::new(""), "JMPREF nowhere\n.label somewhere:\nNOP"),
+            CompileError::UnknownOperation(_, name, _) if name.eq_ignore_ascii_case("nowhere")
+        ));
+        assert!(compile_code_to_cell("NOP\n.label ADD:\nINC").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Lexer of assembly sources **************************************************
+//
+// The source is split into words, commas, blocks and line breaks, each lexeme
+// borrowing its text from the source. A word is a run of letters, digits and
+// `-_./\$@():` with quoted strings possibly in it. A block is the text between
+// matching braces taken as is, to be compiled on its own; braces in comments
+// and strings inside the block are not counted. Comments run from `;` to the
+// end of the line.
+
+use crate::Engine;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Word,
+    Comma,
+    /// text between braces, the position is the one of the opening brace
+    Block,
+    /// line break, possibly ending a comment
+    Newline,
+    /// stray closing brace or a char not allowed in the source
+    Unexpected,
+    /// block or string not closed by the end of the source, the text is the opening char
+    Unclosed,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Lexeme<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub line: usize,
+    pub column: usize,
+    /// the lexeme directly follows a word without a separator
+    pub glued: bool,
+}
+
+pub(crate) struct Lexer<'a> {
+    source: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    line: usize,
+    column: usize,
+    /// the last lexeme is a word ending at the current position
+    after_word: bool,
+}
+
+fn is_word_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.' | '/' | '\\' | '$' | '@' | '(' | ')' | '"' | ':')
+}
+
+impl<'a> Lexer<'a> {
+    /// Lexer of the source starting at the given line and column
+    pub fn new(source: &'a str, line: usize, column: usize) -> Self {
+        Self { source, chars: source.char_indices().peekable(), line, column, after_word: false }
+    }
+
+    /// Line and column of the next char
+    pub fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    fn bump(&mut self) -> Option<(usize, char)> {
+        let (offset, ch) = self.chars.next()?;
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some((offset, ch))
+    }
+
+    /// Skips the rest of the comment up to the line break
+    fn skip_comment(&mut self) {
+        while !matches!(self.chars.peek(), None | Some((_, '\n' | '\r'))) {
+            self.bump();
+        }
+    }
+
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map_or(self.source.len(), |(offset, _)| *offset)
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Lexeme<'a>;
+
+    fn next(&mut self) -> Option<Lexeme<'a>> {
+        let source = self.source;
+        loop {
+            let glued = std::mem::take(&mut self.after_word);
+            let (line, column) = self.position();
+            let (start, ch) = self.bump()?;
+            let lexeme = |kind, text| Some(Lexeme { kind, text, line, column, glued });
+            match ch {
+                '\n' | '\r' => return lexeme(TokenKind::Newline, &source[start..start + 1]),
+                _ if Engine::is_whitespace(ch) => (),
+                ';' => self.skip_comment(),
+                ',' => return lexeme(TokenKind::Comma, &source[start..start + 1]),
+                '{' => {
+                    let mut depth = 1;
+                    let mut in_string = false;
+                    while let Some((offset, ch)) = self.bump() {
+                        match ch {
+                            '"' => in_string = !in_string,
+                            _ if in_string => (),
+                            ';' => self.skip_comment(),
+                            '{' => depth += 1,
+                            '}' => depth -= 1,
+                            _ => ()
+                        }
+                        if depth == 0 {
+                            return lexeme(TokenKind::Block, &source[start + 1..offset])
+                        }
+                    }
+                    return lexeme(TokenKind::Unclosed, "{")
+                }
+                _ if is_word_char(ch) => {
+                    let mut in_string = ch == '"';
+                    while let Some(&(_, ch)) = self.chars.peek() {
+                        match in_string {
+                            true => in_string = ch != '"',
+                            false if is_word_char(ch) => in_string = ch == '"',
+                            false => break
+                        }
+                        self.bump();
+                    }
+                    if in_string {
+                        return lexeme(TokenKind::Unclosed, "\"")
+                    }
+                    self.after_word = true;
+                    let end = self.offset();
+                    return lexeme(TokenKind::Word, &source[start..end])
+                }
+                _ => return lexeme(TokenKind::Unexpected, &source[start..start + ch.len_utf8()]),
+            }
+        }
+    }
+}
+
+/// Whether a word of the source, blocks included, is one of the names in any
+/// case; comments and string literals are not looked into
+pub(crate) fn mentions_any(source: &str, names: &[&str]) -> bool {
+    Lexer::new(source, 1, 1).any(|lexeme| match lexeme.kind {
+        TokenKind::Word => names.iter().any(|name| lexeme.text.eq_ignore_ascii_case(name)),
+        TokenKind::Block => mentions_any(lexeme.text, names),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Vec<(TokenKind, &str)> {
+        Lexer::new(source, 1, 1).map(|lexeme| (lexeme.kind, lexeme.text)).collect()
+    }
+
+    #[test]
+    fn words_commas_and_newlines() {
+        assert_eq!(
+            lex("ADD\nPUSHINT 1, 2 ; comment, {\n"),
+            vec!(
+                (TokenKind::Word, "ADD"), (TokenKind::Newline, "\n"),
+                (TokenKind::Word, "PUSHINT"), (TokenKind::Word, "1"), (TokenKind::Comma, ","), (TokenKind::Word, "2"),
+                (TokenKind::Newline, "\n"),
+            )
+        );
+        assert_eq!(
+            lex("NOP\r\n.loc a/b.sol, 10"),
+            vec!(
+                (TokenKind::Word, "NOP"), (TokenKind::Newline, "\r"), (TokenKind::Newline, "\n"),
+                (TokenKind::Word, ".loc"), (TokenKind::Word, "a/b.sol"), (TokenKind::Comma, ","), (TokenKind::Word, "10"),
+            )
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            lex("PUSHSLICE \"a, b ; {\" NOP"),
+            vec!((TokenKind::Word, "PUSHSLICE"), (TokenKind::Word, "\"a, b ; {\""), (TokenKind::Word, "NOP"))
+        );
+        assert_eq!(lex("PUSHSLICE \"ab"), vec!((TokenKind::Word, "PUSHSLICE"), (TokenKind::Unclosed, "\"")));
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(
+            lex("PUSHCONT { IF { NOP } }"),
+            vec!((TokenKind::Word, "PUSHCONT"), (TokenKind::Block, " IF { NOP } "))
+        );
+        assert_eq!(lex("PUSHCONT { NOP"), vec!((TokenKind::Word, "PUSHCONT"), (TokenKind::Unclosed, "{")));
+        assert_eq!(lex("NOP }"), vec!((TokenKind::Word, "NOP"), (TokenKind::Unexpected, "}")));
+        assert_eq!(lex("#"), vec!((TokenKind::Unexpected, "#")));
+    }
+
+    #[test]
+    fn positions_and_glue() {
+        let lexemes = Lexer::new("NOP\n  PUSHSLICE x{A5C_} { NOP }", 3, 5)
+            .map(|lexeme| (lexeme.text, lexeme.line, lexeme.column, lexeme.glued))
+            .collect::<Vec<_>>();
+        assert_eq!(lexemes, vec!(
+            ("NOP", 3, 5, false),
+            ("\n", 3, 8, false),
+            ("PUSHSLICE", 4, 3, false),
+            ("x", 4, 13, false),
+            ("A5C_", 4, 14, true),
+            (" NOP ", 4, 21, false),
+        ));
+    }
+
+    #[test]
+    fn braces_in_strings_and_comments() {
+        assert_eq!(
+            lex("PUSHCONT { PUSHSLICE \"}\" }"),
+            vec!((TokenKind::Word, "PUSHCONT"), (TokenKind::Block, " PUSHSLICE \"}\" "))
+        );
+        assert_eq!(
+            lex("PUSHCONT {\n  NOP ; }\n}\nNOP"),
+            vec!(
+                (TokenKind::Word, "PUSHCONT"), (TokenKind::Block, "\n  NOP ; }\n"),
+                (TokenKind::Newline, "\n"), (TokenKind::Word, "NOP"),
+            )
+        );
+        assert_eq!(lex("PUSHCONT { ; {\n}"), vec!((TokenKind::Word, "PUSHCONT"), (TokenKind::Block, " ; {\n")));
+        assert_eq!(lex("PUSHCONT { \"{\" }"), vec!((TokenKind::Word, "PUSHCONT"), (TokenKind::Block, " \"{\" ")));
+    }
+
+    #[test]
+    fn mentioned_names() {
+        assert!(mentions_any("NOP\nPUSHCONT {\n  .Inline f\n}", &[".inline"]));
+        assert!(!mentions_any("NOP ; .inline f\nPUSHSLICE \".inline\"", &[".inline"]));
+        assert!(!mentions_any(".inline-computed f", &[".inline"]));
+    }
+}
@@ -11,34 +11,94 @@
 * limitations under the License.
 */
 
-use std::{collections::HashMap, ops::RangeInclusive};
-use ever_block::{Cell, SliceData, BuilderData};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet}, ops::RangeInclusive,
+    sync::Arc,
+};
+use ever_block::{Cell, SliceData, BuilderData, UInt256};
 
-pub use debug::DbgInfo;
+pub use debug::{DbgInfo, FlatDbgInfo, ProcInfo, DBG_INFO_VERSION};
 
 mod errors;
 pub use errors::{
-    CompileError, OperationError, ParameterError, Position,
+    CompileError, Diagnostic, OperationError, ParameterError, Position, Severity, Span,
     ToOperationParameterError,
 };
 
+mod abi;
 mod debug;
+mod dbg_binary;
+#[cfg(feature = "dwarf")]
+pub mod dwarf;
+mod diagnostics;
 mod macros;
 mod parse;
+mod prologue;
+mod procs;
 mod complex;
 mod simple;
 mod convert;
+mod constants;
+mod data;
+mod labels;
+mod include;
+mod fragref;
+mod link;
+mod vars;
+mod lexer;
+#[cfg(feature = "eval")]
+mod eval;
+#[cfg(feature = "parallel")]
+mod parallel;
+#[cfg(test)]
+mod test_helpers;
 
 mod writer;
-pub use writer::{Units, Unit};
-pub use debug::DbgPos;
+pub use writer::{Units, Unit, CellEntry, CellIter, PackingPolicy, Writer};
+#[cfg(feature = "eval")]
+pub use eval::EvalValue;
+pub use prologue::{EngineOptions, Prologue, UNKNOWN_METHOD_EXIT_CODE};
+pub use debug::{CellRole, DbgNode, DbgPos, InlineSite};
+use debug::FileTable;
+use lexer::{Lexer, TokenKind};
+pub use constants::method_id;
+pub use builder::Asm;
+pub use optimize::Opts;
+pub use instructions::{instructions, InstructionInfo, TvmVersion};
 
+pub mod builder;
+pub mod cache;
+pub mod codepage;
 pub mod disasm;
+pub mod events;
+pub mod format;
+pub mod gas;
+pub mod instructions;
+pub mod ir;
+pub mod libraries;
+pub mod lint;
+pub mod lsp;
+pub mod manifest;
+pub mod optimize;
+#[cfg(feature = "fs")]
+pub mod project;
+#[cfg(feature = "fs")]
+pub mod roundtrip;
+pub mod stack;
+pub mod stats;
+pub mod stateinit;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 // Basic types *****************************************************************
 /// Operation Compilation result
-type CompileResult = Result<(), OperationError>;
-type CompileHandler = fn(&mut Engine, &[&str], destination: &mut Units, pos: DbgPos) -> CompileResult;
+pub type CompileResult = Result<(), OperationError>;
+/// Handler writing an instruction or a directive with its operands
+pub type CompileHandler = fn(&mut Engine, &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult;
 
 // CompileError::Operation handlers ***********************************************************
 trait EnsureParametersCountInRange {
@@ -91,15 +151,37 @@ impl CommandContext {
             rule_option,
         }
     }
-    fn abort<X>(&self, error: OperationError) -> Result<X, CompileError> {
-        Err(CompileError::operation(self.line_no_cmd, self.char_no_cmd, self.operation.clone(), error))
+    fn abort<X>(&self, error: OperationError, par: &[Token]) -> Result<X, CompileError> {
+        if let OperationError::UnresolvedLabel(name) = error {
+            let position = Position::new(String::new(), self.line_no_cmd, self.char_no_cmd);
+            return Err(CompileError::UnresolvedLabel(position, name))
+        }
+        let operand = match &error {
+            OperationError::Parameter(name, _) => Self::failed_operand(name, par),
+            _ => None
+        };
+        let error = CompileError::operation(self.line_no_cmd, self.char_no_cmd, self.operation.clone(), error);
+        match operand {
+            Some(token) => Err(error.with_span(Span::new(token.line, token.column, token.token))),
+            None => Err(error.with_token(&self.operation)),
+        }
+    }
+    /// Finds the operand named in a parameter error, e.g. "arg 1", or the only one
+    fn failed_operand<'a, 'b>(name: &str, par: &'b [Token<'a>]) -> Option<&'b Token<'a>> {
+        let index = name.strip_prefix("arg ").and_then(|i| i.parse::<usize>().ok());
+        let token = match (index, par.len()) {
+            (Some(index), _) => par.get(index),
+            (None, 1) => par.first(),
+            _ => None
+        };
+        token.filter(|token| !token.block)
     }
     fn has_command(&self) -> bool {
         self.rule_option.is_some()
     }
     fn compile(
         &mut self,
-        destination: &mut Units,
+        destination: &mut dyn Writer,
         par: &mut Vec<Token>,
         engine: &mut Engine,
     ) -> Result<(), CompileError> {
@@ -107,34 +189,63 @@ impl CommandContext {
             Some(rule) => rule,
             None => return Ok(())
         };
+        if let Some(version) = engine.unsupported(&self.operation) {
+            let position = Position::new(String::new(), self.line_no_cmd, self.char_no_cmd);
+            let error = CompileError::UnsupportedForTarget(position, self.operation.clone(), version);
+            return Err(error.with_token(&self.operation))
+        }
         let (line_no, char_no) = engine.set_pos(self.line_no_par, self.char_no_par);
         let mut n = par.len();
         loop {
-            let par = par[0..n].iter().map(|p| p.token).collect::<Vec<_>>();
+            let values = par[0..n].iter().enumerate()
+                .map(|(i, p)| engine.substitute_const(&self.operation, i, p))
+                .collect::<Vec<_>>();
+            let tokens = values.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
             let pos = engine.dbgpos.clone()
-                .unwrap_or_else(|| DbgPos { filename: engine.source_name.clone(), line: self.line_no_cmd });
-            match rule(engine, &par, destination, pos) {
-                Ok(_) => break,
+                .unwrap_or_else(|| DbgPos {
+                    filename: engine.source_name.clone(),
+                    line: self.line_no_cmd,
+                    column: self.char_no_cmd,
+                    ..Default::default()
+                });
+            engine.ir_take_blocks();
+            match rule(engine, &tokens, destination, pos.clone()) {
+                Ok(_) => {
+                    let position = Position::new(engine.source_name.to_string(), self.line_no_cmd, self.char_no_cmd);
+                    engine.check_command(&self.operation, &tokens, position.clone());
+                    engine.check_code_page(&self.operation, &tokens, position);
+                    engine.ir_record(&self.operation, &par[0..n], pos);
+                    break
+                }
                 Err(OperationError::TooManyParameters) if n != 0 => {
                     n -= 1;
                 }
-                Err(e) => return self.abort(e)
+                Err(e @ OperationError::Parameter(..)) => {
+                    if let Some(error) = engine.undefined_const(&self.operation, &par[0..n]) {
+                        return Err(error)
+                    }
+                    return self.abort(e, &par[0..n])
+                }
+                Err(e) => return self.abort(e, &par[0..n])
             }
         }
         engine.set_pos(line_no, char_no);
         self.rule_option = None;
         // detecting some errors here
-        if n > 1 && self.operation != "IFREFELSEREF" { // the only insn taking two blocks without comma between
+        // IFREFELSEREF is the only insn taking two blocks without comma between,
+        // .macro and .proc take their bodies after the parameter list, .define takes its value without comma
+        if n > 1 && !matches!(self.operation.as_str(), "IFREFELSEREF" | ".MACRO" | ".DEFINE" | ".PROC") {
             for token in &par[1..n] {
                 if !token.was_comma {
-                    return Err(CompileError::syntax(token.line, token.column, "Missing comma"))
+                    return Err(CompileError::syntax(token.line, token.column, "Missing comma").with_token(token.token))
                 }
             }
         }
         par.drain(..n);
         if !par.is_empty() {
             let token = par.remove(0);
-            let position = Position::new(engine.source_name.clone(), token.line, token.column);
+            let position = Position::new(engine.source_name.to_string(), token.line, token.column)
+                .with_token(token.token);
             if token.was_comma || n == 0 {
                 return Err(CompileError::Operation(
                     position,
@@ -144,7 +255,7 @@ impl CommandContext {
             } else {
                 // or CompileError::Syntax "missing comma"
                 return Err(CompileError::UnknownOperation(
-                    position, token.token.into()
+                    position, token.token.into(), Vec::new()
                 ))
             }
         }
@@ -158,10 +269,90 @@ impl CommandContext {
 pub struct Engine {
     line_no: usize,
     char_no: usize,
-    source_name: String,
+    source_name: Arc<str>,
+    /// names of the files met in positions
+    files: FileTable,
+    /// directives and instructions of the current codepage
     handlers: HashMap<&'static str, CompileHandler>,
+    /// instructions by codepage
+    code_pages: HashMap<i16, HashMap<&'static str, CompileHandler>>,
+    code_page: i16,
     named_units: HashMap<String, Unit>,
+    /// fragments defined by `.method` waiting for the dispatcher, by method id
+    methods: BTreeMap<u32, (String, DbgPos)>,
+    abi_functions: Vec<abi::AbiFunction>,
+    abi_filename: String,
+    /// keys of the code dictionaries built with function ids
+    code_dict_keys: BTreeSet<u32>,
+    /// hashes of the placeholder cells made by `.fragref`
+    fragrefs: HashSet<UInt256>,
     dbgpos: Option<DbgPos>,
+    inline_max_bits: Option<usize>,
+    packing_policy: PackingPolicy,
+    /// positions of unreachable code removed by the optimizer
+    stripped_dead_code: Vec<DbgPos>,
+    recover_fragments: bool,
+    fragment_errors: Vec<CompileError>,
+    /// whether to go on compiling after errors collecting them
+    recover_errors: bool,
+    collected_errors: Vec<CompileError>,
+    ir: Option<Vec<IrFrame>>,
+    labels: Vec<labels::LabelScope>,
+    macros: HashMap<String, complex::Macro>,
+    constants: HashMap<String, String>,
+    /// exceptions declared, names to exit codes
+    exceptions: BTreeMap<String, u16>,
+    /// global variables named, names to indexes
+    globals: BTreeMap<String, u8>,
+    procs: BTreeMap<String, ProcInfo>,
+    /// initial data defined by `.data`
+    data: Option<BuilderData>,
+    expansion_depth: usize,
+    /// whether the code being compiled is at the toplevel scope
+    toplevel: bool,
+    include_paths: Vec<std::path::PathBuf>,
+    /// files being included, canonical paths along with the names used in positions
+    includes: Vec<(std::path::PathBuf, String)>,
+    /// units waiting for `link`
+    pending_units: Vec<link::PendingUnit>,
+    diagnostics: Vec<Diagnostic>,
+    target_version: TvmVersion,
+    /// fragments compiled ahead by name, along with their bodies
+    precompiled: HashMap<String, (String, BuilderData, DbgNode, Vec<Diagnostic>)>,
+    cache: Option<cache::CompileCache>,
+    limits: stats::CodeLimits,
+    /// post-processing of the cells of a source before they are sealed into a tree
+    finalize_hooks: Vec<FinalizeHook>,
+    options: EngineOptions,
+    /// consumer of the compilation events
+    listener: Option<events::Listener>,
+    /// cells shared by the last `finalize`
+    dedup_stats: stats::DedupStats,
+}
+
+/// Hook given the cells of a source before [`Engine::finalize`] chains them
+pub type FinalizeHook = Box<dyn Fn(&mut Units) + Send + Sync>;
+
+/// Snapshot of the definitions in the engine
+struct Definitions {
+    fragments: HashMap<String, Unit>,
+    methods: BTreeMap<u32, (String, DbgPos)>,
+    code_dict_keys: BTreeSet<u32>,
+    procs: BTreeMap<String, ProcInfo>,
+    macros: HashMap<String, complex::Macro>,
+    constants: HashMap<String, String>,
+    exceptions: BTreeMap<String, u16>,
+    globals: BTreeMap<String, u8>,
+    data: Option<BuilderData>,
+    diagnostics: usize,
+}
+
+/// Instructions recorded while compiling a block of code
+#[derive(Default)]
+struct IrFrame {
+    instructions: Vec<ir::IrInstruction>,
+    /// nested blocks compiled by the current command
+    blocks: Vec<Vec<ir::IrInstruction>>,
 }
 
 #[derive(Debug)]
@@ -170,11 +361,15 @@ struct Token<'a> {
     column: usize,
     token: &'a str,
     was_comma: bool,
+    block: bool,
 }
 
 impl<'a> Token<'a> {
     fn new(line: usize, column: usize, token: &'a str, was_comma: bool) -> Self {
-        Self { line, column, token, was_comma }
+        Self { line, column, token, was_comma, block: false }
+    }
+    fn block(line: usize, column: usize, token: &'a str, was_comma: bool) -> Self {
+        Self { line, column, token, was_comma, block: true }
     }
 }
 
@@ -183,13 +378,51 @@ impl Engine {
         let mut ret = Self {
             line_no: 1,
             char_no: 1,
-            source_name: source_name.to_string(),
+            source_name: Arc::from(source_name),
+            files: FileTable::default(),
             handlers: HashMap::new(),
+            code_pages: HashMap::new(),
+            code_page: 0,
             named_units: HashMap::new(),
+            methods: BTreeMap::new(),
+            abi_functions: Vec::new(),
+            abi_filename: String::new(),
+            code_dict_keys: BTreeSet::new(),
+            fragrefs: HashSet::new(),
             dbgpos: None,
+            inline_max_bits: None,
+            packing_policy: PackingPolicy::Greedy,
+            stripped_dead_code: Vec::new(),
+            recover_fragments: false,
+            fragment_errors: Vec::new(),
+            recover_errors: false,
+            collected_errors: Vec::new(),
+            ir: None,
+            labels: Vec::new(),
+            macros: HashMap::new(),
+            constants: HashMap::new(),
+            exceptions: BTreeMap::new(),
+            globals: BTreeMap::new(),
+            procs: BTreeMap::new(),
+            data: None,
+            expansion_depth: 0,
+            toplevel: false,
+            include_paths: Vec::new(),
+            includes: Vec::new(),
+            pending_units: Vec::new(),
+            diagnostics: Vec::new(),
+            target_version: TvmVersion::LATEST,
+            precompiled: HashMap::new(),
+            cache: None,
+            limits: stats::CodeLimits::default(),
+            finalize_hooks: Vec::new(),
+            options: EngineOptions::default(),
+            listener: None,
+            dedup_stats: stats::DedupStats::default(),
         };
         ret.add_complex_commands();
         ret.add_simple_commands();
+        ret.init_code_pages();
         ret
     }
 
@@ -203,9 +436,19 @@ impl Engine {
         (l, c)
     }
 
+    /// Builds the unit of the source compiled at the toplevel scope, so that it may define
+    /// fragments, methods and data; named, the unit can be inlined by the following ones
     pub fn build(&mut self, name: Option<String>, source: &str) -> Result<Unit, CompileError> {
-        let (builder, dbg) = self.compile(source)?.finalize();
-        let unit = Unit::new(builder, dbg);
+        self.build_with(name, source, &mut self.new_units())
+    }
+
+    /// Builds the unit writing its toplevel code by the given writer
+    pub fn build_with(&mut self, name: Option<String>, source: &str, writer: &mut dyn Writer) -> Result<Unit, CompileError> {
+        self.write_impl(source, true, writer)?;
+        let (builder, dbg) = writer.finish();
+        let (builder, dbg) = self.resolve_unit(builder, dbg)?;
+        self.report_finalized(&builder);
+        let unit = Unit::new(builder, dbg).with_diagnostics(self.take_diagnostics());
         if let Some(name) = name {
             self.named_units.insert(name, unit.clone());
         }
@@ -215,175 +458,546 @@ impl Engine {
     pub fn reset(&mut self, source_name: String) {
         self.line_no = 1;
         self.char_no = 1;
-        self.source_name = source_name;
+        self.source_name = self.files.intern(&source_name);
         self.dbgpos = None;
     }
 
+    /// Resets the engine for a new independent compilation keeping the instruction
+    /// tables and the options, everything filled by compiling is dropped
+    fn reset_all(&mut self, source_name: String) {
+        self.reset(source_name);
+        self.select_code_page(0);
+        self.named_units.clear();
+        self.methods.clear();
+        self.abi_functions.clear();
+        self.abi_filename.clear();
+        self.code_dict_keys.clear();
+        self.fragrefs.clear();
+        self.macros.clear();
+        self.constants.clear();
+        self.exceptions.clear();
+        self.globals.clear();
+        self.procs.clear();
+        self.data = None;
+        self.stripped_dead_code.clear();
+        self.diagnostics.clear();
+        self.fragment_errors.clear();
+        self.collected_errors.clear();
+        self.ir = None;
+        self.labels.clear();
+        self.expansion_depth = 0;
+        self.includes.clear();
+        self.pending_units.clear();
+        self.precompiled.clear();
+        self.dedup_stats = stats::DedupStats::default();
+    }
+
+    /// Copies of the fragments, methods, procedures, macros, constants and data defined so far
+    /// to roll back to, along with the number of diagnostics issued
+    fn definitions(&self) -> Definitions {
+        Definitions {
+            fragments: self.named_units.clone(),
+            methods: self.methods.clone(),
+            code_dict_keys: self.code_dict_keys.clone(),
+            procs: self.procs.clone(),
+            macros: self.macros.clone(),
+            constants: self.constants.clone(),
+            exceptions: self.exceptions.clone(),
+            globals: self.globals.clone(),
+            data: self.data.clone(),
+            diagnostics: self.diagnostics.len(),
+        }
+    }
+
+    /// Brings fragments, methods, macros, constants and data back to the snapshot,
+    /// both new and redefined ones, and drops the diagnostics issued since
+    fn restore(&mut self, definitions: Definitions) {
+        self.diagnostics.truncate(definitions.diagnostics);
+        self.named_units = definitions.fragments;
+        self.methods = definitions.methods;
+        self.code_dict_keys = definitions.code_dict_keys;
+        self.procs = definitions.procs;
+        self.macros = definitions.macros;
+        self.constants = definitions.constants;
+        self.exceptions = definitions.exceptions;
+        self.globals = definitions.globals;
+        self.data = definitions.data;
+    }
+
+    /// Sets the maximum size of a fragment to be inlined by `.inline`;
+    /// larger fragments are placed behind CALLREF instead
+    pub fn set_inline_max_bits(&mut self, bits: Option<usize>) {
+        self.inline_max_bits = bits;
+    }
+
+    /// Sets how fragments not fitting into the current cell are inlined
+    pub fn set_packing_policy(&mut self, policy: PackingPolicy) {
+        self.packing_policy = policy;
+    }
+
+    /// Installs the listener of the compilation events of this engine replacing the previous one
+    pub fn set_event_listener(&mut self, listener: impl Fn(&events::CompileEvent) + Send + Sync + 'static) {
+        self.listener = Some(Arc::new(listener));
+    }
+
+    pub fn clear_event_listener(&mut self) {
+        self.listener = None;
+    }
+
+    /// Emits the event built by the closure if anybody listens to it
+    pub(crate) fn emit(&self, event: impl FnOnce() -> events::CompileEvent) {
+        events::emit(self.listener.as_ref(), event)
+    }
+
+    /// Empty code reporting its cell splits to the listener of the engine
+    pub(crate) fn new_units(&self) -> Units {
+        Units::with_listener(self.listener.clone())
+    }
+
+    /// Adds a hook run by [`Engine::finalize`] over the cells of the source in the
+    /// order the hooks are added, e.g. to prepend `SETCP0` or a version marker
+    pub fn add_finalize_hook(&mut self, hook: impl Fn(&mut Units) + Send + Sync + 'static) {
+        self.finalize_hooks.push(Box::new(hook));
+    }
+
+    /// Fragments defined so far by name
+    pub fn fragments(&self) -> impl Iterator<Item = (&str, &Unit)> {
+        self.named_units.iter().map(|(name, unit)| (name.as_str(), unit))
+    }
+
     fn compile(&mut self, source: &str) -> Result<Units, CompileError> {
         self.compile_impl(source, false)
     }
 
     pub fn compile_toplevel(&mut self, source: &str) -> Result<Units, CompileError> {
-        self.compile_impl(source, true)
+        if self.ir.is_none() && lint::annotates_stack(source) {
+            self.check_stack_annotations(source)?;
+        }
+        if self.ir.is_none() && vars::declares_vars(source) {
+            let source = self.resolve_stack_vars(source)?;
+            return self.compile_resolved(&source)
+        }
+        self.compile_resolved(source)
+    }
+
+    /// Compiles the toplevel source with the stack variables resolved
+    fn compile_resolved(&mut self, source: &str) -> Result<Units, CompileError> {
+        self.emit(|| events::CompileEvent::UnitStarted { name: self.source_name.to_string() });
+        #[cfg(feature = "parallel")]
+        self.precompile_fragments(source);
+        let result = self.compile_impl(source, true);
+        self.precompiled.clear();
+        let mut units = result?;
+        self.finish_toplevel(&mut units)?;
+        Ok(units)
+    }
+
+    /// Code of the fragment compiled ahead if its body is the same
+    fn take_precompiled(&mut self, name: &str, body: &str) -> Option<(BuilderData, DbgNode)> {
+        match self.precompiled.remove(name) {
+            Some((text, builder, dbg, diagnostics)) if text == body => {
+                self.diagnostics.extend(diagnostics);
+                Some((builder, dbg))
+            }
+            _ => None
+        }
+    }
+
+    /// Compiles the toplevel source given line by line holding only the statement
+    /// being read in memory; a statement ends at the end of a line outside of blocks
+    /// and strings, so labels are resolved within a statement only.
+    /// The code returned is put into cells by [`Engine::finalize`]
+    pub fn feed_lines<S: AsRef<str>>(&mut self, lines: impl IntoIterator<Item = S>) -> Result<Units, CompileError> {
+        self.emit(|| events::CompileEvent::UnitStarted { name: self.source_name.to_string() });
+        let mut units = self.new_units();
+        let mut statement = String::new();
+        let mut depth = 0usize;
+        let mut in_string = false;
+        for line in lines {
+            let line = line.as_ref();
+            let parsed = format::split_line(line, in_string);
+            in_string = parsed.in_string;
+            for token in &parsed.tokens {
+                match token.as_str() {
+                    "{" => depth += 1,
+                    "}" => depth = depth.saturating_sub(1),
+                    _ => ()
+                }
+            }
+            statement.push_str(line);
+            statement.push('\n');
+            if depth == 0 && !in_string {
+                self.write_impl(&statement, true, &mut units)?;
+                statement.clear();
+            }
+        }
+        if !statement.is_empty() {
+            self.write_impl(&statement, true, &mut units)?;
+        }
+        self.finish_toplevel(&mut units)?;
+        Ok(units)
+    }
+
+    /// Completes the toplevel code with the prologue and the dispatcher of methods
+    fn finish_toplevel(&mut self, units: &mut Units) -> Result<(), CompileError> {
+        self.write_prologue(units)?;
+        self.dispatch_methods(units)?;
+        self.resolve_fragrefs(units)
+    }
+
+    /// Appends the dispatcher of methods left without `.dispatch` to the toplevel code
+    fn dispatch_methods(&mut self, units: &mut Units) -> Result<(), CompileError> {
+        let pos = match self.methods.values().map(|(_, pos)| pos).min_by_key(|pos| pos.line) {
+            Some(pos) => pos.clone(),
+            None => return Ok(())
+        };
+        complex::write_dispatcher(self, units, pos.clone())
+            .map_err(|e| CompileError::operation(pos.line, pos.column, ".METHOD", e).with_filename(pos.filename.to_string()))
+    }
+
+    /// Puts the compiled code into a cell tree reporting its completion
+    pub fn finalize(&mut self, mut units: Units) -> (BuilderData, DbgNode) {
+        for hook in &self.finalize_hooks {
+            hook(&mut units);
+        }
+        let (builder, dbg) = units.finalize();
+        let (builder, dedup_stats) = self.share_identical_cells(builder);
+        self.dedup_stats = dedup_stats;
+        debug_assert!(!self.has_fragrefs(&builder), "the code is finalized with unresolved .fragref");
+        self.report_finalized(&builder);
+        (builder, dbg)
+    }
+
+    fn report_finalized(&self, builder: &BuilderData) {
+        self.emit(|| events::CompileEvent::Finalized {
+            name: self.source_name.to_string(),
+            bits: builder.bits_used(),
+            refs: builder.references_used(),
+        });
+    }
+
+    /// Adds the procedures and the names of global variables defined to the debug map
+    pub fn annotate_dbg(&self, dbg: &mut DbgInfo) {
+        dbg.set_procs(self.procs.clone());
+        dbg.set_globals(self.globals.clone());
+    }
+
+    /// Positions of unreachable code removed by [`Opts::strip_dead_code`]
+    pub fn stripped_dead_code(&self) -> &[DbgPos] {
+        &self.stripped_dead_code
+    }
+
+    /// Compiles the source with the given options; optimizations rewrite
+    /// the parsed program which is then compiled from scratch
+    pub fn compile_with_opts(&mut self, source: &str, opts: Opts) -> Result<Units, CompileError> {
+        if !opts.optimize && !opts.strip_dead_code {
+            return self.compile_toplevel(source)
+        }
+        let definitions = self.definitions();
+        let source_name = self.source_name.to_string();
+        let mut ir = self.parse_ir(source)?;
+        self.restore(definitions);
+        vars::resolve_vars(&mut ir.instructions)?;
+        if opts.strip_dead_code {
+            let (code, removed) = optimize::strip_dead_code(ir.instructions);
+            ir.instructions = code;
+            for pos in removed {
+                self.diagnose(Severity::Warning, "dead-code", Position::from_dbgpos(&pos), "unreachable code is removed");
+                self.stripped_dead_code.push(pos);
+            }
+        }
+        if opts.optimize {
+            ir.instructions = optimize::optimize(ir.instructions);
+        }
+        self.reset(source_name);
+        self.compile_toplevel(&ir.to_source_located())
+    }
+
+    /// Compiles the source skipping toplevel fragments which fail to compile;
+    /// on failure returns the fragments that did compile along with all errors
+    pub fn compile_partial(&mut self, source: &str) -> Result<Units, PartialBuild> {
+        self.recover_fragments = true;
+        self.emit(|| events::CompileEvent::UnitStarted { name: self.source_name.to_string() });
+        let result = self.compile_impl(source, true)
+            .and_then(|mut units| self.finish_toplevel(&mut units).map(|_| units));
+        self.recover_fragments = false;
+        let mut errors = std::mem::take(&mut self.fragment_errors);
+        match result {
+            Ok(units) if errors.is_empty() => return Ok(units),
+            Ok(_) => (),
+            Err(e) => errors.push(e),
+        }
+        let fragments = self.named_units.iter()
+            .map(|(name, unit)| (name.clone(), unit.clone()))
+            .collect();
+        Err(PartialBuild { errors, fragments })
+    }
+
+    /// Compiles the source going on after errors; on failure returns all of them
+    pub fn compile_recovering(&mut self, source: &str) -> Result<Units, Vec<CompileError>> {
+        self.recover_errors = true;
+        self.emit(|| events::CompileEvent::UnitStarted { name: self.source_name.to_string() });
+        let result = self.compile_impl(source, true)
+            .and_then(|mut units| self.finish_toplevel(&mut units).map(|_| units));
+        self.recover_errors = false;
+        let mut errors = std::mem::take(&mut self.collected_errors);
+        match result {
+            Ok(units) if errors.is_empty() => return Ok(units),
+            Ok(_) => (),
+            Err(e) => errors.push(e),
+        }
+        Err(errors)
+    }
+
+    /// Records the error to go on compiling if errors are being collected
+    fn recover_syntax(&mut self, error: CompileError) -> Result<(), CompileError> {
+        if !self.recover_errors {
+            return Err(error)
+        }
+        self.collected_errors.push(error.with_filename(self.source_name.to_string()));
+        Ok(())
+    }
+
+    /// Records the error if errors are being collected or if it is in a toplevel
+    /// fragment being skipped in partial mode
+    fn recover(&mut self, toplevel: bool, command_ctx: &CommandContext, error: CompileError) -> Result<(), CompileError> {
+        if self.recover_errors {
+            self.dbgpos = None;
+            return self.recover_syntax(error)
+        }
+        if !toplevel || !self.recover_fragments || command_ctx.operation != ".FRAGMENT" {
+            return Err(error)
+        }
+        self.fragment_errors.push(error);
+        self.dbgpos = None;
+        Ok(())
+    }
+
+    /// Compiles the source and returns it as a list of instructions before encoding
+    pub fn parse_ir(&mut self, source: &str) -> Result<ir::Ir, CompileError> {
+        self.ir = Some(vec!(IrFrame::default()));
+        let result = self.compile_toplevel(source);
+        let mut root = self.ir.take().and_then(|mut frames| frames.pop()).unwrap_or_default();
+        result?;
+        Ok(ir::Ir::new(root.blocks.pop().unwrap_or_default()))
+    }
+
+    fn ir_take_blocks(&mut self) -> Vec<Vec<ir::IrInstruction>> {
+        match self.ir.as_mut().and_then(|frames| frames.last_mut()) {
+            Some(frame) => std::mem::take(&mut frame.blocks),
+            None => Vec::new()
+        }
+    }
+
+    fn ir_record(&mut self, operation: &str, par: &[Token], pos: DbgPos) {
+        if self.ir.is_none() {
+            return
+        }
+        let block_count = par.iter().filter(|t| t.block).count();
+        let mut blocks = self.ir_take_blocks();
+        // blocks which were not compiled as code by the command are kept as is
+        let compiled = blocks.len() == block_count;
+        blocks.reverse();
+        let args = par.iter().map(|t| match (t.block, compiled) {
+            (false, _) => ir::IrArg::Token(t.token.to_string()),
+            (true, true) => ir::IrArg::Block(blocks.pop().unwrap_or_default()),
+            (true, false) => ir::IrArg::Raw(t.token.to_string()),
+        }).collect();
+        if let Some(frame) = self.ir.as_mut().and_then(|frames| frames.last_mut()) {
+            frame.instructions.push(ir::IrInstruction { name: operation.to_string(), args, pos: Some(pos) });
+        }
     }
 
     fn compile_impl(&mut self, source: &str, toplevel: bool) -> Result<Units, CompileError> {
-        let mut ret = Units::new();
+        let mut units = self.new_units();
+        self.write_impl(source, toplevel, &mut units)?;
+        Ok(units)
+    }
+
+    fn write_impl(&mut self, source: &str, toplevel: bool, destination: &mut dyn Writer) -> Result<(), CompileError> {
+        if let Some(frames) = self.ir.as_mut() {
+            frames.push(IrFrame::default());
+        }
+        let outer = std::mem::replace(&mut self.toplevel, toplevel);
+        let outer_code_page = self.code_page;
+        let result = self.compile_labelled(source, toplevel, destination);
+        self.select_code_page(outer_code_page);
+        self.toplevel = outer;
+        if let Some(frames) = self.ir.as_mut() {
+            let frame = frames.pop().unwrap_or_default();
+            if let Some(parent) = frames.last_mut() {
+                parent.blocks.push(frame.instructions);
+            }
+        }
+        result
+    }
+
+    fn compile_source(&mut self, source: &str, toplevel: bool) -> Result<Units, CompileError> {
+        let mut units = self.new_units();
+        self.write_source(source, toplevel, &mut units)?;
+        Ok(units)
+    }
+
+    fn write_source(&mut self, source: &str, toplevel: bool, destination: &mut dyn Writer) -> Result<(), CompileError> {
         let mut par = Vec::new();
-        let mut acc = (0, 0);
         let mut expect_comma = false;
         let mut comma_found = false;
-        let mut was_comma = false; // was comma before token
-        let mut was_newline = false; // was line break before token
-        let mut in_block = 0;
-        let mut in_comment = false;
+        let mut was_newline = false; // was line break since the command
+        let mut unclosed = None;
         let mut command_ctx = CommandContext::default();
         let mut was_dot_inline = false;
-        for ch in source.chars().chain(" ".chars()) {
-            let mut newline_found = false;
-            // Adjust line/char information
-            let mut x = self.char_no;
-            let y = self.line_no;
-            if ch == '\n' {
-                self.line_no += 1;
-                self.char_no = 1
-            } else {
-                self.char_no += 1
-            }
-            let (s0, s1) = acc;
-            let new_s1 = s1 + ch.len_utf8();
-            // Process internal block if any
-            if in_block > 0 {
-                if ch == '{' {
-                    in_block += 1
-                } else if ch == '}' {
-                    in_block -= 1
-                }
-                if in_block == 0 {
-                    par.push(Token::new(y, x, &source[s0..s1], comma_found));
-                    acc = (new_s1, new_s1)
-                } else {
-                    acc = (s0, new_s1)
-                }
-                continue;
-            }
-            // Process comment if any
-            if in_comment {
-                if (ch == '\r') || (ch == '\n') {
-                    in_comment = false;
+        let mut token = String::new();
+        let mut lexer = Lexer::new(source, self.line_no, self.char_no);
+        while let Some(lexeme) = lexer.next() {
+            let (line_no, char_no) = lexer.position();
+            self.set_pos(line_no, char_no);
+            let (y, x) = (lexeme.line, lexeme.column);
+            let word = match lexeme.kind {
+                TokenKind::Word => lexeme.text,
+                TokenKind::Newline => {
                     was_newline = true;
+                    continue
                 }
-                acc = (new_s1, new_s1);
-                continue;
-            }
-            // Analyze char
-            if Engine::is_whitespace(ch) {
-                if (ch == '\r') || (ch == '\n') {
-                    newline_found = true;
-                    was_newline = true;
-                }
-                acc = (new_s1, new_s1);
-                if s0 == s1 {
-                    continue;
-                }
-            } else if ch == ';' {
-                acc = (new_s1, new_s1);
-                in_comment = true;
-                if s0 == s1 {
-                    continue;
-                }
-            } else if ch == ',' {
-                if !expect_comma {
-                    return Err(CompileError::syntax(y, x, ",").with_filename(self.source_name.clone()))
+                TokenKind::Comma => {
+                    if !expect_comma {
+                        self.recover_syntax(CompileError::syntax(y, x, ",").with_token(",").with_filename(self.source_name.to_string()))?;
+                        continue
+                    }
+                    expect_comma = false;
+                    comma_found = true;
+                    continue
                 }
-                acc = (new_s1, new_s1);
-                expect_comma = false;
-                comma_found = true;
-                if s0 == s1 {
-                    continue;
+                TokenKind::Block => {
+                    if lexeme.glued || expect_comma || !command_ctx.has_command() {
+                        // the block is skipped after the error
+                        self.recover_syntax(CompileError::syntax(y, x, '{').with_token("{").with_filename(self.source_name.to_string()))?;
+                        continue
+                    }
+                    command_ctx.line_no_par = y;
+                    command_ctx.char_no_par = x + 1;
+                    par.push(Token::block(y, x, lexeme.text, comma_found));
+                    continue
                 }
-            } else if ch == '{' {
-                if expect_comma || !command_ctx.has_command() {
-                    return Err(CompileError::syntax(y, x, ch).with_filename(self.source_name.clone()))
+                TokenKind::Unexpected => {
+                    let error = match lexeme.text {
+                        "}" => CompileError::syntax(y, x, '}').with_token("}"),
+                        text => CompileError::syntax(y, x, "Bad char").with_token(text),
+                    };
+                    self.recover_syntax(error.with_filename(self.source_name.to_string()))?;
+                    continue
                 }
-                acc = (new_s1, new_s1);
-                in_block = 1;
-                command_ctx.line_no_par = self.line_no;
-                command_ctx.char_no_par = self.char_no;
-                continue;
-            } else if ch == '}' {
-                return Err(CompileError::syntax(y, x, ch).with_filename(self.source_name.clone()))
-            } else if ch.is_ascii_alphanumeric() || (ch == '-') || (ch == '_') || (ch == '.') ||
-                (ch == '/') || (ch == '\\') || (ch == '$') || (ch == '@') {
-                acc = (s0, new_s1);
-                if s0 == s1 { //start of new token
-                    was_comma = comma_found;
-                    comma_found = false;
-                    expect_comma = true
+                TokenKind::Unclosed => {
+                    unclosed = Some(lexeme.text);
+                    continue
                 }
-                continue;
-            } else { // TODO: (message for the owner: please write descriptive explanation)
-                return Err(CompileError::syntax(y, x, "Bad char").with_filename(self.source_name.clone()))
-            }
-            // Token extracted
-            let token = source[s0..s1].to_ascii_uppercase();
+            };
+            let was_comma = std::mem::take(&mut comma_found); // was comma before token
+            expect_comma = true;
+            token.clear();
+            token.push_str(word);
+            token.make_ascii_uppercase();
             log::trace!(target: "tvm", "--> {}\n", token);
-            x -= token.chars().count();
             let rule = if was_dot_inline {
                 // Do not try matching the token if the previous one is .inline,
                 // otherwise `.inline setCode` won't work since setCode gets matched as an insn
                 None
             } else {
-                self.handlers.get(token.as_str())
+                self.handlers.get(token.as_str()).copied()
             };
-            was_dot_inline = token == ".INLINE";
-            match rule {
+            was_dot_inline = matches!(token.as_str(), ".INLINE" | ".MACRO" | ".EXPAND" | ".DEFINE" | ".METHOD-ID" | ".EXCEPTION" | ".GLOBAL" | ".INCLUDE" | ".FRAGREF");
+            let new_rule = match rule {
+                Some(rule) => rule,
+                None if command_ctx.has_command() => {
+                    par.push(Token::new(y, x, word, was_comma));
+                    continue
+                }
                 None => {
-                    if command_ctx.has_command() {
-                        par.push(Token::new(y, x, &source[s0..s1], was_comma));
-                        was_comma = false;
-                        continue
-                    } else {
-                        return Err(CompileError::unknown(y, x, &token).with_filename(self.source_name.clone()))
-                    }
+                    let error = CompileError::unknown(y, x, &token)
+                        .with_token(word)
+                        .with_suggestions(self.suggest(&token));
+                    self.recover_syntax(error.with_filename(self.source_name.to_string()))?;
+                    continue
                 }
-                Some(&new_rule) => {
-                    if !toplevel && token == ".FRAGMENT" {
-                        return Err(CompileError::syntax(y, x, ".fragment can be defined at toplevel scope only"))
-                    }
-                    match command_ctx.compile(&mut ret, &mut par, self) {
-                        Ok(_) => {
-                            command_ctx = CommandContext::new(token, x, y, Some(new_rule));
-                            expect_comma = false;
-                            was_comma = false;
-                            was_newline = newline_found;
-                        }
-                        Err(e @ CompileError::Operation(_, _, OperationError::MissingRequiredParameters)) => {
-                            if was_newline { // it seems realy new command - rturn correct missing params error
-                                return Err(e)
-                            } else {
-                                par.push(Token::new(y, x, &source[s0..s1], was_comma));
-                                was_comma = false;
-                            }
-                        }
-                        Err(e) => return Err(e)
-                    }
+            };
+            if !toplevel && token == ".FRAGMENT" {
+                self.recover_syntax(CompileError::syntax(y, x, ".fragment can be defined at toplevel scope only"))?;
+            }
+            if !toplevel && token == ".METHOD" {
+                self.recover_syntax(CompileError::syntax(y, x, ".method can be defined at toplevel scope only"))?;
+            }
+            if !toplevel && token == ".PROC" {
+                self.recover_syntax(CompileError::syntax(y, x, ".proc can be defined at toplevel scope only"))?;
+            }
+            if !toplevel && token == ".DATA" {
+                self.recover_syntax(CompileError::syntax(y, x, ".data can be defined at toplevel scope only"))?;
+            }
+            match command_ctx.compile(destination, &mut par, self) {
+                Ok(_) => (),
+                // after a line break it seems realy new command - report missing params error
+                Err(CompileError::Operation(_, _, OperationError::MissingRequiredParameters)) if !was_newline => {
+                    par.push(Token::new(y, x, word, was_comma));
+                    continue
+                }
+                Err(e) => {
+                    self.recover(toplevel, &command_ctx, e)?;
+                    par.clear();
                 }
             }
+            command_ctx = CommandContext::new(token.clone(), x, y, Some(new_rule));
+            expect_comma = false;
+            was_newline = false;
         }
         // Compile last pending command if any
-        command_ctx.compile(&mut ret, &mut par, self)?;
-        if in_block != 0 {
-            return Err(CompileError::syntax(self.line_no, 0, "Missing }").with_filename(self.source_name.clone()))
+        if let Err(e) = command_ctx.compile(destination, &mut par, self) {
+            self.recover(toplevel, &command_ctx, e)?;
+        }
+        match unclosed {
+            Some("{") => self.recover_syntax(CompileError::syntax(self.line_no, 0, "Missing }").with_filename(self.source_name.to_string()))?,
+            Some(_) => self.recover_syntax(CompileError::syntax(self.line_no, 0, "Missing \"").with_filename(self.source_name.to_string()))?,
+            None => ()
         }
-        Ok(ret)
+        Ok(())
     }
 
+
+}
+
+/// Artifacts of a compilation which failed in some of the fragments
+#[derive(Debug)]
+pub struct PartialBuild {
+    pub errors: Vec<CompileError>,
+    /// fragments compiled successfully
+    pub fragments: BTreeMap<String, Unit>,
+}
+
+impl PartialBuild {
+    /// Debug info and sizes of the compiled fragments, marked as incomplete
+    pub fn to_json(&self) -> ever_block::Result<serde_json::Value> {
+        let mut fragments = serde_json::Map::new();
+        for (name, unit) in &self.fragments {
+            let cells = unit.cells()?.collect::<ever_block::Result<Vec<_>>>()?.len();
+            let (code, dbg) = unit.clone().finalize();
+            fragments.insert(name.clone(), serde_json::json!({
+                "bits": code.remaining_bits(),
+                "refs": code.remaining_references(),
+                "cells": cells,
+                "dbg": serde_json::to_value(&dbg).map_err(|e| ever_block::error!("{}", e))?,
+            }));
+        }
+        let errors = self.errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        Ok(serde_json::json!({
+            "incomplete": true,
+            "errors": errors,
+            "fragments": fragments,
+        }))
+    }
 }
 
 pub fn compile_code_to_builder(code: &str) -> Result<BuilderData, CompileError> {
     log::trace!(target: "tvm", "begin compile\n");
-    Ok(Engine::new("").compile_toplevel(code)?.finalize().0)
+    let mut engine = Engine::new("");
+    let units = engine.compile_toplevel(code)?;
+    Ok(engine.finalize(units).0)
 }
 
 pub fn compile_code(code: &str) -> Result<SliceData, CompileError> {
@@ -394,6 +1008,25 @@ pub fn compile_code(code: &str) -> Result<SliceData, CompileError> {
     }
 }
 
+pub fn compile_code_with_opts(code: &str, opts: Opts) -> Result<SliceData, CompileError> {
+    let mut engine = Engine::new("");
+    let units = engine.compile_with_opts(code, opts)?;
+    let code = engine.finalize(units).0;
+    match SliceData::load_builder(code) {
+        Ok(code) => Ok(code),
+        Err(_) => Err(CompileError::unknown(0, 0, "failure while convert BuilderData to cell"))
+    }
+}
+
+/// Compiles the code reporting all errors found instead of the first one
+pub fn compile_code_recovering(code: &str) -> Result<SliceData, Vec<CompileError>> {
+    let mut engine = Engine::new("");
+    let units = engine.compile_recovering(code)?;
+    let code = engine.finalize(units).0;
+    SliceData::load_builder(code)
+        .map_err(|_| vec!(CompileError::unknown(0, 0, "failure while convert BuilderData to cell")))
+}
+
 pub fn compile_code_to_cell(code: &str) -> Result<Cell, CompileError> {
     log::trace!(target: "tvm", "begin compile\n");
     let code = compile_code_to_builder(code)?;
@@ -403,16 +1036,124 @@ pub fn compile_code_to_cell(code: &str) -> Result<Cell, CompileError> {
     }
 }
 
+/// Compiles the code into a cell returning its representation hash and debug info,
+/// e.g. to compare the code with the one deployed
+pub fn compile_code_with_hash(code: &str) -> Result<(Cell, UInt256, DbgInfo), CompileError> {
+    let mut engine = Engine::new("");
+    let units = engine.compile_toplevel(code)?;
+    let (builder, dbg) = engine.finalize(units);
+    let cell = builder.into_cell()
+        .map_err(|_| CompileError::unknown(0, 0, "failure while convert BuilderData to cell"))?;
+    let hash = cell.repr_hash();
+    let mut dbg = DbgInfo::from(cell.clone(), dbg);
+    engine.annotate_dbg(&mut dbg);
+    Ok((cell, hash, dbg))
+}
+
 pub fn compile_code_debuggable(source: &str, source_name: &str) -> Result<(SliceData, DbgInfo), CompileError> {
     log::trace!(target: "tvm", "begin compile\n");
-    let (builder, dbg) = Engine::new(source_name).compile_toplevel(source)?.finalize();
+    let mut engine = Engine::new(source_name);
+    let units = engine.compile_toplevel(source)?;
+    let (builder, dbg) = engine.finalize(units);
     let cell = builder.into_cell().unwrap();
     match SliceData::load_cell(cell.clone()) {
         Ok(code) => {
-            let dbg_info = DbgInfo::from(cell, dbg);
+            let mut dbg_info = DbgInfo::from(cell, dbg);
+            engine.annotate_dbg(&mut dbg_info);
             Ok((code, dbg_info))
         }
         Err(_) => Err(CompileError::unknown(0, 0, "failure while convert BuilderData to cell"))
     }
 }
 
+/// Code cell and debug info of a compiled source
+#[derive(Debug)]
+pub struct CompilationResult {
+    pub name: String,
+    pub code: Cell,
+    pub dbg: DbgInfo,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Compiles the sources one by one reusing a single engine
+fn compile_chunk<S: AsRef<str>>(chunk: &[(String, S)]) -> Vec<Result<CompilationResult, CompileError>> {
+    let mut engine = Engine::new("");
+    chunk.iter().map(|(name, code)| {
+        engine.reset_all(name.clone());
+        let units = engine.compile_toplevel(code.as_ref())?;
+        let (builder, dbg) = engine.finalize(units);
+        let code = builder.into_cell()
+            .map_err(|_| CompileError::unknown(0, 0, "failure while convert BuilderData to cell"))?;
+        let mut dbg = DbgInfo::from(code.clone(), dbg);
+        engine.annotate_dbg(&mut dbg);
+        let diagnostics = engine.take_diagnostics();
+        Ok(CompilationResult { name: name.clone(), code, dbg, diagnostics })
+    }).collect()
+}
+
+/// Compiles many independent sources given as (name, code) pairs.
+/// The work is spread over the available cores with one engine per thread
+/// reused for all its sources, or done in the current thread without the
+/// `threads` feature; results are returned in the order of the sources
+pub fn compile_many<I, N, S>(sources: I) -> Vec<Result<CompilationResult, CompileError>>
+where
+    I: IntoIterator<Item = (N, S)>,
+    N: Into<String>,
+    S: AsRef<str> + Sync,
+{
+    let sources = sources.into_iter().map(|(name, code)| (name.into(), code)).collect::<Vec<_>>();
+    if sources.is_empty() {
+        return Vec::new()
+    }
+    #[cfg(not(feature = "threads"))]
+    return compile_chunk(&sources);
+    #[cfg(feature = "threads")]
+    {
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk_size = sources.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let workers = sources.chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || compile_chunk(chunk)))
+                .collect::<Vec<_>>();
+            workers.into_iter()
+                .flat_map(|worker| worker.join().expect("compilation thread panicked"))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_does_not_leak_state() {
+        let results = compile_chunk(&[
+            ("a".to_string(), ".global counter, 10\n.define N, 5\n.fragment f, {\n  INC\n}\nPUSHINT N\n.inline f"),
+            ("b".to_string(), ".inline f"),
+            ("c".to_string(), "PUSHINT N"),
+        ]);
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(first.dbg.globals().get("counter"), Some(&10));
+        assert!(matches!(results[1], Err(CompileError::Operation(_, _, OperationError::FragmentIsNotDefined(_)))));
+        assert!(results[2].is_err());
+    }
+
+    #[test]
+    fn methods_survive_reparsing() {
+        let method = ".method 1, main, {\n  INC\n}\n";
+        let hash = |engine: &mut Engine, units: Units| engine.finalize(units).0.into_cell().unwrap().repr_hash();
+
+        let source = format!("{}DEC", method);
+        let plain = compile_code_to_cell(&source).unwrap().repr_hash();
+        let opts = Opts { optimize: true, strip_dead_code: true };
+        let mut engine = Engine::new("");
+        let units = engine.compile_with_opts(&source, opts).unwrap();
+        assert_eq!(hash(&mut engine, units), plain);
+
+        let plain = compile_code_to_cell(&format!("{}PUSH s0", method)).unwrap().repr_hash();
+        let mut engine = Engine::new("");
+        let units = engine.compile_toplevel(&format!("{}.vars x\nPUSH $x", method)).unwrap();
+        assert_eq!(hash(&mut engine, units), plain);
+    }
+}
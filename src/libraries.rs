@@ -0,0 +1,77 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Public libraries **********************************************************
+//
+// Code refers to a library by a library reference cell, e.g. the one written
+// by `.library-cell`, holding the hash of the library code. Deploying the
+// contract takes a dictionary of these libraries by their hashes.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use serde::Serialize;
+use ever_block::{error, Cell, CellType, Result, SimpleLib, StateInitLib, UInt256};
+
+/// Hashes of the libraries referred to in the code tree
+pub fn library_hashes(code: &Cell) -> Result<BTreeSet<UInt256>> {
+    let mut hashes = BTreeSet::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec!(code.clone());
+    while let Some(cell) = stack.pop() {
+        if !visited.insert(cell.repr_hash()) {
+            continue
+        }
+        if cell.cell_type() == CellType::LibraryReference {
+            let data = cell.data();
+            if data.len() < 33 {
+                return Err(error!("library reference cell {} is too short", cell.repr_hash().to_hex_string()))
+            }
+            hashes.insert(UInt256::from_slice(&data[1..33]));
+        }
+        for i in 0..cell.references_count() {
+            stack.push(cell.reference(i)?);
+        }
+    }
+    Ok(hashes)
+}
+
+/// Libraries of a contract with the ones found among the available cells
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LibraryManifest {
+    /// library hash -> whether its code is in the dictionary
+    pub libraries: BTreeMap<String, bool>,
+    /// hash of the dictionary cell if any library is in it
+    pub dict_hash: Option<String>,
+}
+
+/// Builds the dictionary of public libraries referred to in the code taking
+/// their code from the available cells; unresolved ones are left out of it
+pub fn build_library_dict(code: &Cell, available: &[Cell]) -> Result<(Option<Cell>, LibraryManifest)> {
+    let available = available.iter()
+        .map(|cell| (cell.repr_hash(), cell))
+        .collect::<BTreeMap<_, _>>();
+    let mut dict = StateInitLib::default();
+    let mut manifest = LibraryManifest::default();
+    for hash in library_hashes(code)? {
+        let resolved = match available.get(&hash) {
+            Some(cell) => {
+                dict.set(&hash, &SimpleLib::new((*cell).clone(), true))?;
+                true
+            }
+            None => false
+        };
+        manifest.libraries.insert(hash.to_hex_string(), resolved);
+    }
+    let root = dict.root().cloned();
+    manifest.dict_hash = root.as_ref().map(|cell| cell.repr_hash().to_hex_string());
+    Ok((root, manifest))
+}
@@ -0,0 +1,158 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Linking *******************************************************************
+//
+// Fragments built by `Engine::build` stay visible to the units built after it.
+// Units added with `Engine::add_unit` may also refer to fragments defined by
+// units added later: `Engine::link` builds them in passes, postponing the
+// units which `.inline` or `.fragref` a fragment nobody has defined yet,
+// until all are built or no progress is made. A postponed unit is tried again
+// only once the fragment it stopped at gets defined. Whatever a postponed
+// attempt has defined or redefined is rolled back, so the next attempt starts
+// clean.
+
+use crate::{CompileError, Engine, OperationError, Position, Unit};
+
+pub(crate) struct PendingUnit {
+    name: Option<String>,
+    source_name: String,
+    source: String,
+}
+
+/// Finds the innermost error of an `.inline` or a `.fragref` referring to an undefined fragment
+fn undefined_fragment(error: &CompileError) -> Option<(Position, String)> {
+    match error {
+        CompileError::Operation(_, _, OperationError::Nested(inner)) => undefined_fragment(inner),
+        CompileError::Operation(position, _, OperationError::FragmentIsNotDefined(name)) |
+        CompileError::UndefinedFragment(position, name) => Some((position.clone(), name.clone())),
+        _ => None
+    }
+}
+
+impl Engine {
+    /// Adds the source to be built by [`Engine::link`]; it may `.inline` fragments
+    /// of any other unit, including those added after it
+    pub fn add_unit(&mut self, name: Option<String>, source_name: &str, source: &str) {
+        self.pending_units.push(PendingUnit {
+            name,
+            source_name: source_name.to_string(),
+            source: source.to_string(),
+        });
+    }
+
+    /// Source names of the units waiting for [`Engine::link`]
+    pub fn pending_units(&self) -> impl Iterator<Item = &str> {
+        self.pending_units.iter().map(|unit| unit.source_name.as_str())
+    }
+
+    /// Builds the units added with [`Engine::add_unit`] in an order satisfying their
+    /// references to each other's fragments; returns the units in the order they were added
+    /// or the references to fragments which none of them defines
+    pub fn link(&mut self) -> Result<Vec<Unit>, Vec<CompileError>> {
+        let pending = std::mem::take(&mut self.pending_units);
+        let mut built: Vec<Option<Unit>> = vec!(None; pending.len());
+        // the undefined fragment each postponed unit stopped at
+        let mut waiting: Vec<Option<(Position, String)>> = vec!(None; pending.len());
+        loop {
+            let mut progress = false;
+            for (index, unit) in pending.iter().enumerate() {
+                if built[index].is_some() {
+                    continue
+                }
+                if let Some((_, name)) = &waiting[index] {
+                    if !self.named_units.contains_key(name) {
+                        continue
+                    }
+                }
+                self.reset(unit.source_name.clone());
+                let definitions = self.definitions();
+                match self.build(unit.name.clone(), &unit.source) {
+                    Ok(result) => {
+                        built[index] = Some(result);
+                        waiting[index] = None;
+                        progress = true;
+                    }
+                    Err(error) => {
+                        self.restore(definitions);
+                        let (mut position, name) = undefined_fragment(&error).ok_or_else(|| vec!(error))?;
+                        position.filename = unit.source_name.clone();
+                        waiting[index] = Some((position, name));
+                    }
+                }
+            }
+            if waiting.iter().all(Option::is_none) {
+                return Ok(built.into_iter().flatten().collect())
+            }
+            if !progress {
+                let dangling = waiting.into_iter().flatten()
+                    .map(|(position, name)| CompileError::UndefinedFragment(position, name));
+                return Err(dangling.collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment_hash(engine: &Engine, name: &str) -> ever_block::UInt256 {
+        engine.named_units[name].clone().finalize().0.into_cell().unwrap().repr_hash()
+    }
+
+    #[test]
+    fn link_order() {
+        let mut engine = Engine::new("");
+        engine.add_unit(None, "a.code", ".inline g\nNOP");
+        engine.add_unit(None, "b.code", ".define N 1\n.inline h");
+        engine.add_unit(None, "c.code", ".fragment g, {\n  INC\n}\n.fragment h, {\n  DEC\n}");
+        assert_eq!(engine.link().unwrap().len(), 3);
+        assert_eq!(engine.pending_units().count(), 0);
+    }
+
+    #[test]
+    fn link_dangling() {
+        let mut engine = Engine::new("");
+        engine.define_const("N", "1");
+        engine.add_unit(None, "a.code", ".inline missing");
+        engine.add_unit(None, "b.code", ".fragment g, {\n  INC\n}");
+        let errors = engine.link().unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [CompileError::UndefinedFragment(position, name)] if name == "missing" && position.filename == "a.code"
+        ));
+        assert_eq!(engine.constants.get("N").map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn link_rolls_back() {
+        let mut engine = Engine::new("");
+        engine.build(None, ".fragment g, {\n  INC\n}").unwrap();
+        let original = fragment_hash(&engine, "g");
+
+        // the postponed unit defines N and f again when it is retried
+        engine.add_unit(None, "a.code", ".define N 1\n.fragment f, {\n  NOP\n}\n.inline h");
+        engine.add_unit(None, "b.code", ".fragment h, {\n  DEC\n}");
+        assert_eq!(engine.link().unwrap().len(), 2);
+        assert_eq!(engine.constants.get("N").map(String::as_str), Some("1"));
+
+        // the fragment overwritten by a failed unit comes back
+        engine.add_unit(None, "c.code", ".fragment g, {\n  DEC\n}");
+        assert!(matches!(
+            engine.link().unwrap_err().as_slice(),
+            [CompileError::Operation(_, _, OperationError::FragmentIsAlreadyDefined(name))] if name == "g"
+        ));
+        assert_eq!(fragment_hash(&engine, "g"), original);
+    }
+}
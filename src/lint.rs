@@ -0,0 +1,319 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Checks of assembly sources for common mistakes
+//!
+//! The source is parsed into IR, so the diagnostics of the compilation itself,
+//! like reserved exception codes, are reported along with the ones of the lint:
+//! success codes thrown, DUP followed by DROP, code following unconditional
+//! returns, code not starting with SETCP and stack comments like `;; a b -> c`
+//! disagreeing with the number of values the instruction takes and leaves.
+//!
+//! A structured comment `; stack: a b -> c` annotates the instructions of its
+//! block starting at its line up to the next such comment: they are run by the
+//! stack analysis on the values before the arrow and have to leave the values
+//! after it, a stack-mismatch error is reported otherwise. The compilation
+//! checks these comments too and fails with [`CompileError::StackMismatch`].
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    CompileError, DbgPos, Diagnostic, Engine, Position, Severity,
+    format::split_line,
+    ir::{IrArg, IrInstruction},
+    optimize::strip_dead_code,
+    stack::{sequence_depth, stack_effect, StackIssue},
+};
+
+struct Linter<'a> {
+    source_name: &'a str,
+    diagnostics: Vec<Diagnostic>,
+    /// trailing comments by line
+    comments: HashMap<usize, String>,
+    /// number of instructions by line
+    instructions: HashMap<usize, usize>,
+    /// `; stack:` comments by line, numbers of values before and after
+    annotations: BTreeMap<usize, (usize, usize)>,
+}
+
+fn is_plain(insn: &IrInstruction, name: &str) -> bool {
+    insn.name == name && insn.args.is_empty()
+}
+
+fn blocks(insn: &IrInstruction) -> impl Iterator<Item = &Vec<IrInstruction>> {
+    insn.args.iter().filter_map(|arg| match arg {
+        IrArg::Block(block) => Some(block),
+        _ => None
+    })
+}
+
+/// Numbers of values before and after in a stack effect like `a b -> c`
+fn parse_stack_effect(effect: &str) -> Option<(usize, usize)> {
+    let (before, after) = effect.split_once("->")?;
+    let words = |text: &str| {
+        let list = text.split_whitespace().collect::<Vec<_>>();
+        list.iter()
+            .all(|word| word.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '\''))
+            .then_some(list.len())
+    };
+    Some((words(before)?, words(after)?))
+}
+
+/// Numbers of values before and after in a comment like `;; a b -> c`
+fn parse_stack_comment(comment: &str) -> Option<(usize, usize)> {
+    parse_stack_effect(comment.trim_start_matches(';'))
+}
+
+/// Numbers of values before and after in a comment like `; stack: a b -> c`
+fn parse_stack_annotation(comment: &str) -> Option<(usize, usize)> {
+    parse_stack_effect(comment.trim_start_matches(';').trim_start().strip_prefix("stack:")?)
+}
+
+/// Last line of the instruction along with its blocks
+fn last_line(insn: &IrInstruction) -> usize {
+    let line = insn.pos.as_ref().map_or(0, |pos| pos.line);
+    blocks(insn).flatten().map(last_line).fold(line, usize::max)
+}
+
+impl Linter<'_> {
+    fn diagnose<S: ToString>(&mut self, severity: Severity, code: &'static str, pos: &DbgPos, message: S) {
+        self.diagnostics.push(Diagnostic::new(severity, code, Position::from_dbgpos(pos), message));
+    }
+
+    /// Line of the instruction if it comes from the linted source itself
+    fn own_line(&self, insn: &IrInstruction) -> Option<usize> {
+        insn.pos.as_ref()
+            .filter(|pos| &*pos.filename == self.source_name && pos.inlined_at.is_empty())
+            .map(|pos| pos.line)
+    }
+
+    fn count_lines(&mut self, code: &[IrInstruction]) {
+        for insn in code {
+            if let Some(line) = self.own_line(insn) {
+                *self.instructions.entry(line).or_default() += 1;
+            }
+            for block in blocks(insn) {
+                self.count_lines(block);
+            }
+        }
+    }
+
+    fn check_block(&mut self, code: &[IrInstruction], start: usize) {
+        self.check_annotations(code, start);
+        for (i, insn) in code.iter().enumerate() {
+            let pos = insn.pos.clone().unwrap_or_default();
+            let exit_code = match (insn.name.as_str(), insn.args.first()) {
+                ("THROW" | "THROWIF" | "THROWIFNOT", Some(IrArg::Token(token))) => token.parse::<u32>().ok(),
+                _ => None
+            };
+            if let Some(exit_code @ 0..=1) = exit_code {
+                self.diagnose(Severity::Warning, "throw-code", &pos,
+                    format!("exception code {} is an exit code of success", exit_code));
+            }
+            if is_plain(insn, "DROP") && i > 0 && is_plain(&code[i - 1], "DUP") {
+                let pos = code[i - 1].pos.clone().unwrap_or_default();
+                self.diagnose(Severity::Warning, "dup-drop", &pos, "DUP followed by DROP does nothing");
+            }
+            self.check_stack_comment(insn, &pos);
+            let line = self.own_line(insn).unwrap_or(start);
+            for block in blocks(insn) {
+                self.check_block(block, line);
+            }
+        }
+    }
+
+    fn check_stack_comment(&mut self, insn: &IrInstruction, pos: &DbgPos) {
+        // a comment is attributed to the only instruction of its line
+        let line = match self.own_line(insn) {
+            Some(line) if self.instructions.get(&line) == Some(&1) => line,
+            _ => return
+        };
+        let (before, after) = match self.comments.get(&line).and_then(|c| parse_stack_comment(c)) {
+            Some(effect) => effect,
+            None => return
+        };
+        let (pops, pushes) = match stack_effect(insn) {
+            Some(effect) => effect,
+            None => return
+        };
+        // the comment may show values below the ones the instruction takes
+        if before < pops || after as i64 - before as i64 != pushes as i64 - pops as i64 {
+            self.diagnose(Severity::Warning, "stack-comment", pos, format!(
+                "stack comment shows {} -> {} values, {} takes {} and leaves {}",
+                before, after, insn.name, pops, pushes
+            ));
+        }
+    }
+
+    /// Index of the instruction of the block the annotation at the line starts at
+    fn annotated(&self, code: &[IrInstruction], start: usize, line: usize) -> Option<usize> {
+        if line <= start {
+            return None
+        }
+        let index = code.iter().position(|insn| self.own_line(insn).map_or(false, |l| l >= line))?;
+        // the annotation is inside a block of the previous instruction
+        match index.checked_sub(1).map(|i| last_line(&code[i]) >= line) {
+            Some(true) => None,
+            _ => Some(index),
+        }
+    }
+
+    /// Runs the instructions following each `; stack:` comment of the block up to the next one
+    fn check_annotations(&mut self, code: &[IrInstruction], start: usize) {
+        let mut annotated = self.annotations.iter()
+            .filter_map(|(line, effect)| Some((self.annotated(code, start, *line)?, *effect)))
+            .collect::<Vec<_>>();
+        // of the annotations of the same instruction the last one counts
+        annotated.reverse();
+        annotated.dedup_by_key(|(index, _)| *index);
+        annotated.reverse();
+        for (k, (index, (before, after))) in annotated.iter().enumerate() {
+            let end = annotated.get(k + 1).map_or(code.len(), |(next, _)| *next);
+            let pos = code[*index].pos.clone().unwrap_or_default();
+            let (depth, entries) = sequence_depth(&code[*index..end], *before);
+            let underflow = entries.iter().find(|entry| matches!(entry.issue, StackIssue::Underflow { .. }));
+            if let Some(entry) = underflow {
+                self.diagnose(Severity::Error, "stack-mismatch", &entry.pos, format!(
+                    "stack comment shows {} values before, the code takes more: {}: {}",
+                    before, entry.instruction, entry.issue
+                ));
+            } else if let Some(depth) = depth.filter(|depth| depth != after) {
+                self.diagnose(Severity::Error, "stack-mismatch", &pos, format!(
+                    "stack comment shows {} -> {} values, the code leaves {}",
+                    before, after, depth
+                ));
+            }
+        }
+    }
+
+    /// Checks the `; stack:` comments of the block and of the blocks nested in it
+    fn check_annotated_block(&mut self, code: &[IrInstruction], start: usize) {
+        self.check_annotations(code, start);
+        for insn in code {
+            let line = self.own_line(insn).unwrap_or(start);
+            for block in blocks(insn) {
+                self.check_annotated_block(block, line);
+            }
+        }
+    }
+
+    fn check_entry(&mut self, code: &[IrInstruction]) {
+        let first = code.iter().find(|insn| !insn.name.starts_with('.'));
+        if let Some(insn) = first.filter(|insn| !insn.name.starts_with("SETCP")) {
+            let pos = insn.pos.clone().unwrap_or_default();
+            self.diagnose(Severity::Note, "missing-setcp", &pos, "code does not start with SETCP");
+        }
+    }
+}
+
+/// Structured stack comments of the source by line
+fn annotations(comments: &HashMap<usize, String>) -> BTreeMap<usize, (usize, usize)> {
+    comments.iter()
+        .filter_map(|(line, comment)| Some((*line, parse_stack_annotation(comment)?)))
+        .collect()
+}
+
+/// Trailing comments of the source by line
+fn comments(source: &str) -> HashMap<usize, String> {
+    let mut comments = HashMap::new();
+    let mut in_string = false;
+    for (i, text) in source.lines().enumerate() {
+        let line = split_line(text, in_string);
+        in_string = line.in_string;
+        if let Some(comment) = line.comment {
+            comments.insert(i + 1, comment);
+        }
+    }
+    comments
+}
+
+/// Whether the source has `; stack:` comments to be checked
+pub(crate) fn annotates_stack(source: &str) -> bool {
+    source.contains("stack:") && !annotations(&comments(source)).is_empty()
+}
+
+impl Engine {
+    /// Fails on the first `; stack:` comment of the source disagreeing with the code
+    pub(crate) fn check_stack_annotations(&mut self, source: &str) -> Result<(), CompileError> {
+        let definitions = self.definitions();
+        let source_name = self.source_name.to_string();
+        let ir = self.parse_ir(source)?;
+        self.restore(definitions);
+        self.reset(source_name.clone());
+        let comments = comments(source);
+        let mut linter = Linter {
+            source_name: &source_name,
+            diagnostics: Vec::new(),
+            annotations: annotations(&comments),
+            comments,
+            instructions: HashMap::new(),
+        };
+        linter.check_annotated_block(&ir.instructions, 0);
+        match linter.diagnostics.into_iter().next() {
+            Some(mismatch) => Err(CompileError::StackMismatch(mismatch.position, mismatch.message)),
+            None => Ok(())
+        }
+    }
+}
+
+/// Checks the source for common mistakes; fails if it does not compile
+pub fn lint(source_name: &str, source: &str) -> Result<Vec<Diagnostic>, CompileError> {
+    let mut engine = Engine::new(source_name);
+    let ir = engine.parse_ir(source)?;
+    let comments = comments(source);
+    let mut linter = Linter {
+        source_name,
+        diagnostics: engine.take_diagnostics(),
+        annotations: annotations(&comments),
+        comments,
+        instructions: HashMap::new(),
+    };
+    linter.count_lines(&ir.instructions);
+    linter.check_entry(&ir.instructions);
+    linter.check_block(&ir.instructions, 0);
+    let (_, removed) = strip_dead_code(ir.instructions);
+    for pos in removed {
+        linter.diagnose(Severity::Warning, "dead-code", &pos, "unreachable code");
+    }
+    linter.diagnostics.sort_by_key(|d| (d.position.line, d.position.column));
+    Ok(linter.diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_code_to_cell;
+
+    #[test]
+    fn stack_annotations() {
+        assert!(compile_code_to_cell("; stack: a b -> c\nADD\n; stack: c -> c c\nDUP").is_ok());
+        assert!(matches!(
+            compile_code_to_cell("; stack: a b -> a b\nADD"),
+            Err(CompileError::StackMismatch(position, message)) if position.line == 2 && message.contains("leaves 1")
+        ));
+        // ADD takes two values while one is shown
+        assert!(matches!(compile_code_to_cell("; stack: a -> a\nADD"), Err(CompileError::StackMismatch(..))));
+        assert!(matches!(
+            compile_code_to_cell("PUSHCONT {\n  ; stack: x -> x x\n  DROP\n}"),
+            Err(CompileError::StackMismatch(..))
+        ));
+    }
+
+    #[test]
+    fn lint_stack_annotations() {
+        let diagnostics = lint("main.code", "SETCP0\n; stack: a -> a\nADD").unwrap();
+        assert!(diagnostics.iter().any(|d| d.code == "stack-mismatch" && d.severity == Severity::Error));
+        let diagnostics = lint("main.code", "SETCP0\n; stack: a b -> c\nADD").unwrap();
+        assert!(!diagnostics.iter().any(|d| d.code == "stack-mismatch"));
+    }
+}
@@ -0,0 +1,134 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Errors and diagnostics in the shape of the Language Server Protocol
+//!
+//! Lines and columns of the assembler start at 1 while LSP counts from 0.
+//! Errors nested in blocks are reported at the innermost instruction.
+
+use std::collections::BTreeMap;
+use serde_json::{json, Value};
+
+use crate::{CompileError, Diagnostic, OperationError, Position, Severity};
+
+pub const SOURCE: &str = "ever-assembler";
+
+const LSP_ERROR: u8 = 1;
+const LSP_WARNING: u8 = 2;
+const LSP_INFORMATION: u8 = 3;
+
+fn lsp_position(line: usize, column: usize) -> Value {
+    json!({ "line": line.saturating_sub(1), "character": column.saturating_sub(1) })
+}
+
+fn lsp_range(position: &Position) -> Value {
+    match &position.span {
+        Some(span) => json!({
+            "start": lsp_position(span.line, span.column),
+            "end": lsp_position(span.end_line, span.end_column),
+        }),
+        None => json!({
+            "start": lsp_position(position.line, position.column),
+            "end": lsp_position(position.line, position.column),
+        }),
+    }
+}
+
+impl CompileError {
+    /// Short kebab-case kind of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompileError::Syntax(..) => "syntax",
+            CompileError::UnknownOperation(..) => "unknown-operation",
+            CompileError::Operation(..) => "operation",
+            CompileError::UnresolvedLabel(..) => "unresolved-label",
+            CompileError::UndefinedConstant(..) => "undefined-constant",
+            CompileError::UndefinedFragment(..) => "undefined-fragment",
+            CompileError::UnsupportedForTarget(..) => "unsupported-for-target",
+            CompileError::LimitExceeded(..) => "limit-exceeded",
+            CompileError::StackMismatch(..) => "stack-mismatch",
+        }
+    }
+
+    /// The error inside nested blocks which caused this one
+    pub fn innermost(&self) -> &CompileError {
+        match self {
+            CompileError::Operation(_, _, OperationError::Nested(inner)) => inner.innermost(),
+            _ => self
+        }
+    }
+
+    /// Message of the error without its position
+    pub fn message(&self) -> String {
+        match self {
+            CompileError::Syntax(_, explanation) => format!("Syntax error: {}", explanation),
+            CompileError::UnknownOperation(_, name, suggestions) if !suggestions.is_empty() =>
+                format!("Unknown operation {}, did you mean {}?", name, suggestions.join(" or ")),
+            CompileError::UnknownOperation(_, name, _) => format!("Unknown operation {}", name),
+            CompileError::Operation(_, name, error) => format!("Instruction {}: {}", name, error),
+            CompileError::UnresolvedLabel(_, name) => format!("Unresolved label {}", name),
+            CompileError::UndefinedConstant(_, name) => format!("Undefined constant {}", name),
+            CompileError::UndefinedFragment(_, name) => format!("Fragment {} is not defined by any unit", name),
+            CompileError::UnsupportedForTarget(_, name, version) => format!("Instruction {} requires TVM {}", name, version),
+            CompileError::LimitExceeded(_, explanation, _) => format!("Limit exceeded: {}", explanation),
+            CompileError::StackMismatch(_, message) => format!("Stack mismatch: {}", message),
+        }
+    }
+
+    pub fn to_lsp_diagnostic(&self) -> Value {
+        let error = self.innermost();
+        json!({
+            "range": lsp_range(error.position()),
+            "severity": LSP_ERROR,
+            "code": error.code(),
+            "source": SOURCE,
+            "message": error.message(),
+        })
+    }
+}
+
+impl Diagnostic {
+    pub fn to_lsp_diagnostic(&self) -> Value {
+        let severity = match self.severity {
+            Severity::Note => LSP_INFORMATION,
+            Severity::Warning => LSP_WARNING,
+            Severity::Error => LSP_ERROR,
+        };
+        json!({
+            "range": lsp_range(&self.position),
+            "severity": severity,
+            "code": self.code,
+            "source": SOURCE,
+            "message": self.message,
+        })
+    }
+}
+
+/// Groups errors and diagnostics by file as parameters of `textDocument/publishDiagnostics`
+pub fn publish_diagnostics(errors: &[CompileError], diagnostics: &[Diagnostic]) -> Value {
+    let mut files = BTreeMap::<String, Vec<Value>>::new();
+    for error in errors {
+        // errors in nested blocks may not know the file
+        let filename = [error.innermost(), error].iter()
+            .map(|e| e.position().filename.clone())
+            .find(|name| !name.is_empty())
+            .unwrap_or_default();
+        files.entry(filename).or_default().push(error.to_lsp_diagnostic());
+    }
+    for diagnostic in diagnostics {
+        files.entry(diagnostic.position.filename.clone()).or_default().push(diagnostic.to_lsp_diagnostic());
+    }
+    Value::Array(files.into_iter()
+        .map(|(uri, diagnostics)| json!({ "uri": uri, "diagnostics": diagnostics }))
+        .collect())
+}
@@ -28,7 +28,7 @@ macro_rules! simple_commands {
         pub fn $command(
             &mut self, 
             par: &[&str], 
-            destination: &mut Units,
+            destination: &mut dyn Writer,
             pos: DbgPos
         ) -> CompileResult {
             par.assert_empty()?;
@@ -42,7 +42,7 @@ macro_rules! simple_commands {
         pub fn $command(
             &mut self, 
             par: &[&str], 
-            destination: &mut Units,
+            destination: &mut dyn Writer,
             pos: DbgPos
         ) -> CompileResult {
             let n_params = simple_commands!(@count $($pname = $parser;)*);
@@ -65,9 +65,14 @@ macro_rules! simple_commands {
         $(
             simple_commands!(@resolve $command $($pname = $parser);* => $($code),*);
         )*
-        pub fn $enumerate_commands() -> &'static [(&'static str, CompileHandler)] {
+        pub fn $enumerate_commands() -> &'static [SimpleCommand] {
             &[
-                $( (stringify!($command), Engine::$command), )*
+                $( SimpleCommand {
+                    name: stringify!($command),
+                    handler: Engine::$command,
+                    operands: &[$( (stringify!($pname), stringify!($parser)) ),*],
+                    encoding: &[$( stringify!($code) ),*],
+                }, )*
             ]
         }
     };
@@ -0,0 +1,77 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use ever_block::{error, sha256_digest, Cell, Result};
+
+use crate::{DbgInfo, stats::code_stats};
+
+/// Build manifest allowing to reproduce and attest a compilation
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BuildManifest {
+    pub assembler_version: String,
+    /// source name -> sha256 of its content
+    pub sources: BTreeMap<String, String>,
+    pub options: BTreeMap<String, String>,
+    pub code_hash: String,
+    pub dbg_hash: String,
+    /// number of distinct cells of the code
+    pub cells: usize,
+    /// data bits of the distinct cells
+    pub bits: usize,
+    /// depth of the tree of cells
+    pub depth: u16,
+}
+
+impl BuildManifest {
+    pub fn new(code: &Cell, dbg: &DbgInfo) -> Result<Self> {
+        let dbg_json = canonical_json(dbg)?;
+        let stats = code_stats(code, None, 0);
+        Ok(Self {
+            assembler_version: env!("CARGO_PKG_VERSION").to_string(),
+            sources: BTreeMap::new(),
+            options: BTreeMap::new(),
+            code_hash: code.repr_hash().to_hex_string(),
+            dbg_hash: hex::encode(sha256_digest(dbg_json.as_bytes())),
+            cells: stats.cells,
+            bits: stats.bits,
+            depth: stats.depth,
+        })
+    }
+    pub fn add_source(&mut self, name: &str, content: &[u8]) {
+        self.sources.insert(name.to_string(), hex::encode(sha256_digest(content)));
+    }
+    pub fn set_option<S: ToString>(&mut self, name: &str, value: S) {
+        self.options.insert(name.to_string(), value.to_string());
+    }
+    /// Checks that the code is the one the manifest was made for, e.g. the deployed one
+    pub fn verify(&self, code: &Cell) -> Result<()> {
+        let hash = code.repr_hash().to_hex_string();
+        if hash != self.code_hash {
+            return Err(error!("code hash {} differs from {} of the manifest", hash, self.code_hash))
+        }
+        Ok(())
+    }
+    /// Compact JSON with lexicographically sorted keys
+    pub fn to_canonical_json(&self) -> Result<String> {
+        canonical_json(self)
+    }
+}
+
+/// Serializes the value as compact JSON with lexicographically sorted keys
+pub fn canonical_json<T: Serialize>(value: &T) -> Result<String> {
+    // serde_json::Value keeps object keys in a BTreeMap, i.e. sorted
+    let value = serde_json::to_value(value).map_err(|e| error!("{}", e))?;
+    serde_json::to_string(&value).map_err(|e| error!("{}", e))
+}
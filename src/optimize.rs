@@ -0,0 +1,263 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Peephole optimization of the parsed program
+//!
+//! Known inefficient sequences of adjacent instructions are rewritten before
+//! encoding: `PUSHINT n; EQUAL` becomes `EQINT n`, `SWAP; SWAP` and `NOP` vanish
+//! and so on. A rewritten instruction keeps the source position of the first
+//! instruction of the sequence, so debug info follows the optimized code.
+//! Nested blocks are optimized as well; code of included files and expanded
+//! macros is kept as is.
+//!
+//! Dead code elimination removes instructions following an unconditional
+//! transfer of control like `RET` or `THROW` in the same block, up to the next
+//! label or directive, along with the continuations they would push or call.
+
+use num::ToPrimitive;
+
+use crate::{DbgPos, ir::{IrArg, IrInstruction}, parse::parse_int_literal};
+
+/// Compilation options
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Opts {
+    /// run the peephole optimizer before encoding
+    pub optimize: bool,
+    /// remove unreachable code
+    pub strip_dead_code: bool,
+}
+
+/// Instructions taking the second operand as a tiny constant
+const CONST_FORMS: [(&str, &str); 6] = [
+    ("ADD", "ADDCONST"),
+    ("MUL", "MULCONST"),
+    ("EQUAL", "EQINT"),
+    ("NEQ", "NEQINT"),
+    ("LESS", "LESSINT"),
+    ("GREATER", "GTINT"),
+];
+
+/// Value of PUSHINT fitting into a signed byte
+fn tiny_pushint(insn: &IrInstruction) -> Option<i64> {
+    match (insn.name.as_str(), insn.args.as_slice()) {
+        ("PUSHINT", [IrArg::Token(token)]) => parse_int_literal(token).ok()?
+            .to_i64()
+            .filter(|v| (-128..=127).contains(v)),
+        _ => None
+    }
+}
+
+fn is_plain(insn: &IrInstruction, name: &str) -> bool {
+    insn.name == name && insn.args.is_empty()
+}
+
+/// Rewrites the pair of instructions if it is a known inefficient sequence;
+/// `Some(None)` means both are removed
+fn rewrite_pair(first: &IrInstruction, second: &IrInstruction) -> Option<Option<IrInstruction>> {
+    if is_plain(first, "SWAP") && is_plain(second, "SWAP") {
+        return Some(None)
+    }
+    if (is_plain(first, "DUP") || tiny_pushint(first).is_some()) && is_plain(second, "DROP") {
+        return Some(None)
+    }
+    let value = tiny_pushint(first)?;
+    CONST_FORMS.iter()
+        .find(|(name, _)| is_plain(second, name))
+        .map(|(_, form)| Some(IrInstruction {
+            name: form.to_string(),
+            args: vec!(IrArg::Token(value.to_string())),
+            pos: first.pos.clone(),
+        }))
+}
+
+/// Optimizes the instructions and nested blocks
+pub fn optimize(instructions: Vec<IrInstruction>) -> Vec<IrInstruction> {
+    let mut optimized: Vec<IrInstruction> = Vec::with_capacity(instructions.len());
+    for mut insn in instructions {
+        for arg in insn.args.iter_mut() {
+            if let IrArg::Block(block) = arg {
+                *block = optimize(std::mem::take(block));
+            }
+        }
+        if is_plain(&insn, "NOP") {
+            continue
+        }
+        // a rewritten pair may form a new one with the previous instruction
+        let mut next = Some(insn);
+        while let Some(insn) = next.take() {
+            let rewritten = optimized.last().and_then(|last| rewrite_pair(last, &insn));
+            match rewritten {
+                Some(replacement) => {
+                    optimized.pop();
+                    next = replacement;
+                }
+                None => optimized.push(insn),
+            }
+        }
+    }
+    optimized
+}
+
+/// Instructions never passing control to the next one
+const TERMINATORS: [&str; 16] = [
+    "RET", "RETALT", "RETTRUE", "RETFALSE", "RETARGS", "RETDATA", "RETVARARGS",
+    "THROW", "THROWANY", "JMPX", "JMPXDATA", "JMPXARGS", "JMPXVARARGS",
+    "JMPREF", "JMPREFDATA", "JMPDICT",
+];
+
+/// Removes the code following unconditional transfers of control; returns the
+/// remaining code and the positions of the removed sequences
+pub fn strip_dead_code(instructions: Vec<IrInstruction>) -> (Vec<IrInstruction>, Vec<DbgPos>) {
+    let mut removed = Vec::new();
+    let code = strip_block(instructions, &mut removed);
+    (code, removed)
+}
+
+fn strip_block(instructions: Vec<IrInstruction>, removed: &mut Vec<DbgPos>) -> Vec<IrInstruction> {
+    let mut code = Vec::with_capacity(instructions.len());
+    let mut dead = false;
+    let mut reported = false;
+    for mut insn in instructions {
+        let directive = insn.name.starts_with('.') && insn.name != ".LOC";
+        if dead && !directive {
+            if !reported {
+                if let Some(pos) = insn.pos.clone() {
+                    removed.push(pos);
+                    reported = true;
+                }
+            }
+            continue
+        }
+        dead = false;
+        reported = false;
+        for arg in insn.args.iter_mut() {
+            if let IrArg::Block(block) = arg {
+                *block = strip_block(std::mem::take(block), removed);
+            }
+        }
+        dead = TERMINATORS.contains(&insn.name.as_str());
+        code.push(insn);
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_code_to_cell, Engine};
+
+    fn insn(name: &str, args: &[&str]) -> IrInstruction {
+        IrInstruction { name: name.to_string(), args: args.iter().map(|a| IrArg::Token(a.to_string())).collect(), pos: None }
+    }
+
+    fn at(insn: IrInstruction, line: usize) -> IrInstruction {
+        IrInstruction { pos: Some(DbgPos { line, ..Default::default() }), ..insn }
+    }
+
+    fn block(name: &str, code: Vec<IrInstruction>) -> IrInstruction {
+        IrInstruction { name: name.to_string(), args: vec!(IrArg::Block(code)), pos: None }
+    }
+
+    #[test]
+    fn const_forms() {
+        let optimized = optimize(vec!(at(insn("PUSHINT", &["5"]), 1), at(insn("EQUAL", &[]), 2)));
+        assert_eq!(optimized, vec!(at(insn("EQINT", &["5"]), 1)));
+        assert_eq!(optimize(vec!(insn("PUSHINT", &["-1"]), insn("ADD", &[]))), vec!(insn("ADDCONST", &["-1"])));
+        // too large for the constant form
+        let large = vec!(insn("PUSHINT", &["200"]), insn("ADD", &[]));
+        assert_eq!(optimize(large.clone()), large);
+    }
+
+    #[test]
+    fn removed_sequences() {
+        assert_eq!(optimize(vec!(insn("SWAP", &[]), insn("SWAP", &[]))), vec!());
+        assert_eq!(
+            optimize(vec!(insn("INC", &[]), insn("PUSHINT", &["1"]), insn("DROP", &[]), insn("NOP", &[]), insn("SWAP", &[]))),
+            vec!(insn("INC", &[]), insn("SWAP", &[]))
+        );
+        // removing a pair makes another one
+        assert_eq!(optimize(vec!(insn("SWAP", &[]), insn("DUP", &[]), insn("DROP", &[]), insn("SWAP", &[]))), vec!());
+        // instructions with operands are not plain
+        let xchg = vec!(insn("SWAP", &[]), insn("XCHG", &["s1"]));
+        assert_eq!(optimize(xchg.clone()), xchg);
+    }
+
+    #[test]
+    fn nested_blocks() {
+        assert_eq!(
+            optimize(vec!(block("PUSHCONT", vec!(insn("PUSHINT", &["1"]), insn("ADD", &[]))))),
+            vec!(block("PUSHCONT", vec!(insn("ADDCONST", &["1"]))))
+        );
+    }
+
+    #[test]
+    fn optimized_compilation() {
+        let mut engine = Engine::new("");
+        let opts = Opts { optimize: true, strip_dead_code: false };
+        let units = engine.compile_with_opts("PUSHINT 5\nEQUAL\nNOP\nPUSHCONT {\n  SWAP\n  SWAP\n}", opts).unwrap();
+        let optimized = engine.finalize(units).0.into_cell().unwrap();
+        let plain = compile_code_to_cell("EQINT 5\nPUSHCONT {\n}").unwrap();
+        assert_eq!(optimized.repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn optimized_positions() {
+        let mut engine = Engine::new("my dir/a, b.code");
+        let opts = Opts { optimize: true, strip_dead_code: false };
+        let units = engine.compile_with_opts("NOP\nPUSHINT 5\nEQUAL", opts).unwrap();
+        let (builder, dbg) = engine.finalize(units);
+        let dbg = crate::DbgInfo::from(builder.into_cell().unwrap(), dbg);
+        let (_, positions) = dbg.iter().next().unwrap();
+        let pos = &positions[&0];
+        assert_eq!((&*pos.filename, pos.line), ("my dir/a, b.code", 2));
+    }
+
+    #[test]
+    fn dead_code() {
+        let (code, removed) = strip_dead_code(vec!(
+            at(insn("PUSHINT", &["1"]), 1),
+            at(insn("RET", &[]), 2),
+            at(insn("INC", &[]), 3),
+            at(insn(".LOC", &["a.sol", "4"]), 4),
+            at(insn("DEC", &[]), 5),
+            at(insn(".LABEL", &["next:"]), 6),
+            at(insn("THROW", &["5"]), 7),
+            at(insn("NOP", &[]), 8),
+        ));
+        assert_eq!(code, vec!(
+            at(insn("PUSHINT", &["1"]), 1),
+            at(insn("RET", &[]), 2),
+            at(insn(".LABEL", &["next:"]), 6),
+            at(insn("THROW", &["5"]), 7),
+        ));
+        // a removed sequence is reported once at its start
+        assert_eq!(removed.iter().map(|pos| pos.line).collect::<Vec<_>>(), vec!(3, 8));
+    }
+
+    #[test]
+    fn dead_code_in_blocks() {
+        let (code, removed) = strip_dead_code(vec!(
+            block("PUSHCONT", vec!(insn("JMPREF", &["done"]), at(insn("INC", &[]), 2))),
+            insn("INC", &[]),
+        ));
+        assert_eq!(code, vec!(block("PUSHCONT", vec!(insn("JMPREF", &["done"]))), insn("INC", &[])));
+        assert_eq!(removed.len(), 1);
+
+        let mut engine = Engine::new("");
+        let opts = Opts { optimize: false, strip_dead_code: true };
+        let units = engine.compile_with_opts("PUSHINT 1\nRET\nINC\nDEC", opts).unwrap();
+        let stripped = engine.finalize(units).0.into_cell().unwrap();
+        assert_eq!(stripped.repr_hash(), compile_code_to_cell("PUSHINT 1\nRET").unwrap().repr_hash());
+        assert_eq!(engine.stripped_dead_code().iter().map(|pos| pos.line).collect::<Vec<_>>(), vec!(3));
+    }
+}
@@ -0,0 +1,191 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Parallel compilation of fragments ******************************************
+//
+// Bodies of toplevel `.fragment` directives are independent until inlined, so
+// they are compiled on all the cores before the source is compiled as usual;
+// the sequential pass takes the precompiled code of a fragment instead of
+// compiling its body again and links the fragments together. Sources defining
+// constants, macros or positions are compiled sequentially as a whole, as are
+// the fragments inlining or including other code. A fragment failing to
+// compile is left to the sequential pass to report the error.
+
+use crate::{Engine, Units, cache::DEPENDENT, lexer::{mentions_any, Lexeme, Lexer, TokenKind}};
+
+/// Directives defining what bodies of fragments may refer to
+const DEFINING: [&str; 8] = [".define", ".macro", ".method-id", ".exception", ".global", ".proc", ".include", ".loc"];
+
+/// Body of a toplevel fragment along with the position the body starts at
+struct FragmentSource<'a> {
+    name: String,
+    body: &'a str,
+    line: usize,
+    column: usize,
+}
+
+/// Finds `.fragment name, { body }` directives at the toplevel of the source
+fn toplevel_fragments(source: &str) -> Vec<FragmentSource> {
+    let mut fragments = Vec::new();
+    // last lexemes met since the last block
+    let mut recent = Vec::<Lexeme>::with_capacity(4);
+    for lexeme in Lexer::new(source, 1, 1) {
+        match lexeme.kind {
+            TokenKind::Newline => (),
+            TokenKind::Block => {
+                if let [.., directive, name, comma] = recent.as_slice() {
+                    if directive.text.eq_ignore_ascii_case(".fragment") && name.kind == TokenKind::Word && comma.kind == TokenKind::Comma {
+                        fragments.push(FragmentSource {
+                            name: name.text.to_string(),
+                            body: lexeme.text,
+                            line: lexeme.line,
+                            column: lexeme.column + 1,
+                        });
+                    }
+                }
+                recent.clear();
+            }
+            _ => {
+                recent.push(lexeme);
+                if recent.len() > 3 {
+                    recent.remove(0);
+                }
+            }
+        }
+    }
+    fragments
+}
+
+impl Engine {
+    /// Engine compiling a fragment body the same way this one would
+    fn worker(&self) -> Engine {
+        let mut engine = Engine::new(&self.source_name);
+        engine.set_target_version(self.target_version());
+        engine.set_inline_max_bits(self.inline_max_bits);
+        engine.set_packing_policy(self.packing_policy);
+        engine.constants = self.constants.clone();
+        engine.listener = self.listener.clone();
+        engine
+    }
+
+    /// Compiles the independent fragments of the source on all the cores
+    pub(crate) fn precompile_fragments(&mut self, source: &str) {
+        if self.ir.is_some() || self.recover_errors || self.code_pages.len() > 1 {
+            return
+        }
+        let fragments = toplevel_fragments(source).into_iter()
+            .filter(|fragment| !mentions_any(fragment.body, &DEPENDENT) && !self.is_cached(fragment.body))
+            .collect::<Vec<_>>();
+        if fragments.len() < 2 || mentions_any(source, &DEFINING) {
+            return
+        }
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk_size = fragments.len().div_ceil(threads);
+        let this = &*self;
+        let results = std::thread::scope(|scope| {
+            let workers = fragments.chunks(chunk_size).map(|chunk| scope.spawn(move || {
+                let mut engine = this.worker();
+                chunk.iter().filter_map(|fragment| {
+                    engine.set_pos(fragment.line, fragment.column);
+                    let units: Units = engine.compile(fragment.body).ok()?;
+                    let (builder, dbg) = units.finalize();
+                    let diagnostics = engine.take_diagnostics();
+                    Some((fragment.name.clone(), (fragment.body.to_string(), builder, dbg, diagnostics)))
+                }).collect::<Vec<_>>()
+            })).collect::<Vec<_>>();
+            workers.into_iter()
+                .flat_map(|worker| worker.join().expect("fragment compilation thread panicked"))
+                .collect::<Vec<_>>()
+        });
+        self.precompiled.extend(results);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ever_block::Cell;
+    use crate::DbgInfo;
+
+    const SOURCE: &str = "\
+.fragment f, {
+  PUSHINT 1
+  ADD
+}
+.fragment g, {
+  PUSHCONT {
+    INC
+  }
+  IF
+}
+  .fragment h, { DEC
+}
+.inline f
+.inline g
+CALLREF {
+  .inline h
+}
+";
+
+    fn finalized(engine: &mut Engine, units: Units) -> (Cell, DbgInfo) {
+        let (builder, dbg) = engine.finalize(units);
+        let cell = builder.into_cell().unwrap();
+        (cell.clone(), DbgInfo::from(cell, dbg))
+    }
+
+    /// Code compiled without precompiling the fragments
+    fn sequential(source: &str) -> (Cell, DbgInfo) {
+        let mut engine = Engine::new("a.code");
+        let mut units = engine.compile_impl(source, true).unwrap();
+        engine.finish_toplevel(&mut units).unwrap();
+        finalized(&mut engine, units)
+    }
+
+    fn precompiled(source: &str) -> Vec<String> {
+        let mut engine = Engine::new("a.code");
+        engine.precompile_fragments(source);
+        let mut names = engine.precompiled.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn parallel_same_code() {
+        assert_eq!(precompiled(SOURCE), vec!("f", "g", "h"));
+        let mut engine = Engine::new("a.code");
+        let units = engine.compile_toplevel(SOURCE).unwrap();
+        let (cell, dbg) = finalized(&mut engine, units);
+        let (expected_cell, expected_dbg) = sequential(SOURCE);
+        assert_eq!(cell.repr_hash(), expected_cell.repr_hash());
+        assert!(dbg == expected_dbg);
+    }
+
+    #[test]
+    fn parallel_fallback() {
+        // fragments inlining other code are left to the sequential pass
+        let source = format!("{}.fragment k, {{\n  .inline f\n}}\n", SOURCE);
+        assert_eq!(precompiled(&source), vec!("f", "g", "h"));
+        // as are all of them if the source defines what they may refer to
+        for defining in [".define N 1", ".macro m { NOP }", ".global x"] {
+            let source = format!("{}\n{}", defining, SOURCE);
+            assert!(precompiled(&source).is_empty(), "{}", defining);
+        }
+        let source = format!(".define N 1\n{}", SOURCE);
+        let mut engine = Engine::new("a.code");
+        let units = engine.compile_toplevel(&source).unwrap();
+        let (cell, dbg) = finalized(&mut engine, units);
+        let (expected_cell, expected_dbg) = sequential(&source);
+        assert_eq!(cell.repr_hash(), expected_cell.repr_hash());
+        assert!(dbg == expected_dbg);
+    }
+}
@@ -11,7 +11,7 @@
 * limitations under the License.
 */
 
-use num::Num;
+use num::{BigInt, Num};
 use std::{
     cmp::PartialOrd,
     ops::{Bound, Range, RangeBounds}
@@ -57,6 +57,27 @@ where
     }
 }
 
+/// Parses an integer literal of any length: decimal, hexadecimal with `0x`
+/// or binary with `0b`, optionally negative and with `_` between digits,
+/// e.g. `-0xFF`, `0b1010`, `1_000_000`
+pub(super) fn parse_int_literal(par: &str) -> Result<BigInt, ParameterError> {
+    let (negative, literal) = match par.strip_prefix('-') {
+        Some(literal) => (true, literal),
+        None => (false, par.strip_prefix('+').unwrap_or(par)),
+    };
+    let (digits, radix) = match literal.get(0..2) {
+        Some("0x" | "0X") => (&literal[2..], 16),
+        Some("0b" | "0B") => (&literal[2..], 2),
+        _ => (literal, 10),
+    };
+    if !digits.starts_with(|c: char| c.is_digit(radix)) || digits.ends_with('_') || digits.contains("__") {
+        return Err(ParameterError::UnexpectedType)
+    }
+    let value = BigInt::from_str_radix(&digits.replace('_', ""), radix)
+        .map_err(|_| ParameterError::UnexpectedType)?;
+    Ok(if negative { -value } else { value })
+}
+
 pub(super) fn parse_const_u2(par: &str) -> Result<u8, ParameterError> {
     parse_range(0..4)(par)
 }
@@ -240,6 +261,37 @@ pub(super) fn parse_plduz_parameter(par: &str) -> Result<u8, ParameterError> {
         })
 }
 
+/// Converts an address literal `addr:<workchain>:<64 hex digits>` into the
+/// slice literal of its MsgAddressInt encoding: addr_std without anycast
+pub(super) fn parse_address(par: &str) -> Result<String, ParameterError> {
+    let mut parts = par.splitn(3, ':').skip(1);
+    let workchain = parts.next()
+        .and_then(|w| w.parse::<i8>().ok())
+        .ok_or(ParameterError::OutOfRange)?;
+    let account = parts.next()
+        .filter(|a| a.len() == 64 && a.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or(ParameterError::UnexpectedType)?;
+    // addr_std$10 anycast:nothing$0 workchain_id:int8 address:bits256
+    let mut bits = format!("100{:08b}", workchain as u8);
+    for digit in account.chars() {
+        bits += &format!("{:04b}", digit.to_digit(16).unwrap());
+    }
+    // completion tag makes 268 bits of 67 hex digits
+    bits.push('1');
+    let mut slice = String::from("x");
+    for nibble in bits.as_bytes().chunks(4) {
+        let value = nibble.iter().fold(0, |acc, bit| acc << 1 | (bit - b'0') as u32);
+        slice.push(std::char::from_digit(value, 16).unwrap());
+    }
+    slice.push('_');
+    Ok(slice)
+}
+
+/// Contents of a string literal in double quotes
+pub(super) fn parse_quoted_string(arg: &str) -> Option<&str> {
+    arg.strip_prefix('"')?.strip_suffix('"')
+}
+
 pub(super) fn parse_string(arg: &str) -> Vec<u8> {
     let mut string = String::from(arg);
     if string.to_ascii_uppercase().starts_with('X') {
@@ -251,3 +303,41 @@ pub(super) fn parse_string(arg: &str) -> Vec<u8> {
     }
     Vec::from(arg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bits of the slice literal without the completion tag
+    fn slice_bits(slice: &str) -> String {
+        let hex = slice.strip_prefix('x').unwrap().strip_suffix('_').unwrap();
+        let bits = hex.chars().map(|c| format!("{:04b}", c.to_digit(16).unwrap())).collect::<String>();
+        bits.trim_end_matches('0').strip_suffix('1').unwrap().to_string()
+    }
+
+    #[test]
+    fn address() {
+        let zero = format!("addr:0:{}", "0".repeat(64));
+        assert_eq!(parse_address(&zero).unwrap(), format!("x8{}1_", "0".repeat(65)));
+        let masterchain = format!("addr:-1:{}", "f".repeat(64));
+        assert_eq!(parse_address(&masterchain).unwrap(), format!("x9{}_", "f".repeat(66)));
+
+        let account = "0123456789abcdef".repeat(4);
+        let bits = slice_bits(&parse_address(&format!("addr:-1:{}", account)).unwrap());
+        assert_eq!(bits.len(), 3 + 8 + 256);
+        // addr_std$10, no anycast, workchain -1
+        assert_eq!(&bits[..11], "10011111111");
+        let digits = bits[11..].as_bytes().chunks(4)
+            .map(|nibble| std::char::from_digit(u32::from_str_radix(std::str::from_utf8(nibble).unwrap(), 2).unwrap(), 16).unwrap())
+            .collect::<String>();
+        assert_eq!(digits, account);
+    }
+
+    #[test]
+    fn address_errors() {
+        assert_eq!(parse_address(&format!("addr:0:{}", "0".repeat(63))), Err(ParameterError::UnexpectedType));
+        assert_eq!(parse_address(&format!("addr:0:{}", "0".repeat(66))), Err(ParameterError::UnexpectedType));
+        assert_eq!(parse_address(&format!("addr:0:{}g", "0".repeat(63))), Err(ParameterError::UnexpectedType));
+        assert_eq!(parse_address(&format!("addr:128:{}", "0".repeat(64))), Err(ParameterError::OutOfRange));
+    }
+}
@@ -0,0 +1,158 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+// Procedures ****************************************************************
+//
+// `.proc name, nargs, nrets { ... }` defines a fragment taking nargs values
+// and returning nrets ones, -1 for any number; given a method id as well,
+// `.proc name, nargs, nrets, id { ... }`, it is put into the dictionary of
+// methods too. `CALLPROC name` calls it by CALLREF, or by CALLDICT if it has
+// an id, which takes ids below 2^14 only, so the larger ones, e.g. derived from
+// signatures, are called by `PUSHINT id; PUSHCTR c3; EXECUTE` doing the same;
+// the arguments may be given as registers, `CALLPROC name, s2, s0`,
+// to be pushed in order before the call. The procedures are listed in the
+// debug map.
+
+use crate::{
+    CompileResult, DbgPos, Engine, EnsureParametersCountInRange, OperationError, ParameterError,
+    ToOperationParameterError, Writer,
+    complex::{compile_call, compile_fragment, compile_method, compile_push, compile_pushint, parse_method_id, write_ref},
+    debug::{CellRole, DbgNode, ProcInfo},
+    parse::{parse_const_u4, parse_register},
+};
+
+/// Opcode of CALLREF
+const CALLREF: [u8; 2] = [0xDB, 0x3C];
+/// Opcodes of PUSHCTR c3 and EXECUTE
+const PUSHCTR_C3: [u8; 2] = [0xED, 0x43];
+const EXECUTE: [u8; 1] = [0xD8];
+/// Method ids CALLDICT can encode
+const CALLDICT_IDS: u32 = 1 << 14;
+
+pub(crate) fn compile_proc(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    par.assert_len_in(4..=5)?;
+    let name = par[0];
+    let nargs = parse_const_u4(par[1]).parameter("nargs")?;
+    let nrets = match par[2] {
+        "-1" => -1,
+        nrets => parse_const_u4(nrets).parameter("nrets")? as i8,
+    };
+    let body = par[par.len() - 1];
+    let id = match par.len() {
+        5 => Some(parse_method_id(par[3]).parameter("method id")?),
+        _ => None,
+    };
+    match id {
+        Some(_) => compile_method(engine, &[par[3], name, body], destination, pos.clone())?,
+        None => compile_fragment(engine, &[name, body], destination, pos.clone())?,
+    }
+    engine.procs.insert(name.to_string(), ProcInfo { nargs, nrets, id, pos });
+    Ok(())
+}
+
+pub(crate) fn compile_callproc(engine: &mut Engine, par: &[&str], destination: &mut dyn Writer, pos: DbgPos) -> CompileResult {
+    par.assert_len_in(1..=16)?;
+    let name = par[0];
+    let proc = engine.procs.get(name).cloned()
+        .ok_or_else(|| OperationError::ProcIsNotDefined(name.to_string()))?;
+    let args = &par[1..];
+    if !args.is_empty() && args.len() != proc.nargs as usize {
+        return Err(OperationError::LogicErrorInParameters("number of arguments differs from the one of the procedure"))
+    }
+    for (i, arg) in args.iter().enumerate() {
+        let register = parse_register(arg, 'S', 0..256).parameter(format!("arg {}", i + 1))?;
+        // every value pushed moves the ones below deeper
+        let register = register as usize + i;
+        if register > 255 {
+            return Err(ParameterError::OutOfRange.parameter(format!("arg {}", i + 1)))
+        }
+        compile_push(engine, &[&format!("s{}", register)], destination, pos.clone())?;
+    }
+    match proc.id {
+        Some(id) if id < CALLDICT_IDS => compile_call(engine, &[&id.to_string()], destination, pos),
+        Some(id) => {
+            compile_pushint(engine, &[&id.to_string()], destination, pos.clone())?;
+            destination.write_command(&PUSHCTR_C3, DbgNode::from(pos.clone()))?;
+            destination.write_command(&EXECUTE, DbgNode::from(pos))
+        }
+        None => {
+            let (cont, dbg) = engine.named_units.get(name)
+                .ok_or_else(|| OperationError::FragmentIsNotDefined(name.to_string()))?
+                .clone()
+                .finalize();
+            write_ref(&CALLREF, CellRole::Code, cont, dbg, destination, pos)
+        }
+    }
+}
+
+impl Engine {
+    /// Procedures defined by `.proc` by name
+    pub fn procs(&self) -> &std::collections::BTreeMap<String, ProcInfo> {
+        &self.procs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_code_to_cell, DbgInfo};
+    use crate::test_helpers::{compile, operation_error};
+
+    fn same_code(source: &str, expected: &str) {
+        let code = compile_code_to_cell(source).unwrap();
+        assert_eq!(code.repr_hash(), compile_code_to_cell(expected).unwrap().repr_hash(), "{}", source);
+    }
+
+    #[test]
+    fn callproc_arguments() {
+        // the second argument is one deeper after the first one is pushed
+        same_code(".proc add, 2, 1, {\n  ADD\n}\nCALLPROC add, s1, s0", "PUSH s1\nPUSH s1\nCALLREF {\n  ADD\n}");
+        same_code(".proc add, 2, 1, {\n  ADD\n}\nCALLPROC add", "CALLREF {\n  ADD\n}");
+    }
+
+    #[test]
+    fn callproc_by_id() {
+        same_code(".proc inc, 1, 1, 5, {\n  INC\n}\nCALLPROC inc", ".method 5, inc, {\n  INC\n}\nCALLDICT 5");
+        same_code(
+            ".proc inc, 1, 1, 70000, {\n  INC\n}\nCALLPROC inc",
+            ".method 70000, inc, {\n  INC\n}\nPUSHINT 70000\nPUSHCTR c3\nEXECUTE",
+        );
+    }
+
+    #[test]
+    fn callproc_errors() {
+        assert_eq!(
+            operation_error(&mut Engine::new(""), ".proc add, 2, 1, {\n  ADD\n}\nCALLPROC add, s0"),
+            OperationError::LogicErrorInParameters("number of arguments differs from the one of the procedure")
+        );
+        assert_eq!(
+            operation_error(&mut Engine::new(""), "CALLPROC nothing"),
+            OperationError::ProcIsNotDefined("nothing".to_string())
+        );
+    }
+
+    #[test]
+    fn procs_in_debug_map() {
+        let mut engine = Engine::new("main.code");
+        compile(&mut engine, ".proc add, 2, 1, {\n  ADD\n}\n.proc get, 0, -1, 7, {\n  NOP\n}").unwrap();
+        let mut dbg = DbgInfo::default();
+        engine.annotate_dbg(&mut dbg);
+        let add = &dbg.procs()["add"];
+        assert_eq!((add.nargs, add.nrets, add.id), (2, 1, None));
+        assert_eq!((&*add.pos.filename, add.pos.line), ("main.code", 1));
+        let get = &dbg.procs()["get"];
+        assert_eq!((get.nargs, get.nrets, get.id), (0, -1, Some(7)));
+        assert_eq!(get.pos.line, 4);
+    }
+}
@@ -0,0 +1,212 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Project file (`asm.toml`) describing how to build a set of contracts
+//!
+//! ```toml
+//! include-paths = ["lib"]
+//! tvm-version = "v19"
+//!
+//! [defines]
+//! MAX_SUPPLY = "1000000"
+//!
+//! [profiles.release]
+//! optimize = true
+//! strip-dead-code = true
+//! inline-max-bits = 512
+//!
+//! [[target]]
+//! name = "wallet"
+//! sources = ["src/wallet.code"]
+//! stateinit = "build/wallet.tvc"
+//! data = "data/wallet.boc"
+//! ```
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+use serde::Deserialize;
+use ever_block::{error, fail, Result};
+
+use crate::TvmVersion;
+
+pub const DEFAULT_PROJECT_FILE: &str = "asm.toml";
+pub const DEFAULT_PROFILE: &str = "debug";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Project {
+    pub include_paths: Vec<String>,
+    pub defines: BTreeMap<String, String>,
+    /// version of TVM the code is checked against
+    pub tvm_version: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(rename = "target")]
+    pub targets: Vec<Target>,
+}
+
+/// Build options shared by all targets
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    /// run the peephole optimizer
+    pub optimize: Option<bool>,
+    /// remove unreachable code
+    pub strip_dead_code: Option<bool>,
+    pub gas_threshold: Option<u64>,
+    pub inline_max_bits: Option<usize>,
+    /// directory for outputs of the targets ("build/<profile>" by default)
+    pub output_dir: Option<String>,
+    /// write build manifests next to the bocs
+    pub manifest: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Target {
+    pub name: String,
+    pub sources: Vec<String>,
+    /// extra include paths, searched before the project ones
+    pub include_paths: Vec<String>,
+    /// extra defines, overriding the project ones
+    pub defines: BTreeMap<String, String>,
+    /// overrides the TVM version of the project
+    pub tvm_version: Option<String>,
+    pub boc: Option<String>,
+    pub dbg: Option<String>,
+    /// stateinit with the code to be written as well
+    pub stateinit: Option<String>,
+    /// boc of the data cell of the stateinit
+    pub data: Option<String>,
+    pub workchain: i8,
+    /// per-profile overrides of the project profiles
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Everything needed to build a single target, with paths resolved
+/// relative to the project directory
+#[derive(Clone, Debug)]
+pub struct BuildPlan {
+    pub name: String,
+    pub sources: Vec<PathBuf>,
+    pub include_paths: Vec<PathBuf>,
+    pub defines: BTreeMap<String, String>,
+    pub tvm_version: Option<TvmVersion>,
+    pub profile: Profile,
+    pub boc: PathBuf,
+    pub dbg: PathBuf,
+    pub stateinit: Option<PathBuf>,
+    pub data: Option<PathBuf>,
+    pub workchain: i8,
+    pub manifest: Option<PathBuf>,
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| error!("failed to read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| error!("{}: {}", path.display(), e))
+    }
+    pub fn parse(text: &str) -> Result<Self> {
+        let project: Self = toml::from_str(text).map_err(|e| error!("{}", e))?;
+        for target in &project.targets {
+            if target.name.is_empty() {
+                fail!("target name is not set")
+            }
+            if target.sources.is_empty() {
+                fail!("target {} has no sources", target.name)
+            }
+            if target.data.is_some() && target.stateinit.is_none() {
+                fail!("target {} has data but no stateinit", target.name)
+            }
+        }
+        Ok(project)
+    }
+    /// Resolves the build plans of the selected targets (all if none selected)
+    pub fn plans(&self, root: &Path, profile: &str, selected: &[String]) -> Result<Vec<BuildPlan>> {
+        for name in selected {
+            if !self.targets.iter().any(|t| &t.name == name) {
+                fail!("unknown target {}", name)
+            }
+        }
+        let base = match self.profiles.get(profile) {
+            Some(base) => base.clone(),
+            None if profile == "debug" || profile == "release" => Profile::default(),
+            None => fail!("unknown profile {}", profile)
+        };
+        let mut plans = Vec::new();
+        for target in &self.targets {
+            if !selected.is_empty() && !selected.contains(&target.name) {
+                continue
+            }
+            let profile_opts = match target.profiles.get(profile) {
+                Some(over) => base.merged(over),
+                None => base.clone(),
+            };
+            let output_dir = match &profile_opts.output_dir {
+                Some(dir) => root.join(dir),
+                None => root.join("build").join(profile),
+            };
+            let output = |name: &Option<String>, ext: &str| match name {
+                Some(name) => root.join(name),
+                None => output_dir.join(format!("{}.{}", target.name, ext)),
+            };
+            let mut defines = self.defines.clone();
+            defines.extend(target.defines.clone());
+            let tvm_version = match target.tvm_version.as_ref().or(self.tvm_version.as_ref()) {
+                Some(version) => Some(version.parse::<TvmVersion>()
+                    .map_err(|e| error!("target {}: {}", target.name, e))?),
+                None => None,
+            };
+            plans.push(BuildPlan {
+                name: target.name.clone(),
+                sources: target.sources.iter().map(|s| root.join(s)).collect(),
+                include_paths: target.include_paths.iter()
+                    .chain(self.include_paths.iter())
+                    .map(|p| root.join(p))
+                    .collect(),
+                defines,
+                tvm_version,
+                boc: output(&target.boc, "boc"),
+                dbg: output(&target.dbg, "debug.json"),
+                stateinit: target.stateinit.as_ref().map(|s| root.join(s)),
+                data: target.data.as_ref().map(|d| root.join(d)),
+                workchain: target.workchain,
+                manifest: profile_opts.manifest().then(|| output_dir.join(format!("{}.manifest.json", target.name))),
+                profile: profile_opts,
+            })
+        }
+        Ok(plans)
+    }
+}
+
+impl Profile {
+    pub fn optimize(&self) -> bool {
+        self.optimize.unwrap_or(false)
+    }
+    pub fn strip_dead_code(&self) -> bool {
+        self.strip_dead_code.unwrap_or(false)
+    }
+    pub fn manifest(&self) -> bool {
+        self.manifest.unwrap_or(false)
+    }
+    /// Applies the options set in the other profile over this one
+    fn merged(&self, other: &Profile) -> Profile {
+        Profile {
+            optimize: other.optimize.or(self.optimize),
+            strip_dead_code: other.strip_dead_code.or(self.strip_dead_code),
+            gas_threshold: other.gas_threshold.or(self.gas_threshold),
+            inline_max_bits: other.inline_max_bits.or(self.inline_max_bits),
+            output_dir: other.output_dir.clone().or_else(|| self.output_dir.clone()),
+            manifest: other.manifest.or(self.manifest),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+// Prologue ******************************************************************
+//
+// Boilerplate of contract entrypoints injected by the assembler before the
+// toplevel code of a source, so code generators don't have to emit it. The
+// code following the prologue keeps its debug info moved by the size of the
+// prologue; the prologue itself gets the positions of its source or, for the
+// generated instructions, the start of the compiled source.
+
+use crate::{
+    complex::write_dispatcher, CompileError, DbgNode, DbgPos, Engine, OperationError, ParameterError,
+    ToOperationParameterError, Unit, Units,
+};
+
+/// Exit code of TON Solidity contracts called with an unknown function id
+pub const UNKNOWN_METHOD_EXIT_CODE: u16 = 11;
+
+/// Code put before the toplevel code of every source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Prologue {
+    /// dispatcher of the `.method`s by the id on the stack, throwing the exit
+    /// code when there is no method with the id
+    Dispatcher { unknown_method: u16 },
+    /// code compiled from the assembly source
+    Source(String),
+}
+
+impl Default for Prologue {
+    fn default() -> Self {
+        Prologue::Dispatcher { unknown_method: UNKNOWN_METHOD_EXIT_CODE }
+    }
+}
+
+/// Options of the code injected by the engine
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EngineOptions {
+    /// start the code with `SETCP0`
+    pub prepend_setcp: bool,
+    pub prologue: Option<Prologue>,
+}
+
+/// Name of the source of a [`Prologue::Source`] in positions
+const PROLOGUE_SOURCE_NAME: &str = "<prologue>";
+
+impl Engine {
+    pub fn set_options(&mut self, options: EngineOptions) {
+        self.options = options;
+    }
+
+    pub fn options(&self) -> &EngineOptions {
+        &self.options
+    }
+
+    fn prologue_error(&self, e: OperationError) -> CompileError {
+        CompileError::operation(1, 1, ".PROLOGUE", e).with_filename(self.source_name.to_string())
+    }
+
+    /// Puts the prologue and `SETCP0` if asked before the toplevel code
+    pub(crate) fn write_prologue(&mut self, units: &mut Units) -> Result<(), CompileError> {
+        let start = DbgPos { filename: self.source_name.clone(), line: 1, ..Default::default() };
+        match self.options.prologue.clone() {
+            None => (),
+            Some(Prologue::Dispatcher { unknown_method }) => {
+                let pos = self.methods.values().map(|(_, pos)| pos).min_by_key(|pos| pos.line)
+                    .cloned()
+                    .unwrap_or_else(|| start.clone());
+                if unknown_method >= 1 << 11 {
+                    let e = ParameterError::OutOfRange.parameter("unknown method exit code");
+                    return Err(self.prologue_error(e))
+                }
+                let mut prologue = Units::new();
+                write_dispatcher(self, &mut prologue, pos.clone())
+                    .and_then(|_| {
+                        // THROWARG
+                        let command = [0xF2, 0xC8 | (unknown_method >> 8) as u8, unknown_method as u8];
+                        prologue.write_command(&command, DbgNode::from(pos))
+                    })
+                    .map_err(|e| self.prologue_error(e))?;
+                let (builder, dbg) = prologue.finalize();
+                units.prepend_unit(Unit::new(builder, dbg)).map_err(|e| self.prologue_error(e))?;
+            }
+            Some(Prologue::Source(source)) => {
+                let source_name = std::mem::replace(&mut self.source_name, self.files.intern(PROLOGUE_SOURCE_NAME));
+                let (line_no, char_no) = self.set_pos(1, 1);
+                let result = self.compile(&source);
+                self.set_pos(line_no, char_no);
+                self.source_name = source_name;
+                let (builder, dbg) = result?.finalize();
+                units.prepend_unit(Unit::new(builder, dbg)).map_err(|e| self.prologue_error(e))?;
+            }
+        }
+        if self.options.prepend_setcp {
+            units.prepend_command(&[0xFF, 0x00], DbgNode::from(start)).map_err(|e| self.prologue_error(e))?;
+        }
+        Ok(())
+    }
+}
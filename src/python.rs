@@ -0,0 +1,103 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+//! Python module `pyever_assembler` built with PyO3, e.g. by `maturin build --features python`
+//! which builds the crate as a `cdylib`
+
+use ever_block::write_boc;
+use pyo3::{
+    exceptions::PyValueError, prelude::*,
+    types::{PyBytes, PyDict, PyList},
+};
+use serde_json::Value;
+
+use crate::{disasm::{disasm_boc_ex, BocRoot}, CompileError};
+
+fn compile_error(error: CompileError, source: &str) -> PyErr {
+    PyValueError::new_err(error.render(Some(source), false))
+}
+
+fn value_error(error: impl ToString) -> PyErr {
+    PyValueError::new_err(error.to_string())
+}
+
+/// Python object of the JSON value
+fn to_python(py: Python, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.into_py(py),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => i.into_py(py),
+            (None, Some(u)) => u.into_py(py),
+            (None, None) => n.as_f64().unwrap_or_default().into_py(py),
+        }
+        Value::String(s) => s.into_py(py),
+        Value::Array(items) => {
+            let list = PyList::empty_bound(py);
+            for item in items {
+                list.append(to_python(py, item)?)?;
+            }
+            list.into_py(py)
+        }
+        Value::Object(fields) => {
+            let dict = PyDict::new_bound(py);
+            for (key, field) in fields {
+                dict.set_item(key, to_python(py, field)?)?;
+            }
+            dict.into_py(py)
+        }
+    })
+}
+
+/// Compiles the source into the boc of the code
+#[pyfunction]
+fn compile_code<'py>(py: Python<'py>, code: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let cell = crate::compile_code_to_cell(code).map_err(|e| compile_error(e, code))?;
+    let boc = write_boc(&cell).map_err(value_error)?;
+    Ok(PyBytes::new_bound(py, &boc))
+}
+
+/// Compiles the source into a dict with the boc of the code, its hash and the debug map
+#[pyfunction]
+#[pyo3(signature = (code, source_name = ""))]
+fn compile_code_debuggable<'py>(py: Python<'py>, code: &str, source_name: &str) -> PyResult<Bound<'py, PyDict>> {
+    let (slice, dbg) = crate::compile_code_debuggable(code, source_name).map_err(|e| compile_error(e, code))?;
+    let cell = slice.into_cell();
+    let boc = write_boc(&cell).map_err(value_error)?;
+    let dbg = serde_json::to_value(&dbg).map_err(value_error)?;
+    let result = PyDict::new_bound(py);
+    result.set_item("boc", PyBytes::new_bound(py, &boc))?;
+    result.set_item("code_hash", cell.repr_hash().to_hex_string())?;
+    result.set_item("dbg", to_python(py, &dbg)?)?;
+    Ok(result)
+}
+
+/// Disassembles the code of the boc, or the one of the StateInit in it
+#[pyfunction]
+#[pyo3(signature = (boc, stateinit = false, collapsed = false))]
+fn disasm(boc: &[u8], stateinit: bool, collapsed: bool) -> PyResult<String> {
+    let root = match stateinit {
+        true => BocRoot::StateInit,
+        false => BocRoot::Code,
+    };
+    disasm_boc_ex(boc, root, collapsed).map_err(value_error)
+}
+
+#[pymodule]
+fn pyever_assembler(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(compile_code, m)?)?;
+    m.add_function(wrap_pyfunction!(compile_code_debuggable, m)?)?;
+    m.add_function(wrap_pyfunction!(disasm, m)?)?;
+    Ok(())
+}
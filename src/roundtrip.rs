@@ -0,0 +1,67 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Round trip of code through the disassembler
+//!
+//! The code is compiled, disassembled into reassemblable text and compiled
+//! again; both compilations must give the same cell. A corpus is a directory
+//! of samples to check the same way: assembly sources (`.code`) and compiled
+//! code (`.boc`), the latter disassembled first.
+
+use std::path::{Path, PathBuf};
+use ever_block::{error, read_boc, Cell, Result, SliceData};
+
+use crate::{compile_code_to_cell, disasm::disasm_reassemblable};
+
+/// Disassembles the cell and checks that the text compiles back to it
+pub fn check_cell(cell: &Cell) -> Result<()> {
+    let text = disasm_reassemblable(&mut SliceData::load_cell_ref(cell)?)?;
+    let recompiled = compile_code_to_cell(&text)
+        .map_err(|e| error!("disassembly does not compile: {}\n{}", e, text))?;
+    if recompiled.repr_hash() != cell.repr_hash() {
+        return Err(error!(
+            "disassembly compiles to {} instead of {}:\n{}",
+            recompiled.repr_hash().to_hex_string(), cell.repr_hash().to_hex_string(), text
+        ))
+    }
+    Ok(())
+}
+
+/// Compiles the code and checks that its disassembly compiles to the same cell
+pub fn check(code: &str) -> Result<()> {
+    let cell = compile_code_to_cell(code).map_err(|e| error!("{}", e))?;
+    check_cell(&cell)
+}
+
+/// Checks the sample of a corpus by its extension
+pub fn check_file(path: &Path) -> Result<()> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("boc") => {
+            let cell = read_boc(std::fs::read(path)?)?.withdraw_single_root()?;
+            check_cell(&cell)
+        }
+        _ => check(&std::fs::read_to_string(path)?),
+    }
+}
+
+/// Checks every `.code` and `.boc` sample of the directory, returns the failed ones with the errors
+pub fn check_corpus(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut samples = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    samples.retain(|path| matches!(path.extension().and_then(|ext| ext.to_str()), Some("code" | "boc")));
+    samples.sort();
+    Ok(samples.into_iter()
+        .filter_map(|path| check_file(&path).err().map(|e| (path, e.to_string())))
+        .collect())
+}
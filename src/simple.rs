@@ -13,8 +13,9 @@
 
 use crate::simple_commands;
 use super::{
-    Units, CompileResult, CompileHandler, Engine, EnsureParametersCountInRange,
+    CompileResult, Engine, EnsureParametersCountInRange, Writer,
     errors::ToOperationParameterError,
+    instructions::SimpleCommand,
     parse::*,
 };
 
@@ -701,9 +702,7 @@ impl Engine {
         STRDUMP                              => 0xFE, 0x14
         STRPRINT                             => 0xFE, 0x15
         STREF                                => 0xCC
-        STREF2CONST                          => 0xCF, 0x21
         STREF3CONST                          => 0xCF, 0xE2
-        STREFCONST                           => 0xCF, 0x20
         STREFQ                               => 0xCF, 0x18
         STREFR                               => 0xCF, 0x14
         STREFRQ                              => 0xCF, 0x1C
@@ -876,25 +875,26 @@ impl Engine {
 
     }
 
-    fn add_commands<'a>(&mut self, iter: impl IntoIterator<Item = &'a (&'static str, CompileHandler)>) {
-        // Add automatic commands
-        for (command, handler) in iter {
-            if self.handlers.insert(command, *handler).is_some() {
-                panic!("Token {} was already registered.", command);
-            }
-        }
-    }
-
-    /// Add automatic commands
-    pub fn add_simple_commands(&mut self) {
-        self.add_commands(Self::enumerate_simple_commands());
+    /// Tables of automatic commands enabled by the features
+    pub(crate) fn simple_command_tables() -> Vec<&'static [SimpleCommand]> {
+        let mut tables = vec!(Self::enumerate_simple_commands());
 
         #[cfg(feature = "gosh")]
-        self.add_commands(Self::enumerate_diff_commands());
-        
+        tables.push(Self::enumerate_diff_commands());
+
         #[cfg(feature = "groth")]
-        self.add_commands(Self::enumerate_groth_commands());
+        tables.push(Self::enumerate_groth_commands());
 
-        self.add_commands(Self::enumerate_bls_commands());
+        tables.push(Self::enumerate_bls_commands());
+        tables
+    }
+
+    /// Add automatic commands
+    pub fn add_simple_commands(&mut self) {
+        for command in Self::simple_command_tables().into_iter().flatten() {
+            if self.handlers.insert(command.name, command.handler).is_some() {
+                panic!("Token {} was already registered.", command.name);
+            }
+        }
     }
 }
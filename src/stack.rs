@@ -0,0 +1,650 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Static stack depth analysis
+//!
+//! The source is parsed into IR and its instructions are executed symbolically
+//! tracking the number of values on the stack. Continuations pushed by PUSHCONT
+//! are followed into conditionals, calls and simple loops. Tracking of a block
+//! stops at the first instruction with an unknown or data-dependent effect.
+//!
+//! Values named by `.vars` keep their names while they are moved around, so
+//! the registers the uses of the names resolve to are known on every path.
+
+use std::{collections::{BTreeSet, HashMap, HashSet}, rc::Rc, sync::Arc};
+
+use crate::{
+    CompileError, DbgPos, Engine,
+    ir::{IrArg, IrInstruction},
+};
+
+/// Maximum nesting of continuations followed by the analysis
+const MAX_CALL_DEPTH: usize = 64;
+
+/// Number of values assumed to be on the stack when resolving stack variables,
+/// the ones below the named values are not known
+const VARS_INPUT: usize = 256;
+
+/// Use of a stack variable: the position of the instruction and the index of the argument
+pub(crate) type VarUse = (Arc<str>, usize, usize, usize);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StackIssue {
+    /// the instruction takes more values than the stack holds
+    Underflow { needed: usize, depth: usize },
+    /// the stack effect of the instruction is not known,
+    /// the rest of the block is not analyzed
+    Untracked,
+    /// branches of a conditional leave different numbers of values
+    BranchMismatch { taken: usize, skipped: usize },
+}
+
+impl std::fmt::Display for StackIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StackIssue::Underflow { needed, depth } =>
+                write!(f, "stack underflow: {} values needed, {} available", needed, depth),
+            StackIssue::Untracked =>
+                write!(f, "stack effect is unknown, the rest of the block is not analyzed"),
+            StackIssue::BranchMismatch { taken, skipped } =>
+                write!(f, "branches leave {} and {} values on the stack", taken, skipped),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackEntry {
+    pub pos: DbgPos,
+    pub instruction: String,
+    pub issue: StackIssue,
+}
+
+impl std::fmt::Display for StackEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.pos, self.instruction, self.issue)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StackReport {
+    pub entries: Vec<StackEntry>,
+    /// maximum depth reached on the tracked paths
+    pub max_depth: usize,
+    /// depth at the end of the code if it is known
+    pub final_depth: Option<usize>,
+}
+
+impl StackReport {
+    pub fn has_underflows(&self) -> bool {
+        self.entries.iter().any(|e| matches!(e.issue, StackIssue::Underflow { .. }))
+    }
+}
+
+/// Analyzes the code assuming it starts with an empty stack
+pub fn analyze_stack(code: &str) -> Result<StackReport, CompileError> {
+    analyze_stack_with_input(code, 0)
+}
+
+/// Analyzes the code assuming it starts with the given number of values on the stack
+pub fn analyze_stack_with_input(code: &str, input: usize) -> Result<StackReport, CompileError> {
+    let ir = Engine::new("").parse_ir(code)?;
+    let mut analyzer = Analyzer { max_depth: input, ..Default::default() };
+    let stack = vec!(Slot::Value(None); input);
+    let final_depth = match analyzer.run(&ir.instructions, stack) {
+        Flow::Next(stack) | Flow::Return(stack) => Some(stack.len()),
+        Flow::Stop | Flow::Unknown => None,
+    };
+    Ok(StackReport { entries: analyzer.entries, max_depth: analyzer.max_depth, final_depth })
+}
+
+/// Depth left by the instructions run on the given number of values if it is known,
+/// along with the issues found on the way
+pub(crate) fn sequence_depth(code: &[IrInstruction], input: usize) -> (Option<usize>, Vec<StackEntry>) {
+    let mut analyzer = Analyzer { max_depth: input, ..Default::default() };
+    let depth = match analyzer.run(code, vec!(Slot::Value(None); input)) {
+        Flow::Next(stack) | Flow::Return(stack) => Some(stack.len()),
+        Flow::Stop | Flow::Unknown => None,
+    };
+    (depth, analyzer.entries)
+}
+
+/// Registers the uses of stack variables resolve to on the paths followed by the analysis,
+/// along with the names given to values at different places on joining paths
+pub(crate) fn resolve_var_uses(code: &[IrInstruction]) -> (HashMap<VarUse, BTreeSet<usize>>, HashSet<String>) {
+    let mut analyzer = Analyzer { vars: Some(HashMap::new()), ..Default::default() };
+    analyzer.run(code, vec!(Slot::Value(None); VARS_INPUT));
+    // bodies of fragments and methods are analyzed on their own as well
+    let bodies = code.iter()
+        .filter(|insn| matches!(insn.name.as_str(), ".FRAGMENT" | ".METHOD"))
+        .flat_map(|insn| insn.args.iter())
+        .filter_map(|arg| match arg {
+            IrArg::Block(code) => Some(code),
+            _ => None
+        });
+    for body in bodies {
+        analyzer.run(body, vec!(Slot::Value(None); VARS_INPUT));
+    }
+    let ambiguous = analyzer.ambiguous.iter().map(|name| name.to_string()).collect();
+    (analyzer.vars.unwrap_or_default(), ambiguous)
+}
+
+fn var_use(insn: &IrInstruction, index: usize) -> Option<VarUse> {
+    let pos = insn.pos.as_ref()?;
+    Some((pos.filename.clone(), pos.line, pos.column, index))
+}
+
+fn has_vars(insn: &IrInstruction) -> bool {
+    insn.args.iter().any(|arg| matches!(arg, IrArg::Token(token) if token.starts_with('$')))
+}
+
+#[derive(Clone, Debug)]
+enum Slot {
+    /// value along with the name given by `.vars`
+    Value(Option<Rc<str>>),
+    Cont(Rc<[IrInstruction]>),
+}
+
+enum Flow {
+    /// execution continues with the next instruction
+    Next(Vec<Slot>),
+    /// the current continuation returns to its caller
+    Return(Vec<Slot>),
+    /// execution never reaches the next instruction
+    Stop,
+    /// the stack is not tracked anymore
+    Unknown,
+}
+
+#[derive(Default)]
+struct Analyzer {
+    fragments: HashMap<String, Rc<[IrInstruction]>>,
+    entries: Vec<StackEntry>,
+    max_depth: usize,
+    call_depth: usize,
+    /// registers the uses of stack variables resolve to, if they are resolved
+    vars: Option<HashMap<VarUse, BTreeSet<usize>>>,
+    /// names given to values at different places on joining paths
+    ambiguous: HashSet<Rc<str>>,
+}
+
+fn stack_register(arg: &IrArg) -> Option<usize> {
+    match arg {
+        IrArg::Token(token) => token.strip_prefix(&['s', 'S'][..])?.parse().ok(),
+        _ => None
+    }
+}
+
+fn is_control_register(arg: Option<&IrArg>) -> bool {
+    matches!(arg, Some(IrArg::Token(token)) if token.starts_with(&['c', 'C'][..]))
+}
+
+fn number(arg: Option<&IrArg>) -> Option<usize> {
+    match arg {
+        Some(IrArg::Token(token)) => token.parse().ok(),
+        _ => None
+    }
+}
+
+/// Permutation of the top values: the size of the window and the new contents
+/// of the window given by indices of the old values counting from its bottom
+fn shuffle(name: &str, args: &[IrArg]) -> Option<(usize, Vec<usize>)> {
+    let regs = args.iter().map(stack_register).collect::<Option<Vec<_>>>()?;
+    let push = |i: usize| (i + 1, (0..=i).chain(std::iter::once(0)).collect());
+    let pop = |i: usize| match i {
+        0 => (1, vec!()),
+        i => (i + 1, std::iter::once(i).chain(1..i).collect()),
+    };
+    let xchg = |i: usize, j: usize| {
+        let n = i.max(j) + 1;
+        let mut window = (0..n).collect::<Vec<_>>();
+        window.swap(n - 1 - i, n - 1 - j);
+        (n, window)
+    };
+    Some(match (name, regs.as_slice()) {
+        ("PUSH", [i]) => push(*i),
+        ("DUP", []) => push(0),
+        ("OVER", []) => push(1),
+        ("POP", [i]) => pop(*i),
+        ("DROP", []) => pop(0),
+        ("NIP", []) => pop(1),
+        ("XCHG", [i]) => xchg(0, *i),
+        ("XCHG", [i, j]) => xchg(*i, *j),
+        ("SWAP", []) => xchg(0, 1),
+        ("ROT", []) => (3, vec!(1, 2, 0)),
+        ("ROTREV" | "-ROT", []) => (3, vec!(2, 0, 1)),
+        ("TUCK", []) => (2, vec!(1, 0, 1)),
+        ("DROP2" | "2DROP", []) => (2, vec!()),
+        ("DUP2" | "2DUP", []) => (2, vec!(0, 1, 0, 1)),
+        ("OVER2" | "2OVER", []) => (4, vec!(0, 1, 2, 3, 0, 1)),
+        ("SWAP2" | "2SWAP", []) => (4, vec!(2, 3, 0, 1)),
+        _ => return None
+    })
+}
+
+/// Number of values taken and pushed by instructions which don't execute code
+fn simple_effect(name: &str, args: &[IrArg]) -> Option<(usize, usize)> {
+    Some(match name {
+        "NOP" | "ACCEPT" | "COMMIT" | "SETCP" | "SETCP0" | "SETCPX" => (0, 0),
+        "PUSHINT" | "PUSHPOW2" | "PUSHPOW2DEC" | "PUSHNEGPOW2" | "PUSHNAN" | "ZERO" | "ONE" | "TWO" | "TEN"
+        | "TRUE" | "FALSE" | "NULL" | "PUSHNULL" | "NEWC" | "NEWDICT" | "PUSHSLICE" | "PUSHREF" | "PUSHREFSLICE"
+        | "NOW" | "BLOCKLT" | "LTIME" | "RANDSEED" | "BALANCE" | "MYADDR" | "CONFIGROOT" | "MYCODE"
+        | "INITCODEHASH" | "STORAGEFEE" | "GETPARAM" | "DEPTH" | "PUSHCTR" => (0, 1),
+        "PUSH" if is_control_register(args.first()) => (0, 1),
+        "POP" if is_control_register(args.first()) => (1, 0),
+        "POPCTR" => (1, 0),
+        "INC" | "DEC" | "NEGATE" | "NOT" | "ABS" | "ISZERO" | "ISNEG" | "ISPOS" | "ISNNEG" | "ISNPOS" | "ISNULL"
+        | "ISNAN" | "ISTUPLE" | "CTOS" | "ENDC" | "HASHCU" | "HASHSU" | "SHA256U" | "ADDCONST" | "MULCONST"
+        | "EQINT" | "NEQINT" | "LESSINT" | "GTINT" | "FITS" | "UFITS" | "BITSIZE" | "UBITSIZE" | "FIRST"
+        | "SECOND" | "THIRD" | "INDEX" | "TLEN" | "QTLEN" | "SBITS" | "SREFS" | "BBITS" | "BREFS" | "PLDU"
+        | "PLDI" | "PLDREF" | "PLDDICT" | "SEMPTY" | "SDEMPTY" | "SREMPTY" | "BLESS" => (1, 1),
+        "LSHIFT" | "RSHIFT" if !args.is_empty() => (1, 1),
+        "ADD" | "SUB" | "SUBR" | "MUL" | "DIV" | "DIVC" | "DIVR" | "MOD" | "MIN" | "MAX" | "AND" | "OR" | "XOR"
+        | "LSHIFT" | "RSHIFT" | "LESS" | "LEQ" | "GREATER" | "GEQ" | "EQUAL" | "NEQ" | "CMP" | "SDEQ" | "STU"
+        | "STI" | "STUR" | "STIR" | "STREF" | "STSLICE" | "STB" | "STDICT" | "STGRAMS" | "STVARUINT32" | "PAIR"
+        | "CONS" | "SETFIRST" | "SETSECOND" | "SETTHIRD" | "SETINDEX" => (2, 1),
+        "LDU" | "LDI" | "LDREF" | "LDDICT" | "LDMSGADDR" | "LDGRAMS" | "LDVARUINT32" | "LDSLICE" | "UNPAIR"
+        | "UNCONS" | "SBITREFS" | "BBITREFS" => (1, 2),
+        "DIVMOD" => (2, 2),
+        "ENDS" | "THROWIF" | "THROWIFNOT" | "SETCODE" => (1, 0),
+        "SENDRAWMSG" | "RAWRESERVE" | "SETLIBCODE" => (2, 0),
+        "CONDSEL" | "MULDIV" | "MULDIVR" | "MULDIVC" | "CHKSIGNU" | "CHKSIGNS" => (3, 1),
+        "MULDIVMOD" => (3, 2),
+        "DICTSET" | "DICTISET" | "DICTUSET" | "DICTSETREF" | "DICTISETREF" | "DICTUSETREF" => (4, 1),
+        "TUPLE" => (number(args.first())?, 1),
+        "UNTUPLE" => (1, number(args.first())?),
+        "BLKDROP" => (number(args.first())?, 0),
+        _ => return None
+    })
+}
+
+/// Numbers of values taken and left by the instruction if known without executing it
+pub(crate) fn stack_effect(insn: &IrInstruction) -> Option<(usize, usize)> {
+    match shuffle(&insn.name, &insn.args) {
+        Some((n, window)) => Some((n, window.len())),
+        None => simple_effect(&insn.name, &insn.args),
+    }
+}
+
+impl Analyzer {
+    fn report(&mut self, insn: &IrInstruction, issue: StackIssue) {
+        self.entries.push(StackEntry {
+            pos: insn.pos.clone().unwrap_or_default(),
+            instruction: insn.name.clone(),
+            issue,
+        })
+    }
+
+    fn untracked(&mut self, insn: &IrInstruction) -> Flow {
+        self.report(insn, StackIssue::Untracked);
+        Flow::Unknown
+    }
+
+    /// Checks that the stack holds enough values reporting an underflow otherwise
+    fn ensure(&mut self, insn: &IrInstruction, stack: &[Slot], needed: usize) -> bool {
+        if stack.len() < needed {
+            self.report(insn, StackIssue::Underflow { needed, depth: stack.len() });
+            return false
+        }
+        true
+    }
+
+    fn run(&mut self, code: &[IrInstruction], mut stack: Vec<Slot>) -> Flow {
+        for insn in code {
+            match self.step(insn, stack) {
+                Flow::Next(next) => {
+                    self.max_depth = self.max_depth.max(next.len());
+                    stack = next
+                }
+                flow => return flow
+            }
+        }
+        Flow::Next(stack)
+    }
+
+    /// Executes a continuation as a call: returning from it continues the caller
+    fn call(&mut self, insn: &IrInstruction, code: &[IrInstruction], stack: Vec<Slot>) -> Flow {
+        if self.call_depth >= MAX_CALL_DEPTH {
+            return self.untracked(insn)
+        }
+        self.call_depth += 1;
+        let flow = match self.run(code, stack) {
+            Flow::Return(stack) => Flow::Next(stack),
+            flow => flow,
+        };
+        self.call_depth -= 1;
+        flow
+    }
+
+    /// Executes a continuation as a jump: its end is the end of the current continuation
+    fn jump(&mut self, insn: &IrInstruction, code: &[IrInstruction], stack: Vec<Slot>) -> Flow {
+        match self.call(insn, code, stack) {
+            Flow::Next(stack) => Flow::Return(stack),
+            flow => flow,
+        }
+    }
+
+    /// Joins the paths of a conditional
+    fn merge(&mut self, insn: &IrInstruction, taken: Flow, skipped: Flow) -> Flow {
+        match (taken, skipped) {
+            (Flow::Unknown, _) | (_, Flow::Unknown) => Flow::Unknown,
+            (Flow::Stop, flow) | (flow, Flow::Stop) => flow,
+            (Flow::Return(a), Flow::Return(b)) if a.len() == b.len() => Flow::Return(self.join(a, &b)),
+            (Flow::Return(_), flow) | (flow, Flow::Return(_)) => flow,
+            (Flow::Next(a), Flow::Next(b)) => if a.len() == b.len() {
+                Flow::Next(self.join(a, &b))
+            } else {
+                self.report(insn, StackIssue::BranchMismatch { taken: a.len(), skipped: b.len() });
+                Flow::Unknown
+            }
+        }
+    }
+
+    /// Keeps the names of the values the paths agree on, the other names become ambiguous
+    fn join(&mut self, mut stack: Vec<Slot>, other: &[Slot]) -> Vec<Slot> {
+        for (slot, other) in stack.iter_mut().zip(other) {
+            if let (Slot::Value(name), Slot::Value(other)) = (&*slot, other) {
+                if name != other {
+                    self.ambiguous.extend(name.iter().chain(other).cloned());
+                    *slot = Slot::Value(None);
+                }
+            }
+        }
+        stack
+    }
+
+    /// Replaces the stack variables of the instruction with the registers the values are at
+    fn resolve_vars(&mut self, insn: &IrInstruction, stack: &[Slot]) -> IrInstruction {
+        let mut resolved = insn.clone();
+        for (index, arg) in resolved.args.iter_mut().enumerate() {
+            let name = match arg {
+                IrArg::Token(token) if token.starts_with('$') => token[1..].to_string(),
+                _ => continue
+            };
+            let register = stack.iter().rev()
+                .position(|slot| matches!(slot, Slot::Value(Some(n)) if **n == *name));
+            if let (Some(register), Some(key)) = (register, var_use(insn, index)) {
+                if let Some(vars) = self.vars.as_mut() {
+                    vars.entry(key).or_default().insert(register);
+                }
+                *arg = IrArg::Token(format!("s{}", register));
+            }
+        }
+        resolved
+    }
+
+    /// Pops the values of a conditional: the continuations on top and the flag below
+    fn pop_conditional(&mut self, insn: &IrInstruction, stack: &mut Vec<Slot>, conts: usize) -> Result<Vec<Rc<[IrInstruction]>>, Flow> {
+        if !self.ensure(insn, stack, conts + 1) {
+            return Err(Flow::Unknown)
+        }
+        let mut popped = Vec::new();
+        for _ in 0..conts {
+            match stack.pop() {
+                Some(Slot::Cont(code)) => popped.push(code),
+                _ => return Err(self.untracked(insn))
+            }
+        }
+        stack.pop();
+        popped.reverse();
+        Ok(popped)
+    }
+
+    fn step(&mut self, insn: &IrInstruction, stack: Vec<Slot>) -> Flow {
+        if self.vars.is_some() && has_vars(insn) {
+            let resolved = self.resolve_vars(insn, &stack);
+            return self.execute(&resolved, stack)
+        }
+        self.execute(insn, stack)
+    }
+
+    fn execute(&mut self, insn: &IrInstruction, mut stack: Vec<Slot>) -> Flow {
+        let name = insn.name.as_str();
+        let blocks = insn.args.iter().filter_map(|arg| match arg {
+            IrArg::Block(code) => Some(code.as_slice()),
+            _ => None
+        }).collect::<Vec<_>>();
+        if let Some((n, window)) = shuffle(name, &insn.args) {
+            if !self.ensure(insn, &stack, n) {
+                return Flow::Unknown
+            }
+            let old = stack.split_off(stack.len() - n);
+            stack.extend(window.into_iter().map(|i| old[i].clone()));
+            return Flow::Next(stack)
+        }
+        if let Some((pops, pushes)) = simple_effect(name, &insn.args) {
+            if !self.ensure(insn, &stack, pops) {
+                return Flow::Unknown
+            }
+            stack.truncate(stack.len() - pops);
+            stack.extend(std::iter::repeat(Slot::Value(None)).take(pushes));
+            return Flow::Next(stack)
+        }
+        match (name, blocks.as_slice()) {
+            (".FRAGMENT", [code]) => {
+                if let Some(IrArg::Token(fragment)) = insn.args.first() {
+                    self.fragments.insert(fragment.clone(), Rc::from(code.to_vec()));
+                }
+                Flow::Next(stack)
+            }
+            (".LOC" | ".LABEL" | ".MACRO" | ".DEFINE" | ".METHOD" | ".DATA", _) => Flow::Next(stack),
+            (".VARS", _) => {
+                let names = insn.args.iter().filter_map(|arg| match arg {
+                    IrArg::Token(name) => Some(Rc::<str>::from(name.as_str())),
+                    _ => None
+                }).collect::<Vec<_>>();
+                if !self.ensure(insn, &stack, names.len()) {
+                    return Flow::Unknown
+                }
+                // a name is given to a single value
+                for slot in stack.iter_mut() {
+                    if matches!(slot, Slot::Value(Some(name)) if names.contains(name)) {
+                        *slot = Slot::Value(None);
+                    }
+                }
+                let start = stack.len() - names.len();
+                for (slot, name) in stack[start..].iter_mut().zip(names) {
+                    *slot = Slot::Value(Some(name));
+                }
+                Flow::Next(stack)
+            }
+            (".INLINE", _) => {
+                let fragment = match insn.args.first() {
+                    Some(IrArg::Token(fragment)) => self.fragments.get(fragment).cloned(),
+                    _ => None
+                };
+                match fragment {
+                    Some(code) => self.call(insn, &code, stack),
+                    None => self.untracked(insn),
+                }
+            }
+            ("PUSHCONT" | "PUSHREFCONT", [code]) => {
+                stack.push(Slot::Cont(Rc::from(code.to_vec())));
+                Flow::Next(stack)
+            }
+            ("THROW", _) => Flow::Stop,
+            ("THROWANY", _) => match self.ensure(insn, &stack, 1) {
+                true => Flow::Stop,
+                false => Flow::Unknown,
+            },
+            ("RET", _) => Flow::Return(stack),
+            ("RETALT", _) => Flow::Stop,
+            ("CALLREF", [code]) => self.call(insn, code, stack),
+            ("JMPREF", [code]) => self.jump(insn, code, stack),
+            ("EXECUTE" | "CALLX" | "JMPX", _) => {
+                if !self.ensure(insn, &stack, 1) {
+                    return Flow::Unknown
+                }
+                match stack.pop() {
+                    Some(Slot::Cont(code)) if name == "JMPX" => self.jump(insn, &code, stack),
+                    Some(Slot::Cont(code)) => self.call(insn, &code, stack),
+                    _ => self.untracked(insn),
+                }
+            }
+            ("IF" | "IFNOT" | "IFJMP" | "IFNOTJMP", []) => match self.pop_conditional(insn, &mut stack, 1) {
+                Ok(conts) => {
+                    let taken = match name {
+                        "IF" | "IFNOT" => self.call(insn, &conts[0], stack.clone()),
+                        _ => self.jump(insn, &conts[0], stack.clone()),
+                    };
+                    self.merge(insn, taken, Flow::Next(stack))
+                }
+                Err(flow) => flow,
+            }
+            ("IFREF" | "IFNOTREF" | "IFJMPREF" | "IFNOTJMPREF", [code]) => {
+                if !self.ensure(insn, &stack, 1) {
+                    return Flow::Unknown
+                }
+                stack.pop();
+                let taken = match name {
+                    "IFREF" | "IFNOTREF" => self.call(insn, code, stack.clone()),
+                    _ => self.jump(insn, code, stack.clone()),
+                };
+                self.merge(insn, taken, Flow::Next(stack))
+            }
+            ("IFELSE", []) => match self.pop_conditional(insn, &mut stack, 2) {
+                Ok(conts) => {
+                    let taken = self.call(insn, &conts[0], stack.clone());
+                    let skipped = self.call(insn, &conts[1], stack);
+                    self.merge(insn, taken, skipped)
+                }
+                Err(flow) => flow,
+            }
+            ("IFREFELSE" | "IFELSEREF", [code]) => match self.pop_conditional(insn, &mut stack, 1) {
+                Ok(conts) => {
+                    let taken = self.call(insn, code, stack.clone());
+                    let skipped = self.call(insn, &conts[0], stack);
+                    self.merge(insn, taken, skipped)
+                }
+                Err(flow) => flow,
+            }
+            ("IFREFELSEREF", [first, second]) => {
+                if !self.ensure(insn, &stack, 1) {
+                    return Flow::Unknown
+                }
+                stack.pop();
+                let taken = self.call(insn, first, stack.clone());
+                let skipped = self.call(insn, second, stack);
+                self.merge(insn, taken, skipped)
+            }
+            ("REPEAT" | "UNTIL" | "WHILE" | "AGAIN", []) => self.run_loop(insn, stack),
+            _ => self.untracked(insn),
+        }
+    }
+
+    /// Follows a loop body once accepting it if it keeps the stack balanced
+    fn run_loop(&mut self, insn: &IrInstruction, mut stack: Vec<Slot>) -> Flow {
+        let name = insn.name.as_str();
+        let (conts, counter) = match name {
+            "REPEAT" => (1, true),
+            "WHILE" => (2, false),
+            _ => (1, false),
+        };
+        if !self.ensure(insn, &stack, conts + counter as usize) {
+            return Flow::Unknown
+        }
+        let mut bodies = Vec::new();
+        for _ in 0..conts {
+            match stack.pop() {
+                Some(Slot::Cont(code)) => bodies.push(code),
+                _ => return self.untracked(insn)
+            }
+        }
+        bodies.reverse();
+        if counter {
+            stack.pop();
+        }
+        let depth = stack.len();
+        // the body of UNTIL and the condition of WHILE leave a flag on top
+        let flagged = match name {
+            "UNTIL" | "WHILE" => depth + 1,
+            _ => depth,
+        };
+        let after = match self.call(insn, &bodies[0], stack) {
+            Flow::Next(stack) if stack.len() == flagged => stack,
+            Flow::Next(_) => return self.untracked(insn),
+            flow => return flow,
+        };
+        match name {
+            "AGAIN" => Flow::Stop,
+            "WHILE" => {
+                let mut stack = after;
+                stack.pop();
+                match self.call(insn, &bodies[1], stack.clone()) {
+                    Flow::Next(body) if body.len() == depth => Flow::Next(stack),
+                    Flow::Unknown => Flow::Unknown,
+                    _ => self.untracked(insn),
+                }
+            }
+            "UNTIL" => {
+                let mut stack = after;
+                stack.pop();
+                Flow::Next(stack)
+            }
+            _ => Flow::Next(after),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issues(report: &StackReport) -> Vec<(&str, &StackIssue)> {
+        report.entries.iter().map(|entry| (entry.instruction.as_str(), &entry.issue)).collect()
+    }
+
+    #[test]
+    fn straight_line() {
+        let report = analyze_stack("PUSHINT 1\nPUSHINT 2\nADD\nNOW\nSTORAGEFEE").unwrap();
+        assert_eq!(report, StackReport { entries: vec!(), max_depth: 3, final_depth: Some(3) });
+        let report = analyze_stack_with_input("SWAP\nROT\nDROP\nTUCK", 3).unwrap();
+        assert_eq!((report.final_depth, report.max_depth), (Some(3), 3));
+    }
+
+    #[test]
+    fn underflow_and_untracked() {
+        let report = analyze_stack("PUSHINT 1\nADD").unwrap();
+        assert!(report.has_underflows());
+        assert_eq!(issues(&report), vec!(("ADD", &StackIssue::Underflow { needed: 2, depth: 1 })));
+        assert_eq!(report.final_depth, None);
+
+        let report = analyze_stack("PUSHINT 1\nCALLDICT 5\nADD").unwrap();
+        assert_eq!(issues(&report), vec!(("CALLDICT", &StackIssue::Untracked)));
+        assert_eq!(report.final_depth, None);
+    }
+
+    #[test]
+    fn conditionals() {
+        let report = analyze_stack("PUSHINT 5\nPUSHINT 1\nPUSHCONT {\n  INC\n}\nIF").unwrap();
+        assert_eq!((report.final_depth, report.entries.len()), (Some(1), 0));
+
+        let report = analyze_stack("PUSHINT 1\nPUSHCONT {\n  PUSHINT 2\n}\nIF").unwrap();
+        assert_eq!(issues(&report), vec!(("IF", &StackIssue::BranchMismatch { taken: 1, skipped: 0 })));
+
+        // a branch throwing does not take part in the join
+        let report = analyze_stack("PUSHINT 1\nIFREF {\n  PUSHINT 7\n  THROW 5\n}\nPUSHINT 2").unwrap();
+        assert_eq!((report.final_depth, report.entries.len()), (Some(1), 0));
+
+        let report = analyze_stack("PUSHINT 1\nIFREFELSEREF {\n  PUSHINT 2\n}{\n  PUSHINT 3\n}").unwrap();
+        assert_eq!((report.final_depth, report.entries.len()), (Some(1), 0));
+    }
+
+    #[test]
+    fn loops_and_fragments() {
+        let report = analyze_stack("PUSHINT 0\nPUSHINT 3\nPUSHCONT {\n  INC\n}\nREPEAT").unwrap();
+        assert_eq!((report.final_depth, report.entries.len()), (Some(1), 0));
+
+        let report = analyze_stack(".fragment f, {\n  INC\n}\nPUSHINT 1\n.inline f").unwrap();
+        assert_eq!((report.final_depth, report.entries.len()), (Some(1), 0));
+    }
+}
@@ -0,0 +1,30 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+use ever_block::{Cell, MsgAddressInt, Result, Serializable, StateInit, StateInitLib};
+
+/// StateInit of a contract with the compiled code, the data and the library dictionary
+pub fn build_stateinit(code: Cell, data: Option<Cell>, libraries: Option<Cell>) -> StateInit {
+    StateInit {
+        code: Some(code),
+        data,
+        library: StateInitLib::with_hashmap(libraries),
+        ..Default::default()
+    }
+}
+
+/// Address of the contract deployed with the StateInit to the workchain
+pub fn stateinit_address(state_init: &StateInit, workchain_id: i8) -> Result<MsgAddressInt> {
+    let hash = state_init.serialize()?.repr_hash();
+    MsgAddressInt::with_standart(None, workchain_id, hash.into())
+}
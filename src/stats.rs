@@ -0,0 +1,317 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Size statistics of compiled code
+//!
+//! Cells are counted once however many times they are referred to, as they
+//! are stored in a boc. The largest cells are listed with the source position
+//! of their first instruction to find the code approaching the limits.
+//! The limits set by [`CodeLimits`] are checked right after compilation
+//! instead of at deployment. Identical subtrees produced by different fragments
+//! are shared by [`share_identical_cells`] when the code is finalized, so the
+//! tree in memory takes as many cells as the boc.
+
+use std::collections::{HashMap, HashSet};
+use ever_block::{BuilderData, Cell, CellType, Result, SliceData, UInt256};
+
+use crate::{
+    disasm::{loader::Loader, types::{Code, InstructionParameter}},
+    CompileError, DbgInfo, DbgPos, Engine, Position, Unit,
+};
+
+/// Number of the largest cells listed by default
+pub const LARGEST_CELLS: usize = 10;
+
+/// Cell of the code with the position it starts at
+#[derive(Clone, Debug)]
+pub struct CellSize {
+    pub hash: UInt256,
+    pub bits: usize,
+    pub refs: usize,
+    pub pos: Option<DbgPos>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CodeStats {
+    /// number of distinct cells
+    pub cells: usize,
+    /// data bits of the distinct cells
+    pub bits: usize,
+    pub depth: u16,
+    /// number of cells by the number of their references
+    pub fanout: [usize; 5],
+    /// the largest cells by bits, the largest first
+    pub largest: Vec<CellSize>,
+}
+
+/// Statistics of the tree of cells listing the given number of the largest ones
+pub fn code_stats(root: &Cell, dbg: Option<&DbgInfo>, top: usize) -> CodeStats {
+    let mut stats = CodeStats { depth: root.repr_depth(), ..Default::default() };
+    let mut seen = HashSet::new();
+    let mut stack = vec!(root.clone());
+    let mut sizes = Vec::new();
+    while let Some(cell) = stack.pop() {
+        let hash = cell.repr_hash();
+        if !seen.insert(hash.inner()) {
+            continue
+        }
+        let (bits, refs) = (cell.bit_length(), cell.references_count());
+        stats.cells += 1;
+        stats.bits += bits;
+        stats.fanout[refs] += 1;
+        sizes.push((bits, refs, hash));
+        stack.extend((0..refs).filter_map(|i| cell.reference(i).ok()));
+    }
+    sizes.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.2.as_slice().cmp(b.2.as_slice())));
+    stats.largest = sizes.into_iter().take(top).map(|(bits, refs, hash)| {
+        let pos = dbg.and_then(|dbg| dbg.get(&hash)).and_then(|positions| positions.values().next().cloned());
+        CellSize { hash, bits, refs, pos }
+    }).collect();
+    stats
+}
+
+/// Uses of a mnemonic in the code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpcodeUsage {
+    pub name: String,
+    pub count: usize,
+    /// bits taken by the encodings of the instructions
+    pub bits: usize,
+}
+
+/// Uses of the mnemonics, the ones taking most bits first
+#[derive(Clone, Debug, Default)]
+pub struct OpcodeHistogram {
+    pub opcodes: Vec<OpcodeUsage>,
+}
+
+/// Counts the instructions of the code by mnemonic, a cell referred to several times counted once
+pub fn opcode_histogram(root: &Cell) -> Result<OpcodeHistogram> {
+    fn count(code: &Code, usages: &mut HashMap<String, (usize, usize)>) {
+        for insn in code.iter() {
+            // collapsed cells and continuations have no encoding of their own
+            if let Some(bytecode) = insn.bytecode() {
+                let name = match insn.is_quiet() {
+                    true => format!("{}Q", insn.name()),
+                    false => insn.name().to_string(),
+                };
+                let usage = usages.entry(name).or_default();
+                usage.0 += 1;
+                usage.1 += bytecode.remaining_bits();
+            }
+            for param in insn.params() {
+                if let InstructionParameter::Code { code, .. } = param {
+                    count(code, usages);
+                }
+            }
+        }
+    }
+    let code = Loader::new(true).load(&mut SliceData::load_cell_ref(root)?, false)?;
+    let mut usages = HashMap::new();
+    count(&code, &mut usages);
+    let mut opcodes = usages.into_iter()
+        .map(|(name, (count, bits))| OpcodeUsage { name, count, bits })
+        .collect::<Vec<_>>();
+    opcodes.sort_by(|a, b| b.bits.cmp(&a.bits).then_with(|| a.name.cmp(&b.name)));
+    Ok(OpcodeHistogram { opcodes })
+}
+
+impl std::fmt::Display for OpcodeHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{:<20} {:>7} {:>8}", "mnemonic", "count", "bits")?;
+        for usage in &self.opcodes {
+            writeln!(f, "{:<20} {:>7} {:>8}", usage.name, usage.count, usage.bits)?;
+        }
+        Ok(())
+    }
+}
+
+/// Limits of the compiled code, none by default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CodeLimits {
+    pub max_depth: Option<u16>,
+    pub max_cells: Option<usize>,
+    pub max_bits: Option<usize>,
+}
+
+/// Positions of the cells from the root down to the deepest one
+pub fn deepest_path(root: &Cell, dbg: &DbgInfo) -> Vec<DbgPos> {
+    let mut path = Vec::new();
+    let mut cell = root.clone();
+    loop {
+        if let Some(pos) = dbg.get(&cell.repr_hash()).and_then(|positions| positions.values().next()) {
+            path.push(pos.clone());
+        }
+        let deepest = (0..cell.references_count())
+            .filter_map(|i| cell.reference(i).ok())
+            .max_by_key(|child| child.repr_depth());
+        match deepest {
+            Some(child) => cell = child,
+            None => return path
+        }
+    }
+}
+
+/// Cells shared by [`share_identical_cells`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// references replaced by the cell with the same hash met before
+    pub merged: usize,
+    pub distinct: usize,
+}
+
+fn share(cell: &Cell, seen: &mut HashMap<UInt256, Cell>, stats: &mut DedupStats) -> Result<(Cell, bool)> {
+    let hash = cell.repr_hash();
+    if let Some(shared) = seen.get(&hash) {
+        stats.merged += 1;
+        return Ok((shared.clone(), true))
+    }
+    let mut shared = cell.clone();
+    let mut changed = false;
+    if cell.cell_type() == CellType::Ordinary && cell.references_count() > 0 {
+        let mut children = Vec::new();
+        for i in 0..cell.references_count() {
+            let (child, child_changed) = share(&cell.reference(i)?, seen, stats)?;
+            changed |= child_changed;
+            children.push(child);
+        }
+        if changed {
+            let data = SliceData::load_cell_ref(cell)?.get_bytestring(0);
+            let mut builder = BuilderData::with_raw(&data[..], cell.bit_length())?;
+            for child in children {
+                builder.checked_append_reference(child)?;
+            }
+            shared = builder.into_cell()?;
+        }
+    }
+    seen.insert(hash, shared.clone());
+    Ok((shared, changed))
+}
+
+/// Tree of the same cells where every subtree is a single instance however
+/// many times it occurs
+pub fn share_identical_cells(root: &Cell) -> Result<(Cell, DedupStats)> {
+    let mut seen = HashMap::new();
+    let mut stats = DedupStats::default();
+    let (root, _) = share(root, &mut seen, &mut stats)?;
+    stats.distinct = seen.len();
+    Ok((root, stats))
+}
+
+impl Engine {
+    /// Cells shared when the code was finalized last time
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.dedup_stats
+    }
+
+    /// Shares identical subtrees of the code, keeping it as is on failure
+    pub(crate) fn share_identical_cells(&self, builder: BuilderData) -> (BuilderData, DedupStats) {
+        let shared = builder.clone().into_cell()
+            .and_then(|cell| share_identical_cells(&cell))
+            .and_then(|(cell, stats)| Ok((BuilderData::from_cell(&cell)?, stats)));
+        shared.unwrap_or((builder, DedupStats::default()))
+    }
+
+    /// Sets the limits checked by [`Engine::check_limits`]
+    pub fn set_limits(&mut self, limits: CodeLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> CodeLimits {
+        self.limits
+    }
+
+    /// Checks the compiled code against the limits; an excess is reported at
+    /// the deepest cell along with the path to it, or at the largest cell
+    pub fn check_limits(&self, root: &Cell, dbg: &DbgInfo) -> std::result::Result<(), CompileError> {
+        if self.limits == CodeLimits::default() {
+            return Ok(())
+        }
+        let stats = code_stats(root, Some(dbg), 1);
+        let exceeded = |explanation: String, path: Vec<DbgPos>| {
+            let position = path.last().map_or(Position::new(String::new(), 0, 0), Position::from_dbgpos);
+            Err(CompileError::LimitExceeded(position, explanation, path))
+        };
+        if let Some(max) = self.limits.max_depth.filter(|max| stats.depth > *max) {
+            return exceeded(format!("depth {} of the code exceeds {}", stats.depth, max), deepest_path(root, dbg))
+        }
+        let largest = stats.largest.first().and_then(|cell| cell.pos.clone()).into_iter().collect::<Vec<_>>();
+        if let Some(max) = self.limits.max_cells.filter(|max| stats.cells > *max) {
+            return exceeded(format!("{} cells of the code exceed {}", stats.cells, max), largest)
+        }
+        if let Some(max) = self.limits.max_bits.filter(|max| stats.bits > *max) {
+            return exceeded(format!("{} bits of the code exceed {}", stats.bits, max), largest)
+        }
+        Ok(())
+    }
+}
+
+impl Unit {
+    /// Size statistics of the unit's tree of cells
+    pub fn stats(&self) -> Result<CodeStats> {
+        let (builder, dbg) = self.clone().into_parts();
+        let cell = builder.into_cell()?;
+        let dbg = DbgInfo::from(cell.clone(), dbg);
+        Ok(code_stats(&cell, Some(&dbg), LARGEST_CELLS))
+    }
+}
+
+impl std::fmt::Display for DedupStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} references to identical cells merged, {} distinct cells", self.merged, self.distinct)
+    }
+}
+
+impl std::fmt::Display for CodeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "cells  {:>8}", self.cells)?;
+        writeln!(f, "bits   {:>8}", self.bits)?;
+        writeln!(f, "depth  {:>8}", self.depth)?;
+        writeln!(f, "refs   cells")?;
+        for (refs, count) in self.fanout.iter().enumerate() {
+            writeln!(f, "{:>4}   {:>5}", refs, count)?;
+        }
+        writeln!(f, "largest cells:")?;
+        writeln!(f, "{:>5} {:>4}  {:<64}  position", "bits", "refs", "hash")?;
+        for cell in &self.largest {
+            let pos = cell.pos.as_ref().map_or(String::new(), |pos| pos.to_string());
+            writeln!(f, "{:>5} {:>4}  {}  {}", cell.bits, cell.refs, cell.hash.to_hex_string(), pos)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::compile;
+
+    #[test]
+    fn identical_cells_merged() {
+        let source = ".fragment f, {\n  PUSHREFCONT {\n    INC\n  }\n  DEC\n}\nCALLREF {\n  .inline f\n}\nJMPREF {\n  .inline f\n}";
+        let mut engine = Engine::new("");
+        assert_eq!(engine.dedup_stats(), DedupStats::default());
+        let code = compile(&mut engine, source).unwrap();
+        // the second copy of the fragment is not looked into
+        assert_eq!(engine.dedup_stats(), DedupStats { merged: 1, distinct: 3 });
+        assert_eq!(code.reference(0).unwrap().repr_hash(), code.reference(1).unwrap().repr_hash());
+
+        let (shared, stats) = share_identical_cells(&code).unwrap();
+        assert_eq!(shared.repr_hash(), code.repr_hash());
+        assert_eq!(stats, engine.dedup_stats());
+
+        let mut engine = Engine::new("");
+        compile(&mut engine, "CALLREF {\n  INC\n}\nJMPREF {\n  DEC\n}").unwrap();
+        assert_eq!(engine.dedup_stats(), DedupStats { merged: 0, distinct: 3 });
+    }
+}
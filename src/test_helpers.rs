@@ -0,0 +1,36 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+// Helpers shared by the unit tests of the modules
+
+use ever_block::Cell;
+use crate::{CompileError, Engine, OperationError};
+
+/// Compiles the toplevel source by the engine into the code cell
+pub(crate) fn compile(engine: &mut Engine, source: &str) -> Result<Cell, CompileError> {
+    let units = engine.compile_toplevel(source)?;
+    Ok(engine.finalize(units).0.into_cell().unwrap())
+}
+
+/// Error inside nested blocks which failed the compilation of the source
+pub(crate) fn innermost_error(engine: &mut Engine, source: &str) -> CompileError {
+    compile(engine, source).unwrap_err().innermost().clone()
+}
+
+/// Operation error inside nested blocks which failed the compilation of the source
+pub(crate) fn operation_error(engine: &mut Engine, source: &str) -> OperationError {
+    match innermost_error(engine, source) {
+        CompileError::Operation(_, _, error) => error,
+        error => panic!("unexpected error {}", error),
+    }
+}
@@ -0,0 +1,198 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+//! Generator of random assembly for differential testing and fuzzing
+//!
+//! The code consists of instructions with operands in their valid ranges and
+//! continuations nested up to the given depth, so it always compiles. Whether
+//! it runs without exceptions is not taken care of. Instructions switching the
+//! codepage are not generated since the rest of the code would be lost to the
+//! assembler and the disassembler alike.
+
+use crate::instructions::{instructions, InstructionInfo, OperandKind, TvmVersion};
+
+/// Source of random numbers
+pub trait Rng {
+    fn next_u64(&mut self) -> u64;
+
+    /// Number in `0..bound`, bound must not be 0
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+/// Small deterministic generator to reproduce failures by the seed
+#[derive(Clone, Debug)]
+pub struct SplitMix64(pub u64);
+
+impl Rng for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Limits of the generated code
+#[derive(Clone, Debug)]
+pub struct ArbitraryOptions {
+    /// maximum nesting of continuations
+    pub max_depth: usize,
+    /// maximum number of instructions in a block
+    pub max_instructions: usize,
+    /// instructions introduced later are not generated
+    pub target: TvmVersion,
+}
+
+impl Default for ArbitraryOptions {
+    fn default() -> Self {
+        Self { max_depth: 3, max_instructions: 16, target: TvmVersion::LATEST }
+    }
+}
+
+/// Instructions taking a single block of code
+const BLOCK_INSTRUCTIONS: [&str; 8] = [
+    "PUSHCONT", "CALLREF", "JMPREF", "IFREF", "IFNOTREF", "IFJMPREF", "IFNOTJMPREF", "PUSHREFCONT",
+];
+
+/// Valid control registers
+const CONTROL_REGISTERS: [i64; 7] = [0, 1, 2, 3, 4, 5, 7];
+
+struct Generator<'a, R: Rng> {
+    rng: &'a mut R,
+    opts: &'a ArbitraryOptions,
+    /// instructions with fully described operands
+    simple: Vec<InstructionInfo>,
+    text: String,
+}
+
+impl<R: Rng> Generator<'_, R> {
+    fn pick<'b, T>(&mut self, items: &'b [T]) -> &'b T {
+        &items[self.rng.below(items.len() as u64) as usize]
+    }
+
+    fn in_range(&mut self, start: i64, end: i64) -> i64 {
+        start + self.rng.below((end - start + 1) as u64) as i64
+    }
+
+    fn integer(&mut self) -> String {
+        match self.rng.below(3) {
+            0 => self.in_range(-5, 10).to_string(),
+            1 => self.in_range(-128, 127).to_string(),
+            _ => (self.rng.next_u64() as i64).to_string(),
+        }
+    }
+
+    fn slice(&mut self) -> String {
+        let digits = self.rng.below(32) as usize;
+        let hex = (0..digits).map(|_| format!("{:X}", self.rng.below(16))).collect::<String>();
+        format!("x{{{}}}", hex)
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        self.text += &"  ".repeat(depth);
+        self.text += text;
+        self.text.push('\n');
+    }
+
+    fn simple_instruction(&mut self, depth: usize) {
+        let index = self.rng.below(self.simple.len() as u64) as usize;
+        let insn = &self.simple[index];
+        let name = insn.name;
+        let operands = insn.operands.clone().unwrap_or_default();
+        let values = operands.iter().map(|operand| {
+            let (start, end) = (*operand.range.start(), *operand.range.end());
+            match operand.kind {
+                OperandKind::Integer if name == "PLDUZ" => (32 * self.in_range(1, 8)).to_string(),
+                OperandKind::Integer => self.in_range(start, end).to_string(),
+                OperandKind::StackRegister => format!("s{}", self.in_range(start, end)),
+                OperandKind::ControlRegister => format!("c{}", self.pick(&CONTROL_REGISTERS)),
+            }
+        }).collect::<Vec<_>>();
+        match values.is_empty() {
+            true => self.line(depth, name),
+            false => self.line(depth, &format!("{} {}", name, values.join(", "))),
+        }
+    }
+
+    fn block(&mut self, depth: usize) {
+        let count = self.rng.below(self.opts.max_instructions as u64 + 1);
+        for _ in 0..count {
+            let nested = depth < self.opts.max_depth;
+            match self.rng.below(10) {
+                0 => {
+                    let value = self.integer();
+                    self.line(depth, &format!("PUSHINT {}", value));
+                }
+                1 => {
+                    let slice = self.slice();
+                    self.line(depth, &format!("PUSHSLICE {}", slice));
+                }
+                2 => {
+                    let register = self.in_range(0, 255);
+                    let name = self.pick(&["PUSH", "POP"]);
+                    self.line(depth, &format!("{} s{}", name, register));
+                }
+                3 if nested => {
+                    let name = self.pick(&BLOCK_INSTRUCTIONS);
+                    self.line(depth, &format!("{} {{", name));
+                    self.block(depth + 1);
+                    self.line(depth, "}");
+                }
+                4 if nested => {
+                    self.line(depth, "IFREFELSEREF {");
+                    self.block(depth + 1);
+                    self.line(depth, "} {");
+                    self.block(depth + 1);
+                    self.line(depth, "}");
+                }
+                _ => self.simple_instruction(depth),
+            }
+        }
+    }
+}
+
+/// Random assembly source which compiles for the target of the options
+pub fn arbitrary_code(rng: &mut impl Rng, opts: &ArbitraryOptions) -> String {
+    let simple = instructions()
+        .filter(|insn| insn.operands.is_some() && insn.introduced <= opts.target)
+        .filter(|insn| !insn.name.starts_with("SETCP"))
+        .collect();
+    let mut generator = Generator { rng, opts, simple, text: String::new() };
+    generator.block(0);
+    generator.text
+}
+
+#[cfg(test)]
+mod tests {
+    use ever_block::SliceData;
+    use super::*;
+
+    #[test]
+    fn arbitrary_code_compiles() {
+        let opts = ArbitraryOptions::default();
+        for seed in 0..200 {
+            let code = arbitrary_code(&mut SplitMix64(seed), &opts);
+            let cell = crate::compile_code_to_cell(&code)
+                .unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, code));
+            // the listing must compile back to the same cell when it can be produced
+            if let Ok(listing) = crate::disasm::disasm(&mut SliceData::load_cell(cell.clone()).unwrap()) {
+                let again = crate::compile_code_to_cell(&listing)
+                    .unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, listing));
+                assert_eq!(cell.repr_hash(), again.repr_hash(), "seed {}\n{}", seed, listing);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+// Stack variables ***********************************************************
+//
+// `.vars owner, amount` names the values on the top of the stack, `amount`
+// being the top one, and `$owner` in place of a stack register stands for the
+// register the value is at, e.g. `PUSH $owner`. The source is parsed first,
+// the stack analysis follows the named values through the code, and the uses
+// are replaced with the registers before the code is compiled. A use the
+// analysis does not reach, or one resolving to different registers on
+// different paths, is an error.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    CompileError, CompileResult, DbgPos, Engine, OperationError, ParameterError, Position,
+    ToOperationParameterError, Writer,
+    constants::is_identifier,
+    ir::{IrArg, IrInstruction},
+    stack::{resolve_var_uses, VarUse},
+};
+
+/// Whether the source seems to declare stack variables
+pub(crate) fn declares_vars(source: &str) -> bool {
+    source.to_ascii_lowercase().contains(".vars")
+}
+
+/// `.vars` is only checked here, the names are given by the stack analysis
+pub(crate) fn compile_vars(_engine: &mut Engine, par: &[&str], _destination: &mut dyn Writer, _pos: DbgPos) -> CompileResult {
+    if par.is_empty() {
+        return Err(OperationError::MissingRequiredParameters)
+    }
+    for (i, name) in par.iter().enumerate() {
+        if !is_identifier(name) {
+            return Err(ParameterError::UnexpectedType.parameter(format!("arg {}", i)))
+        }
+    }
+    Ok(())
+}
+
+fn rewrite(
+    code: &mut Vec<IrInstruction>,
+    uses: &HashMap<VarUse, BTreeSet<usize>>,
+    ambiguous: &HashSet<String>,
+) -> Result<(), CompileError> {
+    code.retain(|insn| insn.name != ".VARS");
+    for insn in code.iter_mut() {
+        let pos = insn.pos.clone().unwrap_or_default();
+        for (index, arg) in insn.args.iter_mut().enumerate() {
+            let token = match arg {
+                IrArg::Block(block) => {
+                    rewrite(block, uses, ambiguous)?;
+                    continue
+                }
+                IrArg::Token(token) if token.starts_with('$') => token,
+                _ => continue
+            };
+            let key = (pos.filename.clone(), pos.line, pos.column, index);
+            let registers = uses.get(&key).map(|r| r.iter().collect::<Vec<_>>()).unwrap_or_default();
+            let name = token[1..].to_string();
+            let error = match registers.as_slice() {
+                [register] => {
+                    *token = format!("s{}", register);
+                    continue
+                }
+                [] if !ambiguous.contains(&name) => OperationError::UnknownStackVariable(name),
+                _ => OperationError::AmbiguousStackVariable(name),
+            };
+            let position = Position::from_dbgpos(&pos).with_token(token);
+            return Err(CompileError::Operation(position, insn.name.clone(), error))
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the uses of stack variables in the parsed program with the registers
+/// and removes `.vars`
+pub(crate) fn resolve_vars(code: &mut Vec<IrInstruction>) -> Result<(), CompileError> {
+    let (uses, ambiguous) = resolve_var_uses(code);
+    rewrite(code, &uses, &ambiguous)
+}
+
+impl Engine {
+    /// Source with the stack variables replaced with the registers
+    pub(crate) fn resolve_stack_vars(&mut self, source: &str) -> Result<String, CompileError> {
+        let definitions = self.definitions();
+        let source_name = self.source_name.to_string();
+        let mut ir = self.parse_ir(source)?;
+        self.restore(definitions);
+        resolve_vars(&mut ir.instructions)?;
+        self.reset(source_name);
+        Ok(ir.to_source_located())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{compile, operation_error};
+
+    #[test]
+    fn vars_resolved() {
+        let code = compile(&mut Engine::new(""), ".vars a, b\nPUSH $a\nXCHG $b").unwrap();
+        let plain = compile(&mut Engine::new(""), "PUSH s1\nXCHG s1").unwrap();
+        assert_eq!(code.repr_hash(), plain.repr_hash());
+    }
+
+    #[test]
+    fn vars_errors() {
+        assert_eq!(
+            operation_error(&mut Engine::new(""), ".vars a\nPUSH $b"),
+            OperationError::UnknownStackVariable("b".to_string())
+        );
+        // the values are swapped on one of the paths only
+        assert_eq!(
+            operation_error(&mut Engine::new(""), ".vars a, b, flag\nPUSHCONT {\n  SWAP\n}\nIF\nPUSH $a"),
+            OperationError::AmbiguousStackVariable("a".to_string())
+        );
+    }
+}
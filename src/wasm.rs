@@ -0,0 +1,44 @@
+/*
+* Copyright (C) 2019-2024 EverX. All Rights Reserved.
+*
+* Licensed under the SOFTWARE EVALUATION License (the "License"); you may not use
+* this file except in compliance with the License.
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific EVERX DEV software governing permissions and
+* limitations under the License.
+*/
+
+
+//! Bindings for web IDEs and explorers built with `wasm-bindgen`
+//!
+//! The crate is built for `wasm32-unknown-unknown` as a `cdylib` without
+//! the default features, e.g. `wasm-pack build -- --no-default-features
+//! --features wasm`, so files are not read and nothing is run in threads.
+
+use ever_block::{base64_encode, write_boc};
+use wasm_bindgen::prelude::*;
+
+use crate::compile_code_with_hash;
+
+/// Compiled code as returned to JavaScript
+#[wasm_bindgen(getter_with_clone)]
+pub struct Compiled {
+    /// boc of the code cell in base64
+    pub boc_base64: String,
+    /// debug map in JSON
+    pub dbg_json: String,
+    pub code_hash: String,
+}
+
+/// Compiles the source, an error is thrown with the message rendered along with the source line
+#[wasm_bindgen]
+pub fn compile(code: &str) -> Result<Compiled, JsError> {
+    let (cell, hash, dbg) = compile_code_with_hash(code)
+        .map_err(|e| JsError::new(&e.render(Some(code), false)))?;
+    let boc = write_boc(&cell).map_err(|e| JsError::new(&e.to_string()))?;
+    let dbg_json = serde_json::to_string(&dbg).map_err(|e| JsError::new(&e.to_string()))?;
+    Ok(Compiled { boc_base64: base64_encode(boc), dbg_json, code_hash: hash.to_hex_string() })
+}
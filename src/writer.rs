@@ -11,20 +11,128 @@
 * limitations under the License.
 */
 
-use crate::{OperationError, DbgInfo, CompileResult};
-use ever_block::{BuilderData, SliceData};
+use std::collections::HashSet;
+use crate::{OperationError, DbgInfo, CompileResult, Diagnostic};
+use ever_block::{BuilderData, Cell, SliceData};
 
-use crate::debug::DbgNode;
+use crate::events::Listener;
+use crate::debug::{CellRole, DbgNode, DbgPos};
+use crate::disasm::{loader::Loader, types::{Code, InstructionParameter}};
 
-#[derive(Clone, Default)]
+/// How inlined fragments are placed when they do not fit into the current cell
+///
+/// Code cells form a chain, each one jumping to the next by its last reference,
+/// so a fragment starting a new cell makes the chain and the tree deeper.
+/// A fragment called by CALLREF hangs off the current cell instead at the cost
+/// of the call instruction and its gas. The called continuation has a return
+/// and a codepage of its own, so only fragments which neither return, jump
+/// away, touch c0 or c1 nor switch the codepage are called; the others are
+/// inlined whatever the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PackingPolicy {
+    /// fragments always continue the chain: fewest cells, deepest tree
+    #[default]
+    Greedy,
+    /// fragments of half a cell or more are called by CALLREF
+    Balanced,
+    /// every fragment is called by CALLREF
+    MinimizeDepth,
+}
+
+/// Bits of a cell
+const CELL_BITS: usize = 1023;
+
+impl PackingPolicy {
+    /// Whether the fragment had better be called by CALLREF than written to the chain,
+    /// given it is [`callable`]
+    pub(crate) fn prefers_call(self, destination: &dyn Writer, unit: &Unit) -> bool {
+        if destination.fits(unit) {
+            return false
+        }
+        match self {
+            PackingPolicy::Greedy => false,
+            PackingPolicy::Balanced => unit.bits() * 2 >= CELL_BITS,
+            PackingPolicy::MinimizeDepth => true,
+        }
+    }
+}
+
+/// Instructions which behave differently in a continuation called by CALLREF
+/// than in the code it is inlined into
+const CONTINUATION_DEPENDENT: &[&str] = &[
+    "RET", "RETALT", "RETBOOL", "RETARGS", "RETVARARGS", "RETDATA",
+    "IFRET", "IFNOTRET", "IFRETALT", "IFNOTRETALT",
+    "JMPX", "JMPXARGS", "JMPXVARARGS", "JMPXDATA", "JMPREF", "JMPREFDATA", "JMPDICT",
+    "IFJMP", "IFNOTJMP", "IFJMPREF", "IFNOTJMPREF",
+    "CALLCC", "CALLCCARGS", "CALLCCVARARGS",
+    "REPEATEND", "REPEATENDBRK", "UNTILEND", "UNTILENDBRK",
+    "WHILEEND", "WHILEENDBRK", "AGAINEND", "AGAINENDBRK",
+    "PUSHCTRX", "POPCTRX", "SETEXITALT", "THENRET", "THENRETALT", "INVERT", "BOOLEVAL",
+    "SAMEALT", "SAMEALTSAVE", "COMPOS", "COMPOSALT", "COMPOSBOTH", "ATEXIT", "ATEXITALT",
+    "SETCP", "SETCPX", ".blob",
+];
+
+/// Whether calling the unit by CALLREF runs it the same as inlining it
+pub(crate) fn callable(unit: &Unit) -> bool {
+    let code = unit.builder().clone().into_cell()
+        .and_then(SliceData::load_cell)
+        .and_then(|mut slice| Loader::new(false).load(&mut slice, true));
+    match code {
+        Ok(code) => !depends_on_continuation(&code),
+        Err(_) => false,
+    }
+}
+
+fn depends_on_continuation(code: &Code) -> bool {
+    code.iter().any(|insn| {
+        CONTINUATION_DEPENDENT.contains(&insn.name()) || insn.params().iter().any(|param| match param {
+            InstructionParameter::ControlRegister(0 | 1) => true,
+            InstructionParameter::Code { code, .. } => depends_on_continuation(code),
+            _ => false,
+        })
+    })
+}
+
+impl std::fmt::Display for PackingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            PackingPolicy::Greedy => "greedy",
+            PackingPolicy::Balanced => "balanced",
+            PackingPolicy::MinimizeDepth => "minimize-depth",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for PackingPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "greedy" => Ok(PackingPolicy::Greedy),
+            "balanced" => Ok(PackingPolicy::Balanced),
+            "minimize-depth" => Ok(PackingPolicy::MinimizeDepth),
+            _ => Err(format!("unknown packing policy {}, expected greedy, balanced or minimize-depth", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Unit {
     builder: BuilderData,
     dbg: DbgNode,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Unit {
     pub fn new(builder: BuilderData, dbg: DbgNode) -> Self {
-        Self { builder, dbg }
+        Self { builder, dbg, diagnostics: Vec::new() }
+    }
+    pub fn with_diagnostics(self, diagnostics: Vec<Diagnostic>) -> Self {
+        Self { diagnostics, ..self }
+    }
+    /// Warnings and notes reported while compiling the unit
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
     }
     pub fn finalize(self) -> (SliceData, DbgInfo) {
         let cell = self.builder.into_cell().unwrap();
@@ -32,10 +140,170 @@ impl Unit {
         let dbg_info = DbgInfo::from(cell, self.dbg);
         (slice, dbg_info)
     }
+    /// Code of the unit's root cell
+    pub fn builder(&self) -> &BuilderData {
+        &self.builder
+    }
+    /// Debug info of the unit's tree
+    pub fn dbg(&self) -> &DbgNode {
+        &self.dbg
+    }
+    /// Number of data bits in the unit's root cell
+    pub fn bits(&self) -> usize {
+        self.builder.bits_used()
+    }
+    /// Number of data bits in the unit's tree, chained and referred cells included,
+    /// each distinct cell counted once
+    pub fn tree_bits(&self) -> usize {
+        let mut visited = HashSet::new();
+        let mut bits = self.builder.bits_used();
+        let mut stack = self.builder.references().to_vec();
+        while let Some(cell) = stack.pop() {
+            if visited.insert(cell.repr_hash()) {
+                bits += cell.bit_length();
+                stack.extend((0..cell.references_count()).filter_map(|i| cell.reference(i).ok()));
+            }
+        }
+        bits
+    }
+    pub fn into_parts(self) -> (BuilderData, DbgNode) {
+        (self.builder, self.dbg)
+    }
+    /// Marks the unit as produced from the named fragment
+    pub fn with_fragment(self, name: &str) -> Self {
+        let mut clone = self;
+        clone.dbg.fragment = Some(name.to_string());
+        clone
+    }
+    /// Marks the unit's code as inlined under the name at the given position
+    pub fn inlined_at(self, name: &str, site: &DbgPos) -> Self {
+        Self { dbg: self.dbg.inlined_at(name, site), ..self }
+    }
+    /// Enumerates all cells of the unit's tree with their roles
+    pub fn cells(&self) -> ever_block::Result<CellIter> {
+        let cell = self.builder.clone().into_cell()?;
+        Ok(CellIter::new(cell, &self.dbg))
+    }
+}
+
+/// A cell of the compiled tree
+#[derive(Clone, Debug)]
+pub struct CellEntry {
+    /// indices of references leading from the root to the cell
+    pub path: Vec<usize>,
+    pub cell: Cell,
+    pub role: CellRole,
+    /// name of the fragment the cell was produced from if known
+    pub fragment: Option<String>,
+}
+
+/// Depth-first iterator over a tree of cells and its debug nodes;
+/// a reference failing to load ends the iteration with its error
+pub struct CellIter<'a> {
+    stack: Vec<(Vec<usize>, Cell, Option<&'a DbgNode>, CellRole)>,
+}
+
+impl<'a> CellIter<'a> {
+    pub fn new(cell: Cell, dbg: &'a DbgNode) -> Self {
+        Self { stack: vec!((vec!(), cell, Some(dbg), dbg.role)) }
+    }
+}
+
+impl<'a> Iterator for CellIter<'a> {
+    type Item = ever_block::Result<CellEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, cell, dbg, role) = self.stack.pop()?;
+        for i in (0..cell.references_count()).rev() {
+            let child = match cell.reference(i) {
+                Ok(child) => child,
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e))
+                }
+            };
+            let child_dbg = dbg.and_then(|d| d.children.get(i));
+            let child_role = match child_dbg.map(|d| d.role) {
+                // everything below a data cell is data as well
+                Some(CellRole::Code) | None if role == CellRole::Data => CellRole::Data,
+                Some(child_role) => child_role,
+                None => role,
+            };
+            let mut child_path = path.clone();
+            child_path.push(i);
+            self.stack.push((child_path, child, child_dbg, child_role));
+        }
+        let fragment = dbg.and_then(|d| d.fragment.clone());
+        Some(Ok(CellEntry { path, cell, role, fragment }))
+    }
+}
+
+/// Destination of the code produced by instruction handlers
+///
+/// [`Units`] packs the code into a chain of cells; a custom writer passed to
+/// [`Engine::build_with`](crate::Engine::build_with) may wrap it to observe
+/// every instruction written at the toplevel, e.g. to record instruction
+/// boundaries. Nested blocks are always written by [`Units`].
+pub trait Writer {
+    /// Writes bitstring of the given length
+    fn write_command_bitstring(&mut self, command: &[u8], bits: usize, dbg: DbgNode) -> CompileResult;
+    /// Writes bitstring with additional references, keeping them in the same cell
+    fn write_composite_command_bitstring(
+        &mut self,
+        command: &[u8],
+        bits: usize,
+        references: Vec<BuilderData>,
+        dbg: DbgNode,
+    ) -> CompileResult;
+    /// Writes assembled unit
+    fn write_unit(&mut self, unit: Unit) -> CompileResult;
+    /// Whether the unit can be inlined into the current cell
+    fn fits(&self, unit: &Unit) -> bool;
+    /// Puts the code written so far into a cell tree leaving the writer empty
+    fn finish(&mut self) -> (BuilderData, DbgNode);
+    /// Writes simple command
+    fn write_command(&mut self, command: &[u8], dbg: DbgNode) -> CompileResult {
+        self.write_command_bitstring(command, command.len() * 8, dbg)
+    }
+    /// Writes command with additional references
+    fn write_composite_command(
+        &mut self,
+        command: &[u8],
+        references: Vec<BuilderData>,
+        dbg: DbgNode,
+    ) -> CompileResult {
+        self.write_composite_command_bitstring(command, command.len() * 8, references, dbg)
+    }
+}
+
+impl Writer for Units {
+    fn write_command_bitstring(&mut self, command: &[u8], bits: usize, dbg: DbgNode) -> CompileResult {
+        Units::write_command_bitstring(self, command, bits, dbg)
+    }
+    fn write_composite_command_bitstring(
+        &mut self,
+        command: &[u8],
+        bits: usize,
+        references: Vec<BuilderData>,
+        dbg: DbgNode,
+    ) -> CompileResult {
+        Units::write_composite_command_bitstring(self, command, bits, references, dbg)
+    }
+    fn write_unit(&mut self, unit: Unit) -> CompileResult {
+        Units::write_unit(self, unit)
+    }
+    fn fits(&self, unit: &Unit) -> bool {
+        Units::fits(self, unit)
+    }
+    fn finish(&mut self) -> (BuilderData, DbgNode) {
+        std::mem::take(self).finalize()
+    }
 }
 
 pub struct Units {
-    units: Vec<Unit>
+    units: Vec<Unit>,
+    /// consumer of the cell splits
+    listener: Option<Listener>,
 }
 
 impl Default for Units {
@@ -47,7 +315,61 @@ impl Default for Units {
 impl Units {
     /// Constructor
     pub fn new() -> Self {
-        Self { units: vec!(Unit::default()) }
+        Self::with_listener(None)
+    }
+    pub(crate) fn with_listener(listener: Option<Listener>) -> Self {
+        Self { units: vec!(Unit::default()), listener }
+    }
+    /// Cells written so far in the order they are chained, the first one becomes the root
+    pub fn cells(&self) -> &[Unit] {
+        &self.units
+    }
+    /// Replaces the code and debug info of the cells written so far by the ones returned
+    pub(crate) fn try_map_cells<E>(
+        &mut self,
+        mut f: impl FnMut(&BuilderData, &DbgNode) -> Result<Option<(BuilderData, DbgNode)>, E>,
+    ) -> Result<(), E> {
+        for unit in self.units.iter_mut() {
+            if let Some((builder, dbg)) = f(&unit.builder, &unit.dbg)? {
+                unit.builder = builder;
+                unit.dbg = dbg;
+            }
+        }
+        Ok(())
+    }
+    /// Writes simple command before all the code written so far
+    pub fn prepend_command(&mut self, command: &[u8], dbg: DbgNode) -> CompileResult {
+        self.prepend_command_bitstring(command, command.len() * 8, dbg)
+    }
+    /// Writes bitstring before all the code written so far
+    pub fn prepend_command_bitstring(&mut self, command: &[u8], bits: usize, dbg: DbgNode) -> CompileResult {
+        let builder = BuilderData::with_raw(command, bits).map_err(|_| OperationError::NotFitInSlice)?;
+        self.prepend_unit(Unit::new(builder, dbg))
+    }
+    /// Writes assembled unit before all the code written so far, in a cell
+    /// of its own if the first one doesn't fit into it
+    pub fn prepend_unit(&mut self, first: Unit) -> CompileResult {
+        if let Some(old) = self.units.first() {
+            let mut merged = first.clone();
+            let bits = merged.builder.bits_used();
+            let slice = SliceData::load_builder(old.builder.clone()).map_err(|_| OperationError::NotFitInSlice)?;
+            if merged.builder.checked_append_references_and_data(&slice).is_ok() {
+                merged.dbg.inline_node(bits, old.dbg.clone());
+                merged.diagnostics = old.diagnostics.clone();
+                self.units[0] = merged;
+                return Ok(())
+            }
+        }
+        self.units.insert(0, first);
+        Ok(())
+    }
+    /// Whether the unit can be inlined into the current cell, keeping one
+    /// reference for the next cell
+    pub fn fits(&self, unit: &Unit) -> bool {
+        self.units.last().map_or(false, |last| {
+            last.builder.bits_free() >= unit.builder.bits_used()
+                && last.builder.references_free() > unit.builder.references_used()
+        })
     }
     /// Writes assembled unit
     pub fn write_unit(&mut self, unit: Unit) -> CompileResult {
@@ -67,6 +389,7 @@ impl Units {
             }
         }
         if let Ok(new_last) = BuilderData::with_raw(command, bits) {
+            self.report_split();
             self.units.push(Unit::new(new_last, dbg));
             return Ok(());
         }
@@ -78,12 +401,22 @@ impl Units {
         command: &[u8],
         references: Vec<BuilderData>,
         dbg: DbgNode,
+    ) -> CompileResult {
+        self.write_composite_command_bitstring(command, command.len() * 8, references, dbg)
+    }
+    /// Writes bitstring with additional references, keeping them in the same cell
+    pub fn write_composite_command_bitstring(
+        &mut self,
+        command: &[u8],
+        bits: usize,
+        references: Vec<BuilderData>,
+        dbg: DbgNode,
     ) -> CompileResult {
         assert_eq!(references.len(), dbg.children.len());
         if let Some(mut last) = self.units.last().cloned() {
             let orig_offset = last.builder.bits_used();
             if last.builder.references_free() > references.len() // one cell remains reserved for finalization
-                && last.builder.append_raw(command, command.len() * 8).is_ok()
+                && last.builder.append_raw(command, bits).is_ok()
                 && checked_append_references(&mut last.builder, &references)? {
                 last.dbg.inline_node(orig_offset, dbg);
                 *self.units.last_mut().unwrap() = last;
@@ -91,13 +424,34 @@ impl Units {
             }
         }
         let mut new_last = BuilderData::new();
-        if new_last.append_raw(command, command.len() * 8).is_ok()
+        if new_last.append_raw(command, bits).is_ok()
             && checked_append_references(&mut new_last, &references)? {
+            self.report_split();
             self.units.push(Unit::new(new_last, dbg));
             return Ok(());
         }
         Err(OperationError::NotFitInSlice)
     }
+    fn report_split(&self) {
+        if let Some(last) = self.units.last() {
+            crate::events::emit(self.listener.as_ref(), || crate::events::CompileEvent::CellSplit {
+                bits: last.builder.bits_used(),
+                refs: last.builder.references_used(),
+            });
+        }
+    }
+    /// Puts recorded cells in a linear sequence ending with an implicit jump
+    /// to the given continuation, which is always kept in a separate cell
+    pub fn finalize_with_next(mut self, next: Unit) -> (BuilderData, DbgNode) {
+        let cell = next.builder.into_cell().expect("failed to convert builder to cell");
+        if self.units.last().map_or(true, |last| last.builder.references_free() == 0) {
+            self.units.push(Unit::default());
+        }
+        let last = self.units.last_mut().unwrap();
+        last.builder.checked_append_reference(cell).unwrap();
+        last.dbg.append_node(next.dbg);
+        self.finalize()
+    }
     /// Puts recorded cells in a linear sequence
     pub fn finalize(mut self) -> (BuilderData, DbgNode) {
         let mut cursor = self.units.pop().expect("cells can't be empty");
@@ -126,3 +480,18 @@ fn checked_append_references(builder: &mut BuilderData, refs: &[BuilderData]) ->
     }
     Ok(true)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::Engine;
+
+    #[test]
+    fn unit_cells() {
+        let unit = Engine::new("").build(None, "CALLREF {\n  NOP\n}\nCALLREF {\n  INC\n}").unwrap();
+        let paths = unit.cells().unwrap()
+            .map(|entry| entry.map(|entry| entry.path))
+            .collect::<ever_block::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(paths, vec!(vec!(), vec!(0), vec!(1)));
+    }
+}